@@ -11,6 +11,9 @@
 /// Extended Feature Enable Register (EFER) on IA-32
 pub const IA32_EFER: u32 = 0xc0000080;
 
+/// Page Attribute Table (PAT) on IA-32
+pub const IA32_PAT: u32 = 0x277;
+
 /// Write `value` to the specified `msr`
 ///
 /// # Arguments
@@ -46,3 +49,49 @@ pub unsafe fn enable_nxe() {
     let efer = read(IA32_EFER) | nxe_bit;
     write(IA32_EFER, efer);
 }
+
+/// Write back and invalidate every cache line with `wbinvd`.
+#[inline]
+unsafe fn wbinvd() {
+    asm!("wbinvd" :::: "volatile");
+}
+
+/// Invalidate every TLB entry, including global ones.
+unsafe fn flush_tlb() {
+    use control_regs::{cr3, cr4};
+    if cr4::is_global_enabled() {
+        cr4::enable_global(false);
+        cr4::enable_global(true);
+    } else {
+        cr3::write(cr3::read());
+    }
+}
+
+/// Make PAT entry 4 write-combining.
+///
+/// Entry 4 is selected by pages with only the PAT bit set, and is
+/// write-back by default.
+///
+/// The PAT is changed with the sequence the Intel SDM gives for changing
+/// memory types: the caches are disabled, and then the caches and the TLB
+/// are flushed before and after writing the MSR, so that nothing stays
+/// cached with the old memory type.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+/// + Interrupts should be disabled while this runs, and every CPU must
+///   program the same PAT.
+pub unsafe fn enable_write_combining() {
+    use control_regs::cr0;
+    let write_combining = 0x01 << 32;
+    let pat = (read(IA32_PAT) & !(0xff << 32)) | write_combining;
+    // enter no-fill cache mode
+    cr0::set_not_write_through(false);
+    cr0::disable_cache(true);
+    wbinvd();
+    flush_tlb();
+    write(IA32_PAT, pat);
+    wbinvd();
+    flush_tlb();
+    cr0::disable_cache(false);
+}
@@ -71,6 +71,14 @@ cpu_flag! {
         protect.",
     WP, is_write_protected, enable_write_protect
 }
+cpu_flag! {
+    doc="If set, the memory cache is disabled.",
+    CD, is_cache_disabled, disable_cache
+}
+cpu_flag! {
+    doc="If set, write-through caching is globally disabled.",
+    NW, is_not_write_through, set_not_write_through
+}
 
 ///// Set the write protect bit in `%cr0`.
 //pub fn enable_write_protect() {
@@ -82,6 +82,10 @@ bitflags! {
         ///
         /// If set, enables unmasked SSE exceptions.
         const OSXMMEXCPT = 1 << 10
+      , /// 57-bit Linear Addresses
+        ///
+        /// If set, 5-level paging is in use.
+        const LA57 = 1 << 12
       , /// Virtual Machine Extensions Enable
         const VMXE = 1 << 13
       , /// Safer Mode Extensions Enable
@@ -117,3 +121,20 @@ cpu_flag! {
     doc="If disabled, the `RTDSC` instruction can only be executed in Ring 0.",
     TSD, is_timestamp_disabled, disable_timestamp
 }
+
+cpu_flag! {
+    doc="If set, process-context identifiers are enabled.",
+    PCIDE, is_pcid_enabled, enable_pcid
+}
+
+cpu_flag! {
+    doc="If set, TLB entries for global pages are kept when `$cr3` is \
+        written to.",
+    PGE, is_global_enabled, enable_global
+}
+
+cpu_flag! {
+    doc="If set, 5-level paging is in use. This can't be changed while \
+        paging is enabled.",
+    LA57, is_la57_enabled
+}
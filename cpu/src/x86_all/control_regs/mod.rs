@@ -169,4 +169,72 @@ pub mod cr3 {
     pub unsafe fn set_pagetable_frame(frame: PhysicalPage) {
         write(frame.base_addr())
     }
+
+    /// Mask for the process-context identifier in `$cr3`.
+    pub const PCID_MASK: u64 = 0xfff;
+
+    /// Bit 63 of a value written to `$cr3`, which tells the CPU not to
+    /// flush the TLB entries tagged with the new PCID.
+    pub const NO_FLUSH: u64 = 1 << 63;
+
+    /// Returns true if the CPU supports process-context identifiers.
+    ///
+    /// This is reported by bit 17 of `%ecx` in `CPUID` leaf 1.
+    #[cfg(target_arch = "x86_64")]
+    pub fn is_pcid_supported() -> bool {
+        ::cpuid::cpuid(1, 0).ecx & (1 << 17) != 0
+    }
+
+    /// Returns the process-context identifier in `$cr3`.
+    ///
+    /// # Safety
+    /// + Reading from control registers while not in kernel mode will cause
+    ///   a general protection fault.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn current_pcid() -> u16 {
+        let value: u64 = read().into();
+        (value & PCID_MASK) as u16
+    }
+
+    /// Sets the current Page Directory base frame, tagged with a
+    /// process-context identifier.
+    ///
+    /// PCIDs are enabled in `%cr4` if they aren't already.
+    ///
+    /// # Arguments
+    /// + `frame`: the frame containing the new Page Directory
+    /// + `pcid`: the PCID to tag the new address space with; only the
+    ///           low 12 bits are used.
+    /// + `no_flush`: if true, the TLB entries already tagged with `pcid`
+    ///               are kept.
+    ///
+    /// # Returns
+    /// + `Err` if the CPU doesn't support PCIDs, or they couldn't be
+    ///   enabled because the current `$cr3` has a nonzero PCID field.
+    ///
+    /// # Safety
+    /// + Control registers should generally not be modified during normal
+    ///   operation.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn set_pagetable_frame_pcid( frame: PhysicalPage
+                                          , pcid: u16
+                                          , no_flush: bool)
+                                          -> Result<(), &'static str> {
+        use super::cr4;
+        if !is_pcid_supported() {
+            return Err("CPU does not support PCIDs")
+        }
+        if !cr4::is_pcid_enabled() {
+            // PCIDE can only be set while the PCID in `$cr3` is zero.
+            if current_pcid() != 0 {
+                return Err("can't enable PCIDs with a nonzero PCID in cr3")
+            }
+            cr4::enable_pcid(true);
+        }
+        let mut value: u64 = frame.base_addr().into();
+        value |= pcid as u64 & PCID_MASK;
+        if no_flush { value |= NO_FLUSH }
+        write(PAddr::from(value));
+        Ok(())
+    }
 }
@@ -0,0 +1,41 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Querying the CPU's features with the `CPUID` instruction.
+
+/// The registers returned by one `CPUID` leaf.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CpuidResult { pub eax: u32
+                       , pub ebx: u32
+                       , pub ecx: u32
+                       , pub edx: u32
+                       }
+
+/// Executes `CPUID` for a leaf and sub-leaf.
+///
+/// # Arguments
+/// + `leaf`: the leaf to query, in `%eax`
+/// + `subleaf`: the sub-leaf to query, in `%ecx`. Leaves without sub-leaves
+///              ignore this.
+///
+/// # Returns
+/// + the values of `%eax`, `%ebx`, `%ecx` and `%edx` after `CPUID`
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    // this is safe because `CPUID` is available in every mode, and only
+    // writes the four registers it returns.
+    unsafe {
+        asm!(  "cpuid"
+            :  "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            :  "{eax}"(leaf), "{ecx}"(subleaf)
+            :
+            :  "volatile");
+    }
+    CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
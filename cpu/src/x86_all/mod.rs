@@ -39,6 +39,7 @@ macro_rules! cpu_flag {
 }
 
 pub mod control_regs;
+pub mod cpuid;
 pub mod segment;
 pub mod dtable;
 pub mod flags;
@@ -7,18 +7,131 @@
 //  directory of this repository for more information.
 //
 //! Architecture-specific memory management.
-use ::{Addr, Page};
+use ::{Addr, Page, VAddr, VirtualPage};
 
 use core::{fmt, ops, mem};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+/// The number of bits in the offset into a base page (4KiB pages).
+///
+/// Every page size calculation derives from this, so it's the only place
+/// the base page size is defined.
+#[cfg(not(feature = "base2m"))]
 pub const PAGE_SHIFT: u8 = 12;
-/// The size of a page (4KiB), in bytes
-pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT; // 4k
+/// The number of bits in the offset into a base page (2MiB pages, with the
+/// `base2m` feature).
+#[cfg(feature = "base2m")]
+pub const PAGE_SHIFT: u8 = 21;
+/// The size of a base page, in bytes
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
 /// The size of a large page (2MiB) in bytes
 pub const LARGE_PAGE_SIZE: u64 = 1024 * 1024 * 2;
-/// The size of a huge page (2GiB) in bytes
+/// The size of a huge page (1GiB) in bytes
 pub const HUGE_PAGE_SIZE: u64 = 1024 * 1024 * 1024;
 
+/// The value of `PHYS_MAP_OFFSET` before it's been set.
+const NO_PHYS_MAP: usize = !0;
+
+/// The virtual address that physical memory is mapped at, once it's been
+/// set with `set_phys_map_offset`.
+static PHYS_MAP_OFFSET: AtomicUsize = AtomicUsize::new(NO_PHYS_MAP);
+
+/// Set the virtual address that all of physical memory is mapped at.
+///
+/// This should be called once, after the window has been mapped (such as
+/// by `paging::arch::map_physical_memory`), so that frames can be accessed
+/// with `PhysicalPage::to_virt`.
+///
+/// # Panics
+/// + If the offset was already set to a different address
+pub fn set_phys_map_offset(offset: VAddr) {
+    let old = PHYS_MAP_OFFSET.compare_and_swap( NO_PHYS_MAP, offset.0
+                                              , Ordering::SeqCst);
+    assert!( old == NO_PHYS_MAP || old == offset.0
+           , "physical memory is already mapped at {:#x}, not {:#x}"
+           , old, offset.0);
+}
+
+/// Returns the virtual address that all of physical memory is mapped at.
+///
+/// # Returns
+/// + `Some(VAddr)` if `set_phys_map_offset` has been called
+/// + `None` if it hasn't
+#[inline]
+pub fn phys_map_offset() -> Option<VAddr> {
+    match PHYS_MAP_OFFSET.load(Ordering::Relaxed) {
+        NO_PHYS_MAP => None
+      , offset => Some(VAddr(offset))
+    }
+}
+
+/// Returns the physical memory window's offset.
+///
+/// # Panics
+/// + If `set_phys_map_offset` hasn't been called
+#[inline]
+fn expect_phys_map() -> VAddr {
+    phys_map_offset()
+        .expect("physical memory isn't mapped; call set_phys_map_offset \
+                 before converting frames to virtual addresses")
+}
+
+impl VAddr {
+    /// Returns true if this address is canonical.
+    ///
+    /// On x86_64, bits 48 through 63 of a virtual address must all be copies
+    /// of bit 47.
+    #[inline]
+    pub fn is_canonical(&self) -> bool {
+        let upper = self.0 >> 47;
+        upper == 0 || upper == (1 << 17) - 1
+    }
+
+    /// Returns a new `VAddr`, if `addr` is canonical.
+    ///
+    /// # Returns
+    /// + `Some(VAddr)` if `addr` is a canonical address
+    /// + `None` if it is not
+    #[inline]
+    pub fn new_canonical(addr: usize) -> Option<VAddr> {
+        let vaddr = VAddr(addr);
+        if vaddr.is_canonical() { Some(vaddr) } else { None }
+    }
+
+    /// Returns the physical address this address maps to, if it lies in a
+    /// window mapping all of physical memory at `offset`.
+    ///
+    /// This is the inverse of `PAddr::to_virt`.
+    #[inline]
+    pub fn to_phys(&self, offset: VAddr) -> PAddr {
+        debug_assert!( self.0 >= offset.0
+                     , "{:?} is below the physical memory window", self);
+        PAddr((self.0 - offset.0) as u64)
+    }
+
+    /// Returns the offset of this address from the start of its page.
+    #[inline]
+    pub fn page_offset(&self) -> usize {
+        self.0 % PAGE_SIZE as usize
+    }
+
+    /// Returns this address rounded down to the start of its page.
+    #[inline]
+    pub fn page_base(&self) -> VAddr {
+        VAddr(self.0 - self.page_offset())
+    }
+
+    /// Returns the page containing this address.
+    #[inline]
+    pub fn page(&self) -> VirtualPage { VirtualPage::containing(*self) }
+
+    /// Returns the page containing this address, and the offset of this
+    /// address from the start of that page.
+    #[inline]
+    pub fn page_offset_pair(&self) -> (VirtualPage, usize) {
+        (self.page(), self.page_offset())
+    }
+}
 
 macro_attr! {
     /// A physical (linear) memory address is a 64-bit unsigned integer
@@ -27,6 +140,32 @@ macro_attr! {
     pub struct PAddr(u64);
 }
 
+impl PAddr {
+    /// Returns the virtual address of this address in a window mapping all
+    /// of physical memory at `offset`.
+    #[inline]
+    pub fn to_virt(&self, offset: VAddr) -> VAddr {
+        VAddr(self.0 as usize + offset.0)
+    }
+
+    /// Returns the offset of this address from the start of its frame.
+    #[inline]
+    pub fn frame_offset(&self) -> u64 {
+        self.0 % PAGE_SIZE
+    }
+
+    /// Returns the frame containing this address.
+    #[inline]
+    pub fn frame(&self) -> PhysicalPage { PhysicalPage::containing_addr(*self) }
+
+    /// Returns the frame containing this address, and the offset of this
+    /// address from the start of that frame.
+    #[inline]
+    pub fn frame_offset_pair(&self) -> (PhysicalPage, u64) {
+        (self.frame(), self.frame_offset())
+    }
+}
+
 macro_attr! {
     /// A frame (physical page)
     //  TODO: consider renaming this to `Frame` (less typing)?
@@ -40,6 +179,12 @@ impl fmt::Debug for PhysicalPage {
     }
 }
 
+impl fmt::Display for PhysicalPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame#{} @ {:#x}", self.number, *self.base_addr())
+    }
+}
+
 impl ops::Add<usize> for PhysicalPage {
     type Output = Self;
 
@@ -70,6 +215,14 @@ impl ops::SubAssign<usize> for PhysicalPage {
 
 impl PhysicalPage {
 
+    /// Returns the number of frames needed to hold `bytes` bytes.
+    ///
+    /// A partial frame at the end counts as a whole frame.
+    #[inline]
+    pub fn frames_for_bytes(bytes: usize) -> usize {
+        VirtualPage::pages_for_bytes(bytes)
+    }
+
     /// Returns the physical address where this frame starts.
     #[inline]
     pub const fn base_addr(&self) -> PAddr {
@@ -77,20 +230,110 @@ impl PhysicalPage {
     }
 
     /// Returns a new frame containing `addr`
+    ///
+    /// Any bits of `addr` below the page size are discarded, so an
+    /// unaligned address gives the frame it falls within. Use
+    /// `try_containing` when `addr` must be the start of a frame.
     #[inline]
     pub const fn containing_addr(addr: PAddr) -> PhysicalPage {
         PhysicalPage { number: addr.0 >> PAGE_SHIFT }
     }
 
-    /// Convert the frame into a raw pointer to the frame's base address
+    /// Returns the frame starting at `addr`.
+    ///
+    /// # Returns
+    /// + `Ok(PhysicalPage)` if `addr` is page aligned
+    /// + `Err(PAddr)` with the original address if it is not
+    #[inline]
+    pub fn try_containing(addr: PAddr) -> Result<PhysicalPage, PAddr> {
+        if addr.frame_offset() == 0 {
+            Ok(PhysicalPage::containing_addr(addr))
+        } else {
+            Err(addr)
+        }
+    }
+
+    /// Returns the virtual address of this frame in the window mapping all
+    /// of physical memory.
+    ///
+    /// # Panics
+    /// + If `set_phys_map_offset` hasn't been called
+    #[inline]
+    pub fn to_virt(&self) -> VAddr {
+        self.to_virt_with(expect_phys_map())
+    }
+
+    /// Returns the virtual address of this frame in a window mapping all of
+    /// physical memory that starts at `offset`.
+    ///
+    /// This doesn't read the offset set with `set_phys_map_offset`.
+    #[inline]
+    pub fn to_virt_with(&self, offset: VAddr) -> VAddr {
+        self.base_addr().to_virt(offset)
+    }
+
+    /// Returns the frame containing the memory `ptr` points to in the window
+    /// mapping all of physical memory.
+    ///
+    /// This is the inverse of `as_ptr` and `as_mut_ptr`.
+    ///
+    /// # Panics
+    /// + If `set_phys_map_offset` hasn't been called
+    #[inline]
+    pub fn from_ptr<T>(ptr: *const T) -> PhysicalPage {
+        PhysicalPage::from_ptr_with(ptr, expect_phys_map())
+    }
+
+    /// Returns the frame containing the memory `ptr` points to in a window
+    /// mapping all of physical memory that starts at `offset`.
+    ///
+    /// This is the inverse of `to_virt_with`.
+    #[inline]
+    pub fn from_ptr_with<T>(ptr: *const T, offset: VAddr) -> PhysicalPage {
+        let vaddr = VAddr(ptr as usize);
+        PhysicalPage::containing_addr(vaddr.to_phys(offset))
+    }
+
+    /// Returns a pointer to the start of this frame in the window mapping
+    /// all of physical memory.
+    ///
+    /// # Safety
+    /// + The pointer is only valid while the window is mapped, and nothing
+    ///   stops the frame from being freed while it's in use.
+    ///
+    /// # Panics
+    /// + If `set_phys_map_offset` hasn't been called
     #[inline]
     pub unsafe fn as_ptr<T>(&self) -> *const T {
-        mem::transmute(self.base_addr())
+        self.to_virt().as_ptr()
     }
 
-    /// Convert the frame into a raw mutable pointer to the frame's base address
+    /// Returns a mutable pointer to the start of this frame in the window
+    /// mapping all of physical memory.
+    ///
+    /// # Safety
+    /// + The pointer is only valid while the window is mapped, and nothing
+    ///   stops the frame from being freed, or aliased, while it's in use.
+    ///
+    /// # Panics
+    /// + If `set_phys_map_offset` hasn't been called
     #[inline]
     pub unsafe fn as_mut_ptr<T>(&self) -> *mut T {
+        self.to_virt().as_mut_ptr()
+    }
+
+    /// Convert the frame into a raw pointer to the frame's base address,
+    /// for code that still runs with physical memory identity mapped.
+    #[inline]
+    pub unsafe fn as_identity_ptr<T>(&self) -> *const T {
+        mem::transmute(self.base_addr())
+    }
+
+    /// Convert the frame into a raw mutable pointer to the frame's base
+    /// address, for code that still runs with physical memory identity
+    /// mapped.
+    #[inline]
+    pub unsafe fn as_identity_mut_ptr<T>(&self) -> *mut T {
         *self.base_addr() as *mut u8 as *mut T
     }
 
@@ -20,6 +20,7 @@
 
 #[macro_use] extern crate macro_attr;
 #[macro_use] extern crate util;
+#[cfg(test)] #[macro_use] extern crate std;
 // #[cfg(not(test))] #[macro_use] extern crate vga;
 // extern crate alloc as liballoc; // TODO: workaround
 
@@ -31,7 +32,8 @@ pub mod arch;
 use core::{ops, cmp, convert, fmt};
 use util::Align;
 
-pub use arch::{PAddr, PAGE_SHIFT, PAGE_SIZE};
+pub use arch::{PAddr, PAGE_SHIFT, PAGE_SIZE, LARGE_PAGE_SIZE, HUGE_PAGE_SIZE};
+pub use arch::{phys_map_offset, set_phys_map_offset};
 
 /// Trait representing an address, whether physical or virtual.
 pub trait Addr: ops::Add<Self> + ops::Sub<Self>
@@ -42,9 +44,21 @@ pub trait Addr: ops::Add<Self> + ops::Sub<Self>
               + Sized {
     type Repr: Align;
 
+    /// Round this address down to a multiple of `align`.
+    ///
+    /// `align` must be a power of two.
     fn align_down(&self, align: Self::Repr) -> Self;
+
+    /// Round this address up to a multiple of `align`.
+    ///
+    /// `align` must be a power of two.
     fn align_up(&self, align: Self::Repr) -> Self;
 
+    /// Returns true if this address is a multiple of `align`.
+    ///
+    /// `align` must be a power of two.
+    fn is_aligned(&self, align: Self::Repr) -> bool;
+
     /// Returns true if this address is aligned on a page boundary.
     fn is_page_aligned(&self) -> bool;
 }
@@ -69,6 +83,13 @@ impl VAddr {
     /// Convert this virtual address to a `usize`.
     #[inline] pub const fn as_usize(&self) -> usize { self.0 }
 
+    /// Calculate the index in the PML5 table corresponding to this address.
+    ///
+    /// This is only meaningful when 5-level paging is in use.
+    #[inline] pub fn pml5_index(&self) -> usize {
+        *((self >> 48) & 0b111111111)
+    }
+
     /// Calculate the index in the PML4 table corresponding to this address.
     #[inline] pub fn pml4_index(&self) -> usize {
         *((self >> 39) & 0b111111111 as usize)
@@ -85,6 +106,9 @@ impl VAddr {
     }
 
     /// Calculate the index in the PT table corresponding to this address.
+    ///
+    /// Like the other table indices, this follows the hardware's 4KiB page
+    /// table layout, whatever `PAGE_SHIFT` is.
     #[inline] pub fn pt_index(&self) -> usize {
         *((self >> 12) & 0b111111111)
     }
@@ -115,6 +139,9 @@ where Self: Sized
 
     /// Returns a new `Page` containing the given `Address`.
     ///
+    /// The address is rounded down to the start of the page, so it need not
+    /// be page aligned.
+    ///
     /// N.B. that since trait functions cannot be `const`, implementors of
     /// this trait may wish to provide implementations of this function
     /// outside of the `impl` block and then wrap them here.
@@ -150,8 +177,36 @@ where Self: Sized
         Range { start: *self, end: end }
     }
 
+    /// Returns a `PageRange` from `start` until `end`, checking that the
+    /// range isn't inverted.
+    ///
+    /// This is for building ranges from addresses that can't be trusted,
+    /// such as those in ELF section headers.
+    ///
+    /// # Returns
+    /// + `Some(PageRange)` if `start` is not after `end`
+    /// + `None` if `end` is before `start`
+    #[inline]
+    fn range_checked(start: Self, end: Self) -> Option<Range<Self>> {
+        if end < start { None } else { Some(start.range_until(end)) }
+    }
+
     fn number(&self) -> usize;
 
+    /// Returns the page after this one.
+    #[inline]
+    fn next(&self) -> Self { *self + 1 }
+
+    /// Returns the page before this one.
+    ///
+    /// # Returns
+    /// + `Some(Page)` with the previous page
+    /// + `None` if this is page 0
+    #[inline]
+    fn prev(&self) -> Option<Self> {
+        if self.number() == 0 { None } else { Some(*self - 1) }
+    }
+
 }
 
 
@@ -164,10 +219,30 @@ macro_attr!{
 impl VirtualPage {
     fn containing_addr( addr: VAddr) -> Self {
         use ::PAGE_SHIFT;
-        assert!( (addr < 0x0000_8000_0000_0000) || (addr >= 0xffff_8000_0000_0000)
-               , "invalid address : 0x{:x}", addr );
+        debug_assert!( addr.is_canonical()
+                     , "non-canonical address : 0x{:x}", addr );
         Self { number: addr.0 >> PAGE_SHIFT }
     }
+
+    /// Returns the number of pages needed to hold `bytes` bytes.
+    ///
+    /// A partial page at the end counts as a whole page.
+    #[inline]
+    pub fn pages_for_bytes(bytes: usize) -> usize {
+        let page_size = PAGE_SIZE as usize;
+        // this can't overflow, unlike rounding `bytes` up first.
+        bytes / page_size + (bytes % page_size != 0) as usize
+    }
+
+    /// Returns this page's indices in the PML4, PDPT, PD, and PT tables.
+    ///
+    /// The indices are those of the page's base address, so they're the
+    /// same whatever size a base page is.
+    #[inline]
+    pub fn table_indices(&self) -> (usize, usize, usize, usize) {
+        let base = self.base();
+        (base.pml4_index(), base.pdpt_index(), base.pd_index(), base.pt_index())
+    }
 }
 
 impl fmt::Debug for VirtualPage {
@@ -176,6 +251,12 @@ impl fmt::Debug for VirtualPage {
     }
 }
 
+impl fmt::Display for VirtualPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "page#{} @ {:#x}", self.number, *self.base())
+    }
+}
+
 //
 ///// A range of `Page`s.
 //#[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -183,10 +264,43 @@ impl fmt::Debug for VirtualPage {
 //where P: Page { start: P, end: P }
 //
 pub trait MemRange {
+    /// The type of `Page` in this range.
+    type Page: Page;
+
     /// Returns the number of `Page`s in this ranage
     #[inline]
     fn length(&self) -> usize;
 
+    /// Returns the number of `Page`s in this range.
+    #[inline]
+    fn len(&self) -> usize { self.length() }
+
+    /// Returns true if this range contains no `Page`s.
+    #[inline]
+    fn is_empty(&self) -> bool { self.length() == 0 }
+
+    /// Returns true if `page` is in this range.
+    fn contains(&self, page: Self::Page) -> bool;
+
+    /// Returns true if this range contains no `Page`s.
+    ///
+    /// This is the same as `is_empty`, but it doesn't collide with `Range`'s
+    /// unstable inherent method, so it can be called as a method.
+    #[inline]
+    fn is_empty_range(&self) -> bool { MemRange::is_empty(self) }
+
+    /// Returns true if `page` is in this range.
+    ///
+    /// This is the same as `contains`, but it doesn't collide with `Range`'s
+    /// unstable inherent method, so it can be called as a method.
+    #[inline]
+    fn contains_page(&self, page: Self::Page) -> bool {
+        MemRange::contains(self, page)
+    }
+
+    /// Returns true if this range has any `Page`s in common with `other`.
+    fn overlaps(&self, other: &Self) -> bool;
+
     /// Remove `n` pages from the beginning of this `PageRange`
     fn drop_front(&mut self, n: usize) -> &mut Self;
 
@@ -198,6 +312,29 @@ pub trait MemRange {
 
     /// Add `n` pages at the back of this `PageRange`
     fn add_back(&mut self, n: usize) -> &mut Self;
+
+    /// Returns a value that displays this range's addresses and size.
+    fn display(&self) -> RangeDisplay<Self::Page>;
+}
+
+/// Displays a range of pages as the addresses it spans, the number of pages
+/// in it, and its size in bytes, like `[0x1000..0x3000] (2 pages, 0x2000
+/// bytes)`.
+///
+/// `Range` is defined in `core`, so it can't implement `Display` itself;
+/// use `MemRange::display` instead.
+#[derive(Copy, Clone)]
+pub struct RangeDisplay<P> { start: P, end: P }
+
+impl<P> fmt::Display for RangeDisplay<P>
+where P: Page {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addr = |page: P| (page.number() as u64) << PAGE_SHIFT;
+        let length = self.end.number() - self.start.number();
+        write!( f, "[{:#x}..{:#x}] ({} pages, {:#x} bytes)"
+              , addr(self.start), addr(self.end)
+              , length, length as u64 * PAGE_SIZE)
+    }
 }
     //pub const fn start(&self) -> P { self.start }
    //
@@ -213,6 +350,7 @@ pub trait MemRange {
 
 impl<P> MemRange for Range<P>
 where P: Page {
+    type Page = P;
 
     /// Returns the number of `Page`s in this ranage
     #[inline]
@@ -220,6 +358,22 @@ where P: Page {
         self.end.number() - self.start.number()
     }
 
+    #[inline]
+    fn contains(&self, page: P) -> bool {
+        self.start <= page && page < self.end
+    }
+
+    #[inline]
+    fn overlaps(&self, other: &Self) -> bool {
+        !self.is_empty_range() && !other.is_empty_range()
+            && self.start < other.end && other.start < self.end
+    }
+
+    #[inline]
+    fn display(&self) -> RangeDisplay<P> {
+        RangeDisplay { start: self.start, end: self.end }
+    }
+
     /// Remove `n` pages from the beginning of this `PageRange`
     fn drop_front(&mut self, n: usize) -> &mut Self {
         assert!(n < self.length());
@@ -250,7 +404,16 @@ where P: Page {
 /// An iterator over a range of pages
 pub struct RangeIter<'a, P>
 where P: Page
-    , P: 'a { range: &'a Range<P>, current: P }
+    , P: 'a { range: &'a Range<P>, current: P, back: P }
+
+impl<'a, P> RangeIter<'a, P>
+where P: Page {
+    /// Returns an iterator over the pages in `range`.
+    #[inline]
+    pub fn new(range: &'a Range<P>) -> Self {
+        RangeIter { range: range, current: range.start, back: range.end }
+    }
+}
 
 impl<'a, P> Iterator for RangeIter<'a, P>
 where P: Page
@@ -258,15 +421,570 @@ where P: Page
     type Item = P;
 
     fn next(&mut self) -> Option<P> {
-        let end = self.range.end;
-      assert!(self.range.start <= end);
-      if self.current < end {
-          let page = self.current.clone();
-          self.current += 1;
-          Some(page)
-      } else {
-          None
-      }
-  }
+        // stop when we meet the back of the iterator, rather than the end of
+        // the range, so we don't yield pages `next_back` already has. an
+        // inverted range is just empty.
+        if self.current < self.back {
+            let page = self.current.clone();
+            self.current += 1;
+            Some(page)
+        } else {
+            None
+        }
+    }
 
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.len();
+        (len, Some(len))
+    }
+
+}
+
+impl<'a, P> ExactSizeIterator for RangeIter<'a, P>
+where P: Page
+    , P: Clone {
+    #[inline]
+    fn len(&self) -> usize {
+        if self.current < self.back {
+            self.back.number() - self.current.number()
+        } else {
+            0
+        }
+    }
+}
+
+impl<'a, P> DoubleEndedIterator for RangeIter<'a, P>
+where P: Page
+    , P: Clone {
+    fn next_back(&mut self) -> Option<P> {
+        if self.current < self.back {
+            self.back -= 1;
+            Some(self.back.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_pages() {
+        let page_size = PAGE_SIZE as usize;
+        let vaddr = VAddr::from(3 * page_size + 0x123);
+        assert_eq!(vaddr.page(), VirtualPage { number: 3 });
+        assert_eq!( vaddr.page_offset_pair()
+                  , (VirtualPage { number: 3 }, 0x123));
+        assert_eq!(VAddr::from(3 * page_size).page_offset_pair().1, 0);
+
+        let paddr = PAddr::from(5 * PAGE_SIZE + 0x456);
+        assert_eq!(paddr.frame(), PhysicalPage { number: 5 });
+        assert_eq!( paddr.frame_offset_pair()
+                  , (PhysicalPage { number: 5 }, 0x456));
+        assert_eq!( PAddr::from(6 * PAGE_SIZE - 1).frame_offset_pair()
+                  , (PhysicalPage { number: 5 }, PAGE_SIZE - 1));
+    }
+
+    #[test]
+    fn test_pages_for_bytes() {
+        let page_size = PAGE_SIZE as usize;
+        assert_eq!(VirtualPage::pages_for_bytes(0), 0);
+        assert_eq!(VirtualPage::pages_for_bytes(1), 1);
+        assert_eq!(VirtualPage::pages_for_bytes(page_size), 1);
+        assert_eq!(VirtualPage::pages_for_bytes(page_size + 1), 2);
+        assert_eq!(VirtualPage::pages_for_bytes(3 * page_size), 3);
+        assert_eq!( VirtualPage::pages_for_bytes(usize::max_value())
+                  , usize::max_value() / page_size + 1);
+
+        assert_eq!(PhysicalPage::frames_for_bytes(0), 0);
+        assert_eq!(PhysicalPage::frames_for_bytes(page_size), 1);
+        assert_eq!(PhysicalPage::frames_for_bytes(page_size + 1), 2);
+        assert_eq!(PhysicalPage::frames_for_bytes(2 * page_size - 1), 2);
+    }
+
+    #[test]
+    fn test_hex_formatting() {
+        assert_eq!(format!("{:#010x}", VAddr::from(0x1234)), "0x00001234");
+        assert_eq!(format!("{:x}", VAddr::from(0xabcd)), "abcd");
+        assert_eq!(format!("{:#X}", PAddr::from(0xabcd)), "0xABCD");
+        assert_eq!(format!("{:08X}", PAddr::from(0xabcd)), "0000ABCD");
+    }
+
+    #[test]
+    fn test_hash_map_keys() {
+        use std::collections::HashMap;
+        let mut pages = HashMap::new();
+        pages.insert(VirtualPage { number: 42 }, PhysicalPage { number: 7 });
+        pages.insert(VirtualPage { number: 43 }, PhysicalPage { number: 9 });
+        assert_eq!( pages.get(&VirtualPage { number: 42 })
+                  , Some(&PhysicalPage { number: 7 }));
+        assert_eq!(pages.get(&VirtualPage { number: 44 }), None);
+
+        let mut frames = HashMap::new();
+        frames.insert(PhysicalPage { number: 7 }, PAddr::from(0x7000));
+        assert_eq!( frames.get(&PhysicalPage { number: 7 })
+                  , Some(&PAddr::from(0x7000)));
+
+        let mut addrs = HashMap::new();
+        addrs.insert(VAddr::from(0x1234), 1);
+        addrs.insert(VAddr::from(0x1234), 2);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs.get(&VAddr::from(0x1234)), Some(&2));
+    }
+
+    #[test]
+    fn test_vaddr_align() {
+        let addr = VAddr::from(0x1234);
+        assert_eq!(addr.align_down(0x1000), VAddr::from(0x1000));
+        assert_eq!(addr.align_up(0x1000), VAddr::from(0x2000));
+        assert_eq!(VAddr::from(0x2000).align_up(0x1000), VAddr::from(0x2000));
+        assert!(VAddr::from(0x2000).is_aligned(0x1000));
+        assert!(!addr.is_aligned(0x1000));
+        assert!(addr.is_aligned(4));
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_paddr_align() {
+        let addr = PAddr::from(0x1234);
+        assert_eq!(addr.align_down(PAGE_SIZE), PAddr::from(0x1000));
+        assert_eq!(addr.align_up(PAGE_SIZE), PAddr::from(0x2000));
+        assert!(PAddr::from(LARGE_PAGE_SIZE).is_aligned(LARGE_PAGE_SIZE));
+        assert!(!addr.is_aligned(PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_canonical() {
+        assert!(VAddr::new_canonical(0x0000_8000_0000_0000).is_none());
+        assert!(VAddr::new_canonical(0xffff_7fff_ffff_ffff).is_none());
+        assert!(VAddr::new_canonical(0xffff_8000_0000_0000).is_some());
+        assert!(VAddr::new_canonical(0x0000_7fff_ffff_ffff).is_some());
+        assert!(VAddr::from(0).is_canonical());
+    }
+
+    #[test]
+    fn test_frame_range_syntax() {
+        let start = PhysicalPage { number: 5 };
+        let frames: [PhysicalPage; 3] = [start, start + 1, start + 2];
+        let mut n = 0;
+        for (frame, expected) in (start .. start + 3).zip(frames.iter()) {
+            assert_eq!(frame, *expected);
+            n += 1;
+        }
+        assert_eq!(n, 3);
+        // the end is excluded
+        assert_eq!((start .. start + 3).last(), Some(start + 2));
+        assert_eq!((start .. start + 3).size_hint(), (3, Some(3)));
+    }
+
+    #[test]
+    fn test_frame_range_empty_and_single() {
+        let start = PhysicalPage { number: 5 };
+        assert_eq!((start .. start).next(), None);
+        assert_eq!((start .. start).count(), 0);
+        // a backwards range is empty, too
+        assert_eq!((start + 1 .. start).next(), None);
+
+        let mut single = start .. start + 1;
+        assert_eq!(single.next(), Some(start));
+        assert_eq!(single.next(), None);
+    }
+
+    #[test]
+    fn test_frame_range_doesnt_wrap() {
+        let last = PhysicalPage { number: u64::max_value() };
+        let mut range = last - 1 .. last;
+        assert_eq!(range.next(), Some(last - 1));
+        assert_eq!(range.next(), None);
+        // skipping past the highest frame number ends the range rather than
+        // wrapping around to frame 0.
+        let mut range = PhysicalPage { number: 0 } .. last;
+        assert_eq!(range.nth(usize::max_value()), None);
+
+        let last = VirtualPage { number: usize::max_value() };
+        let mut range = last - 1 .. last;
+        assert_eq!(range.next(), Some(last - 1));
+        assert_eq!(range.next(), None);
+    }
+
+    #[test]
+    fn test_physical_window() {
+        let offset = VAddr::from(0xffff_8000_0000_0000);
+        let paddr = PAddr::from(0x1234_5678);
+        let vaddr = paddr.to_virt(offset);
+        assert_eq!(vaddr, VAddr::from(0xffff_8000_1234_5678));
+        assert_eq!(vaddr.to_phys(offset), paddr);
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_frame_to_virt_with() {
+        let offset = VAddr::from(0xffff_8000_0000_0000);
+        let frame = PhysicalPage { number: 0x12345 };
+        let vaddr = frame.to_virt_with(offset);
+        assert_eq!(vaddr, VAddr::from(0xffff_8000_1234_5000));
+        // frames round-trip through pointers into the window
+        let ptr = vaddr.as_ptr::<u64>();
+        assert_eq!(PhysicalPage::from_ptr_with(ptr, offset), frame);
+        let inside = (vaddr + 0x123).as_ptr::<u8>();
+        assert_eq!(PhysicalPage::from_ptr_with(inside, offset), frame);
+        // the identity pointers don't go through the window
+        let identity = unsafe { frame.as_identity_ptr::<u64>() };
+        assert_eq!(identity as usize, 0x1234_5000);
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_frame_to_virt() {
+        use std::panic;
+        // the offset is global, so every case is checked in one test, and
+        // no other test may set it; they pass their offsets explicitly.
+        let frame = PhysicalPage { number: 0x12345 };
+        assert_eq!(phys_map_offset(), None);
+        assert!( panic::catch_unwind(|| frame.to_virt()).is_err()
+               , "converting a frame without an offset should panic");
+
+        let offset = VAddr::from(0xffff_8000_0000_0000);
+        set_phys_map_offset(offset);
+        assert_eq!(phys_map_offset(), Some(offset));
+        assert_eq!(frame.to_virt(), frame.to_virt_with(offset));
+        let ptr = unsafe { frame.as_ptr::<u64>() };
+        assert_eq!(ptr as usize, frame.to_virt_with(offset).as_usize());
+        assert_eq!(PhysicalPage::from_ptr(ptr), frame);
+        let inside = unsafe { frame.as_mut_ptr::<u8>().offset(0x123) };
+        assert_eq!(PhysicalPage::from_ptr(inside), frame);
+        // setting the same offset again is fine
+        set_phys_map_offset(offset);
+        assert!(panic::catch_unwind(|| set_phys_map_offset(VAddr::from(0)))
+                    .is_err());
+    }
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(VAddr::from(0).page_offset(), 0);
+        assert_eq!(VAddr::from(0).page_base(), VAddr::from(0));
+
+        let last = VAddr::from(PAGE_SIZE as usize - 1);
+        assert_eq!(last.page_offset(), PAGE_SIZE as usize - 1);
+        assert_eq!(last.page_base(), VAddr::from(0));
+
+        let next = VAddr::from(PAGE_SIZE as usize);
+        assert_eq!(next.page_offset(), 0);
+        assert_eq!(next.page_base(), next);
+
+        let addr = VAddr::from(0x1234);
+        assert_eq!(addr.page_base() + addr.page_offset(), addr);
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_frame_offset() {
+        assert_eq!(PAddr::from(0).frame_offset(), 0);
+        assert_eq!(PAddr::from(PAGE_SIZE - 1).frame_offset(), PAGE_SIZE - 1);
+        assert_eq!(PAddr::from(PAGE_SIZE).frame_offset(), 0);
+        assert_eq!(PAddr::from(0x1234).frame_offset(), 0x234);
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_frame_try_containing() {
+        let aligned = PAddr::from(0x5000);
+        assert_eq!( PhysicalPage::try_containing(aligned)
+                  , Ok(PhysicalPage { number: 5 }));
+        assert_eq!( PhysicalPage::try_containing(PAddr::from(0))
+                  , Ok(PhysicalPage { number: 0 }));
+
+        let unaligned = PAddr::from(0x5001);
+        assert_eq!(PhysicalPage::try_containing(unaligned), Err(unaligned));
+        let last = PAddr::from(0x5fff);
+        assert_eq!(PhysicalPage::try_containing(last), Err(last));
+        // the truncating version still rounds down
+        assert_eq!( PhysicalPage::containing(last)
+                  , PhysicalPage { number: 5 });
+    }
+
+    #[test]
+    fn test_table_indices() {
+        let addr = VAddr::from(0);
+        assert_eq!(addr.pml5_index(), 0);
+        assert_eq!(addr.pml4_index(), 0);
+
+        let addr = VAddr::from(1 << 48);
+        assert_eq!(addr.pml5_index(), 1);
+        assert_eq!(addr.pml4_index(), 0);
+
+        // the highest 57-bit address
+        let addr = VAddr::from((1 << 57) - 1);
+        assert_eq!(addr.pml5_index(), 511);
+        assert_eq!(addr.pml4_index(), 511);
+        assert_eq!(addr.pdpt_index(), 511);
+        assert_eq!(addr.pd_index(), 511);
+        assert_eq!(addr.pt_index(), 511);
+
+        let addr = VAddr::from(0x0123_4567_89ab_c000);
+        assert_eq!(addr.pml5_index(), 0x123);
+        assert_eq!(addr.pml4_index(), (0x0123_4567_89ab_c000 >> 39) & 0o777);
+    }
+
+    #[test]
+    fn test_indices_follow_page_size() {
+        let addr = VAddr::from(0x0000_7fff_dead_b000);
+        let page = VirtualPage::containing(addr);
+        assert_eq!(page.number, addr.as_usize() >> PAGE_SHIFT);
+        assert_eq!(page.base(), addr.align_down(PAGE_SIZE as usize));
+        assert!(page.base().is_page_aligned());
+        // the address's indices don't depend on the base page size...
+        assert_eq!( ( addr.pml4_index(), addr.pdpt_index()
+                    , addr.pd_index(), addr.pt_index() )
+                  , (0xff, 0x1ff, 0xf5, 0xdb));
+        // ...and nor do its page's, apart from the offset into the page.
+        if cfg!(feature = "base2m") {
+            assert_eq!(PAGE_SIZE, LARGE_PAGE_SIZE);
+            assert_eq!(page.table_indices(), (0xff, 0x1ff, 0xf5, 0));
+        } else {
+            assert_eq!(PAGE_SIZE, 0x1000);
+            assert_eq!(page.table_indices(), (0xff, 0x1ff, 0xf5, 0xdb));
+        }
+        assert_eq!(page.next().base(), page.base() + PAGE_SIZE as usize);
+
+        let frame = PhysicalPage::containing(PAddr::from(3 * PAGE_SIZE + 1));
+        assert_eq!(frame, PhysicalPage { number: 3 });
+        assert_eq!(frame.base(), PAddr::from(3 * PAGE_SIZE));
+        assert_eq!(frame.end_address(), PAddr::from(4 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_next_and_prev() {
+        let page = VirtualPage { number: 5 };
+        assert_eq!(page.next(), VirtualPage { number: 6 });
+        assert_eq!(page.prev(), Some(VirtualPage { number: 4 }));
+        assert_eq!(page.next().prev(), Some(page));
+        assert_eq!(VirtualPage { number: 0 }.prev(), None);
+
+        let frame = PhysicalPage { number: 0 };
+        assert_eq!(frame.prev(), None);
+        assert_eq!(frame.next(), PhysicalPage { number: 1 });
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_page_table_indices() {
+        let addr = VAddr::from(0x0000_7fff_dead_b000);
+        assert_eq!( VirtualPage::containing(addr).table_indices()
+                  , ( addr.pml4_index(), addr.pdpt_index()
+                    , addr.pd_index(), addr.pt_index() ));
+
+        // the last page in a page table...
+        let last = VirtualPage { number: 511 };
+        assert_eq!(last.table_indices(), (0, 0, 0, 511));
+        // ...is followed by the first page in the next one.
+        assert_eq!(last.next().table_indices(), (0, 0, 1, 0));
+        assert_eq!(last.next().prev(), Some(last));
+
+        // rolling over every level at once
+        let last = VirtualPage { number: (1 << 27) - 1 };
+        assert_eq!(last.table_indices(), (0, 511, 511, 511));
+        assert_eq!(last.next().table_indices(), (1, 0, 0, 0));
+    }
+
+    #[test]
+    fn test_range_iter_matches_for_loop() {
+        let start = VirtualPage { number: 4 };
+        let range = start.range_until(start + 6);
+        let borrowed = RangeIter::new(&range);
+        let mut n = 0;
+        // the range itself is an iterator, so it can be used in a `for` loop.
+        for (page, expected) in range.clone().zip(borrowed) {
+            assert_eq!(page, expected);
+            n += 1;
+        }
+        assert_eq!(n, 6);
+        assert_eq!(range.clone().count(), RangeIter::new(&range).len());
+        // iterating didn't consume the borrowed range.
+        assert_eq!(range, start .. start + 6);
+    }
+
+    #[test]
+    fn test_range_checked() {
+        let start = VirtualPage { number: 8 };
+        assert_eq!(VirtualPage::range_checked(start, start - 1), None);
+        assert_eq!( PhysicalPage::range_checked( PhysicalPage { number: 2 }
+                                               , PhysicalPage { number: 1 })
+                  , None);
+        assert_eq!( VirtualPage::range_checked(start, start)
+                  , Some(start .. start));
+
+        let range = VirtualPage::range_checked(start, start + 3).unwrap();
+        let pages: [VirtualPage; 3] = [start, start + 1, start + 2];
+        assert!(range.clone().eq(pages.iter().cloned()));
+        assert!(RangeIter::new(&range).eq(pages.iter().cloned()));
+
+        // an inverted range that wasn't checked is empty, and iterating
+        // over it doesn't panic.
+        let inverted = start .. start - 1;
+        assert_eq!(RangeIter::new(&inverted).next(), None);
+        assert_eq!(RangeIter::new(&inverted).len(), 0);
+    }
+
+    #[test]
+    fn test_range_iter_empty() {
+        let page = VirtualPage { number: 4 };
+        let range = page.range_of(0);
+        let mut iter = RangeIter::new(&range);
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_range_iter_one() {
+        let page = VirtualPage { number: 4 };
+        let range = page.range_of(1);
+        let mut iter = RangeIter::new(&range);
+        assert_eq!(iter.len(), 1);
+        assert_eq!(iter.next_back(), Some(page));
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_range_iter_alternating() {
+        let page = VirtualPage { number: 4 };
+        let range = page.range_of(5);
+        let mut iter = RangeIter::new(&range);
+        assert_eq!(iter.len(), 5);
+        assert_eq!(iter.next(), Some(page));
+        assert_eq!(iter.next_back(), Some(page + 4));
+        assert_eq!(iter.next(), Some(page + 1));
+        assert_eq!(iter.next_back(), Some(page + 3));
+        assert_eq!(iter.len(), 1);
+        assert_eq!(iter.next(), Some(page + 2));
+        assert_eq!(iter.next_back(), None);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+    }
+
+    #[test]
+    fn test_range_contains() {
+        // `Range` has unstable inherent `contains` and `is_empty` methods, so
+        // call the `MemRange` methods explicitly.
+        let page = VirtualPage { number: 4 };
+        let range = page.range_of(4);
+        assert_eq!(MemRange::len(&range), 4);
+        assert!(!MemRange::is_empty(&range));
+        assert!(MemRange::contains(&range, page));
+        assert!(MemRange::contains(&range, page + 3));
+        assert!(!MemRange::contains(&range, page + 4));
+        assert!(!MemRange::contains(&range, page - 1));
+        assert!(MemRange::is_empty(&page.range_of(0)));
+
+        // the aliases can be called as methods.
+        assert!(!range.is_empty_range());
+        assert!(range.contains_page(page));
+        assert!(range.contains_page(page + 3));
+        assert!(!range.contains_page(page + 4));
+        assert!(!range.contains_page(page - 1));
+        assert!(page.range_of(0).is_empty_range());
+
+        let frame = PhysicalPage { number: 4 };
+        let frames = frame.range_of(2);
+        assert!(frames.contains_page(frame + 1));
+        assert!(!frames.contains_page(frame + 2));
+    }
+
+    #[test]
+    fn test_range_overlaps() {
+        let page = VirtualPage { number: 4 };
+        let range = page.range_of(4);
+        // adjacent, but not overlapping
+        assert!(!range.overlaps(&(page + 4).range_of(4)));
+        assert!(!range.overlaps(&(page - 4).range_of(4)));
+        // fully contained
+        assert!(range.overlaps(&(page + 1).range_of(2)));
+        assert!((page + 1).range_of(2).overlaps(&range));
+        assert!(range.overlaps(&range));
+        // partial overlaps
+        assert!(range.overlaps(&(page + 3).range_of(4)));
+        assert!(range.overlaps(&(page - 3).range_of(4)));
+        // empty ranges overlap nothing
+        assert!(!range.overlaps(&(page + 1).range_of(0)));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_vaddr_align_not_pow2() {
+        VAddr::from(0x1234).align_up(3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_paddr_is_aligned_not_pow2() {
+        PAddr::from(0x1234).is_aligned(0x1800);
+    }
+
+    #[test]
+    fn test_paddr_checked_arithmetic() {
+        let max = PAddr::from(u64::max_value());
+        assert_eq!(max.checked_add(1), None);
+        assert_eq!(max.checked_add(0), Some(max));
+        assert_eq!(PAddr::from(0).checked_sub(1), None);
+        assert_eq!(max.checked_sub(1), Some(PAddr::from(u64::max_value() - 1)));
+        assert_eq!(max.saturating_add(0x1000), max);
+        assert_eq!( PAddr::from(0x1000).saturating_add(0x1000)
+                  , PAddr::from(0x2000));
+        assert_eq!(max.wrapping_add(1), PAddr::from(0));
+        assert_eq!(max.wrapping_add(0x1000), PAddr::from(0xfff));
+    }
+
+    #[test]
+    fn test_vaddr_checked_arithmetic() {
+        let max = VAddr::from(usize::max_value());
+        assert_eq!(max.checked_add(1), None);
+        assert_eq!(max.checked_add(0), Some(max));
+        assert_eq!(VAddr::from(0).checked_sub(1), None);
+        assert_eq!( max.checked_sub(1)
+                  , Some(VAddr::from(usize::max_value() - 1)));
+        assert_eq!(max.saturating_add(0x1000), max);
+        assert_eq!( VAddr::from(0x1000).saturating_add(0x1000)
+                  , VAddr::from(0x2000));
+        assert_eq!(max.wrapping_add(1), VAddr::from(0));
+        assert_eq!(max.wrapping_add(0x1000), VAddr::from(0xfff));
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_page_display() {
+        use std::string::ToString;
+        let frame = PhysicalPage { number: 42 };
+        assert_eq!(frame.to_string(), "frame#42 @ 0x2a000");
+        let page = VirtualPage { number: 42 };
+        assert_eq!(page.to_string(), "page#42 @ 0x2a000");
+        // `Debug` is unchanged
+        assert_eq!(format!("{:?}", page), "page #42");
+    }
+
+    #[test]
+    #[cfg(not(feature = "base2m"))]
+    fn test_range_display() {
+        use std::string::ToString;
+        let pages = VirtualPage { number: 1 }.range_of(2);
+        assert_eq!( pages.display().to_string()
+                  , "[0x1000..0x3000] (2 pages, 0x2000 bytes)");
+        let frames = PhysicalPage { number: 0 }.range_of(0);
+        assert_eq!( frames.display().to_string()
+                  , "[0x0..0x0] (0 pages, 0x0 bytes)");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_last_frame_end_address() {
+        PhysicalPage { number: u64::max_value() >> PAGE_SHIFT }.end_address();
+    }
 }
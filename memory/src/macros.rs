@@ -47,6 +47,15 @@ macro_rules! Addr {
             #[inline] fn from(ptr: *const T) -> Self { $ty(ptr as $size) }
         }
 
+        /// Addresses hash the same as the integers they wrap, so that they
+        /// can key a hash map.
+        impl ::core::hash::Hash for $ty {
+            #[inline]
+            fn hash<H: ::core::hash::Hasher>(&self, state: &mut H) {
+                self.0.hash(state)
+            }
+        }
+
         impl ::core::ops::Deref for $ty {
             type Target = $size;
             #[inline] fn deref(&self) -> &Self::Target { &self.0 }
@@ -62,7 +71,39 @@ macro_rules! Addr {
             #[inline(always)]
             pub const fn new(value: $size) -> Self { $ty(value) }
 
+            /// Add `rhs` to this address.
+            ///
+            /// # Returns
+            /// + `Some` with the sum, if it didn't overflow
+            /// + `None` if it did
+            #[inline]
+            pub fn checked_add(&self, rhs: $size) -> Option<Self> {
+                self.0.checked_add(rhs).map($ty)
+            }
+
+            /// Subtract `rhs` from this address.
+            ///
+            /// # Returns
+            /// + `Some` with the difference, if it didn't underflow
+            /// + `None` if it did
+            #[inline]
+            pub fn checked_sub(&self, rhs: $size) -> Option<Self> {
+                self.0.checked_sub(rhs).map($ty)
+            }
+
+            /// Add `rhs` to this address, stopping at the highest address
+            /// rather than overflowing.
+            #[inline]
+            pub fn saturating_add(&self, rhs: $size) -> Self {
+                $ty(self.0.saturating_add(rhs))
+            }
 
+            /// Add `rhs` to this address, wrapping around past the highest
+            /// address.
+            #[inline]
+            pub fn wrapping_add(&self, rhs: $size) -> Self {
+                $ty(self.0.wrapping_add(rhs))
+            }
         }
 
         impl_ops! {
@@ -118,17 +159,26 @@ macro_rules! Addr {
 
             #[inline] fn align_down(&self, align: Self::Repr) -> Self {
                 use util::Align;
+                debug_assert!( align.is_power_of_two()
+                             , "alignment {} is not a power of two", align);
                 $ty ( self.0.align_down(align) )
             }
 
             #[inline] fn align_up(&self, align: Self::Repr) -> Self {
                 use util::Align;
-                // assert!(align.is_page_aligned());
+                debug_assert!( align.is_power_of_two()
+                             , "alignment {} is not a power of two", align);
                 $ty ( self.0.align_up(align) )
             }
 
+            #[inline] fn is_aligned(&self, align: Self::Repr) -> bool {
+                debug_assert!( align.is_power_of_two()
+                             , "alignment {} is not a power of two", align);
+                self.0 & (align - 1) == 0
+            }
+
             #[inline] fn is_page_aligned(&self) -> bool {
-                **self % PAGE_SIZE as <Self as Addr>::Repr == 0 as <Self as Addr>::Repr
+                self.is_aligned(PAGE_SIZE as <Self as Addr>::Repr)
             }
         }
 
@@ -206,9 +256,18 @@ macro_rules! Page {
             }
 
             /// Return the end address of this page
+            ///
+            /// The end of the last page is past the highest address. That's
+            /// caught in debug builds; in release builds it wraps to zero.
             #[inline]
             fn end_address(&self) -> $addr {
-                self.base() + PAGE_SIZE as <Self::Address as Addr>::Repr
+                let size = PAGE_SIZE as <Self::Address as Addr>::Repr;
+                if cfg!(debug_assertions) {
+                    self.base().checked_add(size)
+                        .expect("page end address overflowed")
+                } else {
+                    self.base().wrapping_add(size)
+                }
             }
 
             #[inline] fn number(&self) -> usize {
@@ -224,6 +283,12 @@ macro_rules! Page {
         //     }
         // }
 
+        /// Stepping from one page to the next backs iterating over a range
+        /// of pages with `start .. end`.
+        ///
+        /// Ranges are half-open, so `start .. start` is empty, and stepping
+        /// past the highest page number ends the iteration rather than
+        /// wrapping around to page 0.
         impl ::core::iter::Step for $ty {
             #[inline]
             fn add_usize(&self, n: usize) -> Option<Self> {
@@ -245,10 +310,22 @@ macro_rules! Page {
 
             #[inline] fn add_one(&self) -> Self { self + 1 }
 
-            #[inline] fn replace_one(&mut self) -> Self { unimplemented!() }
+            #[inline] fn replace_one(&mut self) -> Self {
+                ::core::mem::replace(self, $ty { number: 1 })
+            }
 
-            #[inline] fn replace_zero(&mut self) -> Self { unimplemented!() }
+            #[inline] fn replace_zero(&mut self) -> Self {
+                ::core::mem::replace(self, $ty { number: 0 })
+            }
+
+        }
 
+        /// Pages hash the same as their page numbers.
+        impl ::core::hash::Hash for $ty {
+            #[inline]
+            fn hash<H: ::core::hash::Hasher>(&self, state: &mut H) {
+                self.number.hash(state)
+            }
         }
 
         impl<A> ::core::convert::From<A> for $ty
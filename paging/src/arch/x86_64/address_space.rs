@@ -0,0 +1,256 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Address spaces other than the currently active one.
+use memory::{PageRange, PAddr, PhysicalPage, VAddr, VirtualPage};
+use alloc::{FrameAllocator, VirtualRegionAllocator};
+use spin::Mutex;
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use super::{tlb, ActivePageTable, InactivePageTable};
+use super::table::EntryFlags;
+use ::{Mapper, MapResult, MapErr};
+
+/// The maximum number of free regions an `AddressSpace` can track.
+pub use alloc::region::MAX_FREE_REGIONS;
+
+/// The number of process-context identifiers; PCIDs are 12 bits wide.
+const N_PCIDS: usize = 1 << 12;
+
+/// The next PCID to hand out to a new `AddressSpace`.
+///
+/// PCID 0 is left for the kernel's own address space.
+static NEXT_PCID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns a PCID for a new address space.
+///
+/// Once all 4095 PCIDs have been handed out they are reused, so a PCID
+/// only stays unique until then. Every CPU's TLB is flushed each time they
+/// wrap around, so that a reused PCID doesn't pick up the entries left
+/// behind by the address space that had it before.
+fn next_pcid() -> u16 {
+    let n = NEXT_PCID.fetch_add(1, Ordering::Relaxed);
+    if n != 0 && n % (N_PCIDS - 1) == 0 {
+        trace!("PCIDs wrapped around, flushing every TLB");
+        // this is safe because the paging code only runs in kernel mode.
+        unsafe { tlb::shootdown_all() }
+    }
+    (n % (N_PCIDS - 1) + 1) as u16
+}
+
+/// An address space backed by an `InactivePageTable`.
+///
+/// Edits to the address space are performed through
+/// `ActivePageTable::using`, with the active table's own temporary page, so
+/// callers need only pass in the active table. An `AddressSpace` must be
+/// freed with `free` once it's no longer needed; if it's just dropped, its
+/// page tables and frames are leaked.
+///
+/// Each address space is given a process-context identifier when it's
+/// created, which stays the same for its whole life, so that it can be
+/// switched to with `cr3::set_pagetable_frame_pcid` without flushing the
+/// TLB.
+pub struct AddressSpace<'alloc, A>
+where A: FrameAllocator
+    , A: 'alloc { name: &'static str
+                , pcid: u16
+                , table: InactivePageTable
+                , free_regions: VirtualRegionAllocator
+                , allocator: &'alloc Mutex<A>
+                , /// Set once `free` has freed the page tables
+                  freed: bool
+                }
+
+impl<'alloc, A> fmt::Debug for AddressSpace<'alloc, A>
+where A: FrameAllocator
+    , A: 'alloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!( f, "AddressSpace {:?} (PCID {}, {:?})"
+              , self.name, self.pcid, self.table)
+    }
+}
+
+impl<'alloc, A> AddressSpace<'alloc, A>
+where A: FrameAllocator
+    , A: 'alloc {
+
+    /// Create a new, empty `AddressSpace`.
+    ///
+    /// # Arguments
+    /// + `name`: a name for the address space, for debugging
+    /// + `allocator`: the `FrameAllocator` that page tables are allocated
+    ///                from, and freed to when the address space is dropped.
+    /// + `active`: the `ActivePageTable`
+    pub fn new( name: &'static str
+              , allocator: &'alloc Mutex<A>
+              , active: &mut ActivePageTable)
+              -> MapResult<Self> {
+        let mut alloc = allocator.lock();
+        let table = active.with_temp_page(&mut *alloc, |active, temp, alloc| {
+            let frame = unsafe { alloc.allocate() }
+                .map_err(|err| MapErr::Alloc { message: "allocate a PML4"
+                                             , page: **temp
+                                             , cause: err })?;
+            InactivePageTable::new(frame, active, temp)
+        })?;
+        let pcid = next_pcid();
+        trace!("created address space {:?} with PCID {}", name, pcid);
+        Ok(AddressSpace { name: name
+                        , pcid: pcid
+                        , table: table
+                        , free_regions: VirtualRegionAllocator::new()
+                        , allocator: allocator
+                        , freed: false
+                        })
+    }
+
+    /// Returns the name of this address space.
+    #[inline] pub fn name(&self) -> &'static str { self.name }
+
+    /// Returns the process-context identifier of this address space.
+    #[inline] pub fn pcid(&self) -> u16 { self.pcid }
+
+    /// Returns the frame containing this address space's PML4.
+    #[inline] pub fn pml4_frame(&self) -> PhysicalPage {
+        self.table.pml4_frame
+    }
+
+    /// Modifies the page tables so that `page` maps to `frame`.
+    ///
+    /// # Arguments
+    /// + `active`: the `ActivePageTable`
+    /// + `page`: the virtual `Page` to map
+    /// + `frame`: the physical `Frame` that `Page` should map to.
+    /// + `flags`: the page table entry flags.
+    pub fn map( &mut self
+              , active: &mut ActivePageTable
+              , page: VirtualPage
+              , frame: PhysicalPage
+              , flags: EntryFlags)
+              -> MapResult<()> {
+        let table = &mut self.table;
+        let mut alloc = self.allocator.lock();
+        active.with_temp_page(&mut *alloc, |active, temp, alloc| {
+            active.using(table, temp, |pml4| {
+                pml4.map(page, frame, flags, alloc)
+            })
+        })
+    }
+
+    /// Unmap the given `VirtualPage`.
+    ///
+    /// The frame `page` was mapped to is returned to this address space's
+    /// `FrameAllocator`.
+    pub fn unmap(&mut self, active: &mut ActivePageTable, page: VirtualPage)
+                -> MapResult<()> {
+        let table = &mut self.table;
+        let mut alloc = self.allocator.lock();
+        active.with_temp_page(&mut *alloc, |active, temp, alloc| {
+            active.using(table, temp, |pml4| pml4.unmap(page, alloc))
+        })
+    }
+
+    /// Translates a virtual address to a physical address in this
+    /// address space.
+    ///
+    /// # Returns
+    /// + `Some(PAddr)` if `vaddr` is mapped
+    /// + `None` if `vaddr` is not mapped
+    pub fn translate(&mut self, active: &mut ActivePageTable, vaddr: VAddr)
+                    -> MapResult<Option<PAddr>> {
+        let table = &mut self.table;
+        let mut alloc = self.allocator.lock();
+        active.with_temp_page(&mut *alloc, |active, temp, _| {
+            active.using(table, temp, |pml4| Ok(pml4.translate(vaddr)))
+        })
+    }
+
+    /// Add a range of pages to the free regions available for
+    /// `allocate_region`.
+    ///
+    /// The range is merged with any free regions directly before or after
+    /// it.
+    pub fn add_free_region(&mut self, pages: PageRange) -> MapResult<()> {
+        let start = pages.start;
+        self.free_regions.free(pages)
+            .map_err(|err| MapErr::Alloc { message: "add free region"
+                                         , page: start
+                                         , cause: err })
+    }
+
+    /// Take `n_pages` contiguous pages from the free regions.
+    ///
+    /// The smallest free region large enough to fit the request is used.
+    ///
+    /// # Returns
+    /// + `Some(PageRange)` if a large enough free region existed
+    /// + `None` if no free region was large enough
+    pub fn allocate_region(&mut self, n_pages: usize) -> Option<PageRange> {
+        self.free_regions.alloc(n_pages, 1)
+    }
+
+    /// Allocate `n_pages` contiguous pages from the free regions, and map
+    /// them to newly allocated frames.
+    ///
+    /// # Returns
+    /// + the `PageRange` that was mapped
+    /// + `MapErr::NoPage` if no free region was large enough
+    pub fn map_region( &mut self
+                     , active: &mut ActivePageTable
+                     , n_pages: usize
+                     , flags: EntryFlags)
+                     -> MapResult<PageRange> {
+        let pages = self.allocate_region(n_pages)
+                        .ok_or(MapErr::NoPage {
+                            message: "map region"
+                          , cause: "no free region was large enough"
+                        })?;
+        let table = &mut self.table;
+        let mut alloc = self.allocator.lock();
+        active.with_temp_page(&mut *alloc, |active, temp, alloc| {
+            active.using(table, temp, |pml4| {
+                for page in pages.clone() {
+                    pml4.map_to_any(page, flags, alloc)?;
+                }
+                Ok(())
+            })
+        })?;
+        Ok(pages)
+    }
+
+    /// Free this address space's page tables, and every frame still mapped
+    /// in it, to its `FrameAllocator`.
+    ///
+    /// # Arguments
+    /// + `active`: the `ActivePageTable`
+    pub fn free(mut self, active: &mut ActivePageTable) -> MapResult<()> {
+        trace!("freeing address space {:?}", self.name);
+        self.freed = true;
+        let table = &mut self.table;
+        let mut alloc = self.allocator.lock();
+        active.with_temp_page(&mut *alloc, |active, temp, alloc| {
+            table.free_frames(active, temp, alloc)
+        })
+    }
+
+}
+
+impl<'alloc, A> Drop for AddressSpace<'alloc, A>
+where A: FrameAllocator
+    , A: 'alloc {
+    fn drop(&mut self) {
+        // we can't be handed the active table when we're dropped, so the
+        // tables can only be freed by `free`.
+        if !self.freed {
+            warn!("address space {:?} was dropped without being freed, \
+                   leaking its page tables", self.name);
+        }
+    }
+}
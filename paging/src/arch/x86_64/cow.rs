@@ -0,0 +1,261 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Copy-on-write cloning of page tables, and sharing frames between them.
+use memory::{PAGE_SHIFT, Page, PageRange, PhysicalPage, VirtualPage};
+use alloc::FrameAllocator;
+
+use core::cmp;
+
+use super::{ActivePageTable, InactivePageTable, is_kernel_entry};
+use super::table::{ Entry, EntryFlags, N_ENTRIES, PTLevel, Sublevel, Table
+                  , PRESENT, RECURSIVE_INDEX, WRITABLE };
+use super::temp::TempPage;
+use super::tlb;
+use ::{MapResult, MapErr};
+
+/// The number of pages `copy_mapping_from` translates in `src` each time it
+/// switches to it.
+const COPY_BATCH: usize = 64;
+
+/// A page table that can be copied for copy-on-write.
+trait CowCopy {
+    /// Copy this table into a newly allocated frame, recursively copying
+    /// any tables it points to, and write-protect every page it maps.
+    ///
+    /// # Returns
+    /// + the `PhysicalPage` containing the copy.
+    fn cow_copy<A>( &mut self
+                  , active: &mut ActivePageTable
+                  , temp: &mut TempPage
+                  , alloc: &mut A)
+                  -> MapResult<PhysicalPage>
+    where A: FrameAllocator;
+}
+
+/// Allocate a frame for a new page table and zero it, using the temp page.
+fn new_table_frame<A>( active: &mut ActivePageTable
+                     , temp: &mut TempPage
+                     , alloc: &mut A)
+                     -> MapResult<PhysicalPage>
+where A: FrameAllocator {
+    let frame = unsafe { alloc.allocate() }
+        .map_err(|err| MapErr::Alloc { message: "allocate a page table"
+                                     , page: **temp
+                                     , cause: err })?;
+    temp.map_to_table(frame, active)?.zero();
+    temp.unmap(active)?;
+    Ok(frame)
+}
+
+/// Write-protect a leaf `entry`, and add a reference to each of the
+/// `n_frames` frames it maps.
+///
+/// # Returns
+/// + `Some((frame, flags))` that the copied entry should be set to
+/// + `None` if the entry isn't present
+fn share_entry<A>( entry: &mut Entry
+                 , n_frames: usize
+                 , page: VirtualPage
+                 , alloc: &mut A)
+                 -> MapResult<Option<(PhysicalPage, EntryFlags)>>
+where A: FrameAllocator {
+    let frame = match entry.get_frame() {
+        Some(frame) => frame
+      , None => return Ok(None)
+    };
+    let flags = entry.flags() - WRITABLE;
+    entry.set(frame, flags);
+    for shared in frame.range_of(n_frames) {
+        unsafe { alloc.add_ref(shared) }
+            .map_err(|err| MapErr::Alloc { message: "share a frame"
+                                         , page: page
+                                         , cause: err })?;
+    }
+    Ok(Some((frame, flags)))
+}
+
+impl CowCopy for Table<PTLevel> {
+    fn cow_copy<A>( &mut self
+                  , active: &mut ActivePageTable
+                  , temp: &mut TempPage
+                  , alloc: &mut A)
+                  -> MapResult<PhysicalPage>
+    where A: FrameAllocator {
+        let frame = unsafe { alloc.allocate() }
+            .map_err(|err| MapErr::Alloc { message: "allocate a page table"
+                                         , page: **temp
+                                         , cause: err })?;
+        let page = **temp;
+        let result = {
+            let copy = temp.map_to_table(frame, active)?;
+            copy.zero();
+            let mut result = Ok(());
+            for i in 0..N_ENTRIES {
+                // if this is the table containing the temp page, skip the
+                // temp page's own mapping (to the copy we're writing).
+                if self[i].get_frame() == Some(frame) { continue }
+                match share_entry(&mut self[i], 1, page, alloc) {
+                    Ok(Some((shared, flags))) => copy[i].set(shared, flags)
+                  , Ok(None) => {}
+                  , Err(err) => { result = Err(err); break }
+                }
+            }
+            result
+        };
+        temp.unmap(active)?;
+        result.map(|_| frame)
+    }
+}
+
+impl<L> CowCopy for Table<L>
+where L: Sublevel
+    , Table<L::Next>: CowCopy {
+    fn cow_copy<A>( &mut self
+                  , active: &mut ActivePageTable
+                  , temp: &mut TempPage
+                  , alloc: &mut A)
+                  -> MapResult<PhysicalPage>
+    where A: FrameAllocator {
+        let frame = new_table_frame(active, temp, alloc)?;
+        let page = **temp;
+        // number of 4KiB frames mapped by a huge page at this level
+        let huge_frames = 1 << (L::ADDR_SHIFT_AMOUNT - PAGE_SHIFT as usize);
+        for i in 0..N_ENTRIES {
+            let copied = if self[i].is_huge() {
+                share_entry(&mut self[i], huge_frames, page, alloc)?
+            } else {
+                match self.next_table_mut(i) {
+                    Some(next) => Some(( next.cow_copy(active, temp, alloc)?
+                                       , self[i].flags() ))
+                  , None => None
+                }
+            };
+            if let Some((child, flags)) = copied {
+                temp.map_to_table(frame, active)?[i].set(child, flags);
+                temp.unmap(active)?;
+            }
+        }
+        Ok(frame)
+    }
+}
+
+impl ActivePageTable {
+
+    /// Map the pages in `range` to the same frames they're mapped to in
+    /// `src`, so that both tables share them.
+    ///
+    /// Pages that `src` doesn't map are skipped. `alloc` gains a reference
+    /// to every shared frame. `src` is read through the recursive entry, as
+    /// with `InactivePageTable::with`, but never changed; up to `COPY_BATCH`
+    /// pages are translated each time it's switched to.
+    ///
+    /// # Arguments
+    /// + `src`: the `InactivePageTable` to copy mappings from
+    /// + `range`: the pages to copy
+    /// + `flags`: the page table entry flags to map the pages with
+    /// + `temp`: a `TempPage` for reading `src`
+    /// + `alloc`: a `FrameAllocator` that can share frames
+    ///
+    /// # Returns
+    /// + `MapErr::Alloc` if `alloc` can't share frames
+    /// + `MapErr::AlreadyInUse` if a page is already mapped in this table.
+    ///   The pages before it stay mapped.
+    pub fn copy_mapping_from<A>( &mut self
+                               , src: &InactivePageTable
+                               , range: PageRange
+                               , flags: EntryFlags
+                               , temp: &mut TempPage
+                               , alloc: &mut A)
+                               -> MapResult<()>
+    where A: FrameAllocator {
+        // `using` borrows the table mutably, even though it's only read.
+        let mut src = InactivePageTable { pml4_frame: src.pml4_frame };
+        let mut batch = range.start;
+        while batch < range.end {
+            let n_pages = cmp::min(COPY_BATCH, range.end.number - batch.number);
+            let mut frames = [None; COPY_BATCH];
+            self.using(&mut src, temp, |pml4| {
+                for (slot, page) in frames.iter_mut()
+                                          .zip(batch.range_of(n_pages)) {
+                    *slot = pml4.translate_page(page);
+                }
+                Ok(())
+            })?;
+            for (page, &frame) in batch.range_of(n_pages).zip(frames.iter()) {
+                let frame = match frame {
+                    Some(frame) => frame
+                  , None => continue
+                };
+                unsafe { alloc.add_ref(frame) }
+                    .map_err(|err| MapErr::Alloc { message: "share a frame"
+                                                 , page: page
+                                                 , cause: err })?;
+                if let Err(err) = self.map(page, frame, flags, alloc) {
+                    unsafe { alloc.remove_ref(frame) };
+                    return Err(err)
+                }
+            }
+            batch = batch + n_pages;
+        }
+        trace!("copied the mappings of {:?} from {:?}", range, src);
+        Ok(())
+    }
+
+    /// Clone this address space for copy-on-write.
+    ///
+    /// The new table maps every page to the same frame as this one, and
+    /// `WRITABLE` is cleared in both tables, so that writes to either one
+    /// fault and can be resolved by copying the frame. The page tables
+    /// themselves are copied, and `alloc` gains a reference to every
+    /// shared frame. The `KERNEL_PML4_ENTRIES` are shared with the copy as
+    /// they are, rather than copied, so the kernel stays writable.
+    ///
+    /// # Arguments
+    /// + `temp`: a `TempPage` for writing to the new tables
+    /// + `alloc`: a `FrameAllocator` that can share frames
+    ///
+    /// # Returns
+    /// + the copy, as an `InactivePageTable`
+    /// + `MapErr::Alloc` if a table couldn't be allocated, or `alloc`
+    ///   can't share frames. The partially-built copy is leaked.
+    pub fn clone_cow<A>(&mut self, temp: &mut TempPage, alloc: &mut A)
+                       -> MapResult<InactivePageTable>
+    where A: FrameAllocator {
+        trace!("cloning {:?} for copy-on-write", self);
+        let pml4_frame = unsafe { alloc.allocate() }
+            .map_err(|err| MapErr::Alloc { message: "allocate a PML4"
+                                         , page: **temp
+                                         , cause: err })?;
+        let clone = InactivePageTable::new(pml4_frame, self, temp)?;
+
+        // the recursive entry must not be copied, and `new` has already
+        // shared the kernel's entries.
+        let private = |&i: &usize| i != RECURSIVE_INDEX && !is_kernel_entry(i);
+        for i in (0..N_ENTRIES).filter(private) {
+            // the PDPT is reached through the recursive mapping, so it stays
+            // valid while `self` is borrowed to map the temp page.
+            let pdpt = match self.pml4().next_table_mut(i) {
+                Some(pdpt) => pdpt as *mut Table<_>
+              , None => continue
+            };
+            let flags = self.pml4()[i].flags() | PRESENT;
+            let copy = unsafe { (*pdpt).cow_copy(self, temp, alloc)? };
+            temp.map_to_table(clone.pml4_frame, self)?[i].set(copy, flags);
+            temp.unmap(self)?;
+        }
+
+        unsafe {
+            // this is safe to execute; we are in kernel mode. global pages
+            // were write-protected too, so flush them as well.
+            tlb::flush_all_global();
+        }
+        Ok(clone)
+    }
+
+}
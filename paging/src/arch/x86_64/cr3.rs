@@ -1,6 +1,7 @@
 use super::table::{Table, PML4Level};
 use cpu::control_regs::cr3::{read, write};
 pub use cpu::control_regs::cr3::*;
+use memory::{PAddr, Page, PhysicalPage};
 
 /// Returns the current Page Meta-Level 4 table
 ///
@@ -23,3 +24,50 @@ pub unsafe fn current_pml4() -> *mut Table<PML4Level> {
 pub unsafe fn set_pml4(pml4: Table<PML4Level>) {
     write(pml4.frame().base_addr())
 }
+
+/// Returns true if `frame` is the PML4 frame currently loaded in `$cr3`.
+///
+/// `$cr3` is only read once. Only the frame is compared, so it doesn't
+/// matter which PCID the table is tagged with.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub fn is_active(frame: PhysicalPage) -> bool {
+    // this is safe since the paging code only runs in kernel mode
+    is_active_with(frame, || unsafe { read() })
+}
+
+/// Returns true if `frame` is the PML4 frame in the `$cr3` value returned
+/// by `read_cr3`.
+///
+/// This is `is_active` with the read from `$cr3` swapped out, so that it
+/// can be checked against a mock value.
+pub fn is_active_with<F>(frame: PhysicalPage, read_cr3: F) -> bool
+where F: FnOnce() -> PAddr {
+    PhysicalPage::containing(read_cr3()) == frame
+}
+
+/// Loads `frame` into `$cr3`, and returns the frame that was loaded before.
+///
+/// # Safety
+/// + Control registers should generally not be modified during normal
+///   operation.
+/// + `frame` must hold a PML4 that maps the running kernel.
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub unsafe fn swap_pagetable_frame(frame: PhysicalPage) -> PhysicalPage {
+    swap_with(frame, || read(), |addr| write(addr))
+}
+
+/// Writes `frame` with `write_cr3`, and returns the frame in the `$cr3`
+/// value `read_cr3` returned before that.
+///
+/// This is `swap_pagetable_frame` with the `$cr3` accesses swapped out, so
+/// that it can be checked against a mock register.
+pub fn swap_with<R, W>(frame: PhysicalPage, read_cr3: R, write_cr3: W)
+                      -> PhysicalPage
+where R: FnOnce() -> PAddr
+    , W: FnOnce(PAddr) {
+    let prev = PhysicalPage::containing(read_cr3());
+    write_cr3(frame.base_addr());
+    prev
+}
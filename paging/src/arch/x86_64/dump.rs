@@ -0,0 +1,224 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Dumping the mappings in a page table, for diagnostics.
+use memory::{MemRange, Page, PageRange, PhysicalPage, VirtualPage};
+
+use core::mem;
+
+use super::ActivePML4;
+use super::table::{ EntryFlags, PML4Level, Table
+                  , ACCESSED, DIRTY, NO_EXECUTE, N_ENTRIES, RECURSIVE_INDEX
+                  , WRITABLE };
+
+/// The number of pages in the 48-bit virtual address space.
+const N_PAGES: usize = 1 << 36;
+
+/// The first page number in the higher half of the address space.
+const HIGHER_HALF: usize = 1 << 35;
+
+/// A run of pages mapped to contiguous frames with the same flags.
+///
+/// Pages are numbered by their index in the 48-bit address space, rather
+/// than by their canonical address.
+#[derive(Copy, Clone, Debug)]
+struct Run { page: usize
+           , n_pages: usize
+           , frame: PhysicalPage
+           , flags: EntryFlags
+           }
+
+impl Run {
+    /// Returns true if `next` directly follows this run, so they can be
+    /// combined.
+    #[inline]
+    fn is_followed_by(&self, next: &Run) -> bool {
+        self.page + self.n_pages == next.page
+            && next.page != HIGHER_HALF
+            && self.frame + self.n_pages == next.frame
+            && self.flags == next.flags
+    }
+
+    /// Convert this run into the range of `VirtualPage`s it covers.
+    fn into_mapping(self) -> (PageRange, PhysicalPage, EntryFlags) {
+        // sign-extend pages in the higher half to get canonical addresses
+        let number = if self.page >= HIGHER_HALF {
+            ((self.page << 12) | 0xffff_0000_0000_0000) >> 12
+        } else {
+            self.page
+        };
+        let start = VirtualPage { number: number };
+        (start.range_of(self.n_pages), self.frame, self.flags)
+    }
+}
+
+/// An iterator over the mappings in a page table.
+///
+/// Contiguous pages mapped to contiguous frames with the same flags are
+/// coalesced into a single range. The accessed and dirty flags are ignored,
+/// since the CPU sets them on its own.
+pub struct Mappings<'a> { pml4: &'a Table<PML4Level>
+                        , next: usize
+                        , pending: Option<Run>
+                        }
+
+/// Returns the first page number after `page` aligned on `1 << shift`.
+#[inline]
+fn align_next(page: usize, shift: usize) -> usize {
+    ((page >> shift) + 1) << shift
+}
+
+impl<'a> Mappings<'a> {
+
+    /// Returns the next mapped 4KiB page or huge page.
+    fn next_leaf(&mut self) -> Option<Run> {
+        while self.next < N_PAGES {
+            let page = self.next;
+            let pml4_index = (page >> 27) % N_ENTRIES;
+            // the recursive mapping is not dumped.
+            if pml4_index == RECURSIVE_INDEX {
+                self.next = N_PAGES;
+                break
+            }
+
+            let pdpt = match self.pml4.next_table(pml4_index) {
+                Some(pdpt) => pdpt
+              , None => { self.next = align_next(page, 27); continue }
+            };
+            let pdpt_entry = &pdpt[(page >> 18) % N_ENTRIES];
+            if pdpt_entry.is_huge() {
+                self.next = align_next(page, 18);
+                if let Some(frame) = pdpt_entry.get_frame() {
+                    return Some(Run { page: page, n_pages: 1 << 18
+                                    , frame: frame
+                                    , flags: pdpt_entry.flags() })
+                }
+                continue
+            }
+
+            let pd = match pdpt.next_table((page >> 18) % N_ENTRIES) {
+                Some(pd) => pd
+              , None => { self.next = align_next(page, 18); continue }
+            };
+            let pd_entry = &pd[(page >> 9) % N_ENTRIES];
+            if pd_entry.is_huge() {
+                self.next = align_next(page, 9);
+                if let Some(frame) = pd_entry.get_frame() {
+                    return Some(Run { page: page, n_pages: 1 << 9
+                                    , frame: frame
+                                    , flags: pd_entry.flags() })
+                }
+                continue
+            }
+
+            let pt = match pd.next_table((page >> 9) % N_ENTRIES) {
+                Some(pt) => pt
+              , None => { self.next = align_next(page, 9); continue }
+            };
+            self.next = page + 1;
+            let pt_entry = &pt[page % N_ENTRIES];
+            if let Some(frame) = pt_entry.get_frame() {
+                return Some(Run { page: page, n_pages: 1
+                                , frame: frame
+                                , flags: pt_entry.flags() })
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = (PageRange, PhysicalPage, EntryFlags);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(mut leaf) = self.next_leaf() {
+            leaf.flags.remove(ACCESSED | DIRTY);
+            let extends = self.pending.map(|run| run.is_followed_by(&leaf))
+                                      .unwrap_or(false);
+            if extends {
+                if let Some(ref mut run) = self.pending {
+                    run.n_pages += leaf.n_pages;
+                }
+                continue
+            }
+            if let Some(run) = mem::replace(&mut self.pending, Some(leaf)) {
+                return Some(run.into_mapping())
+            }
+        }
+        self.pending.take().map(Run::into_mapping)
+    }
+}
+
+/// An iterator over the ranges of pages in a page table that are both
+/// writable and executable.
+pub struct WxViolations<'a> { mappings: Mappings<'a> }
+
+impl<'a> Iterator for WxViolations<'a> {
+    type Item = PageRange;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((pages, _, flags)) = self.mappings.next() {
+            if flags.contains(WRITABLE) && !flags.contains(NO_EXECUTE) {
+                return Some(pages)
+            }
+        }
+        None
+    }
+}
+
+impl ActivePML4 {
+    /// Returns an iterator over every mapping in this page table.
+    ///
+    /// Contiguous 4KiB mappings with the same flags are combined into a
+    /// single range, and huge pages are reported as single ranges. Only
+    /// present tables are visited, and nothing is allocated.
+    pub fn dump_mappings(&self) -> Mappings {
+        Mappings { pml4: self.pml4(), next: 0, pending: None }
+    }
+
+    /// Returns an iterator over the pages that are both writable and
+    /// executable, which no page should be.
+    ///
+    /// Only the flags on the last-level entry are checked, so a page whose
+    /// higher-level tables already forbid writing or execution is still
+    /// reported. Contiguous pages are reported as a single range, as in
+    /// `dump_mappings`.
+    pub fn audit_wx(&self) -> WxViolations {
+        WxViolations { mappings: self.dump_mappings() }
+    }
+
+    /// Returns the number of pages mapped by this page table.
+    ///
+    /// Huge pages are counted as the number of 4KiB pages they cover. The
+    /// recursive mapping isn't counted.
+    pub fn count_mapped_pages(&self) -> usize {
+        self.dump_mappings()
+            .map(|(pages, _, _)| pages.length())
+            .sum()
+    }
+
+    /// Returns the number of page tables in this page table, including the
+    /// PML4.
+    ///
+    /// Only present tables are counted, and the PML4 isn't counted again
+    /// through the recursive mapping.
+    pub fn count_tables(&self) -> usize {
+        let pml4 = self.pml4();
+        let mut count = 1;
+        for pdpt in (0..N_ENTRIES).filter(|&i| i != RECURSIVE_INDEX)
+                                  .filter_map(|i| pml4.next_table(i)) {
+            count += 1;
+            for pd in (0..N_ENTRIES).filter_map(|i| pdpt.next_table(i)) {
+                count += 1;
+                count += (0..N_ENTRIES).filter_map(|i| pd.next_table(i))
+                                       .count();
+            }
+        }
+        count
+    }
+}
@@ -0,0 +1,94 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Mappings that are unmapped again when they go out of scope.
+//!
+//! `Drop` can't be passed an allocator, so a [`MappedRegion`] borrows both
+//! the `ActivePageTable` and the `FrameAllocator` it was mapped with for as
+//! long as it lives. Nothing else can change the page table in the
+//! meantime, so the page is certain to still be mapped when it's dropped.
+//!
+//! [`MappedRegion`]: struct.MappedRegion.html
+use memory::{Page, PhysicalPage, VirtualPage};
+use alloc::FrameAllocator;
+
+use super::ActivePageTable;
+use super::table::EntryFlags;
+use ::{Mapper, MapResult};
+
+/// A page mapped by `ActivePageTable::map_guarded`, which is unmapped when
+/// this is dropped.
+///
+/// The frame the page is mapped to isn't deallocated; only page tables that
+/// become empty are returned to the allocator. Errors unmapping the page
+/// are ignored on drop; call `unmap` to see them.
+pub struct MappedRegion<'a, A>
+where A: FrameAllocator + 'a { table: &'a mut ActivePageTable
+                              , alloc: &'a mut A
+                              , page: VirtualPage
+                              , unmapped: bool
+                              }
+
+impl<'a, A> MappedRegion<'a, A>
+where A: FrameAllocator {
+    /// Returns the mapped page.
+    #[inline]
+    pub fn page(&self) -> VirtualPage { self.page }
+
+    /// Returns a pointer to the start of the mapped page.
+    ///
+    /// The pointer is only valid until this `MappedRegion` is dropped.
+    #[inline]
+    pub fn as_mut_ptr<T>(&self) -> *mut T { self.page.base().as_mut_ptr() }
+
+    /// Unmap the page now, returning any error.
+    pub fn unmap(mut self) -> MapResult<()> {
+        self.unmapped = true;
+        self.table.unmap_keep_frames(self.page, self.alloc)
+    }
+}
+
+impl<'a, A> Drop for MappedRegion<'a, A>
+where A: FrameAllocator {
+    fn drop(&mut self) {
+        if !self.unmapped {
+            trace!("unmapping guarded page {:?}", self.page);
+            let _ = self.table.unmap_keep_frames(self.page, self.alloc);
+        }
+    }
+}
+
+impl ActivePageTable {
+    /// Map `page` to `frame` until the returned `MappedRegion` is dropped.
+    ///
+    /// The `MappedRegion` borrows this table and `alloc` until then.
+    ///
+    /// # Arguments
+    /// + `page`: the page to map
+    /// + `frame`: the frame to map it to, which the caller still owns
+    /// + `flags`: the flags to map the page with
+    /// + `alloc`: the `FrameAllocator` to allocate page tables from, and
+    ///   return them to
+    ///
+    /// # Returns
+    /// + a `MappedRegion` that unmaps `page` when it's dropped
+    /// + `MapErr::AlreadyInUse` if `page` is already mapped
+    pub fn map_guarded<'a, A>( &'a mut self
+                             , page: VirtualPage
+                             , frame: PhysicalPage
+                             , flags: EntryFlags
+                             , alloc: &'a mut A)
+                             -> MapResult<MappedRegion<'a, A>>
+    where A: FrameAllocator {
+        self.map(page, frame, flags, alloc)?;
+        Ok(MappedRegion { table: self
+                        , alloc: alloc
+                        , page: page
+                        , unmapped: false })
+    }
+}
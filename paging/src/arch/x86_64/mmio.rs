@@ -0,0 +1,126 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Mapping device memory into the kernel's address space.
+use memory::{Page, PAddr, VAddr, VirtualPage};
+use alloc::FrameAllocator;
+
+use super::ActivePageTable;
+use super::table::EntryFlags;
+use ::{Mapper, MapResult, MapErr};
+
+/// The first page of the region that `ActivePageTable::map_mmio` places
+/// device memory in (PML4 entry 509, just below the stack area).
+pub const MMIO_AREA_START: usize = 0xffff_fe80_0000_0000 >> 12;
+
+/// The number of pages in the region device memory is placed in.
+pub const MMIO_AREA_PAGES: usize = 512 * 512 * 512;
+
+/// The maximum number of MMIO regions that can be mapped at once.
+pub const MAX_MMIO: usize = 16;
+
+/// A region of device memory mapped by `map_mmio`.
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRegion { start: VirtualPage
+                      , n_pages: usize
+                      }
+
+impl ActivePageTable {
+    /// Map `len` bytes of device memory starting at `phys` into the MMIO
+    /// area, uncached.
+    ///
+    /// The region is placed in the first unmapped run of pages in the MMIO
+    /// area that is large enough. The device's frames are not allocated
+    /// from, or returned to, a `FrameAllocator`.
+    ///
+    /// # Arguments
+    /// + `phys`: the physical address of the device memory
+    /// + `len`: the length of the device memory, in bytes
+    /// + `alloc`: the `FrameAllocator` to allocate page tables from
+    ///
+    /// # Returns
+    /// + the virtual address `phys` is mapped to, at the same offset into
+    ///   its page
+    /// + `MapErr::NoPage` if `len` is zero, or the MMIO area is full
+    /// + `MapErr::Capacity` if `MAX_MMIO` regions are already mapped
+    pub fn map_mmio<A>(&mut self, phys: PAddr, len: usize, alloc: &mut A)
+                      -> MapResult<VAddr>
+    where A: FrameAllocator {
+        if len == 0 {
+            return Err(MapErr::NoPage {
+                message: "map MMIO region"
+              , cause: "the region must be at least one byte"
+            })
+        }
+        let slot = self.mmio.iter().position(|slot| slot.is_none())
+                       .ok_or(MapErr::Capacity { message: "map MMIO region"
+                                               , max: MAX_MMIO })?;
+
+        let (frame, offset) = phys.frame_offset_pair();
+        let offset = offset as usize;
+        let n_pages = VirtualPage::pages_for_bytes(offset + len);
+
+        let area = VirtualPage { number: MMIO_AREA_START }
+                       .range_of(MMIO_AREA_PAGES);
+        let run = self.find_free_region(n_pages, area)
+                      .ok_or(MapErr::NoPage {
+                          message: "map MMIO region"
+                        , cause: "no unmapped region in the MMIO area was \
+                                  large enough"
+                      })?;
+        for (i, page) in run.clone().enumerate() {
+            if let Err(err) =
+                self.map(page, frame + i, EntryFlags::mmio(), alloc) {
+                for page in run.start .. page {
+                    let _ = self.unmap_unflushed(page, alloc);
+                }
+                self.flush_range(run.start .. page);
+                return Err(err)
+            }
+        }
+        self.mmio[slot] = Some(MmioRegion { start: run.start
+                                          , n_pages: n_pages });
+        let vaddr = run.start.base() + offset;
+        trace!("mapped MMIO {:#x} ({} bytes) at {:#x}", phys, len, vaddr);
+        Ok(vaddr)
+    }
+
+    /// Unmap a region of device memory mapped by `map_mmio`.
+    ///
+    /// # Arguments
+    /// + `vaddr`: the virtual address returned by `map_mmio`
+    /// + `alloc`: the `FrameAllocator` to return empty page tables to
+    ///
+    /// # Returns
+    /// + `MapErr::Other` if `vaddr` isn't in the first page of an MMIO
+    ///   region
+    pub fn unmap_mmio<A>(&mut self, vaddr: VAddr, alloc: &mut A)
+                        -> MapResult<()>
+    where A: FrameAllocator {
+        let page = VirtualPage::containing(vaddr);
+        let slot = self.mmio.iter()
+                       .position(|slot| match *slot {
+                           Some(ref region) => region.start == page
+                         , None => false
+                       })
+                       .ok_or(MapErr::Other {
+                           message: "unmap MMIO region"
+                         , page: page
+                         , cause: "the address is not an MMIO region"
+                       })?;
+        let region = self.mmio[slot].take()
+                                    .expect("MMIO slot was just found");
+        let pages = region.start.range_of(region.n_pages);
+        for page in pages.clone() {
+            self.unmap_unflushed(page, alloc)?;
+        }
+        self.flush_range(pages);
+        trace!("unmapped MMIO region at {:#x}", vaddr);
+        Ok(())
+    }
+}
@@ -14,11 +14,18 @@
 //! finally the bottom-level Page Table (PT).
 use core::{fmt, ops};
 use core::ptr::Unique;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+#[cfg(feature = "la57")]
+use core::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
 
-use alloc::FrameAllocator;
-use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
+use alloc::{AllocErr, AllocResult, FrameAllocator};
+use cpu::cpuid::cpuid;
+use memory::{ Addr, FrameRange, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE, MemRange
+            , PAGE_SHIFT, PAGE_SIZE, PAddr, Page, PageRange, PhysicalPage
+            , VAddr, VirtualPage };
 use params::InitParams;
-use ::{Mapper, MapResult, MapErr};
+use spin::Mutex;
+use ::{HugePageSize, Mapper, MapResult, MapErr, PageSize};
 
 use self::table::*;
 use self::temp::TempPage;
@@ -27,8 +34,161 @@ pub mod table;
 pub mod tlb;
 pub mod temp;
 pub mod cr3;
+pub mod address_space;
+pub mod dump;
+pub mod mmio;
+pub mod guard;
+pub mod plan;
+mod cow;
+
+pub use self::plan::{kernel_remap_plan, RemapPlan};
+/// The maximum number of regions an `ActivePageTable` can reserve.
+pub const MAX_RESERVED: usize = 16;
+
+/// The maximum number of guard pages that can be marked at once.
+pub const MAX_GUARDS: usize = 32;
+
+/// The PML4 entries that map the kernel: the identity-mapped kernel image
+/// and temp page, the MMIO area, and the kernel stacks.
+///
+/// These are the same in every address space. `InactivePageTable::new`
+/// points them at the active table's PDPTs, so anything mapped in them
+/// shows up in every table, and the kernel's `GLOBAL` pages really are the
+/// same everywhere. Their PDPTs are never freed.
+pub const KERNEL_PML4_ENTRIES: [usize; 3] =
+    [ 0
+    , (mmio::MMIO_AREA_START >> 27) & 0o777
+    , (::stack::STACK_AREA_START >> 27) & 0o777 ];
+
+/// Returns true if PML4 entry `i` is one of the `KERNEL_PML4_ENTRIES`.
+#[inline]
+pub fn is_kernel_entry(i: usize) -> bool {
+    KERNEL_PML4_ENTRIES.contains(&i)
+}
+
+/// A region of pages reserved for demand paging.
+#[derive(Copy, Clone, Debug)]
+struct Reserved { /// The frame of the PML4 the region was reserved in
+                  table: PhysicalPage
+                , start: VirtualPage
+                , n_pages: usize
+                , flags: EntryFlags
+                }
+
+impl Reserved {
+    /// Returns the range of pages in this region.
+    #[inline]
+    fn pages(&self) -> PageRange {
+        self.start.range_of(self.n_pages)
+    }
+}
+
+/// The functions that frames for resolving page faults in reserved regions
+/// are allocated from, and freed to.
+///
+/// The page fault handler can't be handed a `FrameAllocator`, so these are
+/// registered with `set_fault_frames` instead.
+#[derive(Copy, Clone)]
+pub struct FaultFrames { /// Allocate a frame
+                         pub allocate: fn() -> AllocResult<PhysicalPage>
+                       , /// Free a frame returned by `allocate`
+                         pub deallocate: fn(PhysicalPage)
+                       }
+
+impl FrameAllocator for FaultFrames {
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        (self.allocate)()
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        (self.deallocate)(frame)
+    }
+
+    unsafe fn allocate_range(&mut self, _num: usize)
+                            -> AllocResult<FrameRange> {
+        Err(AllocErr::invalid_input("fault frames are allocated one at a time"))
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range {
+            (self.deallocate)(frame)
+        }
+    }
+}
+
+/// The registered `FaultFrames`, if there are any.
+static FAULT_FRAMES: Mutex<Option<FaultFrames>> = Mutex::new(None);
+
+/// The `ActivePageTable` that page faults are resolved in, once it's been
+/// handed over with `set_fault_table`.
+static FAULT_TABLE: Mutex<Option<ActivePageTable>> = Mutex::new(None);
+
+/// Register the functions that page faults in reserved regions are resolved
+/// with frames from.
+///
+/// Until they're registered, `handle_page_fault` resolves no faults.
+///
+/// # Returns
+/// + the previously registered `FaultFrames`, if there were any
+pub fn set_fault_frames(frames: FaultFrames) -> Option<FaultFrames> {
+    ::core::mem::replace(&mut *FAULT_FRAMES.lock(), Some(frames))
+}
+
+/// Hand the `ActivePageTable` over to the page fault handler, so that
+/// faults in the regions it reserved can be resolved.
+///
+/// # Returns
+/// + the previously handed over `ActivePageTable`, if there was one
+pub fn set_fault_table(table: ActivePageTable) -> Option<ActivePageTable> {
+    ::core::mem::replace(&mut *FAULT_TABLE.lock(), Some(table))
+}
+
+/// Resolve a page fault at `faulting` in the `ActivePageTable` handed over
+/// with `set_fault_table`, with a frame from the registered `FaultFrames`.
+///
+/// This is for the page fault handler, which is passed `$cr2`.
+///
+/// # Returns
+/// + `Ok(())` if the fault was resolved, and the access can be retried
+/// + `MapErr::NoPage` if no `FaultFrames` are registered, or no table was
+///   handed over
+/// + `MapErr::Other` if the fault interrupted code that was borrowing the
+///   table
+/// + the error from `ActivePageTable::resolve_fault` if it's a genuine
+///   fault
+pub fn handle_page_fault(faulting: VAddr) -> MapResult<()> {
+    let frames = *FAULT_FRAMES.lock();
+    let mut frames = frames.ok_or(MapErr::NoPage {
+        message: "resolve page fault"
+      , cause: "no frames were registered for resolving faults"
+    })?;
+    // if the table is already locked, the fault came from code that was
+    // editing it, so it can't be borrowed again without deadlocking.
+    let mut table = FAULT_TABLE.try_lock().ok_or(MapErr::Other {
+        message: "resolve page fault"
+      , page: VirtualPage::containing(faulting)
+      , cause: "the page table was in use when the fault happened"
+    })?;
+    let table = table.as_mut().ok_or(MapErr::NoPage {
+        message: "resolve page fault"
+      , cause: "no page table was handed over for resolving faults"
+    })?;
+    table.resolve_fault(faulting, &mut frames)
+}
+
 #[derive(Debug)]
-pub struct ActivePageTable { pml4: ActivePML4 }
+pub struct ActivePageTable { pml4: ActivePML4
+                           , /// The regions reserved by `reserve`, in every
+                             /// page table this one has switched to
+                             reserved: [Option<Reserved>; MAX_RESERVED]
+                           , /// The regions mapped by `map_mmio`, which are
+                             /// in a kernel PML4 entry that every table
+                             /// shares, so they're kept across switches
+                             mmio: [Option<mmio::MmioRegion>; mmio::MAX_MMIO]
+                           , /// The temporary page lent by `with_temp_page`
+                             temp: Option<TempPage>
+                           , frame: PhysicalPage
+                           }
 
 impl ops::Deref for ActivePageTable {
     type Target = ActivePML4;
@@ -44,21 +204,244 @@ impl ops::DerefMut for ActivePageTable {
     }
 }
 
+/// Set once the `ActivePageTable` has been handed out by `take`.
+static TAKEN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Set while `using` has the recursive entry pointed at an inactive table,
+/// so that `map` and `unmap` don't mistake it for corruption.
+static USING_INACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
 impl ActivePageTable {
+    /// Returns the `ActivePageTable` the first time this is called, and
+    /// `None` every time after that.
+    ///
+    /// Only one `ActivePageTable` can exist without aliasing the live PML4,
+    /// so this is the safe way to get it.
+    pub fn take() -> Option<ActivePageTable> {
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            // this is safe since no other `ActivePageTable` was taken
+            Some(unsafe { ActivePageTable::new() })
+        }
+    }
+
+    /// Returns an `ActivePageTable`, whether or not one already exists.
+    ///
+    /// # Safety
+    /// + If another `ActivePageTable` is still in use, both will alias the
+    ///   live PML4. This is for reclaiming the page table when the one that
+    ///   was taken can't be reached, such as when it's dropped.
     pub unsafe fn new() -> ActivePageTable {
-        ActivePageTable { pml4: ActivePML4::new() }
+        ActivePageTable { pml4: ActivePML4::new()
+                        , reserved: [None; MAX_RESERVED]
+                        , mmio: [None; mmio::MAX_MMIO]
+                        , temp: None
+                        , frame: cr3::current_pagetable_frame()
+                        }
+    }
+
+    /// Returns true if this is still the page table loaded in `$cr3`.
+    ///
+    /// This catches an `ActivePageTable` that was held on to after another
+    /// one switched to a different table.
+    #[inline]
+    pub fn is_current(&self) -> bool { cr3::is_active(self.frame) }
+
+    /// Reserve a range of pages to be mapped on demand.
+    ///
+    /// No frames are allocated until a page in the range is first accessed,
+    /// and the page fault handler calls `resolve_fault`. The range is only
+    /// reserved in the page table that's currently loaded, so faults in it
+    /// aren't resolved once this switches to another table.
+    ///
+    /// # Arguments
+    /// + `range`: the pages to reserve
+    /// + `flags`: the flags the pages should be mapped with
+    ///
+    /// # Returns
+    /// + `MapErr::Overlaps` if `range` overlaps another reserved region
+    /// + `MapErr::Capacity` if `MAX_RESERVED` regions are already reserved
+    pub fn reserve(&mut self, range: PageRange, flags: EntryFlags)
+                  -> MapResult<()> {
+        let table = self.frame;
+        let overlaps = self.reserved.iter()
+                           .filter_map(|slot| slot.as_ref())
+                           .filter(|region| region.table == table)
+                           .any(|region| region.pages().overlaps(&range));
+        if overlaps {
+            return Err(MapErr::Overlaps { message: "reserve pages"
+                                        , page: range.start })
+        }
+        let region = Reserved { table: table
+                              , start: range.start
+                              , n_pages: range.length()
+                              , flags: flags };
+        match self.reserved.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                trace!("reserved {:?} for demand paging", range);
+                *slot = Some(region);
+                Ok(())
+            }
+          , None => Err(MapErr::Capacity { message: "reserve pages"
+                                         , max: MAX_RESERVED })
+        }
+    }
+
+    /// Release a range of pages reserved by `reserve`.
+    ///
+    /// Pages in the range that were already mapped on demand stay mapped.
+    ///
+    /// # Returns
+    /// + `MapErr::Other` if `range` isn't exactly a reserved region
+    pub fn unreserve(&mut self, range: PageRange) -> MapResult<()> {
+        let table = self.frame;
+        let slot = self.reserved.iter_mut()
+                       .find(|slot| match **slot {
+                           Some(ref region) => region.table == table
+                                            && region.pages() == range
+                         , None => false
+                       });
+        match slot {
+            Some(slot) => {
+                trace!("unreserved {:?}", range);
+                *slot = None;
+                Ok(())
+            }
+          , None => Err(MapErr::Other {
+                message: "unreserve pages"
+              , page: range.start
+              , cause: "the range isn't a reserved region"
+            })
+        }
+    }
+
+    /// Forget every region reserved in the page table whose PML4 is in
+    /// `table`, because it's being freed.
+    fn forget_reserved(&mut self, table: PhysicalPage) {
+        for slot in self.reserved.iter_mut() {
+            if slot.map_or(false, |region| region.table == table) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Mark `page` as a guard page, without mapping it.
+    ///
+    /// A fault on a guard page is an overflow of whatever is above it, such
+    /// as a stack, rather than a stray access to unmapped memory. Marking a
+    /// page that's already a guard page does nothing. A guard page can't be
+    /// mapped until it's unmarked with `unmark_guard`.
+    ///
+    /// # Returns
+    /// + `MapErr::Other` if `page` is mapped
+    /// + `MapErr::Capacity` if `MAX_GUARDS` pages are already guard pages
+    pub fn mark_guard(&mut self, page: VirtualPage) -> MapResult<()> {
+        if self.is_guard(page) { return Ok(()) }
+        if self.is_mapped(&page) {
+            return Err(MapErr::Other {
+                message: "mark guard page"
+              , page: page
+              , cause: "the page is mapped"
+            })
+        }
+        match self.pml4.guards.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                trace!("marked guard page {:?}", page);
+                *slot = Some(page);
+                Ok(())
+            }
+          , None => Err(MapErr::Capacity { message: "mark guard page"
+                                         , max: MAX_GUARDS })
+        }
+    }
+
+    /// Stop treating `page` as a guard page, so that it can be mapped again.
+    ///
+    /// # Returns
+    /// + `true` if `page` was a guard page
+    pub fn unmark_guard(&mut self, page: VirtualPage) -> bool {
+        match self.pml4.guards.iter_mut().find(|slot| **slot == Some(page)) {
+            Some(slot) => {
+                trace!("unmarked guard page {:?}", page);
+                *slot = None;
+                true
+            }
+          , None => false
+        }
+    }
+
+    /// Returns true if `page` was marked as a guard page by `mark_guard`.
+    pub fn is_guard(&self, page: VirtualPage) -> bool {
+        self.pml4.is_guard_page(page)
+    }
+
+    /// Resolve a page fault at `faulting` by mapping the page it's in, if
+    /// it's in a reserved region.
+    ///
+    /// The page is mapped to a newly allocated, zeroed frame, with the flags
+    /// its region was reserved with.
+    ///
+    /// # Returns
+    /// + `Ok(())` if the fault was resolved, and the faulting access can be
+    ///   retried.
+    /// + `MapErr::Other` if this is a genuine fault: the address is in a
+    ///   guard page, it isn't in a reserved region, or its page is already
+    ///   mapped.
+    pub fn resolve_fault<A>(&mut self, faulting: VAddr, alloc: &mut A)
+                           -> MapResult<()>
+    where A: FrameAllocator {
+        let page = VirtualPage::containing(faulting);
+        if self.is_guard(page) {
+            return Err(MapErr::Other {
+                message: "resolve page fault"
+              , page: page
+              , cause: "stack overflow: the address is in a guard page"
+            })
+        }
+        let table = self.frame;
+        let region = self.reserved.iter()
+                         .filter_map(|slot| slot.as_ref())
+                         .find(|region| region.table == table
+                                     && region.pages().contains_page(page))
+                         .cloned();
+        let region = region.ok_or(MapErr::Other {
+            message: "resolve page fault"
+          , page: page
+          , cause: "the address is not in a reserved region"
+        })?;
+        if self.is_mapped(&page) {
+            return Err(MapErr::Other {
+                message: "resolve page fault"
+              , page: page
+              , cause: "the page is already mapped"
+            })
+        }
+        trace!("resolving page fault at {:?}", faulting);
+        self.map_to_any_zeroed(page, region.flags, alloc)
     }
 
     /// Execute a closure with the recursive mapping temporarily changed to a
     /// new page table
-    pub fn using<F>( &mut self
-                   , table: &mut InactivePageTable
-                   , temp_page: &mut temp::TempPage
-                   , f: F)
-                   -> MapResult
-    where F: FnOnce(&mut ActivePML4) -> MapResult {
-        let result: MapResult;
-        use self::tlb::flush_all;
+    ///
+    /// Whatever the closure returns is passed back to the caller, once the
+    /// recursive mapping has been restored. Inactive tables are four-level,
+    /// so this fails when the CPU is using 5-level paging.
+    pub fn using<F, T>( &mut self
+                      , table: &mut InactivePageTable
+                      , temp_page: &mut temp::TempPage
+                      , f: F)
+                      -> MapResult<T>
+    where F: FnOnce(&mut ActivePML4) -> MapResult<T> {
+        let result: MapResult<T>;
+        use self::tlb::Flush;
+        if paging_levels() != 4 {
+            return Err(MapErr::Other {
+                message: "use an inactive page table"
+              , page: **temp_page
+              , cause: "inactive page tables require 4-level paging"
+            })
+        }
         {
             // back up the current PML4 frame
             let prev_pml4_frame = unsafe {
@@ -69,22 +452,29 @@ impl ActivePageTable {
             // map temporary_page to current p4 table
             let pml4 = temp_page.map_to_table(prev_pml4_frame.clone(), self)?;
 
-            // remap the 511th PML4 entry (the recursive entry) to map to the // frame containing the new PML4.
-            self.pml4_mut()[511].set(table.pml4_frame, PRESENT | WRITABLE);
+            // remap the 511th PML4 entry (the recursive entry) to map to the
+            // frame containing the new PML4, and invalidate the current
+            // tables at the addresses they were recursively mapped to.
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                flush_recursive(self.pml4());
+                self.pml4_mut()[RECURSIVE_INDEX]
+                    .set(table.pml4_frame, PRESENT | WRITABLE);
+                VAddr::from(PML4_VADDR as usize).invlpg();
             }
 
             // execute the closure
+            USING_INACTIVE.store(true, Ordering::SeqCst);
             result = f(self);
+            USING_INACTIVE.store(false, Ordering::SeqCst);
 
-            // remap the 511th entry to point back to the original frame
-            pml4[511].set(prev_pml4_frame, PRESENT | WRITABLE);
-
+            // remap the 511th entry to point back to the original frame, and
+            // invalidate the inactive table's recursive mappings.
             unsafe {
                 // this is safe to execute; we are in kernel mode
-                flush_all();
+                flush_recursive(self.pml4());
+                pml4[RECURSIVE_INDEX].set(prev_pml4_frame, PRESENT | WRITABLE);
+                VAddr::from(PML4_VADDR as usize).invlpg();
             }
         }
         let _ = temp_page.unmap(self)?;
@@ -94,14 +484,35 @@ impl ActivePageTable {
 
     /// Replace the current `ActivePageTable` with the given `InactivePageTable`
     ///
+    /// Before switching, `new_table`'s PML4 is mapped to the temp page to
+    /// check that its recursive entry points back at itself, since loading a
+    /// PML4 without one would triple fault.
+    ///
     /// # Arguments
     /// + `new_table`: the `InactivePageTable` that will replace the current
     ///                `ActivePageTable`.
+    /// + `temp`: a `TempPage` for checking the new table
     ///
     /// # Returns
     /// + the old active page table as an `InactivePageTable`.
-    pub fn replace_with(&mut self, new_table: InactivePageTable)
-                       -> InactivePageTable {
+    /// + `MapErr::Other` if `new_table`'s recursive entry was missing.
+    pub fn replace_with( &mut self
+                       , new_table: InactivePageTable
+                       , temp: &mut temp::TempPage)
+                       -> MapResult<InactivePageTable> {
+        let recursive = {
+            let pml4 = temp.map_to_table(new_table.pml4_frame, self)?;
+            recursive_entry_maps(pml4, new_table.pml4_frame)
+        };
+        temp.unmap(self)?;
+        if !recursive {
+            return Err(MapErr::Other {
+                message: "replace active page table"
+              , page: **temp
+              , cause: "the new PML4's recursive entry was not set"
+            })
+        }
+
         unsafe {
             trace!("replacing {:?} with {:?}", self, new_table);
             // this is safe to execute; we are in kernel mode
@@ -109,14 +520,50 @@ impl ActivePageTable {
             trace!("current pml4 frame is {:?}", old_pml4_frame);
 
             cr3::set_pagetable_frame(new_table.pml4_frame);
+            self.frame = new_table.pml4_frame;
             trace!("set new pml4 frame to {:?}", new_table.pml4_frame);
 
-            InactivePageTable {
+            Ok(InactivePageTable {
                 pml4_frame: old_pml4_frame
-            }
+            })
         }
     }
 
+    /// Switch to the page table in `frame`, and return the frame of the one
+    /// that was active.
+    ///
+    /// Unlike `replace_with`, no `InactivePageTable` is consumed or
+    /// returned, so a scheduler can keep each address space's table and
+    /// switch between them as often as it likes. Nothing about the new
+    /// table is checked.
+    ///
+    /// # Safety
+    /// + `frame` must hold a PML4 whose recursive entry maps itself, and
+    ///   which maps the running kernel.
+    pub unsafe fn switch_to(&mut self, frame: PhysicalPage) -> PhysicalPage {
+        let prev = cr3::swap_pagetable_frame(frame);
+        self.frame = frame;
+        trace!("switched page table from {:?} to {:?}", prev, frame);
+        prev
+    }
+
+}
+
+/// The page table entries found by walking the page tables for an address.
+///
+/// Each entry is `None` if the walk stopped before reaching its level.
+#[derive(Debug)]
+pub struct WalkResult<'a> {
+    /// The PML4 entry
+    pub pml4: Option<&'a Entry>
+  , /// The PDPT entry
+    pub pdpt: Option<&'a Entry>
+  , /// The PD entry
+    pub pd: Option<&'a Entry>
+  , /// The PT entry
+    pub pt: Option<&'a Entry>
+  , /// The size of the huge page the walk stopped at, if it found one
+    pub huge: Option<HugePageSize>
 }
 
 /// Struct representing the currently active PML4 instance.
@@ -124,11 +571,18 @@ impl ActivePageTable {
 /// The `ActivePML4` is a `Unique` reference to a PML4-level page table. It's
 /// unique because, well, there can only be one active PML4 at a given time.
 ///
-pub struct ActivePML4(Unique<Table<PML4Level>>);
+pub struct ActivePML4 { table: Unique<Table<PML4Level>>
+                      , /// The pages marked as guard pages by `mark_guard`.
+                        ///
+                        /// Stacks are in one of the kernel's PML4 entries,
+                        /// which every table shares, so these are kept
+                        /// when the active table is switched.
+                        guards: [Option<VirtualPage>; MAX_GUARDS]
+                      }
 impl fmt::Debug for ActivePML4 {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Active {:?}", unsafe { self.0.as_ref() })
+        write!(f, "Active {:?}", unsafe { self.table.as_ref() })
     }
 }
 /// The active PML4 table is the single point of entry for page mapping.
@@ -136,35 +590,21 @@ impl Mapper for ActivePML4 {
     type Flags = EntryFlags;
 
     fn translate(&self, vaddr: VAddr) -> Option<PAddr> {
-        let offset = *vaddr % PAGE_SIZE as usize;
-        self.translate_page(Page::containing(vaddr))
-            .map(|frame| PAddr::from(frame.number + offset as u64) )
+        let (page, offset) = vaddr.page_offset_pair();
+        self.translate_page(page)
+            .map(|frame| frame.base_addr() + offset as u64)
     }
 
     fn translate_page(&self, page: VirtualPage) -> Option<PhysicalPage> {
-        let pdpt = self.pml4().next_table(page);
-
-        let huge_page = || {
-            pdpt.and_then(|pdpt|
-                pdpt[page]
-                    .do_huge(PDLevel::index_of(page) + PTLevel::index_of(page))
-                    .or_else(|| {
-                        pdpt.next_table(page).and_then(|pd|
-                            pd[page].do_huge(PTLevel::index_of(page))
-                        )
-                    })
-                )
-        };
-
-        pdpt.and_then(|pdpt| pdpt.next_table(page))
-            .and_then(|pd| pd.next_table(page))
-            .and_then(|pt| pt[page].get_frame())
-            .or_else(huge_page)
+        self.translate_page_sized(page).map(|(frame, _)| frame)
     }
 
 
     /// Modifies the page tables so that `page` maps to `frame`.
     ///
+    /// Pages in the region reserved for the recursive mapping can't be
+    /// mapped.
+    ///
     /// # Arguments
     /// + `page`: the virtual `Page` to map
     /// + `frame`: the physical `Frame` that `Page` should map to.
@@ -174,8 +614,8 @@ impl Mapper for ActivePML4 {
              , flags: EntryFlags, alloc: &mut A)
              -> MapResult<()>
     where A: FrameAllocator {
-        // base virtual address of page being mapped
-        // let addr = page.base();
+        self.debug_check_recursive();
+        self.check_mappable(page)?;
 
         // access or create all the lower-level page tables.
         let mut page_table // get the PML4
@@ -202,6 +642,47 @@ impl Mapper for ActivePML4 {
         }
     }
 
+    fn map_huge<A>( &mut self, page: VirtualPage, frame: PhysicalPage
+                  , size: HugePageSize, flags: EntryFlags, alloc: &mut A)
+                  -> MapResult<()>
+    where A: FrameAllocator {
+        self.check_mappable(page)?;
+        // both the page and the frame must start on a huge page boundary.
+        if page.base().as_usize() as u64 % size.bytes() != 0 {
+            return Err(MapErr::Other {
+                message: "map huge page"
+              , page: page
+              , cause: "the page was not aligned to the huge page size"
+            })
+        }
+        if *frame.base_addr() % size.bytes() != 0 {
+            return Err(MapErr::Other {
+                message: "map huge page"
+              , page: page
+              , cause: "the frame was not aligned to the huge page size"
+            })
+        }
+
+        // get or create the PDPT table at the page's PML4 index
+        let pdpt = self.pml4_mut().create_next(page, alloc)?;
+        let entry = match size {
+            // 1 GiB pages are mapped by the PDPT entry...
+            HugePageSize::Size1G => &mut pdpt[page]
+          , // ...and 2 MiB pages by the PD entry.
+            HugePageSize::Size2M => &mut pdpt.create_next(page, alloc)?[page]
+        };
+        if entry.is_unused() {
+            entry.set(frame, flags | table::PRESENT | table::HUGE_PAGE);
+            Ok(())
+        } else {
+            Err(MapErr::AlreadyInUse {
+                message: "map huge page"
+              , page: page
+              , frame: frame
+            })
+        }
+    }
+
     fn identity_map<A>(&mut self, frame: PhysicalPage, flags: EntryFlags
                       , alloc: &mut A)
                       -> MapResult<()>
@@ -212,6 +693,17 @@ impl Mapper for ActivePML4 {
                 , alloc )
     }
 
+    fn identity_map_range<A>( &mut self, frames: FrameRange
+                            , flags: EntryFlags, alloc: &mut A )
+                            -> MapResult<()>
+    where A: FrameAllocator {
+        for frame in frames {
+            let vaddr = VAddr::from(*frame.base_addr() as usize);
+            self.map_or_merge(Page::containing(vaddr), frame, flags, alloc)?;
+        }
+        Ok(())
+    }
+
     fn map_to_any<A>( &mut self
                     , page: VirtualPage
                     , flags: EntryFlags
@@ -225,162 +717,3660 @@ impl Mapper for ActivePML4 {
               , cause: err
           })?;
         self.map(page, frame, flags, alloc)
+            .map_err(|err| {
+                unsafe { alloc.deallocate(frame) };
+                err
+            })
     }
 
-    /// Unmap the given `VirtualPage`.
-    ///
-    /// All freed frames are returned to the given `FrameAllocator`.
-    fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
-    where A: FrameAllocator {
+    fn update_flags(&mut self, page: VirtualPage, flags: EntryFlags)
+                   -> MapResult<()> {
         use self::tlb::Flush;
 
-        // get the page table entry corresponding to the page.
-        let page_table = self.pml4_mut()
-                             .next_table_mut(page)
-                             .and_then(|pdpt| pdpt.next_table_mut(page))
-                             .and_then(|pd| pd.next_table_mut(page))
-                             .ok_or(MapErr::Other {
-                                message: "unmap"
-                              , page: page
-                              , cause: "huge pages not supported"
-                            })?;
-        // index the entry from the table
-        let entry = &mut page_table[page];
-        trace!("got page table entry for {:?}", page);
-        // get the pointed frame for the page table entry.
-        let frame = entry.get_frame()
-                         .ok_or(MapErr::Other {
-                           message: "unmap"
-                         , page: page
-                         , cause: "it was not mapped"
-                       })?;
-        trace!("page table entry for {:?} points to {:?}", page, frame);
-        // mark the page table entry as unused
-        entry.set_unused();
-        trace!("set page table entry for {:?} as unused", page);
-        // deallocate the frame and flush the translation lookaside buffer
+        let not_mapped = MapErr::Other {
+            message: "update flags"
+          , page: page
+          , cause: "not mapped"
+        };
+        let entry = &mut self.pml4_mut()
+                             .page_table_mut_for(page)
+                             .ok_or(not_mapped.clone())?[page];
+        let frame = entry.get_frame().ok_or(not_mapped)?;
+        entry.set(frame, flags | table::PRESENT);
+        trace!("updated flags for {:?} to {:?}", page, flags);
         // this is safe because we're in kernel mode
         unsafe { page.invlpg() };
-        trace!("flushed TLB");
-        unsafe {
-            // this is hopefully safe because nobody else should be using an
-            // allocated page frame
-            alloc.deallocate(frame);
-            trace!("deallocated page {:?}", frame);
-        }
-        // TODO: check if page tables containing the unmapped page are empty
-        //       and deallocate them too?
         Ok(())
     }
 
-}
+    fn remap_to<A>( &mut self, page: VirtualPage, new_frame: PhysicalPage
+                  , flags: EntryFlags, _alloc: &mut A )
+                  -> MapResult<PhysicalPage>
+    where A: FrameAllocator {
+        use self::tlb::Flush;
 
-impl ActivePML4 {
+        let not_mapped = MapErr::Other {
+            message: "remap page"
+          , page: page
+          , cause: "not mapped"
+        };
+        let (entry, size) = self.leaf_mut(page).ok_or(not_mapped.clone())?;
+        if size != PageSize::Size4K {
+            return Err(MapErr::Other {
+                message: "remap page"
+              , page: page
+              , cause: "the page is part of a huge page"
+            })
+        }
+        let old_frame = entry.get_frame().ok_or(not_mapped)?;
+        entry.set(new_frame, flags | table::PRESENT);
+        trace!("remapped {:?} from {:?} to {:?}", page, old_frame, new_frame);
+        // this is safe because we're in kernel mode
+        unsafe { page.invlpg() };
+        Ok(old_frame)
+    }
 
-    pub unsafe fn new() -> Self {
-        ActivePML4(Unique::new(PML4_PTR))
+    fn protect_range(&mut self, range: PageRange, flags: EntryFlags)
+                    -> MapResult<usize> {
+        let mut count = 0;
+        let mut page = range.start;
+        while page < range.end {
+            let n_pages = match self.leaf_mut(page) {
+                Some((entry, size)) => {
+                    // a huge page has to stay huge, or its frame would be
+                    // read as the address of a page table. `GLOBAL`, and
+                    // `PAT` in a PT entry, aren't protections, so they're
+                    // kept too.
+                    let kept = match size {
+                        PageSize::Size4K => table::PAT | table::GLOBAL
+                      , _ => table::HUGE_PAGE | table::GLOBAL
+                    };
+                    if let Some(frame) = entry.get_frame() {
+                        let kept = entry.flags() & kept;
+                        entry.set(frame, flags | kept | table::PRESENT);
+                        count += 1;
+                    }
+                    size.n_pages()
+                }
+              , None => 1
+            };
+            // skip the rest of a huge page, which may have started before
+            // `page`, so it's only counted once.
+            page = VirtualPage { number: (page.number & !(n_pages - 1))
+                                         + n_pages };
+        }
+        trace!("updated flags for {} pages in {:?} to {:?}"
+              , count, range, flags);
+        self.flush_range(range);
+        Ok(count)
     }
 
-    fn pml4(&self) -> &Table<PML4Level> {
-        unsafe { self.0.as_ref() }
+    fn is_accessed(&self, page: VirtualPage) -> Option<bool> {
+        self.leaf_entry(page)
+            .map(|entry| entry.flags().contains(table::ACCESSED))
     }
 
-    fn pml4_mut(&mut self) -> &mut Table<PML4Level> {
-        unsafe { self.0.as_mut() }
+    fn is_dirty(&self, page: VirtualPage) -> Option<bool> {
+        self.leaf_entry(page)
+            .map(|entry| entry.flags().contains(table::DIRTY))
     }
 
-    /// Returns true if the given page is mapped.
-    #[inline]
-    pub fn is_mapped(&self, page: &VirtualPage) -> bool {
-         self.translate_page(*page).is_some()
+    fn clear_accessed(&mut self, page: VirtualPage) -> MapResult<()> {
+        use self::tlb::Flush;
+
+        let not_mapped = MapErr::Other {
+            message: "clear accessed bit"
+          , page: page
+          , cause: "not mapped"
+        };
+        let entry = self.leaf_entry_mut(page).ok_or(not_mapped.clone())?;
+        let frame = entry.get_frame().ok_or(not_mapped)?;
+        let flags = entry.flags() - table::ACCESSED;
+        entry.set(frame, flags);
+        // the TLB may cache the accessed bit, so if we don't flush the page,
+        // the CPU won't set it again the next time the page is accessed.
+        // this is safe because we're in kernel mode
+        unsafe { page.invlpg() };
+        Ok(())
     }
 
+    /// Unmap the given `VirtualPage`.
+    ///
+    /// All freed frames are returned to the given `FrameAllocator`.
+    fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
+    where A: FrameAllocator {
+        self.debug_check_recursive();
+        let frames = self.unmap_keep_frames(page, alloc)?;
+        // this is hopefully safe because nobody else should be using an
+        // allocated page frame, unless it's shared and still referenced.
+        unsafe { deallocate_run(frames, alloc) };
+        Ok(())
+    }
 
-}
+    /// Unmap every page in the given `PageRange`.
+    ///
+    /// The unmapped pages are flushed from the TLB together once they've
+    /// all been unmapped, using `tlb::flush_range`. Freed frames that are
+    /// contiguous are returned to `alloc` together with
+    /// `deallocate_contiguous`, rather than one at a time. A huge page in
+    /// the range is unmapped and flushed as a whole, even if the range only
+    /// covers part of it.
+    fn unmap_range<A>(&mut self, pages: PageRange, alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator {
+        // the run of contiguous frames freed so far
+        let mut run: Option<FrameRange> = None;
+        // the first page unmapped, which is before the start of the range if
+        // it starts part way through a huge page
+        let mut first = pages.start;
+        let mut page = pages.start;
+        let mut result = Ok(());
+        while page < pages.end {
+            let frames = match self.unmap_unflushed(page, alloc) {
+                Ok(frames) => frames
+              , Err(err) => { result = Err(err); break }
+            };
+            // a huge page starts on the first page aligned to its size, and
+            // the next mapping starts just after its end.
+            let n_pages = frames.length();
+            let start = VirtualPage { number: page.number & !(n_pages - 1) };
+            if start < first { first = start }
+            page = start + n_pages;
+            let extends = run.as_ref()
+                             .map(|run| run.end == frames.start)
+                             .unwrap_or(false);
+            if extends {
+                run = run.map(|run| run.start .. frames.end);
+            } else {
+                if let Some(done) = run.take() {
+                    unsafe { deallocate_run(done, alloc) };
+                }
+                run = Some(frames);
+            }
+        }
+        // flush everything that was unmapped, even if unmapping stopped
+        // part way through the range.
+        self.flush_range(first .. page);
+        if let Some(run) = run {
+            unsafe { deallocate_run(run, alloc) };
+        }
+        result
+    }
 
-/// An inactive page table that the CPU is not currently using
-#[derive(Debug)]
-pub struct InactivePageTable {
-    pml4_frame: PhysicalPage
 }
 
-impl InactivePageTable {
-    pub fn new( frame: PhysicalPage
-              , active_table: &mut ActivePageTable
-              , temp: &mut TempPage)
-              -> MapResult<Self> {
-        {
-            trace!("Mapping page {} to frame {}", temp.number, frame.number);
-            let table = temp.map_to_table(frame.clone(), active_table)?;
-            trace!( " . . . Mapped temp page to table frame .");
-            table.zero();
-            trace!( " . . . Zeroed inactive table frame.");
-            table[511].set( frame.clone(), PRESENT | WRITABLE);
-            trace!(" . . . Set active table to point to new inactive table.")
+/// Remove a reference to each frame in a run of contiguous frames, and
+/// return the frames whose last reference is gone to `alloc`.
+///
+/// Frames that are still shared with another mapping are kept. The rest
+/// are returned in as few contiguous runs as possible.
+///
+/// # Safety
+/// + The frames must not be mapped by the pages being unmapped any more.
+unsafe fn deallocate_run<A>(run: FrameRange, alloc: &mut A)
+where A: FrameAllocator {
+    // the frames whose last reference is gone, that haven't been returned
+    let mut freed = run.start .. run.start;
+    for frame in run {
+        if alloc.remove_ref(frame) > 0 {
+            trace!("{:?} is still shared", frame);
+            continue
         }
-        let _ = temp.unmap(active_table)?;
-        trace!(" . . Unmapped temp page.");
-
-        Ok(InactivePageTable { pml4_frame: frame })
+        if freed.end != frame {
+            free_contiguous(freed, alloc);
+            freed = frame .. frame;
+        }
+        freed.end = frame + 1;
     }
+    free_contiguous(freed, alloc);
 }
 
-pub fn test_paging<A>(alloc: &mut A) -> MapResult<()>
+/// Return contiguous `frames` to `alloc`.
+unsafe fn free_contiguous<A>(frames: FrameRange, alloc: &mut A)
 where A: FrameAllocator {
-    info!("testing paging");
+    match frames.length() {
+        0 => return
+      , 1 => alloc.deallocate(frames.start)
+      , n => alloc.deallocate_contiguous(frames.start, n)
+    }
+    trace!("deallocated {:?}", frames);
+}
+
+/// Clear the huge page mapped by the given PD or PDPT `entry`.
+///
+/// The huge page is *not* flushed from the TLB; that's up to the caller.
+///
+/// # Arguments
+/// + `entry`: the page table entry with the huge page flag set
+/// + `page`: any `VirtualPage` inside the huge page
+/// + `size`: the size of the huge page
+///
+/// # Returns
+/// + the range of frames covered by the huge page.
+fn clear_huge(entry: &mut Entry, page: VirtualPage, size: HugePageSize)
+              -> MapResult<FrameRange> {
+    let start_frame = entry.get_frame()
+                           .ok_or(MapErr::Other {
+                              message: "unmap huge page"
+                            , page: page
+                            , cause: "it was not mapped"
+                          })?;
+    entry.set_unused();
+    trace!("set huge page entry for {:?} as unused", page);
+    Ok(start_frame.range_of(size.n_pages()))
+}
+
+/// Returns true if the CPU supports 5-level paging.
+///
+/// This is reported by bit 16 of `%ecx` in `CPUID` leaf 7.
+#[cfg(feature = "la57")]
+fn is_la57_supported() -> bool {
+    cpuid(7, 0).ecx & (1 << 16) != 0
+}
+
+/// The number of page table levels, once `paging_levels` has checked.
+///
+/// `%cr4.LA57` can't change while paging is on, so this never goes stale.
+#[cfg(feature = "la57")]
+static PAGING_LEVELS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Returns the number of page table levels the CPU is using.
+///
+/// This is 5 if the `la57` feature is enabled and the CPU has 5-level
+/// paging turned on, and 4 otherwise. The CPU is only asked the first time;
+/// page table walks call this, so later calls return the cached answer.
+#[cfg(feature = "la57")]
+pub fn paging_levels() -> usize {
+    use cpu::control_regs::cr4;
+    let levels = PAGING_LEVELS.load(Ordering::Relaxed);
+    if levels != 0 { return levels }
+    // this is safe because we're in kernel mode
+    let levels = if is_la57_supported() && unsafe { cr4::is_la57_enabled() } {
+        5
+    } else {
+        4
+    };
+    PAGING_LEVELS.store(levels, Ordering::Relaxed);
+    levels
+}
+
+/// Returns the number of page table levels the CPU is using.
+#[cfg(not(feature = "la57"))]
+#[inline]
+pub fn paging_levels() -> usize { 4 }
+
+/// Returns a pointer to the PML4 that page table walks start from.
+///
+/// With 5-level paging, the recursively mapped top-level table is the PML5,
+/// and walks start from the PML4 in its first entry, which maps the lowest
+/// 256 TiB of the address space. Nothing above that can be mapped: SOS only
+/// uses 5-level paging to run on CPUs that have it turned on, and keeps
+/// its whole address space in that first PML4.
+///
+/// # Panics
+/// + If 5-level paging is on and the first PML5 entry isn't present. The
+///   boot code always creates it.
+#[cfg(feature = "la57")]
+unsafe fn top_pml4() -> *mut Table<PML4Level> {
+    if paging_levels() == 5 {
+        (*PML5_PTR).next_table_mut(0)
+                   .map(|pml4| pml4 as *mut _)
+                   .expect("PML5 has no first PML4")
+    } else {
+        PML4_PTR
+    }
+}
+
+#[cfg(not(feature = "la57"))]
+#[inline]
+unsafe fn top_pml4() -> *mut Table<PML4Level> { PML4_PTR }
+
+impl ActivePML4 {
+
+    /// Returns the active page table.
+    ///
+    /// With the `la57` feature, this checks whether the CPU is using 5-level
+    /// paging, and if it is, starts walks below the PML5. Only the lowest
+    /// 256 TiB of the address space can be reached then.
+    ///
+    /// # Panics
+    /// + If 5-level paging is on and the PML5 has no first PML4.
+    pub unsafe fn new() -> Self {
+        ActivePML4 { table: Unique::new(top_pml4())
+                   , guards: [None; MAX_GUARDS] }
+    }
+
+    fn pml4(&self) -> &Table<PML4Level> {
+        unsafe { self.table.as_ref() }
+    }
+
+    fn pml4_mut(&mut self) -> &mut Table<PML4Level> {
+        unsafe { self.table.as_mut() }
+    }
+
+    /// Returns true if `page` is marked as a guard page.
+    #[inline]
+    fn is_guard_page(&self, page: VirtualPage) -> bool {
+        self.guards.iter().any(|slot| *slot == Some(page))
+    }
+
+    /// Returns an error if `page` can't be mapped.
+    ///
+    /// Pages at non-canonical addresses can never be mapped, and neither can
+    /// pages in the region reserved for the recursive mapping, since they
+    /// would clobber the page tables. Guard pages can't be mapped while
+    /// they're marked, or they'd stop catching overflows.
+    fn check_mappable(&self, page: VirtualPage) -> MapResult<()> {
+        let addr = page.base();
+        if !addr.is_canonical() {
+            Err(MapErr::NonCanonical { addr: addr })
+        } else if addr.pml4_index() == RECURSIVE_INDEX {
+            Err(MapErr::ReservedRegion { page: page })
+        } else if self.is_guard_page(page) {
+            Err(MapErr::Other { message: "map page"
+                              , page: page
+                              , cause: "it is marked as a guard page" })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns true if the recursive entry still maps the frame in `$cr3`.
+    ///
+    /// If it doesn't, the page tables are corrupt, and every table reached
+    /// through the recursive mapping is garbage. The recursively mapped
+    /// table is the top-level one, so with 5-level paging this checks the
+    /// PML5.
+    pub fn verify_recursive_mapping(&self) -> bool {
+        // this is safe since the paging code only runs in kernel mode, and
+        // the top-level table is always recursively mapped at `PML4_PTR`.
+        unsafe {
+            recursive_entry_maps(&*PML4_PTR, cr3::current_pagetable_frame())
+        }
+    }
+
+    /// Panic if the recursive entry is corrupt, in debug builds.
+    ///
+    /// The entry is expected to point elsewhere while `using` has switched
+    /// it to an inactive table.
+    #[inline]
+    fn debug_check_recursive(&self) {
+        debug_assert!( USING_INACTIVE.load(Ordering::SeqCst)
+                    || self.verify_recursive_mapping()
+                     , "the recursive entry doesn't map the PML4 in $cr3");
+    }
+
+    /// Map `page` to `frame`, tolerating an existing mapping to the same
+    /// frame.
+    ///
+    /// This is for mapping regions which may overlap, like the kernel's ELF
+    /// sections, where the same frame may be mapped more than once.
+    ///
+    /// # Returns
+    /// + `Ok(())` if `page` was unmapped, or was already mapped to `frame`.
+    ///   If it was mapped with different flags, they are replaced with
+    ///   `flags`.
+    /// + `MapErr::AlreadyInUse` if `page` is mapped to a different frame,
+    ///   or is part of a huge page.
+    pub fn map_or_update<A>( &mut self, page: VirtualPage
+                           , frame: PhysicalPage, flags: EntryFlags
+                           , alloc: &mut A)
+                           -> MapResult<()>
+    where A: FrameAllocator {
+        use self::tlb::Flush;
+
+        // the CPU sets these on its own, so they don't count as a change.
+        let ignored = table::ACCESSED | table::DIRTY;
+        let flags = flags | table::PRESENT;
+        let existing = self.leaf(page)
+                           .map(|(entry, size)| ( entry.get_frame()
+                                                , entry.flags() - ignored
+                                                , size != PageSize::Size4K ));
+        match existing {
+            None => self.map(page, frame, flags, alloc)
+          , Some((Some(mapped), _, false)) if mapped != frame =>
+                Err(MapErr::AlreadyInUse { message: "map or update frame"
+                                         , page: page
+                                         , frame: frame })
+          , Some((_, old_flags, false)) => {
+                if old_flags != flags - ignored {
+                    if let Some(entry) = self.leaf_entry_mut(page) {
+                        entry.set(frame, flags);
+                    }
+                    // this is safe because we're in kernel mode
+                    unsafe { page.invlpg() };
+                    trace!("updated flags of {:?} to {:?}", page, flags);
+                }
+                Ok(())
+            }
+          , Some(_) => Err(MapErr::AlreadyInUse { message: "map or update frame"
+                                                , page: page
+                                                , frame: frame })
+        }
+    }
+
+    /// Map `page` to `frame`, merging `flags` with those of an existing
+    /// mapping to the same frame.
+    ///
+    /// This is for regions that may share a frame but need different
+    /// permissions, like a kernel code section that ends in the same frame
+    /// as a data section starts. The merged mapping is writable if either
+    /// mapping is, and executable if either is.
+    ///
+    /// # Returns
+    /// + `Ok(())` if `page` was unmapped, or was already mapped to `frame`
+    /// + `MapErr::AlreadyInUse` if `page` is mapped to a different frame,
+    ///   is part of a huge page, or is mapped with different caching
+    pub fn map_or_merge<A>( &mut self, page: VirtualPage
+                          , frame: PhysicalPage, flags: EntryFlags
+                          , alloc: &mut A)
+                          -> MapResult<()>
+    where A: FrameAllocator {
+        let caching = WRITE_THROUGH | NO_CACHE | PAT;
+        let old = self.leaf(page)
+                      .and_then(|(entry, size)| match entry.get_frame() {
+                          Some(mapped) if mapped == frame
+                                       && size == PageSize::Size4K =>
+                              Some(entry.flags() - (ACCESSED | DIRTY))
+                        , _ => None
+                      });
+        let flags = match old {
+            // unmapped, or a conflict that `map_or_update` will report
+            None => flags
+          , Some(old) if !((old ^ flags) & caching).is_empty() =>
+                return Err(MapErr::AlreadyInUse {
+                    message: "merge the caching of a shared frame"
+                  , page: page
+                  , frame: frame
+                })
+          , Some(old) => (old | flags) - NO_EXECUTE
+                       | (old & flags & NO_EXECUTE)
+        };
+        self.map_or_update(page, frame, flags, alloc)
+    }
+
+    /// Translates a virtual address to a physical address, and also returns
+    /// the size of the page it's mapped by.
+    ///
+    /// # Returns
+    /// + `Some((PAddr, PageSize))` if `vaddr` is mapped
+    /// + `None` if `vaddr` is not mapped
+    pub fn translate_detailed(&self, vaddr: VAddr)
+                              -> Option<(PAddr, PageSize)> {
+        let (page, offset) = vaddr.page_offset_pair();
+        self.translate_page_sized(page)
+            .map(|(frame, size)| (frame.base_addr() + offset as u64, size))
+    }
+
+    /// Find the first run of `pages` unmapped pages in `search_range`.
+    ///
+    /// Each page is checked with `translate_page`, and the search restarts
+    /// after any page in a candidate run that turns out to be mapped.
+    ///
+    /// # Returns
+    /// + `Some(PageRange)` with the first run of unmapped pages that fits
+    /// + `None` if `pages` is zero, or no unmapped run was large enough
+    pub fn find_free_region(&self, pages: usize, search_range: PageRange)
+                            -> Option<PageRange> {
+        if pages == 0 { return None }
+        let mut start = search_range.start;
+        while start.number + pages <= search_range.end.number {
+            let run = start.range_of(pages);
+            let mapped = run.clone()
+                            .find(|&page| self.translate_page(page).is_some());
+            match mapped {
+                // if a page in the run is mapped, try starting after it.
+                Some(mapped) => start = mapped + 1
+              , None => return Some(run)
+            }
+        }
+        None
+    }
+
+    /// Translates a virtual page to the 4KiB frame it's mapped to, and the
+    /// size of the page mapping it.
+    fn translate_page_sized(&self, page: VirtualPage)
+                            -> Option<(PhysicalPage, PageSize)> {
+        let pdpt = self.pml4().next_table(page);
+
+        let huge_page = || {
+            pdpt.and_then(|pdpt|
+                pdpt[page]
+                    .do_huge( PDLevel::index_of(page) * N_ENTRIES
+                            + PTLevel::index_of(page)
+                            , N_ENTRIES * N_ENTRIES)
+                    .map(|frame| (frame, PageSize::Size1G))
+                    .or_else(|| {
+                        pdpt.next_table(page).and_then(|pd|
+                            pd[page].do_huge(PTLevel::index_of(page), N_ENTRIES)
+                                    .map(|frame| (frame, PageSize::Size2M))
+                        )
+                    })
+                )
+        };
+
+        pdpt.and_then(|pdpt| pdpt.next_table(page))
+            .and_then(|pd| pd.next_table(page))
+            .and_then(|pt| pt[page].get_frame())
+            .map(|frame| (frame, PageSize::Size4K))
+            .or_else(huge_page)
+    }
+
+    /// Walk the page tables for `vaddr`, returning the entry at every level.
+    ///
+    /// The walk stops at the first level with no table, or at a huge page.
+    /// Nothing is modified.
+    pub fn walk(&self, vaddr: VAddr) -> WalkResult {
+        let mut result = WalkResult { pml4: Some(&self.pml4()[vaddr])
+                                    , pdpt: None
+                                    , pd: None
+                                    , pt: None
+                                    , huge: None
+                                    };
+        let pdpt = match self.pml4().next_table(vaddr) {
+            Some(pdpt) => pdpt
+          , None => return result
+        };
+        result.pdpt = Some(&pdpt[vaddr]);
+        if pdpt[vaddr].is_huge() {
+            result.huge = Some(HugePageSize::Size1G);
+            return result
+        }
+
+        let pd = match pdpt.next_table(vaddr) {
+            Some(pd) => pd
+          , None => return result
+        };
+        result.pd = Some(&pd[vaddr]);
+        if pd[vaddr].is_huge() {
+            result.huge = Some(HugePageSize::Size2M);
+            return result
+        }
+
+        result.pt = pd.next_table(vaddr).map(|pt| &pt[vaddr]);
+        result
+    }
+
+    /// Like `map_to_any`, but the frame is zeroed before this returns, so
+    /// none of its previous contents are visible through `page`.
+    ///
+    /// The page is mapped writable while it's zeroed, and then given
+    /// `flags`.
+    ///
+    /// # Arguments
+    /// + `page`: the`VirtualPage` to map
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    pub fn map_to_any_zeroed<A>( &mut self
+                               , page: VirtualPage
+                               , flags: EntryFlags
+                               , alloc: &mut A)
+                               -> MapResult<()>
+    where A: FrameAllocator {
+        use core::ptr;
+        self.map_to_any(page, flags | WRITABLE, alloc)?;
+        unsafe {
+            ptr::write_bytes( page.base().as_mut_ptr::<u8>()
+                            , 0, PAGE_SIZE as usize);
+        }
+        if flags.contains(WRITABLE) {
+            Ok(())
+        } else {
+            self.update_flags(page, flags)
+        }
+    }
+
+    /// Unmap the given `VirtualPage` without deallocating the frames it was
+    /// mapped to.
+    ///
+    /// This is intended for pages mapped to frames that the caller does not
+    /// own, such as a `TempPage`. Any page tables that become empty are still
+    /// returned to the given `FrameAllocator`.
+    ///
+    /// # Returns
+    /// + the range of frames that `page` was mapped to. If `page` was part of
+    ///   a huge page, the whole huge page is unmapped.
+    pub fn unmap_keep_frames<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                               -> MapResult<FrameRange>
+    where A: FrameAllocator {
+        let frames = self.unmap_unflushed(page, alloc)?;
+        // a huge page starts on the first page aligned to its size
+        let n_pages = frames.length();
+        let start = VirtualPage { number: page.number & !(n_pages - 1) };
+        self.flush_range(start.range_of(n_pages));
+        Ok(frames)
+    }
+
+    /// Unmap `page` without deallocating its frames or flushing it from the
+    /// TLB.
+    ///
+    /// Any page tables that become empty are still returned to `alloc`.
+    fn unmap_unflushed<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                         -> MapResult<FrameRange>
+    where A: FrameAllocator {
+        let frames = self.clear_entry(page)?;
+        trace!("page table entry for {:?} pointed to {:?}", page, frames);
+        self.free_empty_tables(page, alloc);
+        Ok(frames)
+    }
+
+    /// Invalidate every page in `range` in the TLB.
+    ///
+    /// Past `tlb::FLUSH_THRESHOLD` pages, the entire TLB is flushed instead.
+    #[inline]
+    pub fn flush_range(&self, range: PageRange) {
+        // this is safe because we're in kernel mode
+        unsafe { tlb::flush_range(range) };
+        trace!("flushed TLB");
+    }
+
+    /// Returns the lowest-level present entry mapping `page`.
+    ///
+    /// This is the PT entry for a 4KiB page, or the PD or PDPT entry for a
+    /// huge page. An entry that isn't present is never returned, even if it
+    /// still holds a frame or the huge page bit.
+    fn leaf_entry(&self, page: VirtualPage) -> Option<&Entry> {
+        self.leaf(page).map(|(entry, _)| entry)
+    }
+
+    /// Mutably borrows the lowest-level present entry mapping `page`.
+    ///
+    /// See `leaf_entry`.
+    fn leaf_entry_mut(&mut self, page: VirtualPage) -> Option<&mut Entry> {
+        self.leaf_mut(page).map(|(entry, _)| entry)
+    }
+
+    /// Returns the lowest-level present entry mapping `page`, and the size
+    /// of the page it maps.
+    ///
+    /// The size comes from the level the entry was found at, so a PT entry
+    /// with `PAT` set is still a 4KiB page, though `is_huge` says otherwise.
+    fn leaf(&self, page: VirtualPage) -> Option<(&Entry, PageSize)> {
+        let pdpt = self.pml4().next_table(page)?;
+        let (entry, size) = if pdpt[page].is_huge() {
+            (&pdpt[page], PageSize::Size1G)
+        } else {
+            let pd = pdpt.next_table(page)?;
+            if pd[page].is_huge() { (&pd[page], PageSize::Size2M) }
+            else { (&pd.next_table(page)?[page], PageSize::Size4K) }
+        };
+        if entry.flags().is_present() { Some((entry, size)) } else { None }
+    }
+
+    /// Mutably borrows the lowest-level present entry mapping `page`, and
+    /// returns the size of the page it maps.
+    ///
+    /// See `leaf`.
+    fn leaf_mut(&mut self, page: VirtualPage)
+               -> Option<(&mut Entry, PageSize)> {
+        let pdpt = self.pml4_mut().next_table_mut(page)?;
+        let (entry, size) = if pdpt[page].is_huge() {
+            (&mut pdpt[page], PageSize::Size1G)
+        } else {
+            let pd = pdpt.next_table_mut(page)?;
+            if pd[page].is_huge() { (&mut pd[page], PageSize::Size2M) }
+            else { (&mut pd.next_table_mut(page)?[page], PageSize::Size4K) }
+        };
+        if entry.flags().is_present() { Some((entry, size)) } else { None }
+    }
+
+    /// Clear the page table entry mapping `page`.
+    ///
+    /// The entry is *not* flushed from the TLB; that's up to the caller.
+    ///
+    /// # Returns
+    /// + the range of frames that were mapped by the cleared entry.
+    fn clear_entry(&mut self, page: VirtualPage) -> MapResult<FrameRange> {
+        // get the PDPT containing the page.
+        let pdpt = self.pml4_mut()
+                       .next_table_mut(page)
+                       .ok_or(MapErr::TableNotFound {
+                          message: "unmap"
+                        , page: page
+                        , what: "PDPT"
+                      })?;
+        // if the PDPT entry maps a 1 GiB huge page, unmap it directly rather
+        // than descending further.
+        if pdpt[page].is_huge() {
+            return clear_huge(&mut pdpt[page], page, HugePageSize::Size1G)
+        }
+
+        let pd = pdpt.next_table_mut(page)
+                     .ok_or(MapErr::TableNotFound {
+                        message: "unmap"
+                      , page: page
+                      , what: "PD"
+                    })?;
+        // likewise, if the PD entry maps a 2 MiB huge page, unmap that.
+        if pd[page].is_huge() {
+            return clear_huge(&mut pd[page], page, HugePageSize::Size2M)
+        }
+
+        // get the page table entry corresponding to the page.
+        let page_table = pd.next_table_mut(page)
+                           .ok_or(MapErr::TableNotFound {
+                              message: "unmap"
+                            , page: page
+                            , what: "PT"
+                          })?;
+        // index the entry from the table
+        let entry = &mut page_table[page];
+        trace!("got page table entry for {:?}", page);
+        // get the pointed frame for the page table entry.
+        let frame = entry.get_frame()
+                         .ok_or(MapErr::Other {
+                           message: "unmap"
+                         , page: page
+                         , cause: "it was not mapped"
+                       })?;
+        // mark the page table entry as unused
+        entry.set_unused();
+        trace!("set page table entry for {:?} as unused", page);
+        Ok(frame.range_of(1))
+    }
+
+    /// Deallocate any of the page tables containing `page` that are empty.
+    ///
+    /// The tables are checked from the bottom up, so that freeing a PT can
+    /// in turn leave its PD empty, and so on. The PDPTs of the
+    /// `KERNEL_PML4_ENTRIES` are shared with every other table, so they're
+    /// kept even when they're empty.
+    fn free_empty_tables<A>(&mut self, page: VirtualPage, alloc: &mut A)
+    where A: FrameAllocator {
+        let pml4 = self.pml4_mut();
+        if let Some(pdpt) = pml4.next_table_mut(page) {
+            if let Some(pd) = pdpt.next_table_mut(page) {
+                if let Some(frame) = pd.free_next_if_empty(page) {
+                    trace!("freed empty PT at {:?}", frame);
+                    unsafe { alloc.deallocate(frame) };
+                }
+            }
+            if let Some(frame) = pdpt.free_next_if_empty(page) {
+                trace!("freed empty PD at {:?}", frame);
+                unsafe { alloc.deallocate(frame) };
+            }
+        }
+        if is_kernel_entry(PML4Level::index_of(page)) { return }
+        if let Some(frame) = pml4.free_next_if_empty(page) {
+            trace!("freed empty PDPT at {:?}", frame);
+            unsafe { alloc.deallocate(frame) };
+        }
+    }
+
+    /// Promote the 512 4KiB pages in the 2 MiB region containing `page` to
+    /// a single 2 MiB huge page, and free the PT that mapped them.
+    ///
+    /// This is only possible if every entry in the PT is present, they all
+    /// have the same flags, and they map a contiguous run of frames starting
+    /// on a 2 MiB boundary. The huge page is accessed or dirty if any of the
+    /// 4KiB pages were.
+    ///
+    /// # Returns
+    /// + `Ok(true)` if the pages were promoted
+    /// + `Ok(false)` if they couldn't be, in which case nothing was changed
+    pub fn try_promote_2m<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                            -> MapResult<bool>
+    where A: FrameAllocator {
+        let start = VirtualPage { number: page.number & !(N_ENTRIES - 1) };
+        let pt_frame = {
+            let pd = match self.pml4_mut().next_table_mut(page)
+                               .and_then(|pdpt| pdpt.next_table_mut(page)) {
+                Some(pd) => pd
+              , None => return Ok(false)
+            };
+            // the flags that may differ between the pages
+            let usage = table::ACCESSED | table::DIRTY;
+            let (first, flags) = {
+                // if the PD entry is already huge, there's no PT.
+                let pt = match pd.next_table_mut(page) {
+                    Some(pt) => pt
+                  , None => return Ok(false)
+                };
+                let first = match pt[0].get_frame() {
+                    Some(frame) if frame.number as usize % N_ENTRIES == 0 =>
+                        frame
+                  , _ => return Ok(false)
+                };
+                // a 2 MiB page selects PAT entry 4 with bit 12, which is
+                // part of its frame address here, so write-combining pages
+                // are left as they are.
+                if pt[0].pt_caching() == Caching::WriteCombining {
+                    return Ok(false)
+                }
+                let flags = pt[0].flags() - usage;
+                let mut used = EntryFlags::empty();
+                for i in 0..N_ENTRIES {
+                    if pt[i].get_frame() != Some(first + i)
+                        || pt[i].flags() - usage != flags {
+                        return Ok(false)
+                    }
+                    used = used | (pt[i].flags() & usage);
+                }
+                (first, flags | used)
+            };
+            let pt_frame = pd[page].get_frame()
+                                   .expect("a PT was mapped without a frame");
+            // this is safe because we're in kernel mode
+            unsafe { pd.invlpg_next(page) };
+            pd[page].set(first, flags | table::PRESENT | table::HUGE_PAGE);
+            pt_frame
+        };
+        self.flush_range(start.range_of(N_ENTRIES));
+        unsafe { alloc.deallocate(pt_frame) };
+        trace!("promoted {:?} to a 2 MiB page, freeing {:?}", start, pt_frame);
+        Ok(true)
+    }
+
+    /// Split the 2 MiB huge page containing `page` into 512 4KiB pages,
+    /// mapped by a new PT.
+    ///
+    /// The 4KiB pages map the same frames with the same flags as the huge
+    /// page did, so that one of them can then be changed on its own. The
+    /// huge page is briefly unmapped while the PT is filled in, so it must
+    /// not be in use by the code doing the split.
+    ///
+    /// # Returns
+    /// + `MapErr::Other` if `page` isn't mapped by a 2 MiB page
+    /// + `MapErr::Alloc` if the PT couldn't be allocated
+    pub fn split_2m<A>(&mut self, page: VirtualPage, alloc: &mut A)
+                      -> MapResult<()>
+    where A: FrameAllocator {
+        let start = VirtualPage { number: page.number & !(N_ENTRIES - 1) };
+        let not_huge = MapErr::Other {
+            message: "split 2 MiB page"
+          , page: page
+          , cause: "not mapped by a 2 MiB page"
+        };
+        {
+            let pd = self.pml4_mut().next_table_mut(page)
+                         .and_then(|pdpt| pdpt.next_table_mut(page))
+                         .ok_or(not_huge.clone())?;
+            if !pd[page].is_huge() { return Err(not_huge) }
+            let first = pd[page].get_frame().ok_or(not_huge)?;
+            let flags = pd[page].flags() - table::HUGE_PAGE;
+
+            let pt_frame = unsafe { alloc.allocate() }
+                .map_err(|err| MapErr::Alloc { message: "split 2 MiB page"
+                                             , page: page
+                                             , cause: err })?;
+            pd[page].set( pt_frame
+                        , table::PRESENT | table::WRITABLE
+                        | (flags & table::USER_ACCESSIBLE));
+            // the PT's recursive address may still be cached as part of
+            // the huge page.
+            unsafe { pd.invlpg_next(page) };
+            let pt = pd.next_table_mut(page)
+                       .expect("the new PT wasn't mapped");
+            for i in 0..N_ENTRIES {
+                pt[i].set(first + i, flags);
+            }
+        }
+        self.flush_range(start.range_of(N_ENTRIES));
+        trace!("split the 2 MiB page at {:?}", start);
+        Ok(())
+    }
+
+    /// Returns true if the given page is mapped.
+    #[inline]
+    pub fn is_mapped(&self, page: &VirtualPage) -> bool {
+         self.translate_page(*page).is_some()
+    }
+
+    /// Returns true if every page in `range` is mapped.
+    ///
+    /// An empty range is always mapped.
+    #[inline]
+    pub fn is_mapped_range(&self, range: PageRange) -> bool {
+        self.first_unmapped(range).is_none()
+    }
+
+    /// Returns the first page in `range` that isn't mapped, if there is one.
+    pub fn first_unmapped(&self, range: PageRange) -> Option<VirtualPage> {
+        range.into_iter().find(|page| !self.is_mapped(page))
+    }
+
+}
+
+/// An inactive page table that the CPU is not currently using
+#[derive(Debug)]
+pub struct InactivePageTable {
+    pml4_frame: PhysicalPage
+}
+
+impl InactivePageTable {
+    /// Create a new `InactivePageTable` in `frame` that maps the kernel.
+    ///
+    /// The `KERNEL_PML4_ENTRIES` that are present in the active table are
+    /// copied, so the new table shares the kernel's PDPTs, and everything
+    /// else is left unmapped.
+    ///
+    /// # Arguments
+    /// + `frame`: the frame to put the new PML4 in
+    /// + `active_table`: the `ActivePageTable`, whose kernel is shared
+    /// + `temp`: a `TempPage` for writing to the new PML4
+    pub fn new( frame: PhysicalPage
+              , active_table: &mut ActivePageTable
+              , temp: &mut TempPage)
+              -> MapResult<Self> {
+        let mut kernel = [None; 3];
+        for (slot, &i) in kernel.iter_mut().zip(KERNEL_PML4_ENTRIES.iter()) {
+            let entry = &active_table.pml4()[i];
+            *slot = entry.get_frame().map(|frame| (i, frame, entry.flags()));
+        }
+        let table = InactivePageTable::new_empty(frame, active_table, temp)?;
+        {
+            let pml4 = temp.map_to_table(frame, active_table)?;
+            for &(i, pdpt, flags) in kernel.iter().filter_map(|e| e.as_ref()) {
+                pml4[i].set(pdpt, flags);
+            }
+        }
+        temp.unmap(active_table)?;
+        trace!("shared the kernel's PML4 entries with {:?}", table);
+        Ok(table)
+    }
+
+    /// Create a new `InactivePageTable` in `frame` that maps nothing but
+    /// itself, through its recursive entry.
+    ///
+    /// Nothing is shared with the active table, so the kernel must be
+    /// mapped before switching to it. This is for building the kernel's
+    /// own table.
+    pub fn new_empty( frame: PhysicalPage
+                    , active_table: &mut ActivePageTable
+                    , temp: &mut TempPage)
+                    -> MapResult<Self> {
+        {
+            trace!("Mapping page {} to frame {}", temp.number, frame.number);
+            let table = temp.map_to_table(frame.clone(), active_table)?;
+            trace!( " . . . Mapped temp page to table frame .");
+            table.zero();
+            trace!( " . . . Zeroed inactive table frame.");
+            table[RECURSIVE_INDEX].set( frame.clone(), PRESENT | WRITABLE);
+            trace!(" . . . Set active table to point to new inactive table.")
+        }
+        let _ = temp.unmap(active_table)?;
+        trace!(" . . Unmapped temp page.");
+
+        Ok(InactivePageTable { pml4_frame: frame })
+    }
+
+    /// Create a new `InactivePageTable` containing the given mappings.
+    ///
+    /// A fresh PML4 is allocated from `alloc`, and each mapping is applied
+    /// to it through the recursive entry, as with `with`. If any mapping
+    /// fails, every page table allocated so far and the PML4 itself are
+    /// returned to `alloc`. The mapped frames belong to the caller, and are
+    /// never freed.
+    ///
+    /// # Arguments
+    /// + `mappings`: the `(page, frame, flags)` triples to map
+    /// + `active_table`: the `ActivePageTable`
+    /// + `temp`: a `TempPage` for editing the new table
+    /// + `alloc`: the `FrameAllocator` to allocate page tables from
+    pub fn from_mappings<I, A>( mappings: I
+                              , active_table: &mut ActivePageTable
+                              , temp: &mut TempPage
+                              , alloc: &mut A)
+                              -> MapResult<Self>
+    where I: IntoIterator<Item = (VirtualPage, PhysicalPage, EntryFlags)>
+        , A: FrameAllocator {
+        let frame = unsafe { alloc.allocate() }
+            .map_err(|err| MapErr::Alloc { message: "allocate a PML4"
+                                         , page: **temp
+                                         , cause: err })?;
+        let mut table =
+            match InactivePageTable::new(frame, active_table, temp) {
+                Ok(table) => table
+              , Err(err) => {
+                    unsafe { alloc.deallocate(frame) };
+                    return Err(err)
+                }
+            };
+        let result = table.with(active_table, temp, |pml4| {
+            for (page, frame, flags) in mappings {
+                if let Err(err) = pml4.map(page, frame, flags, alloc) {
+                    trace!("mapping {:?} failed, unwinding", page);
+                    if unmap_all_keep_frames(pml4, alloc).is_err() {
+                        warn!("couldn't free the page tables for {:?}", page);
+                    }
+                    return Err(err)
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(table)
+          , Err(err) => {
+                unsafe { alloc.deallocate(table.pml4_frame) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Edit this table without switching to it.
+    ///
+    /// The active table's recursive entry is pointed at this table while `f`
+    /// runs, so `f` can map pages in it as though it were active. The
+    /// recursive entry is restored afterwards, even if `f` returns an error.
+    ///
+    /// # Arguments
+    /// + `active_table`: the `ActivePageTable`
+    /// + `temp`: a `TempPage` for restoring the recursive entry
+    /// + `f`: a closure that edits this table
+    ///
+    /// # Returns
+    /// + whatever `f` returns
+    pub fn with<F, T>( &mut self
+                     , active_table: &mut ActivePageTable
+                     , temp: &mut TempPage
+                     , f: F)
+                     -> MapResult<T>
+    where F: FnOnce(&mut ActivePML4) -> MapResult<T> {
+        active_table.using(self, temp, f)
+    }
+
+    /// Free this page table, and every frame it maps.
+    ///
+    /// Every page table below the PML4, and the PML4 frame itself, are
+    /// returned to `alloc`. A reference to each mapped frame is removed with
+    /// `FrameAllocator::remove_ref`, and the frames that were only mapped
+    /// here are returned to `alloc` too. The kernel's PDPTs are shared, so
+    /// they're never freed. Use `free_tables` to leave the mapped frames
+    /// alone.
+    ///
+    /// # Arguments
+    /// + `active_table`: the `ActivePageTable`
+    /// + `temp`: a `TempPage` for editing this table
+    /// + `alloc`: the `FrameAllocator` to free frames to
+    pub fn free<A>( mut self
+                  , active_table: &mut ActivePageTable
+                  , temp: &mut TempPage
+                  , alloc: &mut A)
+                  -> MapResult<()>
+    where A: FrameAllocator {
+        self.free_frames(active_table, temp, alloc)
+    }
+
+    /// Free this page table's page tables, without freeing the frames they
+    /// map.
+    ///
+    /// Every page table below the PML4, and the PML4 frame itself, are
+    /// returned to `alloc`. This is for a table whose mapped frames are
+    /// still in use elsewhere. The kernel's PDPTs are shared, so they're
+    /// never freed.
+    ///
+    /// # Arguments
+    /// + `active_table`: the `ActivePageTable`
+    /// + `temp`: a `TempPage` for editing this table
+    /// + `alloc`: the `FrameAllocator` to free the tables to
+    ///
+    /// # Returns
+    /// + the number of page tables freed, including the PML4
+    pub fn free_tables<A>( mut self
+                         , active_table: &mut ActivePageTable
+                         , temp: &mut TempPage
+                         , alloc: &mut A)
+                         -> MapResult<usize>
+    where A: FrameAllocator {
+        trace!("freeing the page tables of {:?}", self);
+        // the kernel's entries are shared, so they're skipped.
+        let result = self.free_lower_tables( active_table, temp, alloc
+                                           , |i| !is_kernel_entry(i));
+        active_table.forget_reserved(self.pml4_frame);
+        unsafe { alloc.deallocate(self.pml4_frame) };
+        result.map(|n_freed| n_freed + 1)
+    }
+
+    /// Free the page tables of the bootstrap page table, once `kernel_remap`
+    /// has replaced it.
+    ///
+    /// Every PDPT, PD, and PT is returned to `alloc`, including the ones in
+    /// the kernel's PML4 entries, since the table that replaced this one
+    /// shares none of them. The frames it maps and the PML4 frame are left
+    /// alone, so that the PML4's page can be the guard page. The bootstrap
+    /// tables are in the kernel's .bss, which `alloc` never handed out, so
+    /// this gives it frames it didn't have before.
+    ///
+    /// # Arguments
+    /// + `active_table`: the `ActivePageTable` that replaced this table
+    /// + `temp`: a `TempPage` for editing this table
+    /// + `alloc`: the `FrameAllocator` to free the tables to
+    ///
+    /// # Returns
+    /// + the number of page tables freed
+    pub fn reclaim_bootstrap_tables<A>( mut self
+                                      , active_table: &mut ActivePageTable
+                                      , temp: &mut TempPage
+                                      , alloc: &mut A)
+                                      -> MapResult<usize>
+    where A: FrameAllocator {
+        trace!("reclaiming the page tables of {:?}", self);
+        self.free_lower_tables(active_table, temp, alloc, |_| true)
+    }
+
+    /// Free the page tables in the PML4 entries that `free_entry` returns
+    /// true for, without freeing the frames they map or the PML4.
+    ///
+    /// # Returns
+    /// + the number of page tables freed
+    fn free_lower_tables<A, F>( &mut self
+                              , active_table: &mut ActivePageTable
+                              , temp: &mut TempPage
+                              , alloc: &mut A
+                              , free_entry: F)
+                              -> MapResult<usize>
+    where A: FrameAllocator
+        , F: Fn(usize) -> bool {
+        active_table.using(self, temp, |pml4| {
+            let pml4 = pml4.pml4_mut();
+            let mut n_freed = 0;
+            // skip the recursive entry, since it points back to the PML4.
+            for i in (0..RECURSIVE_INDEX).filter(|&i| free_entry(i)) {
+                if let Some(pdpt) = pml4.next_table_mut(i) {
+                    n_freed += unsafe { pdpt.free_tables(alloc) } + 1;
+                    let frame = pml4[i].get_frame()
+                                       .expect("PDPT was just found");
+                    unsafe {
+                        pml4.invlpg_next(i);
+                        alloc.deallocate(frame);
+                    }
+                    pml4[i].set_unused();
+                }
+            }
+            Ok(n_freed)
+        })
+    }
+
+    /// Free every frame belonging to this page table, without consuming it.
+    ///
+    /// The table must not be used again afterwards.
+    fn free_frames<A>( &mut self
+                     , active_table: &mut ActivePageTable
+                     , temp: &mut TempPage
+                     , alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator {
+        trace!("freeing {:?}", self);
+        let result = active_table.using(self, temp, |pml4| {
+            let pml4 = pml4.pml4_mut();
+            // skip the recursive entry, since it points back to the PML4,
+            // and the kernel's entries, which are shared.
+            for i in (0..RECURSIVE_INDEX).filter(|&i| !is_kernel_entry(i)) {
+                if let Some(pdpt) = pml4.next_table_mut(i) {
+                    unsafe { pdpt.free_frames(alloc) };
+                }
+                if let Some(frame) = pml4[i].get_frame() {
+                    unsafe {
+                        pml4.invlpg_next(i);
+                        alloc.deallocate(frame);
+                    }
+                    pml4[i].set_unused();
+                }
+            }
+            Ok(())
+        });
+        active_table.forget_reserved(self.pml4_frame);
+        unsafe { alloc.deallocate(self.pml4_frame) };
+        result
+    }
+}
+
+/// Unmap every page mapped in `pml4` without deallocating the frames they
+/// were mapped to.
+///
+/// The page tables that become empty are returned to `alloc`.
+fn unmap_all_keep_frames<A>(pml4: &mut ActivePML4, alloc: &mut A)
+                           -> MapResult<()>
+where A: FrameAllocator {
+    loop {
+        let next = pml4.dump_mappings().next();
+        let (pages, _, _) = match next {
+            Some(mapping) => mapping
+          , None => return Ok(())
+        };
+        let mut page = pages.start;
+        while page < pages.end {
+            page = page + pml4.unmap_keep_frames(page, alloc)?.length();
+        }
+    }
+}
+
+/// A page table whose frames can be freed.
+trait FreeFrames {
+    /// Free every page table below this table, remove a reference to every
+    /// frame they map, and clear all of this table's entries.
+    ///
+    /// Mapped frames are freed with `deallocate_run`, so frames that are
+    /// still shared are kept.
+    unsafe fn free_frames<A>(&mut self, alloc: &mut A)
+    where A: FrameAllocator;
+}
+
+impl FreeFrames for Table<PTLevel> {
+    unsafe fn free_frames<A>(&mut self, alloc: &mut A)
+    where A: FrameAllocator {
+        for i in 0..N_ENTRIES {
+            if let Some(frame) = self[i].get_frame() {
+                self[i].set_unused();
+                deallocate_run(frame.range_of(1), alloc);
+            }
+        }
+    }
+}
+
+impl<L> FreeFrames for Table<L>
+where L: Sublevel
+    , Table<L::Next>: FreeFrames {
+    unsafe fn free_frames<A>(&mut self, alloc: &mut A)
+    where A: FrameAllocator {
+        // number of 4KiB frames mapped by a huge page at this level
+        let huge_frames = 1 << (L::ADDR_SHIFT_AMOUNT - PAGE_SHIFT as usize);
+        for i in 0..N_ENTRIES {
+            if let Some(next) = self.next_table_mut(i) {
+                next.free_frames(alloc);
+            }
+            match self[i].get_frame() {
+                Some(frame) if self[i].is_huge() =>
+                    deallocate_run(frame.range_of(huge_frames), alloc)
+              , Some(frame) => alloc.deallocate(frame)
+              , None => continue
+            }
+            // the freed table may still be cached at its recursive address
+            self.invlpg_next(i);
+            self[i].set_unused();
+        }
+    }
+}
+
+/// A page table whose page tables can be freed, without freeing the frames
+/// they map.
+trait FreeTables {
+    /// Free every page table below this table, and clear the entries that
+    /// pointed to them.
+    ///
+    /// # Returns
+    /// + the number of page tables freed
+    unsafe fn free_tables<A>(&mut self, alloc: &mut A) -> usize
+    where A: FrameAllocator;
+}
+
+impl FreeTables for Table<PTLevel> {
+    #[inline]
+    unsafe fn free_tables<A>(&mut self, _alloc: &mut A) -> usize
+    where A: FrameAllocator {
+        0
+    }
+}
+
+impl<L> FreeTables for Table<L>
+where L: Sublevel
+    , Table<L::Next>: FreeTables {
+    unsafe fn free_tables<A>(&mut self, alloc: &mut A) -> usize
+    where A: FrameAllocator {
+        let mut n_freed = 0;
+        for i in 0..N_ENTRIES {
+            // huge pages aren't tables, so they're skipped here
+            if let Some(next) = self.next_table_mut(i) {
+                n_freed += next.free_tables(alloc) + 1;
+                let frame = self[i].get_frame().expect("table was just found");
+                // the freed table may still be cached at its recursive address
+                self.invlpg_next(i);
+                alloc.deallocate(frame);
+                self[i].set_unused();
+            }
+        }
+        n_freed
+    }
+}
+
+/// Returns true if `pml4`'s recursive entry maps `frame`.
+///
+/// `frame` should be the frame `pml4` is in, since a PML4 whose recursive
+/// entry maps anything else can't be walked through the recursive mapping.
+pub fn recursive_entry_maps(pml4: &Table<PML4Level>, frame: PhysicalPage)
+                           -> bool {
+    pml4[RECURSIVE_INDEX].get_frame() == Some(frame)
+}
+
+/// Invalidate every page table below `pml4` at the address it is recursively
+/// mapped to, so that the recursive entry can be changed without flushing
+/// the whole TLB.
+///
+/// Every other translation stays valid, since the recursive entry only
+/// affects addresses under PML4 index 511. Non-present entries are never
+/// cached, so tables that aren't in the hierarchy when this is called don't
+/// need to be invalidated; tables freed while the recursive entry pointed
+/// elsewhere are invalidated when they're freed. Each table is invalidated
+/// after its entries have been read, so reading it doesn't cache it again.
+/// `pml4` itself must be invalidated by the caller, after the recursive
+/// entry is changed; `invlpg` also clears the paging-structure caches, so
+/// no stale intermediate entries survive either.
+///
+/// If there are more than `tlb::FLUSH_THRESHOLD` tables, the whole TLB is
+/// flushed instead. The recursive mappings are never global, so reloading
+/// `$cr3` is enough to evict them.
+///
+/// # Returns
+/// + the number of tables invalidated, or zero if the whole TLB was flushed
+///
+/// # Safety
+/// + This must be executed in kernel mode.
+unsafe fn flush_recursive(pml4: &Table<PML4Level>) -> usize {
+    if count_tables(pml4) > tlb::FLUSH_THRESHOLD {
+        trace!("flushing entire TLB instead of the recursive mappings");
+        tlb::flush_all();
+        return 0
+    }
+    let mut n_flushed = 0;
+    // skip the recursive entry, since it points back to the PML4.
+    for i in 0..RECURSIVE_INDEX {
+        if let Some(pdpt) = pml4.next_table(i) {
+            for j in 0..N_ENTRIES {
+                if let Some(pd) = pdpt.next_table(j) {
+                    n_flushed += (0..N_ENTRIES).filter(|&k| pd.invlpg_next(k))
+                                               .count();
+                    n_flushed += pdpt.invlpg_next(j) as usize;
+                }
+            }
+            n_flushed += pml4.invlpg_next(i) as usize;
+        }
+    }
+    n_flushed
+}
+
+/// Returns the number of page tables below `pml4`, not counting `pml4`
+/// itself or the recursive entry.
+fn count_tables(pml4: &Table<PML4Level>) -> usize {
+    let mut n_tables = 0;
+    for i in 0..RECURSIVE_INDEX {
+        if let Some(pdpt) = pml4.next_table(i) {
+            n_tables += 1;
+            for j in 0..N_ENTRIES {
+                if let Some(pd) = pdpt.next_table(j) {
+                    n_tables += 1;
+                    n_tables += (0..N_ENTRIES)
+                        .filter(|&k| pd.next_table(k).is_some())
+                        .count();
+                }
+            }
+        }
+    }
+    n_tables
+}
+
+/// Page number of the temporary page used for editing inactive page tables.
+///
+/// Page number chosen fairly arbitrarily.
+const TEMP_PAGE_NUMBER: usize = 0xfacade;
+
+pub fn test_paging<A>(alloc: &mut A) -> MapResult<()>
+where A: FrameAllocator {
+    info!("testing paging");
     // This testing code shamelessly stolen from Phil Oppermann.
-    let mut pml4 = unsafe { ActivePML4::new() };
+    let mut pml4 = unsafe { ActivePageTable::new() };
+
+    // address 0 is mapped
+    trace!("Some = {:?}", pml4.translate(VAddr::from(0)));
+     // second PT entry
+    trace!("Some = {:?}", pml4.translate(VAddr::from(4096)));
+    // second PD entry
+    trace!("Some = {:?}", pml4.translate(VAddr::from(512 * 4096)));
+    // 300th PD entry
+    trace!("Some = {:?}", pml4.translate(VAddr::from(300 * 512 * 4096)));
+    // second PDPT entry
+    trace!("None = {:?}", pml4.translate(VAddr::from(512 * 512 * 4096)));
+    // last mapped byte
+    trace!("Some = {:?}", pml4.translate(VAddr::from(512 * 512 * 4096 - 1)));
+
+
+    let addr = VAddr::from(42 * 512 * 512 * 4096); // 42th PDPT entry
+    let page = VirtualPage::containing(addr);
+    let frame = unsafe { alloc.allocate().expect("no more frames") };
+    trace!("None = {:?}, map to {:?}",
+             pml4.translate(addr),
+             frame);
+    let _ = pml4.map(page, frame, EntryFlags::empty(), alloc)?;
+    trace!("Some = {:?}", pml4.translate(addr));
+    trace!( "next free frame: {:?}"
+            , unsafe { alloc.allocate() });
+
+    //trace!("{:#x}", *(Page::containing(addr).as_ptr()));
+
+    let _ = pml4.unmap(Page::containing(addr), alloc)?;
+    trace!("None = {:?}", pml4.translate(addr));
+
+    // map a 2 MiB huge page, then unmap it.
+    let huge_addr = VAddr::from(43 * 512 * 512 * 4096); // 43rd PDPT entry
+    let huge_page = VirtualPage::containing(huge_addr);
+    // the frame is never accessed, it just needs to be 2 MiB aligned.
+    let huge_frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+    let _ = pml4.map_huge( huge_page, huge_frame, HugePageSize::Size2M
+                         , WRITABLE, alloc)?;
+    trace!("Some = {:?}", pml4.translate(huge_addr));
+    // the frames weren't allocated, so they mustn't be deallocated.
+    let frames = pml4.unmap_keep_frames(huge_page + 1, alloc)?;
+    assert_eq!(frames, huge_frame.range_of(HugePageSize::Size2M.n_pages()));
+    assert!( pml4.translate(huge_addr).is_none()
+           , "huge page at {:?} was still mapped after unmapping!"
+           , huge_addr);
+    trace!("None = {:?}", pml4.translate(huge_addr));
+
+    // map a 1 GiB huge page and translate an address in the middle of it.
+    let huge_addr = VAddr::from(44 * 512 * 512 * 4096); // 44th PDPT entry
+    let huge_page = VirtualPage::containing(huge_addr);
+    let huge_frame = PhysicalPage::containing(PAddr::from(HUGE_PAGE_SIZE));
+    let _ = pml4.map_huge( huge_page, huge_frame, HugePageSize::Size1G
+                         , WRITABLE, alloc)?;
+    let offset = HUGE_PAGE_SIZE / 2 + 0x123;
+    assert_eq!( pml4.translate(huge_addr + offset as usize)
+              , Some(huge_frame.base_addr() + offset) );
+    trace!("Some = {:?}", pml4.translate(huge_addr + offset as usize));
+    let _ = pml4.unmap_keep_frames(huge_page, alloc)?;
+    assert!( pml4.translate(huge_addr).is_none()
+           , "huge page at {:?} was still mapped after unmapping!"
+           , huge_addr);
+    trace!("None = {:?}", pml4.translate(huge_addr));
+
+    // map and unmap a page in an otherwise unused PML4 entry, so that the
+    // PDPT, PD, and PT containing it should all be freed.
+    let addr = VAddr::from(512 * 512 * 512 * 4096); // 2nd PML4 entry
+    let page = VirtualPage::containing(addr);
+    {
+        let mut counting = CountingAlloc::new(alloc);
+        let _ = pml4.map_to_any(page, WRITABLE, &mut counting)?;
+        let _ = pml4.unmap(page, &mut counting)?;
+        // the mapped frame, plus the three intermediate tables
+        assert_eq!(counting.deallocated, 1 + 3);
+        trace!("freed {} frames unmapping {:?}", counting.deallocated, page);
+    }
+
+    // map a page with the no-execute flag, and check that bit 63 is set in
+    // the raw page table entry.
+    let nx_page = VirtualPage::containing(VAddr::from(45 * 512 * 512 * 4096));
+    let _ = pml4.map_to_any(nx_page, WRITABLE | NO_EXECUTE, alloc)?;
+    {
+        let entry = &pml4.pml4()
+                         .page_table_for(nx_page)
+                         .expect("no page table for a mapped page!")[nx_page];
+        assert!( entry.as_u64() & (1 << 63) != 0
+               , "no-execute bit was not set in {:?}", entry);
+        trace!("NX entry for {:?} is {:#x}", nx_page, entry.as_u64());
+    }
+    let _ = pml4.unmap(nx_page, alloc)?;
+
+    // map a page, clear its accessed bit, and check that the CPU sets the bit
+    // again when the page is read.
+    let touched_page =
+        VirtualPage::containing(VAddr::from(47 * 512 * 512 * 4096));
+    let _ = pml4.map_to_any(touched_page, WRITABLE, alloc)?;
+    let _ = pml4.clear_accessed(touched_page)?;
+    assert_eq!(pml4.is_accessed(touched_page), Some(false));
+    unsafe {
+        use core::ptr::read_volatile;
+        let _ = read_volatile(touched_page.base().as_ptr::<u64>());
+    }
+    assert_eq!(pml4.is_accessed(touched_page), Some(true));
+    assert_eq!(pml4.is_dirty(touched_page), Some(false));
+    trace!("{:?} was accessed after reading it", touched_page);
+    // an entry marked not present isn't reported, even though it still holds
+    // its frame and accessed bit.
+    {
+        let frame = pml4.translate_page(touched_page)
+                        .expect("no frame for a mapped page!");
+        let flags = {
+            let pt = pml4.pml4_mut()
+                         .page_table_mut_for(touched_page)
+                         .expect("no page table for a mapped page!");
+            let flags = pt[touched_page].flags();
+            pt[touched_page].set(frame, flags - PRESENT);
+            flags
+        };
+        assert_eq!(pml4.is_accessed(touched_page), None);
+        pml4.pml4_mut()
+            .page_table_mut_for(touched_page)
+            .expect("no page table for a mapped page!")[touched_page]
+            .set(frame, flags);
+    }
+    let _ = pml4.unmap(touched_page, alloc)?;
+    assert_eq!(pml4.is_accessed(touched_page), None);
+
+    // clone the address space copy-on-write, and check that a private page
+    // is mapped to the same frame, read-only, in both tables, while the
+    // kernel's entries are shared as they are.
+    {
+        let cow_page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        let _ = pml4.map_to_any(cow_page, WRITABLE, alloc)?;
+        let cow_frame = pml4.translate_page(cow_page)
+                            .expect("no frame for a mapped page!");
+        let mut temp = test_temp_page(alloc);
+        let mut sharing = SharingAlloc { alloc: &mut *alloc
+                                       , frame: cow_frame
+                                       , refs: 1 };
+        let mut clone = pml4.clone_cow(&mut temp, &mut sharing)?;
+        assert_eq!(sharing.ref_count(cow_frame), Some(2));
+        let mut kernel = [None; 3];
+        for (slot, &i) in kernel.iter_mut().zip(KERNEL_PML4_ENTRIES.iter()) {
+            *slot = pml4.pml4()[i].get_frame();
+        }
+        assert!( !pml4.pml4()
+                      .page_table_for(cow_page)
+                      .expect("no page table for a mapped page!")[cow_page]
+                      .flags().contains(WRITABLE)
+               , "{:?} was still writable after cloning", cow_page);
+        pml4.using(&mut clone, &mut temp, |pml4| {
+            assert_eq!(pml4.translate_page(cow_page), Some(cow_frame));
+            assert!( !pml4.pml4()
+                          .page_table_for(cow_page)
+                          .expect("no page table for a cloned page!")[cow_page]
+                          .flags().contains(WRITABLE)
+                   , "{:?} was writable in the clone", cow_page);
+            for (&i, &pdpt) in KERNEL_PML4_ENTRIES.iter().zip(kernel.iter()) {
+                assert_eq!(pml4.pml4()[i].get_frame(), pdpt);
+            }
+            Ok(())
+        })?;
+
+        // unmapping the page from one table leaves the frame to the other,
+        // and it's only freed once the clone is freed too.
+        pml4.unmap(cow_page, &mut sharing)?;
+        assert_eq!(sharing.ref_count(cow_frame), Some(1));
+        let still_mapped = pml4.using(&mut clone, &mut temp, |pml4| {
+            Ok(pml4.translate_page(cow_page))
+        })?;
+        assert_eq!(still_mapped, Some(cow_frame));
+        clone.free(&mut pml4, &mut temp, &mut sharing)?;
+        assert_eq!(sharing.ref_count(cow_frame), Some(0));
+        assert!(pml4.pml4()[TEST_PML4_ENTRY].get_frame().is_none());
+        trace!("cloned {:?} copy-on-write", cow_page);
+    }
+
+    // map and unmap a page in a new address space, and check that all its
+    // page tables are freed when it's freed.
+    {
+        use spin::Mutex;
+        use self::address_space::AddressSpace;
+        let counting = Mutex::new(CountingAlloc::new(alloc));
+        {
+            let mut space = AddressSpace::new("test", &counting, &mut pml4)?;
+            let page =
+                VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+            let frame = unsafe { counting.lock().allocate() }
+                .expect("no more frames");
+            let _ = space.map(&mut pml4, page, frame, WRITABLE)?;
+            assert_eq!( space.translate(&mut pml4, page.base())?
+                      , Some(frame.base_addr()) );
+            let _ = space.unmap(&mut pml4, page)?;
+            space.free(&mut pml4)?;
+        }
+        // the mapped frame, the PDPT, PD, and PT, and the PML4
+        assert_eq!(counting.lock().deallocated, 1 + 3 + 1);
+        trace!( "freed {} frames freeing an address space"
+              , counting.lock().deallocated);
+    }
+
+    // walk the page tables for a mapped page, and check that there's an
+    // entry at every level.
+    let walk_page = VirtualPage::containing(VAddr::from(48 * 512 * 512 * 4096));
+    let _ = pml4.map_to_any(walk_page, WRITABLE, alloc)?;
+    {
+        let walk = pml4.walk(walk_page.base());
+        trace!("walked {:?}: {:?}", walk_page, walk);
+        assert!( walk.pml4.is_some() && walk.pdpt.is_some()
+              && walk.pd.is_some() && walk.pt.is_some()
+               , "walk of {:?} was missing a level: {:?}", walk_page, walk);
+        assert!(walk.huge.is_none());
+        assert_eq!( walk.pt.and_then(Entry::get_frame)
+                  , pml4.translate_page(walk_page));
+    }
+    let _ = pml4.unmap(walk_page, alloc)?;
+
+    // map three consecutive pages to consecutive frames, and check that
+    // they're dumped as a single range.
+    let dump_pages = VirtualPage::containing(VAddr::from(49 * 512 * 512 * 4096))
+                                .range_of(3);
+    // the frames are never accessed.
+    let dump_frames = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE))
+                                  .range_of(3);
+    let _ = pml4.map_range( dump_pages.clone(), dump_frames.clone()
+                          , WRITABLE, alloc)?;
+    {
+        let (range, frame, flags) =
+            pml4.dump_mappings()
+                .find(|&(ref range, _, _)| range.start == dump_pages.start)
+                .expect("mapped pages were not dumped!");
+        trace!("dumped {:?} -> {:?} ({:?})", range, frame, flags);
+        assert_eq!(range, dump_pages);
+        assert_eq!(frame, dump_frames.start);
+        assert!(flags.contains(WRITABLE));
+    }
+    for page in dump_pages {
+        let _ = pml4.unmap_keep_frames(page, alloc)?;
+    }
+
+    // the recursive addresses of tables at each level.
+    {
+        assert_eq!( recursive_table_addr(&[])
+                  , VAddr::from(PML4_VADDR as usize));
+        assert_eq!( recursive_table_addr(&[0])
+                  , VAddr::from(0xffff_ffff_ffe0_0000));
+        assert_eq!( recursive_table_addr(&[1, 2])
+                  , VAddr::from(0xffff_ffff_c020_2000));
+        assert_eq!( recursive_table_addr(&[1, 2, 3])
+                  , VAddr::from(0xffff_ff80_4040_3000));
+        // following the recursive entry leads back to the same table
+        assert_eq!( recursive_table_addr(&[RECURSIVE_INDEX])
+                  , recursive_table_addr(&[]));
+
+        // and they should agree with the tables we actually walk through,
+        // as long as the PML4 is the top-level table.
+        let page = VirtualPage::containing(VAddr::from(4096));
+        let (i, j, _, _) = page.table_indices();
+        if let Some(pdpt) = pml4.pml4().next_table(page) {
+            if paging_levels() == 4 {
+                assert_eq!( VAddr::from(pdpt as *const _ as usize)
+                          , recursive_table_addr(&[i]));
+            }
+            if let Some(pd) = pdpt.next_table(page) {
+                if paging_levels() == 4 {
+                    assert_eq!( VAddr::from(pd as *const _ as usize)
+                              , recursive_table_addr(&[i, j]));
+                }
+            }
+        }
+    }
+
+    // mapping a page over the recursive mapping should fail.
+    {
+        let page = VirtualPage::containing(
+            VAddr::from(RECURSIVE_REGION_START + 0x1000));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        match pml4.map(page, frame, WRITABLE, alloc) {
+            Err(MapErr::ReservedRegion { page: reserved }) =>
+                assert_eq!(reserved, page)
+          , other => panic!("mapping {:?} returned {:?}", page, other)
+        }
+        let pages = page.range_of(2);
+        assert!(pml4.map_range(pages, frame.range_of(2), WRITABLE, alloc)
+                    .is_err());
+        let page = VirtualPage::containing(VAddr::from(RECURSIVE_REGION_START));
+        assert!(pml4.map_huge( page, frame, HugePageSize::Size2M
+                             , WRITABLE, alloc)
+                    .is_err());
+        // the recursive mapping still works
+        let pml4_frame = unsafe { cr3::current_pagetable_frame() };
+        assert_eq!( pml4.pml4()[RECURSIVE_INDEX].get_frame()
+                  , Some(pml4_frame));
+    }
+
+    // mapping a page at a non-canonical address should fail.
+    let non_canonical = VirtualPage { number: 0x0000_8000_0000_0000 >> 12 };
+    let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+    assert!( pml4.map(non_canonical, frame, WRITABLE, alloc).is_err()
+           , "mapped non-canonical {:?}", non_canonical);
+
+    // switching to a table without a recursive entry should fail, rather
+    // than triple faulting.
+    {
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        let _ = temp.map_to_table(frame, &mut pml4)?.zero();
+        let _ = temp.unmap(&mut pml4)?;
+        let bad_table = InactivePageTable { pml4_frame: frame };
+        assert!( pml4.replace_with(bad_table, &mut temp).is_err()
+               , "switched to a PML4 with no recursive entry!");
+        trace!("refused to switch to {:?} with no recursive entry", frame);
+        unsafe { alloc.deallocate(frame) };
+    }
+
+    // populate an inactive table with `with`, then switch to it and check
+    // that the pages mapped in it are there.
+    {
+        use core::ptr;
+        let page = VirtualPage { number: TEST_PML4_ENTRY << 27 };
+        assert!(pml4.pml4()[TEST_PML4_ENTRY].get_frame().is_none());
+
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+
+        // the new table shares the kernel's PDPTs, so that the kernel is
+        // still mapped once it's switched to.
+        let mut kernel = [None; 3];
+        for (slot, &i) in kernel.iter_mut().zip(KERNEL_PML4_ENTRIES.iter()) {
+            *slot = pml4.pml4()[i].get_frame();
+        }
+        let mapped = table.with(&mut pml4, &mut temp, |inactive| {
+            for (&i, &pdpt) in KERNEL_PML4_ENTRIES.iter().zip(kernel.iter()) {
+                assert!(pdpt.is_some(), "kernel PML4 entry {} is empty", i);
+                assert_eq!(inactive.pml4()[i].get_frame(), pdpt);
+            }
+            inactive.map_to_any(page, WRITABLE, alloc)?;
+            inactive.map_to_any(page + 1, WRITABLE, alloc)?;
+            Ok([ inactive.translate_page(page)
+               , inactive.translate_page(page + 1) ])
+        })?;
+        assert!(mapped[0].is_some() && mapped[1].is_some());
+        assert!( pml4.translate_page(page).is_none()
+               , "mapping in an inactive table changed the active table");
+
+        // a region reserved in the old table isn't reserved in the new one.
+        let reserved = (page + 2).range_of(1);
+        pml4.reserve(reserved.clone(), WRITABLE)?;
+        let old_table = pml4.replace_with(table, &mut temp)?;
+        assert_eq!(pml4.translate_page(page), mapped[0]);
+        assert_eq!(pml4.translate_page(page + 1), mapped[1]);
+        assert!(pml4.resolve_fault(reserved.start.base(), alloc).is_err());
+        assert!(!pml4.is_mapped(&reserved.start));
+        for (i, &page) in [page, page + 1].iter().enumerate() {
+            let ptr = *page.base() as *mut u64;
+            unsafe {
+                ptr::write_volatile(ptr, 0xdead_0000 + i as u64);
+                assert_eq!(ptr::read_volatile(ptr), 0xdead_0000 + i as u64);
+            }
+        }
+        let table = pml4.replace_with(old_table, &mut temp)?;
+        assert!(pml4.translate_page(page).is_none());
+        pml4.unreserve(reserved)?;
+        // freeing the test table leaves the kernel's PDPTs alone.
+        table.free(&mut pml4, &mut temp, alloc)?;
+        for (&i, &pdpt) in KERNEL_PML4_ENTRIES.iter().zip(kernel.iter()) {
+            assert_eq!(pml4.pml4()[i].get_frame(), pdpt);
+        }
+        trace!("switched to a table sharing the kernel's PML4 entries");
+    }
+
+    // values returned by the closure passed to `using` are passed back out,
+    // and so are errors.
+    {
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+        let page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        let mapped = pml4.using(&mut table, &mut temp, |inactive| {
+            inactive.map_to_any(page, WRITABLE, alloc)?;
+            Ok(inactive.translate_page(page))
+        })?;
+        assert!(mapped.is_some());
+        assert!(pml4.translate_page(page) != mapped);
+
+        let result: MapResult<usize> =
+            pml4.using(&mut table, &mut temp, |_| Err(MapErr::NoPage {
+                message: "test using"
+              , cause: "the closure failed"
+            }));
+        assert!(result.is_err());
+        // the recursive mapping was restored after the error
+        assert!(pml4.translate_page(page) != mapped);
+        table.free(&mut pml4, &mut temp, alloc)?;
+    }
+
+    // build a small inactive table, free it along with the frames it maps,
+    // and check that every frame allocated for it was returned.
+    {
+        let mut temp = test_temp_page(alloc);
+        let mut counting = CountingAlloc::new(alloc);
+        let frame = unsafe { counting.allocate() }.expect("no more frames");
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+        let page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        pml4.using(&mut table, &mut temp, |pml4| {
+            pml4.map_to_any(page, WRITABLE, &mut counting)?;
+            pml4.map_to_any(page + 1, WRITABLE, &mut counting)
+        })?;
+        table.free(&mut pml4, &mut temp, &mut counting)?;
+        assert_eq!(counting.allocated, counting.deallocated);
+        trace!("freed all {} frames of an inactive table", counting.allocated);
+    }
+
+    // freeing an inactive table on its own returns its page tables, and
+    // leaves the frames it maps alone.
+    {
+        let mut temp = test_temp_page(alloc);
+        let mut counting = CountingAlloc::new(alloc);
+        let frame = unsafe { counting.allocate() }.expect("no more frames");
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+        let page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        let mapped = pml4.using(&mut table, &mut temp, |pml4| {
+            pml4.map_to_any(page, WRITABLE, &mut counting)?;
+            Ok(pml4.translate_page(page))
+        })?;
+        // a PML4, a PDPT, a PD, a PT, and the mapped frame
+        assert_eq!(counting.allocated, 5);
+        assert_eq!(table.free_tables(&mut pml4, &mut temp, &mut counting)?, 4);
+        assert_eq!(counting.deallocated, 4);
+        let mapped = mapped.expect("the page wasn't mapped");
+        unsafe { counting.deallocate(mapped) };
+        assert_eq!(counting.allocated, counting.deallocated);
+        trace!("freed the page tables of an inactive table");
+    }
+
+    // free just the page tables of a small inactive table, and check that
+    // the frames it maps are left alone.
+    {
+        let mut temp = test_temp_page(alloc);
+        let mut counting = CountingAlloc::new(alloc);
+        let pml4_frame = unsafe { counting.allocate() }
+                             .expect("no more frames");
+        let mut table =
+            InactivePageTable::new(pml4_frame, &mut pml4, &mut temp)?;
+        // two pages in different PTs, under the same PD
+        let page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        let other = page + 512;
+        let frames = table.with(&mut pml4, &mut temp, |inactive| {
+            inactive.map_to_any(page, WRITABLE, &mut counting)?;
+            inactive.map_to_any(other, WRITABLE, &mut counting)?;
+            Ok([ inactive.translate_page(page)
+               , inactive.translate_page(other) ])
+        })?;
+        // a PML4, a PDPT, a PD, two PTs, and two mapped frames
+        assert_eq!(counting.allocated, 7);
+
+        let reclaimed = table.free_tables(&mut pml4, &mut temp, &mut counting)?;
+        assert_eq!(reclaimed, 5);
+        assert_eq!(counting.deallocated, 5);
+        unsafe {
+            for frame in frames.iter().filter_map(|&frame| frame) {
+                counting.deallocate(frame);
+            }
+        }
+        assert_eq!(counting.allocated, counting.deallocated);
+        trace!("reclaimed {} page tables from an inactive table", reclaimed);
+    }
+
+    // reclaim the page tables of a small bootstrap-like table, which shares
+    // nothing with the active table, and check that the ones in a kernel
+    // entry are freed too, while its PML4 and the frames it maps are kept.
+    {
+        let mut temp = test_temp_page(alloc);
+        let mut counting = CountingAlloc::new(alloc);
+        let pml4_frame = unsafe { counting.allocate() }
+                             .expect("no more frames");
+        let mut table =
+            InactivePageTable::new_empty(pml4_frame, &mut pml4, &mut temp)?;
+        // one page in a kernel entry, and one outside them
+        let kernel_page =
+            VirtualPage::containing(VAddr::from(82 * HUGE_PAGE_SIZE as usize));
+        let page = VirtualPage { number: (TEST_PML4_ENTRY << 27) + 1 };
+        let frames = table.with(&mut pml4, &mut temp, |inactive| {
+            inactive.map_to_any(kernel_page, WRITABLE, &mut counting)?;
+            inactive.map_to_any(page, WRITABLE, &mut counting)?;
+            Ok([ inactive.translate_page(kernel_page)
+               , inactive.translate_page(page) ])
+        })?;
+        // a PML4, two PDPTs, two PDs, two PTs, and two mapped frames
+        assert_eq!(counting.allocated, 9);
+
+        let reclaimed = table.reclaim_bootstrap_tables( &mut pml4, &mut temp
+                                                      , &mut counting)?;
+        assert_eq!(reclaimed, 6);
+        assert_eq!(counting.deallocated, 6);
+        assert!(pml4.pml4()[0].get_frame().is_some());
+        unsafe {
+            for frame in frames.iter().filter_map(|&frame| frame) {
+                counting.deallocate(frame);
+            }
+            counting.deallocate(pml4_frame);
+        }
+        assert_eq!(counting.allocated, counting.deallocated);
+        trace!("reclaimed {} page tables from a bootstrap table", reclaimed);
+    }
+
+    // build an inactive table from a list of mappings, and check that
+    // they're all there, before and after it's switched to. if a mapping
+    // fails, every frame the table allocated should be freed again.
+    {
+        let mut temp = test_temp_page(alloc);
+        let frames = unsafe { allocate_run(alloc, 3) }
+                         .expect("no more frames");
+        // pages in different PDPTs, PDs, and PTs, outside the kernel's
+        // PML4 entries so they aren't mapped in the active table too.
+        let start = VirtualPage { number: TEST_PML4_ENTRY << 27 };
+        let pages = [ start + 1, start + (1 << 18), start + (1 << 27) ];
+        let mappings = [ (pages[0], frames.start, WRITABLE)
+                       , (pages[1], frames.start + 1, WRITABLE)
+                       , (pages[2], frames.start + 2, WRITABLE) ];
+        let mut table = InactivePageTable::from_mappings( mappings.iter()
+                                                                  .cloned()
+                                                        , &mut pml4
+                                                        , &mut temp
+                                                        , alloc)?;
+        let mut mapped = [None; 3];
+        table.with(&mut pml4, &mut temp, |inactive| {
+            for (slot, &page) in mapped.iter_mut().zip(pages.iter()) {
+                *slot = inactive.translate_page(page);
+            }
+            Ok(())
+        })?;
+        for (&frame, translated) in mapped.iter().zip(frames.clone()) {
+            assert_eq!(frame, Some(translated));
+        }
+        assert!( pml4.translate_page(pages[0]).is_none()
+               , "from_mappings changed the active table");
+
+        let old_table = pml4.replace_with(table, &mut temp)?;
+        for (&page, frame) in pages.iter().zip(frames.clone()) {
+            assert_eq!(pml4.translate_page(page), Some(frame));
+        }
+        let table = pml4.replace_with(old_table, &mut temp)?;
+        assert!(pml4.translate_page(pages[0]).is_none());
+        // the test frames aren't freed along with the table
+        table.free_tables(&mut pml4, &mut temp, alloc)?;
+
+        // mapping the first page twice fails part way through.
+        let mut counting = CountingAlloc::new(alloc);
+        let twice = mappings.iter().cloned().chain(Some(mappings[0]));
+        assert!(InactivePageTable::from_mappings( twice
+                                                , &mut pml4
+                                                , &mut temp
+                                                , &mut counting).is_err());
+        assert!(counting.allocated > 0);
+        assert_eq!(counting.allocated, counting.deallocated);
+        for frame in frames {
+            unsafe { alloc.deallocate(frame) };
+        }
+    }
+
+    // map a fake 4 GiB of physical memory at 64 GiB, and check that a
+    // physical address can be reached through the window.
+    {
+        let offset = VAddr::from(64 * HUGE_PAGE_SIZE as usize);
+        let phys_end = PAddr::from(4 * HUGE_PAGE_SIZE);
+        map_physical_memory(&mut pml4, offset, phys_end, alloc)?;
+        let paddr = PAddr::from(0x1234_5678);
+        assert_eq!(pml4.translate(paddr.to_virt(offset)), Some(paddr));
+        assert_eq!( pml4.translate(PAddr::from(0xffff_ffff).to_virt(offset))
+                  , Some(PAddr::from(0xffff_ffff)));
+        trace!("{:?} is mapped at {:?}", paddr, paddr.to_virt(offset));
+
+        let mut paddr = PAddr::from(0);
+        while paddr < phys_end {
+            let page = VirtualPage::containing(paddr.to_virt(offset));
+            let frames = pml4.unmap_keep_frames(page, alloc)?;
+            paddr = paddr + frames.length() as u64 * PAGE_SIZE;
+        }
+    }
+
+    // allocate a stack, and check that it's writable and that the page
+    // below it is an unmapped guard page, then free it.
+    {
+        let stack = pml4.alloc_stack(4, alloc)?;
+        assert_eq!(*stack.top - *stack.bottom, 4 * PAGE_SIZE as usize);
+        let guard = VirtualPage::containing(stack.bottom) - 1;
+        assert_eq!(pml4.translate_page(guard), None);
+        assert!(pml4.is_guard(guard));
+        let mut addr = stack.bottom;
+        while addr < stack.top {
+            unsafe { *addr.as_mut_ptr::<u64>() = 0xdead_beef };
+            assert_eq!(unsafe { *addr.as_ptr::<u64>() }, 0xdead_beef);
+            addr = addr + PAGE_SIZE as usize;
+        }
+        trace!("allocated {:?} with guard page {:?}", stack, guard);
+        pml4.free_stack(stack, alloc)?;
+        assert!(!pml4.is_guard(guard));
+        assert_eq!(pml4.translate_page(guard + 1), None);
+    }
+
+    // write a byte to a data frame through the temp page, and read it back
+    // after remapping it.
+    {
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        let ptr = temp.map_to(frame, &mut pml4)?;
+        unsafe { *ptr.offset(42) = 0x5a };
+        temp.unmap(&mut pml4)?;
+        let byte = temp.with(frame, &mut pml4, |ptr| unsafe {
+            *ptr.offset(42)
+        })?;
+        assert_eq!(byte, 0x5a);
+        assert_eq!(pml4.translate_page(*temp), None);
+        trace!("read {:#x} back from {:?} through the temp page", byte, frame);
+        unsafe { alloc.deallocate(frame) };
+    }
+
+    // dirty a frame, free it, and check that zeroed allocations don't
+    // expose its old contents.
+    {
+        use self::temp::AllocateZeroed;
+        let mut temp = test_temp_page(alloc);
+        let dirty = unsafe { alloc.allocate() }.expect("no more frames");
+        temp.with(dirty, &mut pml4, |ptr| unsafe {
+            ::core::ptr::write_bytes(ptr, 0xff, PAGE_SIZE as usize)
+        })?;
+        unsafe { alloc.deallocate(dirty) };
+
+        let frame = alloc.allocate_zeroed(&mut temp, &mut pml4)?;
+        let page =
+            VirtualPage::containing(VAddr::from(50 * HUGE_PAGE_SIZE as usize));
+        pml4.map(page, frame, WRITABLE, alloc)?;
+        let bytes = unsafe {
+            ::core::slice::from_raw_parts( page.base().as_ptr::<u8>()
+                                         , PAGE_SIZE as usize)
+        };
+        assert!(bytes.iter().all(|&b| b == 0), "{:?} wasn't zeroed", frame);
+        pml4.unmap(page, alloc)?;
+
+        pml4.map_to_any_zeroed(page, PRESENT, alloc)?;
+        let bytes = unsafe {
+            ::core::slice::from_raw_parts( page.base().as_ptr::<u8>()
+                                         , PAGE_SIZE as usize)
+        };
+        assert!(bytes.iter().all(|&b| b == 0), "{:?} wasn't zeroed", page);
+        pml4.unmap(page, alloc)?;
+        trace!("allocated zeroed frames");
+
+        // a frame that can't be zeroed, because the temp page is already in
+        // use, is given back.
+        let in_use = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map(*temp, in_use, PRESENT, alloc)?;
+        {
+            let mut counting = CountingAlloc::new(alloc);
+            assert!(counting.allocate_zeroed(&mut temp, &mut pml4).is_err());
+            assert_eq!(counting.allocated, 1);
+            assert_eq!(counting.deallocated, 1);
+        }
+        pml4.unmap_keep_frames(*temp, alloc)?;
+    }
+
+    // a global page should stay in the TLB when CR3 is reloaded, and only
+    // be evicted by toggling global pages.
+    {
+        use core::ptr;
+        let mut temp = test_temp_page(alloc);
+        let old = unsafe { alloc.allocate() }.expect("no more frames");
+        let new = unsafe { alloc.allocate() }.expect("no more frames");
+        temp.with(old, &mut pml4, |ptr| unsafe { *ptr = 1 })?;
+        temp.with(new, &mut pml4, |ptr| unsafe { *ptr = 2 })?;
+
+        let page =
+            VirtualPage::containing(VAddr::from(51 * HUGE_PAGE_SIZE as usize));
+        let ptr = page.base().as_ptr::<u8>();
+        unsafe { tlb::enable_global_pages() };
+        pml4.map(page, old, GLOBAL, alloc)?;
+        assert_eq!(unsafe { ptr::read_volatile(ptr) }, 1);
+
+        // change the mapping without invalidating it
+        pml4.leaf_entry_mut(page).expect("page not mapped")
+            .set(new, PRESENT | GLOBAL);
+        unsafe { tlb::flush_all() };
+        assert_eq!( unsafe { ptr::read_volatile(ptr) }, 1
+                  , "global page was flushed by a CR3 reload");
+        unsafe { tlb::flush_all_global() };
+        assert_eq!( unsafe { ptr::read_volatile(ptr) }, 2
+                  , "global page wasn't flushed");
+
+        pml4.unmap_keep_frames(page, alloc)?;
+        unsafe {
+            alloc.deallocate(old);
+            alloc.deallocate(new);
+        }
+    }
+
+    // map_to_any should take exactly one frame from the allocator (since the
+    // page's tables already exist) and map the page to it.
+    {
+        let page = VirtualPage::containing(VAddr::from(4096 * 3));
+        let mut counting = CountingAlloc::new(alloc);
+        assert_eq!(pml4.translate_page(page), None);
+        pml4.map_to_any(page, WRITABLE, &mut counting)?;
+        assert_eq!(counting.allocated, 1);
+        assert!( pml4.translate_page(page).is_some()
+               , "map_to_any didn't map {:?}", page);
+        // mapping it again is an error, not a panic, and the frame that was
+        // allocated for it is given back.
+        assert!(pml4.map_to_any(page, WRITABLE, &mut counting).is_err());
+        assert_eq!(counting.allocated, 2);
+        assert_eq!(counting.deallocated, 1);
+        pml4.unmap(page, &mut counting)?;
+        assert_eq!(counting.allocated, counting.deallocated);
+        assert_eq!(pml4.translate_page(page), None);
+        trace!("map_to_any mapped {:?}", page);
+    }
+
+    // unmapping pages mapped to contiguous frames should free the frames
+    // with a single contiguous deallocation.
+    {
+        let frames = unsafe { allocate_run(alloc, 4) }.expect("no more frames");
+        let addr = VAddr::from(53 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(4);
+        pml4.map_range(pages.clone(), frames, WRITABLE, alloc)?;
+        let mut counting = CountingAlloc::new(alloc);
+        pml4.unmap_range(pages.clone(), &mut counting)?;
+        assert_eq!(counting.contiguous, 1);
+        assert_eq!(counting.deallocated, 4);
+        assert!(pages.clone().all(|page| pml4.translate_page(page).is_none()));
+        trace!("unmapped {:?} with one contiguous free", pages);
+    }
+
+    // check whole ranges for unmapped pages.
+    {
+        let addr = VAddr::from(54 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(3);
+        let hole = pages.start + 1;
+        for page in pages.clone() {
+            pml4.map_to_any(page, WRITABLE, alloc)?;
+        }
+        assert!(pml4.is_mapped_range(pages.clone()));
+        assert_eq!(pml4.first_unmapped(pages.clone()), None);
+
+        pml4.unmap(hole, alloc)?;
+        assert!(!pml4.is_mapped_range(pages.clone()));
+        assert_eq!(pml4.first_unmapped(pages.clone()), Some(hole));
+
+        // an empty range has no unmapped pages
+        assert!(pml4.is_mapped_range(hole .. hole));
+        pml4.unmap(pages.start, alloc)?;
+        pml4.unmap(pages.start + 2, alloc)?;
+        trace!("checked mapped ranges");
+    }
+
+    // reserve a range for demand paging, and check that a fault inside it
+    // is resolved while one outside it isn't.
+    {
+        let addr = VAddr::from(55 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(4);
+        pml4.reserve(pages.clone(), WRITABLE)?;
+        match pml4.reserve(pages.clone(), WRITABLE) {
+            Err(MapErr::Overlaps { .. }) => {}
+          , other => panic!("reserving a range twice gave {:?}", other)
+        }
+        assert!(!pml4.is_mapped(&(pages.start + 2)));
+
+        let faulting = (pages.start + 2).base() + 0x123;
+        pml4.resolve_fault(faulting, alloc)?;
+        assert!(pml4.is_mapped(&(pages.start + 2)));
+        assert!(!pml4.is_mapped(&(pages.start + 1)));
+        assert_eq!(unsafe { *faulting.as_ptr::<u8>() }, 0);
+        // faulting on a page that's already mapped isn't resolvable
+        assert!(pml4.resolve_fault(faulting, alloc).is_err());
+        assert!(pml4.resolve_fault(pages.end.base(), alloc).is_err());
+        trace!("resolved a demand paging fault at {:?}", faulting);
+        pml4.unmap(pages.start + 2, alloc)?;
+
+        // once it's unreserved, a fault in it is genuine.
+        pml4.unreserve(pages.clone())?;
+        assert!(pml4.unreserve(pages.clone()).is_err());
+        assert!(pml4.resolve_fault(faulting, alloc).is_err());
+    }
+
+    // mapping a page again through `map_or_update` succeeds if it's mapped
+    // to the same frame, updating the flags if they differ.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(60 * HUGE_PAGE_SIZE as usize));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map_or_update(page, frame, WRITABLE, alloc)?;
+        assert_eq!(pml4.translate_page(page), Some(frame));
+        // the same mapping again
+        pml4.map_or_update(page, frame, WRITABLE, alloc)?;
+        assert!(pml4.map(page, frame, WRITABLE, alloc).is_err());
+
+        // only the flags differ
+        pml4.map_or_update(page, frame, PRESENT, alloc)?;
+        let writable = pml4.leaf_entry(page)
+                           .map(|entry| entry.flags().contains(WRITABLE));
+        assert_eq!(writable, Some(false));
+        assert_eq!(pml4.translate_page(page), Some(frame));
+
+        // a different frame is a genuine conflict
+        match pml4.map_or_update(page, frame + 1, PRESENT, alloc) {
+            Err(MapErr::AlreadyInUse { .. }) => {}
+          , other => panic!("remapping to another frame gave {:?}", other)
+        }
+        assert_eq!(pml4.translate_page(page), Some(frame));
+        pml4.unmap_keep_frames(page, alloc)?;
+    }
+
+    // mapping a frame again through `map_or_merge` keeps the permissions
+    // that either mapping needs, like a data section sharing a frame with
+    // the end of a code section.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(60 * HUGE_PAGE_SIZE as usize));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map_or_merge(page, frame, WRITABLE | NO_EXECUTE, alloc)?;
+        pml4.map_or_merge(page, frame, PRESENT, alloc)?;
+        let flags = pml4.leaf_entry(page).map(|entry| entry.flags());
+        let flags = flags.expect("merged page wasn't mapped");
+        assert!(flags.contains(WRITABLE), "{:?} lost WRITABLE", page);
+        assert!(!flags.contains(NO_EXECUTE), "{:?} wasn't executable", page);
+
+        // mappings with different caching can't be merged
+        match pml4.map_or_merge(page, frame, EntryFlags::mmio(), alloc) {
+            Err(MapErr::AlreadyInUse { .. }) => {}
+          , other => panic!("merging caching modes gave {:?}", other)
+        }
+        pml4.unmap_keep_frames(page, alloc)?;
+        trace!("merged the flags of {:?}", page);
+    }
+
+    // plan remapping a fake kernel, and check the pages and tables counted.
+    {
+        use params::InitParams;
+        let frames = |start: u64, end: u64| {
+            PhysicalPage { number: start } .. PhysicalPage { number: end }
+        };
+        // two sections sharing a frame, and one in another PT.
+        let plan = RemapPlan::for_ranges(&[ frames(0x100, 0x104)
+                                          , frames(0x103, 0x108)
+                                          , frames(0x400, 0x401) ]);
+        assert_eq!(plan, RemapPlan { pages: 9, tables: 1 + 1 + 1 + 2 });
+
+        // with no ELF sections, just the VGA buffer and multiboot info.
+        let mut params = InitParams::default();
+        params.multiboot_start = Some(PAddr::from(0x20_0000));
+        params.multiboot_end = Some(PAddr::from(0x20_2000));
+        // the VGA buffer and the multiboot info are in different PTs.
+        assert_eq!( kernel_remap_plan(&params)
+                  , RemapPlan { pages: 3, tables: 1 + 1 + 1 + 2 });
+        trace!("planned remapping a fake kernel");
+    }
+
+    // map a 16KiB MMIO region, and check that every page is uncacheable.
+    {
+        // the local APIC's registers, which aren't RAM. they're never
+        // accessed, just mapped.
+        let phys = PAddr::from(0xfee0_0000);
+        let vaddr = pml4.map_mmio(phys, 4 * PAGE_SIZE as usize, alloc)?;
+        let start = VirtualPage::containing(vaddr);
+        for page in start.range_of(4) {
+            let bits = pml4.walk(page.base()).pt.expect("MMIO page not mapped")
+                           .as_u64();
+            assert!(bits & (1 << 4) != 0, "PCD not set in {:#x}", bits);
+        }
+        assert_eq!(pml4.translate(vaddr), Some(phys));
+
+        // an unaligned region keeps its offset into the page.
+        let unaligned = pml4.map_mmio(phys + 0x10, 8, alloc)?;
+        assert_eq!( unaligned.page_offset() as u64
+                  , (phys + 0x10).frame_offset());
+        assert_eq!(pml4.translate(unaligned), Some(phys + 0x10));
+        assert!(VirtualPage::containing(unaligned) != start);
+
+        pml4.unmap_mmio(unaligned, alloc)?;
+        pml4.unmap_mmio(vaddr, alloc)?;
+        assert!(pml4.translate_page(start).is_none());
+        assert!(pml4.unmap_mmio(vaddr, alloc).is_err());
+        trace!("mapped and unmapped MMIO region at {:#x}", vaddr);
+    }
+
+    // a page that's both writable and executable is flagged by the W^X
+    // audit, and stops being flagged once it's no longer executable.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(61 * HUGE_PAGE_SIZE as usize));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map(page, frame, WRITABLE, alloc)?;
+        assert!( pml4.audit_wx().any(|pages| pages.contains_page(page))
+               , "W^X audit missed {:?}", page);
+
+        pml4.map_or_update(page, frame, WRITABLE | NO_EXECUTE, alloc)?;
+        assert!(!pml4.audit_wx().any(|pages| pages.contains_page(page)));
+        trace!("W^X audit flagged a writable, executable page");
+        pml4.unmap_keep_frames(page, alloc)?;
+    }
+
+    // indexing a table by page, by address, and by number all pick the same
+    // entry, at every level.
+    {
+        let addr = VAddr::from(0o123_456_701_234_5670);
+        let page = VirtualPage::containing(addr);
+        assert_eq!(entry_index::<PML4Level, _>(page), 0o123);
+        assert_eq!(entry_index::<PDPTLevel, _>(page), 0o456);
+        assert_eq!(entry_index::<PDLevel, _>(page), 0o701);
+        assert_eq!(entry_index::<PTLevel, _>(page), 0o234);
+        assert_eq!(entry_index::<PTLevel, _>(addr), 0o234);
+        assert_eq!(entry_index::<PTLevel, _>(N_ENTRIES - 1), N_ENTRIES - 1);
+        let pml4 = pml4.pml4();
+        assert_eq!( &pml4[RECURSIVE_INDEX] as *const Entry
+                  , &pml4[VAddr::from(PML4_VADDR as usize)] as *const Entry);
+    }
+
+    // finding a free region skips runs with mapped pages in them.
+    {
+        let start =
+            VirtualPage::containing(VAddr::from(79 * HUGE_PAGE_SIZE as usize));
+        let search = start.range_of(8);
+        // pages 0, 1 and 4 are mapped, leaving gaps at 2..4 and 5..8.
+        for &i in &[0, 1, 4] {
+            pml4.map_to_any(start + i, WRITABLE | NO_EXECUTE, alloc)?;
+        }
+        assert_eq!( pml4.find_free_region(2, search.clone())
+                  , Some((start + 2) .. (start + 4)));
+        assert_eq!( pml4.find_free_region(3, search.clone())
+                  , Some((start + 5) .. (start + 8)));
+        assert_eq!(pml4.find_free_region(4, search.clone()), None);
+        assert_eq!(pml4.find_free_region(0, search.clone()), None);
+        for &i in &[0, 1, 4] {
+            pml4.unmap(start + i, alloc)?;
+        }
+        assert_eq!(pml4.find_free_region(8, search.clone()), Some(search));
+        trace!("found free regions around mapped pages");
+    }
+
+    // remapping only logs each frame when it's asked to be verbose.
+    {
+        /// Counts the lines written to it.
+        struct LineCount(usize);
+        impl fmt::Write for LineCount {
+            fn write_str(&mut self, _: &str) -> fmt::Result { Ok(()) }
+            fn write_fmt(&mut self, _: fmt::Arguments) -> fmt::Result {
+                self.0 += 1;
+                Ok(())
+            }
+        }
+
+        // the frames are never accessed, so they needn't be allocated.
+        let frames = PhysicalPage::containing(PAddr::from(78 * HUGE_PAGE_SIZE))
+                                 .range_of(3);
+        let mut lines = [0; 2];
+        for (i, &verbose) in [false, true].iter().enumerate() {
+            let mut log = LineCount(0);
+            identity_map_logged( &mut *pml4, frames.clone(), PRESENT
+                               , RemapOptions { verbose: verbose }
+                               , &mut log, alloc)?;
+            lines[i] = log.0;
+            for frame in frames.clone() {
+                let vaddr = VAddr::from(*frame.base_addr() as usize);
+                pml4.unmap_keep_frames(VirtualPage::containing(vaddr), alloc)?;
+            }
+        }
+        assert_eq!(lines, [0, 3]);
+        assert!(!RemapOptions::default().verbose);
+        trace!("verbose remapping logged {} lines", lines[1]);
+    }
+
+    // translating an address whose PDPT is absent stops at the PML4, even
+    // if the PML4 entry still holds a stale frame.
+    {
+        // use whichever lower-half PML4 entry is free, rather than relying
+        // on an earlier test having emptied one.
+        let i = (1..N_ENTRIES / 2).find(|&i| pml4.pml4()[i].is_unused())
+                                  .expect("every PML4 entry is in use");
+        let addr = VAddr::from((i << 39) + 0x1234);
+        let page = VirtualPage::containing(addr);
+        assert_eq!(pml4.translate(addr), None);
+
+        let stale = unsafe { alloc.allocate() }.expect("no more frames");
+        pml4.pml4_mut()[page].set(stale, WRITABLE);
+        assert_eq!(pml4.translate(addr), None);
+        assert_eq!(pml4.translate_page(page), None);
+        assert!(pml4.walk(addr).pdpt.is_none());
+        pml4.pml4_mut()[page].set_unused();
+        unsafe { alloc.deallocate(stale) };
+        trace!("translated {:?} without following a stale entry", addr);
+    }
+
+    // a region copied from another address space maps the same frames in
+    // both, and the source's unmapped pages stay unmapped.
+    {
+        let mut temp = test_temp_page(alloc);
+        let start = VirtualPage { number: TEST_PML4_ENTRY << 27 };
+        let frames = unsafe {
+            [ alloc.allocate().expect("no more frames")
+            , alloc.allocate().expect("no more frames")
+            , alloc.allocate().expect("no more frames") ]
+        };
+        let mappings = [ (start, frames[0], WRITABLE)
+                       , (start + 1, frames[1], WRITABLE)
+                       , (start + 3, frames[2], WRITABLE) ];
+        let mut src = InactivePageTable::from_mappings( mappings.iter().cloned()
+                                                      , &mut pml4, &mut temp
+                                                      , alloc)?;
+        {
+            let mut sharing = SharingAlloc { alloc: &mut *alloc
+                                           , frame: frames[0]
+                                           , refs: 1 };
+            pml4.copy_mapping_from( &src, start.range_of(4)
+                                  , WRITABLE | NO_EXECUTE
+                                  , &mut temp, &mut sharing)?;
+            assert_eq!(sharing.ref_count(frames[0]), Some(2));
+        }
+        let expected = [ Some(frames[0]), Some(frames[1])
+                       , None, Some(frames[2]) ];
+        for (page, &frame) in start.range_of(4).zip(expected.iter()) {
+            assert_eq!(pml4.translate_page(page), frame);
+        }
+        pml4.using(&mut src, &mut temp, |pml4| {
+            for (page, &frame) in start.range_of(4).zip(expected.iter()) {
+                assert_eq!(pml4.translate_page(page), frame);
+            }
+            Ok(())
+        })?;
+
+        // the frames still belong to the source table.
+        for &(page, _, _) in &mappings {
+            pml4.unmap_keep_frames(page, alloc)?;
+        }
+        src.free(&mut pml4, &mut temp, alloc)?;
+        trace!("shared {:?} between two address spaces", start.range_of(4));
+    }
+
+    // the flags builder rejects bits that don't belong at an entry's level.
+    {
+        let flags = EntryFlagsBuilder::new().writable().no_execute();
+        assert_eq!( flags.build_leaf::<PTLevel>()
+                  , Ok(PRESENT | WRITABLE | NO_EXECUTE));
+        assert_eq!( flags.cache(Caching::WriteCombining).build_leaf::<PTLevel>()
+                  , Ok(EntryFlags::write_combining() | PRESENT));
+        assert_eq!( flags.cache(Caching::Uncached).build_leaf::<PTLevel>()
+                  , Ok(EntryFlags::mmio() | PRESENT));
+        assert_eq!( flags.huge().global().build_leaf::<PDLevel>()
+                  , Ok(PRESENT | WRITABLE | NO_EXECUTE | HUGE_PAGE | GLOBAL));
+        assert!(flags.huge().build_leaf::<PDPTLevel>().is_ok());
+        assert!( flags.huge().cache(Caching::WriteCombining)
+                      .build_leaf::<PDLevel>().is_err());
+        // huge pages only exist in PDs and PDPTs, and must be huge there.
+        assert!(flags.huge().build_leaf::<PTLevel>().is_err());
+        assert!(flags.build_leaf::<PDLevel>().is_err());
+        assert!(flags.huge().build_leaf::<PML4Level>().is_err());
+        assert!(flags.build_leaf::<PML4Level>().is_err());
+
+        assert_eq!( EntryFlagsBuilder::new().writable().user()
+                                            .build_table::<PML4Level>()
+                  , Ok(PRESENT | WRITABLE | USER_ACCESSIBLE));
+        assert!(flags.huge().build_table::<PDLevel>().is_err());
+        assert!(flags.global().build_table::<PDPTLevel>().is_err());
+        assert!( flags.cache(Caching::WriteCombining)
+                      .build_table::<PDLevel>().is_err());
+    }
+
+    // a write-combining PT entry still maps a 4KiB page, though its `PAT`
+    // bit is the huge page bit at the other levels.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(81 * HUGE_PAGE_SIZE as usize));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        let flags = EntryFlags::write_combining();
+        pml4.map(page, frame, flags, alloc)?;
+        assert_eq!( pml4.leaf(page)
+                        .map(|(entry, size)| (entry.pt_caching(), size))
+                  , Some((Caching::WriteCombining, PageSize::Size4K)));
+        assert_eq!(pml4.remap_to(page, frame + 1, flags, alloc)?, frame);
+        pml4.map_or_update(page, frame + 1, flags - WRITABLE, alloc)?;
+        assert_eq!( pml4.translate_page_sized(page)
+                  , Some((frame + 1, PageSize::Size4K)));
+        pml4.unmap_keep_frames(page, alloc)?;
+        trace!("write-combining {:?} is still a 4KiB page", page);
+    }
+
+    // switching page tables hands back the previous frame, so switching
+    // back and forth between two tables needs neither to be owned.
+    {
+        use core::cell::Cell;
+        let (a, b) = ( PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE))
+                     , PhysicalPage::containing(PAddr::from(HUGE_PAGE_SIZE)) );
+        let mock_cr3 = Cell::new(a.base_addr() + 42);
+        let swap = |frame| cr3::swap_with( frame, || mock_cr3.get()
+                                         , |addr| mock_cr3.set(addr));
+        assert_eq!(swap(b), a);
+        assert_eq!(swap(a), b);
+        assert_eq!(swap(b), a);
+        assert!(cr3::is_active_with(b, || mock_cr3.get()));
+
+        // switching to the active table leaves it active.
+        let frame = pml4.frame;
+        assert_eq!(unsafe { pml4.switch_to(frame) }, frame);
+        assert!(pml4.is_current());
+        trace!("switched page tables without owning them");
+    }
+
+    // only the present entries in a table are visited, with their indices.
+    {
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        {
+            let table = temp.map_to_table(frame, &mut pml4)?;
+            table.zero();
+            for &i in &[0, 7, N_ENTRIES - 1] {
+                table[i].set(frame, PRESENT | WRITABLE);
+            }
+            // an entry with flags, but not present, isn't visited.
+            table[3].set(frame, WRITABLE);
+
+            let expected = [0, 7, N_ENTRIES - 1];
+            assert_eq!(table.present_entries().count(), expected.len());
+            for ((i, entry), &expected) in
+                table.present_entries().zip(expected.iter()) {
+                assert_eq!(i, expected);
+                assert_eq!(entry.get_frame(), Some(frame));
+            }
+        }
+        temp.unmap(&mut pml4)?;
+        unsafe { alloc.deallocate(frame) };
+        trace!("visited the present entries in {:?}", frame);
+    }
+
+    // the pooled temporary page is only created once, so later uses don't
+    // allocate.
+    {
+        use core::ptr::{read_volatile, write_volatile};
+        let frame = unsafe { alloc.allocate().expect("no more frames") };
+        let mut counting = CountingAlloc::new(alloc);
+        let page = pml4.with_temp_page(&mut counting, |table, temp, _| {
+            temp.with(frame, table, |ptr| unsafe {
+                write_volatile(ptr as *mut u64, 0xfeed_f00d)
+            }).map(|_| **temp)
+        })?;
+        let allocated = counting.allocated;
+        let (value, reused) =
+            pml4.with_temp_page(&mut counting, |table, temp, _| {
+                temp.with(frame, table, |ptr| unsafe {
+                    read_volatile(ptr as *const u64)
+                }).map(|value| (value, **temp))
+            })?;
+        assert_eq!(value, 0xfeed_f00d);
+        assert_eq!(reused, page);
+        assert_eq!(counting.allocated, allocated);
+        unsafe { counting.alloc.deallocate(frame) };
+        trace!("reused the pooled temp page {:?}", page);
+    }
+
+    // mapped pages and the tables that map them are counted, with a huge
+    // page counted as the 4 KiB pages it covers.
+    {
+        let (pages, tables) = (pml4.count_mapped_pages(), pml4.count_tables());
+        let start =
+            VirtualPage::containing(VAddr::from(76 * HUGE_PAGE_SIZE as usize));
+        for page in start.range_of(3) {
+            pml4.map_to_any(page, WRITABLE | NO_EXECUTE, alloc)?;
+        }
+        // a new PD and PT were needed.
+        assert_eq!(pml4.count_mapped_pages(), pages + 3);
+        assert_eq!(pml4.count_tables(), tables + 2);
+
+        // the huge page shares the PD, and needs no PT.
+        let huge_page = start + 512;
+        // the frame is never accessed, it just needs to be 2 MiB aligned.
+        let huge_frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map_huge( huge_page, huge_frame, HugePageSize::Size2M
+                     , WRITABLE | NO_EXECUTE, alloc)?;
+        assert_eq!(pml4.count_mapped_pages(), pages + 3 + 512);
+        assert_eq!(pml4.count_tables(), tables + 2);
+
+        pml4.unmap_keep_frames(huge_page, alloc)?;
+        pml4.unmap_range(start.range_of(3), alloc)?;
+        assert_eq!(pml4.count_mapped_pages(), pages);
+        trace!("counted {} pages in {} tables", pages, tables);
+    }
+
+    // each frame in an identity mapped range translates to itself, and
+    // mapping the range again is tolerated, without taking away the
+    // permissions it was already mapped with.
+    {
+        // the frames are never accessed, so they needn't be allocated.
+        let frames = PhysicalPage::containing(PAddr::from(75 * HUGE_PAGE_SIZE))
+                                 .range_of(3);
+        pml4.identity_map_range(frames.clone(), WRITABLE, alloc)?;
+        pml4.identity_map_range(frames.clone(), WRITABLE, alloc)?;
+        pml4.identity_map_range(frames.clone(), PRESENT, alloc)?;
+        for frame in frames.clone() {
+            let vaddr = VAddr::from(*frame.base_addr() as usize);
+            let page = VirtualPage::containing(vaddr);
+            assert_eq!(pml4.translate_page(page), Some(frame));
+            let writable = pml4.leaf_entry(page)
+                               .map(|entry| entry.flags().contains(WRITABLE));
+            assert_eq!(writable, Some(true));
+            pml4.unmap_keep_frames(page, alloc)?;
+        }
+        trace!("identity mapped {:?}", frames);
+    }
+
+    // repointing a mapped page changes what it translates to, without
+    // unmapping it in between.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(74 * HUGE_PAGE_SIZE as usize));
+        let old_frame = unsafe { alloc.allocate().expect("no more frames") };
+        let new_frame = unsafe { alloc.allocate().expect("no more frames") };
+        assert!(pml4.remap_to(page, new_frame, WRITABLE, alloc).is_err());
+
+        pml4.map(page, old_frame, WRITABLE, alloc)?;
+        assert_eq!( pml4.remap_to(page, new_frame, WRITABLE, alloc)?
+                  , old_frame);
+        assert_eq!(pml4.translate_page(page), Some(new_frame));
+        assert_eq!( pml4.translate(page.base() + 0x123)
+                  , Some(new_frame.base_addr() + 0x123));
+
+        // the old frame is the caller's to free.
+        unsafe { alloc.deallocate(old_frame) };
+        pml4.unmap(page, alloc)?;
+        trace!("remapped {:?} to {:?}", page, new_frame);
+    }
+
+    // the clock hand gives accessed pages a second chance, and evicts the
+    // first page it finds that hasn't been accessed.
+    {
+        use core::ptr::read_volatile;
+        use clock::ClockSweeper;
+        let start =
+            VirtualPage::containing(VAddr::from(73 * HUGE_PAGE_SIZE as usize));
+        let pages: [VirtualPage; 4] = [start, start + 1, start + 2, start + 3];
+        for &page in &pages {
+            pml4.map_to_any(page, WRITABLE | NO_EXECUTE, alloc)?;
+            pml4.clear_accessed(page)?;
+        }
+        // every page but the third has been accessed.
+        for &i in &[0, 1, 3] {
+            let _ = unsafe { read_volatile(pages[i].base().as_ptr::<u64>()) };
+        }
+
+        let mut clock = ClockSweeper::new(&pages);
+        assert_eq!(clock.evict_candidate(&mut *pml4), Some(pages[2]));
+        assert_eq!(clock.hand(), 3);
+        // the pages the hand passed lost their accessed bits.
+        assert_eq!(pml4.is_accessed(pages[0]), Some(false));
+        assert_eq!(pml4.is_accessed(pages[1]), Some(false));
+        assert_eq!(pml4.is_accessed(pages[3]), Some(true));
+        // the fourth page gets a second chance, but the first doesn't.
+        assert_eq!(clock.evict_candidate(&mut *pml4), Some(pages[0]));
+        assert_eq!(pml4.is_accessed(pages[3]), Some(false));
+
+        pml4.unmap_range(start.range_of(4), alloc)?;
+        let mut clock = ClockSweeper::new(&pages);
+        assert_eq!(clock.evict_candidate(&mut *pml4), None);
+        trace!("clock sweep chose the unaccessed page");
+    }
+
+    // a guard page is told apart from any other unmapped page, and faults on
+    // it aren't resolved.
+    {
+        let guard =
+            VirtualPage::containing(VAddr::from(72 * HUGE_PAGE_SIZE as usize));
+        assert!(!pml4.is_guard(guard));
+        pml4.mark_guard(guard)?;
+        assert!(pml4.is_guard(guard));
+        assert!(!pml4.is_mapped(&guard));
+        assert!(!pml4.is_guard(guard + 1));
+        assert!(pml4.resolve_fault(guard.base(), alloc).is_err());
+        // marking it again is fine.
+        pml4.mark_guard(guard)?;
 
-    // address 0 is mapped
-    trace!("Some = {:?}", pml4.translate(VAddr::from(0)));
-     // second PT entry
-    trace!("Some = {:?}", pml4.translate(VAddr::from(4096)));
-    // second PD entry
-    trace!("Some = {:?}", pml4.translate(VAddr::from(512 * 4096)));
-    // 300th PD entry
-    trace!("Some = {:?}", pml4.translate(VAddr::from(300 * 512 * 4096)));
-    // second PDPT entry
-    trace!("None = {:?}", pml4.translate(VAddr::from(512 * 512 * 4096)));
-    // last mapped byte
-    trace!("Some = {:?}", pml4.translate(VAddr::from(512 * 512 * 4096 - 1)));
+        pml4.map_to_any(guard + 1, WRITABLE | NO_EXECUTE, alloc)?;
+        assert!( pml4.mark_guard(guard + 1).is_err()
+               , "marked mapped page {:?} as a guard page", guard + 1);
+        pml4.unmap(guard + 1, alloc)?;
 
+        // a guard page can't be mapped until it's unmarked
+        assert!(pml4.map_to_any(guard, WRITABLE | NO_EXECUTE, alloc).is_err());
+        assert!(pml4.unmark_guard(guard));
+        assert!(!pml4.unmark_guard(guard));
+        assert!(!pml4.is_guard(guard));
+        pml4.map_to_any(guard, WRITABLE | NO_EXECUTE, alloc)?;
+        pml4.unmap(guard, alloc)?;
+        trace!("marked guard page {:?}", guard);
+    }
 
-    let addr = VAddr::from(42 * 512 * 512 * 4096); // 42th PDPT entry
-    let page = VirtualPage::containing(addr);
-    let frame = unsafe { alloc.allocate().expect("no more frames") };
-    trace!("None = {:?}, map to {:?}",
-             pml4.translate(addr),
-             frame);
-    let _ = pml4.map(page, frame, EntryFlags::empty(), alloc)?;
-    trace!("Some = {:?}", pml4.translate(addr));
-    trace!( "next free frame: {:?}"
-            , unsafe { alloc.allocate() });
+    // a PML4 whose recursive entry doesn't map itself fails the check, as
+    // does the active table while `using` has pointed its recursive entry
+    // at another one.
+    {
+        assert!(pml4.verify_recursive_mapping());
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        {
+            let table = temp.map_to_table(frame, &mut pml4)?;
+            table.zero();
+            table[RECURSIVE_INDEX].set(frame, PRESENT | WRITABLE);
+            assert!(recursive_entry_maps(table, frame));
+            table[RECURSIVE_INDEX].set(frame + 1, PRESENT | WRITABLE);
+            assert!(!recursive_entry_maps(table, frame));
+        }
+        temp.unmap(&mut pml4)?;
 
-    //trace!("{:#x}", *(Page::containing(addr).as_ptr()));
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+        let verified = pml4.using(&mut table, &mut temp, |inactive| {
+            Ok(inactive.verify_recursive_mapping())
+        })?;
+        assert!(!verified, "the switched recursive entry passed the check");
+        assert!(pml4.verify_recursive_mapping());
+        unsafe { alloc.deallocate(frame) };
+        trace!("detected a corrupt recursive entry");
+    }
 
-    let _ = pml4.unmap(Page::containing(addr), alloc)?;
-    trace!("None = {:?}", pml4.translate(addr));
+    // a guarded mapping is unmapped when the guard is dropped, and the frame
+    // it was mapped to isn't deallocated.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(71 * HUGE_PAGE_SIZE as usize));
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        {
+            let region = pml4.map_guarded( page, frame, WRITABLE | NO_EXECUTE
+                                         , alloc)?;
+            assert_eq!(region.page(), page);
+            unsafe { *region.as_mut_ptr::<u64>() = 0xdead_beef };
+        }
+        assert!(!pml4.is_mapped(&page), "{:?} wasn't unmapped", page);
+
+        let region = pml4.map_guarded(page, frame, NO_EXECUTE, alloc)?;
+        assert_eq!(unsafe { *region.as_mut_ptr::<u64>() }, 0xdead_beef);
+        region.unmap()?;
+        assert!(!pml4.is_mapped(&page));
+        unsafe { alloc.deallocate(frame) };
+        trace!("unmapped {:?} when its guard was dropped", page);
+    }
+
+    // anonymous pages are zeroed before they're mapped.
+    {
+        let addr = VAddr::from(70 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(3);
+        let mut temp = test_temp_page(alloc);
+        pml4.map_zeroed_anon( pages.start, WRITABLE | NO_EXECUTE
+                            , &mut temp, alloc)?;
+        let bytes = unsafe {
+            ::core::slice::from_raw_parts( pages.start.base().as_ptr::<u8>()
+                                         , PAGE_SIZE as usize)
+        };
+        assert!( bytes.iter().all(|&b| b == 0)
+               , "{:?} wasn't zeroed", pages.start);
+        // the page is already mapped.
+        assert!(pml4.map_zeroed_anon( pages.start, WRITABLE | NO_EXECUTE
+                                    , &mut temp, alloc).is_err());
+        pml4.unmap(pages.start, alloc)?;
+
+        pml4.map_zeroed_anon_range( pages.clone(), WRITABLE | NO_EXECUTE
+                                  , &mut temp, alloc)?;
+        for page in pages.clone() {
+            let bytes = unsafe {
+                ::core::slice::from_raw_parts( page.base().as_ptr::<u8>()
+                                             , PAGE_SIZE as usize)
+            };
+            assert!(bytes.iter().all(|&b| b == 0), "{:?} wasn't zeroed", page);
+        }
+        pml4.unmap_range(pages.clone(), alloc)?;
+        trace!("mapped zeroed anonymous pages");
+    }
+
+    // split a 2 MiB page, and check that each of its 4KiB pages maps the
+    // right frame.
+    {
+        // the frames are never accessed, they just need to be 2 MiB aligned.
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        let addr = VAddr::from(69 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(N_ENTRIES);
+        pml4.map_huge( pages.start, frame, HugePageSize::Size2M
+                     , WRITABLE | NO_EXECUTE, alloc)?;
+        // a 4KiB page can't be split.
+        let small = pages.start + N_ENTRIES;
+        pml4.map(small, frame, WRITABLE | NO_EXECUTE, alloc)?;
+        assert!(pml4.split_2m(small, alloc).is_err());
+        assert_eq!(pml4.translate_page(small), Some(frame));
+        let _ = pml4.unmap_keep_frames(small, alloc)?;
+
+        let mut counting = CountingAlloc::new(alloc);
+        pml4.split_2m(pages.start + 100, &mut counting)?;
+        // just the new PT
+        assert_eq!(counting.allocated, 1);
+        for (i, page) in pages.clone().enumerate() {
+            assert_eq!(pml4.translate_page(page), Some(frame + i));
+        }
+        assert_eq!( pml4.translate_detailed(addr + 0x1234)
+                  , Some(( frame.base_addr() + 0x1234
+                         , PageSize::Size4K )));
+        // each page can now be write-protected on its own.
+        pml4.update_flags(pages.start + 1, NO_EXECUTE)?;
+        assert_eq!(pml4.translate_page(pages.start + 1), Some(frame + 1));
+        assert!(pml4.leaf_entry(pages.start)
+                    .map_or(false, |entry| entry.flags()
+                                                .contains(WRITABLE)));
+        // the frames weren't allocated, so they mustn't be deallocated.
+        for page in pages.clone() {
+            let _ = pml4.unmap_keep_frames(page, alloc)?;
+        }
+        assert!(!pml4.is_mapped(&pages.start));
+        trace!("split {:?} into 4KiB pages", pages);
+    }
+
+    // 512 pages mapped to an aligned, contiguous run of frames can be
+    // promoted to one 2 MiB page, and the PT that mapped them is freed.
+    {
+        // the frames are never accessed, they just need to be 2 MiB aligned.
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        let addr = VAddr::from(68 * HUGE_PAGE_SIZE as usize);
+        let pages = VirtualPage::containing(addr).range_of(N_ENTRIES);
+        pml4.map_range( pages.clone(), frame.range_of(N_ENTRIES)
+                      , WRITABLE | NO_EXECUTE, alloc)?;
+        // a page with different flags can't be promoted.
+        pml4.update_flags(pages.start + 7, NO_EXECUTE)?;
+        assert_eq!(pml4.try_promote_2m(pages.start, alloc)?, false);
+        pml4.update_flags(pages.start + 7, WRITABLE | NO_EXECUTE)?;
+
+        let mut counting = CountingAlloc::new(alloc);
+        assert!(pml4.try_promote_2m(pages.start + 3, &mut counting)?);
+        assert_eq!(counting.deallocated, 1);
+        assert_eq!( pml4.translate_detailed(addr + 0x1234)
+                  , Some(( frame.base_addr() + 0x1234
+                         , PageSize::Size2M )));
+        // it's already huge, so it can't be promoted again.
+        assert_eq!(pml4.try_promote_2m(pages.start, alloc)?, false);
+        // the frames weren't allocated, so they mustn't be deallocated.
+        let frames = pml4.unmap_keep_frames(pages.start, alloc)?;
+        assert_eq!(frames, frame.range_of(N_ENTRIES));
+        assert!(!pml4.is_mapped(&pages.start));
+        trace!("promoted {:?} to a 2 MiB page", pages);
+    }
+
+    // ELF section flags are turned into page flags one permission at a
+    // time, and writable code is rejected.
+    {
+        use elf::section::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE};
+        // .text is R-X
+        assert_eq!( EntryFlags::from_section_flags(SHF_ALLOC | SHF_EXECINSTR)
+                  , Ok(PRESENT));
+        // .data is RW-
+        assert_eq!( EntryFlags::from_section_flags(SHF_ALLOC | SHF_WRITE)
+                  , Ok(PRESENT | WRITABLE | NO_EXECUTE));
+        // .rodata is R--
+        assert_eq!( EntryFlags::from_section_flags(SHF_ALLOC)
+                  , Ok(PRESENT | NO_EXECUTE));
+        assert!(EntryFlags::from_section_flags( SHF_ALLOC | SHF_WRITE
+                                              | SHF_EXECINSTR ).is_err());
+        assert!(EntryFlags::from_section_flags(SHF_EXECINSTR).is_err());
+    }
+
+    // only the frame in CR3 is active, whichever PCID it's tagged with, and
+    // a table held on to after a switch is no longer current.
+    {
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        let mock_cr3 = || PAddr::from(LARGE_PAGE_SIZE + 42);
+        assert!(cr3::is_active_with(frame, &mock_cr3));
+        assert!(!cr3::is_active_with(frame + 1, &mock_cr3));
+
+        assert!(pml4.is_current());
+        assert!(cr3::is_active(pml4.frame));
+        // a table held on to after a switch has a different frame from the
+        // one in CR3.
+        assert!(!cr3::is_active(pml4.frame + 1));
+    }
+
+    // a frame mapped at two pages is only freed once both are unmapped.
+    {
+        use alloc::{FrameRefCount, RefCounted};
+        use core::{mem, slice};
+
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        // enough pages to hold a count for every frame up to `frame`
+        let n_counts = frame.number as usize + 1;
+        let count_pages =
+            VirtualPage::containing(VAddr::from(66 * HUGE_PAGE_SIZE as usize))
+                .range_of(VirtualPage::pages_for_bytes(
+                    n_counts * mem::size_of::<u32>()));
+        for page in count_pages.clone() {
+            pml4.map_to_any(page, WRITABLE | NO_EXECUTE, alloc)?;
+        }
+        {
+            let counts = unsafe {
+                slice::from_raw_parts_mut( count_pages.start.base()
+                                                      .as_mut_ptr::<u32>()
+                                         , n_counts)
+            };
+            let counting = CountingAlloc::new(alloc);
+            let mut shared =
+                RefCounted::new(counting, FrameRefCount::new(counts));
+            // one reference for each page the frame is mapped at
+            for _ in 0..2 {
+                unsafe { shared.add_ref(frame) }
+                    .expect("couldn't count a reference to the frame");
+            }
+
+            let page = VirtualPage::containing(
+                VAddr::from(67 * HUGE_PAGE_SIZE as usize));
+            pml4.map(page, frame, WRITABLE, &mut shared)?;
+            pml4.map(page + 1, frame, WRITABLE, &mut shared)?;
+
+            pml4.unmap(page, &mut shared)?;
+            assert_eq!(shared.ref_count(frame), Some(1));
+            assert_eq!(shared.inner().deallocated, 0);
+            assert_eq!(pml4.translate_page(page + 1), Some(frame));
+
+            // the frame is freed along with the page tables it needed.
+            pml4.unmap(page + 1, &mut shared)?;
+            assert_eq!(shared.ref_count(frame), Some(0));
+            assert_eq!( shared.inner().deallocated
+                      , shared.inner().allocated + 1);
+            trace!("freed shared {:?} once it was unmapped twice", frame);
+        }
+        pml4.unmap_range(count_pages, alloc)?;
+    }
+
+    // write-protect a range for copy-on-write, skipping the unmapped page
+    // in the middle of it.
+    {
+        let start =
+            VirtualPage::containing(VAddr::from(65 * HUGE_PAGE_SIZE as usize));
+        let pages = start.range_of(5);
+        let hole = start + 2;
+        for page in pages.clone().filter(|&page| page != hole) {
+            pml4.map_to_any(page, WRITABLE | NO_EXECUTE, alloc)?;
+        }
+        assert_eq!(pml4.protect_range(pages.clone(), NO_EXECUTE)?, 4);
+        for page in pages.clone().filter(|&page| page != hole) {
+            let flags = pml4.leaf_entry(page)
+                            .expect("protected page was unmapped")
+                            .flags();
+            assert!( !flags.contains(WRITABLE)
+                   , "{:?} is still writable", page);
+            assert!(flags.contains(PRESENT | NO_EXECUTE));
+        }
+        assert!(!pml4.is_mapped(&hole));
+        for page in pages.filter(|&page| page != hole) {
+            pml4.unmap(page, alloc)?;
+        }
+
+        // a global, write-combining page keeps both, and a huge page only
+        // counts once, however many of its pages are in the range.
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map(start, frame, EntryFlags::write_combining() | GLOBAL, alloc)?;
+        assert_eq!(pml4.protect_range(start.range_of(1), NO_EXECUTE)?, 1);
+        assert_eq!( pml4.leaf_entry(start).map(|entry| entry.flags())
+                  , Some(PRESENT | NO_EXECUTE | table::PAT | GLOBAL));
+        pml4.unmap_keep_frames(start, alloc)?;
+
+        let huge = start + N_ENTRIES;
+        pml4.map_huge(huge, frame, HugePageSize::Size2M, WRITABLE, alloc)?;
+        let range = huge + 1 .. huge + N_ENTRIES + 1;
+        assert_eq!(pml4.protect_range(range, NO_EXECUTE)?, 1);
+        assert_eq!( pml4.leaf_entry(huge).map(|entry| entry.flags())
+                  , Some(PRESENT | NO_EXECUTE | HUGE_PAGE));
+        pml4.unmap_keep_frames(huge, alloc)?;
+    }
+
+    // the active page table can only be taken once; `kernel_remap` will
+    // usually have taken it already.
+    {
+        let _first = ActivePageTable::take();
+        assert!(ActivePageTable::take().is_none());
+        assert!(ActivePageTable::take().is_none());
+    }
+
+    // clear some of a table's entries, and check that the others are left
+    // alone.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(63 * HUGE_PAGE_SIZE as usize));
+        pml4.map_to_any(page, WRITABLE, alloc)?;
+        let table: &mut Table<PTLevel> =
+            unsafe { &mut *page.base().as_mut_ptr() };
+        table.zero();
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        for i in 0..8 {
+            table[i].set(frame + i, PRESENT);
+        }
+        table.clear_range(2, 5);
+        for i in 0..8 {
+            if i >= 2 && i < 5 {
+                assert!(table[i].is_unused(), "entry {} wasn't cleared", i);
+            } else {
+                assert_eq!(table[i].get_frame(), Some(frame + i));
+            }
+        }
+        assert!(!table.is_empty());
+        table.clear_range(0, N_ENTRIES);
+        assert!(table.is_empty());
+        pml4.unmap(page, alloc)?;
+    }
+
+    // a huge PDPT entry whose frame isn't aligned to 1 GiB can't be
+    // translated, rather than panicking.
+    {
+        let vaddr = VAddr::from(62 * HUGE_PAGE_SIZE as usize);
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        {
+            let pdpt = pml4.pml4_mut().next_table_mut(vaddr)
+                           .expect("no PDPT for the first 512 GiB");
+            assert!(pdpt[vaddr].is_unused());
+            pdpt[vaddr].set(frame, PRESENT | WRITABLE | HUGE_PAGE);
+        }
+        assert_eq!(pml4.translate(vaddr), None);
+        assert_eq!(pml4.translate_page(VirtualPage::containing(vaddr)), None);
+        pml4.pml4_mut().next_table_mut(vaddr)
+            .expect("no PDPT for the first 512 GiB")[vaddr]
+            .set_unused();
+        trace!("misaligned huge page at {:?} was not translated", vaddr);
+    }
+
+    // map an MMIO page, and check that it's uncacheable.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(52 * HUGE_PAGE_SIZE as usize));
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        pml4.map(page, frame, EntryFlags::mmio(), alloc)?;
+        let bits = pml4.walk(page.base()).pt.expect("MMIO page not mapped")
+                       .as_u64();
+        assert!(bits & (1 << 4) != 0, "PCD not set in {:#x}", bits);
+        assert!(bits & (1 << 3) != 0, "PWT not set in {:#x}", bits);
+        trace!("mapped MMIO {:?} with entry {:#x}", page, bits);
+        pml4.unmap_keep_frames(page, alloc)?;
+    }
+
+    // check that every kind of `MapErr` formats readably.
+    {
+        use core::fmt::Write;
+        let page = VirtualPage::containing(VAddr::from(0xdead000));
+        let frame = PhysicalPage::containing(PAddr::from(0x1000));
+        let request = ::alloc::Layout::from_size_align(4096, 4096);
+        let cases: [(MapErr, &str); 8] = [
+            ( MapErr::AlreadyInUse { message: "map frame"
+                                   , page: page, frame: frame }
+            , "cannot map frame 0x1000 to page 0xdead000: already in use" )
+          , ( MapErr::Alloc { message: "allocate a page table", page: page
+                            , cause: ::alloc::AllocErr::OutOfMemory {
+                                  request: request } }
+            , "cannot allocate a page table for page 0xdead000: out of \
+               memory (4096 bytes aligned to 4096 requested)" )
+          , ( MapErr::NoPage { message: "map region"
+                             , cause: "no free region was large enough" }
+            , "cannot map region: no free region was large enough" )
+          , ( MapErr::Other { message: "update flags", page: page
+                            , cause: "not mapped" }
+            , "cannot update flags at page 0xdead000: not mapped" )
+          , ( MapErr::TableNotFound { message: "unmap", page: page
+                                    , what: "PT" }
+            , "cannot unmap page 0xdead000: no PT table" )
+          , ( MapErr::NonCanonical {
+                  addr: VAddr::from(0x0000_8000_0000_0000) }
+            , "0x800000000000 is not a canonical address" )
+          , ( MapErr::Overlaps { message: "reserve pages", page: page }
+            , "cannot reserve pages at page 0xdead000: it overlaps an \
+               existing region" )
+          , ( MapErr::Capacity { message: "reserve pages", max: 16 }
+            , "cannot reserve pages: all 16 slots are in use" )
+        ];
+        for &(ref err, expected) in cases.iter() {
+            let mut buf = FmtBuf::new();
+            let _ = write!(buf, "{}", err);
+            assert_eq!(buf.as_str(), expected);
+        }
+        // errors about frames format too
+        let mut buf = FmtBuf::new();
+        let err: MapErr<PhysicalPage> =
+            MapErr::NoPage { message: "zero frame", cause: "no temp page" };
+        let _ = write!(buf, "{}", err);
+        assert_eq!(buf.as_str(), "cannot zero frame: no temp page");
+        trace!("MapErr formats as expected");
+    }
+
+    // tag the current table with a PCID, check that both the frame and the
+    // PCID read back out of CR3, and then switch back to PCID 0 and put
+    // CR4.PCIDE back how it was.
+    #[cfg(feature = "pcid")]
+    unsafe {
+        use cpu::control_regs::cr4;
+        let frame = cr3::current_pagetable_frame();
+        let was_enabled = cr4::is_pcid_enabled();
+        match cr3::set_pagetable_frame_pcid(frame, 42, false) {
+            Ok(()) => {
+                assert_eq!(cr3::current_pagetable_frame(), frame);
+                assert_eq!(cr3::current_pcid(), 42);
+                cr3::set_pagetable_frame_pcid(frame, 0, false)
+                    .expect("couldn't switch back to PCID 0");
+                // PCIDE can only be cleared while the PCID in CR3 is zero.
+                cr4::enable_pcid(was_enabled);
+                trace!("round-tripped {:?} with PCID 42 through CR3", frame);
+            }
+          , Err(why) => warn!("skipping PCID test: {}", why)
+        }
+    }
+
+    // translate_detailed reports the size of the page mapping an address
+    {
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+
+        let addr = VAddr::from(57 * HUGE_PAGE_SIZE as usize);
+        let page = VirtualPage::containing(addr);
+        pml4.map(page, frame, WRITABLE, alloc)?;
+        assert_eq!( pml4.translate_detailed(addr + 0x123)
+                  , Some((frame.base_addr() + 0x123, PageSize::Size4K)));
+        assert!(pml4.translate_detailed(addr + PAGE_SIZE as usize).is_none());
+        pml4.unmap_keep_frames(page, alloc)?;
+
+        pml4.map_huge(page, frame, HugePageSize::Size2M, WRITABLE, alloc)?;
+        let offset = LARGE_PAGE_SIZE - 0x10;
+        assert_eq!( pml4.translate_detailed(addr + offset as usize)
+                  , Some((frame.base_addr() + offset, PageSize::Size2M)));
+        pml4.unmap_keep_frames(page, alloc)?;
+
+        let frame = PhysicalPage::containing(PAddr::from(HUGE_PAGE_SIZE));
+        pml4.map_huge(page, frame, HugePageSize::Size1G, WRITABLE, alloc)?;
+        let offset = HUGE_PAGE_SIZE / 2 + 0x456;
+        assert_eq!( pml4.translate_detailed(addr + offset as usize)
+                  , Some((frame.base_addr() + offset, PageSize::Size1G)));
+        pml4.unmap_keep_frames(page, alloc)?;
+        assert!(pml4.translate_detailed(addr).is_none());
+        assert_eq!(PageSize::from(HugePageSize::Size1G), PageSize::Size1G);
+    }
+
+    // page numbers and addresses agree on PML5 indices
+    #[cfg(feature = "la57")]
+    {
+        let addr = VAddr::from(0x0123_4567_89ab_c000);
+        assert_eq!(PML5Level::index_of(addr), addr.pml5_index());
+        assert_eq!(PML5Level::index_of(addr), 0x123);
+        assert_eq!( PML5Level::index_of(VirtualPage::containing(addr))
+                  , addr.pml5_index());
+        assert_eq!(PML4Level::index_of(addr), addr.pml4_index());
+        let levels = paging_levels();
+        assert!(levels == 4 || levels == 5);
+        kinfoln!(dots: " . . ", "Using {}-level paging", levels);
+    }
+
+    // shootdowns are passed on to the registered IPI handler
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(56 * HUGE_PAGE_SIZE as usize));
+        let pages = [page, page + 1];
+        tlb::set_ipi_handler(record_shootdown);
+        unsafe { tlb::shootdown(&pages) };
+        {
+            let record = SHOOTDOWNS.lock();
+            assert_eq!(record.n_pages, 2);
+            assert_eq!(record.first, Some(page));
+            assert_eq!(record.n_flush_all, 0);
+        }
+
+        // too many pages to invalidate one at a time
+        let many = [page; tlb::FLUSH_THRESHOLD + 1];
+        unsafe { tlb::shootdown(&many) };
+        assert_eq!(SHOOTDOWNS.lock().n_flush_all, 1);
+
+        // without a handler, only the current CPU is flushed
+        assert!(tlb::clear_ipi_handler().is_some());
+        unsafe { tlb::shootdown(&pages) };
+        let record = SHOOTDOWNS.lock();
+        assert_eq!(record.n_pages, 2);
+        assert_eq!(record.n_flush_all, 1);
+    }
+
+    // ranges up to the threshold are invalidated a page at a time, and
+    // anything longer flushes the whole TLB.
+    {
+        let page =
+            VirtualPage::containing(VAddr::from(59 * HUGE_PAGE_SIZE as usize));
+        let mut n_invlpgs = 0;
+        let mut n_flush_all = 0;
+        tlb::flush_range_with( page.range_of(tlb::FLUSH_THRESHOLD)
+                             , |_| n_invlpgs += 1
+                             , || n_flush_all += 1 );
+        assert_eq!(n_invlpgs, tlb::FLUSH_THRESHOLD);
+        assert_eq!(n_flush_all, 0);
+
+        n_invlpgs = 0;
+        tlb::flush_range_with( page.range_of(tlb::FLUSH_THRESHOLD + 1)
+                             , |_| n_invlpgs += 1
+                             , || n_flush_all += 1 );
+        assert_eq!(n_invlpgs, 0);
+        assert_eq!(n_flush_all, 1);
+
+        // the real thing should also work on long ranges.
+        let pages = page.range_of(tlb::FLUSH_THRESHOLD + 1);
+        for page in pages.clone() {
+            pml4.map_to_any(page, WRITABLE, alloc)?;
+        }
+
+        // and evict global pages in them, which a CR3 reload wouldn't.
+        {
+            use core::ptr;
+            let (a, b) = (pages.start, pages.start + 1);
+            let frame_a = pml4.translate_page(a).expect("page not mapped");
+            let frame_b = pml4.translate_page(b).expect("page not mapped");
+            unsafe {
+                tlb::enable_global_pages();
+                ptr::write_volatile(a.base().as_mut_ptr::<u8>(), 1);
+                ptr::write_volatile(b.base().as_mut_ptr::<u8>(), 2);
+            }
+            let ptr = a.base().as_ptr::<u8>();
+            pml4.update_flags(a, WRITABLE | GLOBAL)?;
+            assert_eq!(unsafe { ptr::read_volatile(ptr) }, 1);
+            // repoint `a` without invalidating it
+            pml4.leaf_entry_mut(a).expect("page not mapped")
+                .set(frame_b, PRESENT | WRITABLE | GLOBAL);
+            pml4.flush_range(pages.clone());
+            assert_eq!( unsafe { ptr::read_volatile(ptr) }, 2
+                      , "a global page survived flushing a long range");
+            pml4.remap_to(a, frame_a, WRITABLE, alloc)?;
+        }
+        pml4.unmap_range(pages.clone(), alloc)?;
+        assert!(pages.clone().all(|page| !pml4.is_mapped(&page)));
+    }
+
+    // once there are more page tables than the flush threshold, the
+    // recursive mappings are flushed along with the rest of the TLB, rather
+    // than a table at a time.
+    {
+        let start =
+            VirtualPage::containing(VAddr::from(80 * HUGE_PAGE_SIZE as usize));
+        // the frame is never accessed.
+        let frame = PhysicalPage::containing(PAddr::from(LARGE_PAGE_SIZE));
+        // each page is in a PT of its own
+        for i in 0..(tlb::FLUSH_THRESHOLD + 1) {
+            pml4.map(start + i * N_ENTRIES, frame, PRESENT, alloc)?;
+        }
+        assert!(count_tables(pml4.pml4()) > tlb::FLUSH_THRESHOLD);
+        assert_eq!(unsafe { flush_recursive(pml4.pml4()) }, 0);
+        for i in 0..(tlb::FLUSH_THRESHOLD + 1) {
+            pml4.unmap_keep_frames(start + i * N_ENTRIES, alloc)?;
+        }
+        trace!("flushed the whole TLB for more than {} tables"
+              , tlb::FLUSH_THRESHOLD);
+    }
+
+    // compare the cost of switching to an inactive table with `using`
+    // against flushing the whole TLB the same number of times.
+    #[cfg(feature = "bench")]
+    {
+        use cpu::timer::timestamp::rtdsc;
+        const N_SWITCHES: u64 = 1000;
+
+        let mut temp = test_temp_page(alloc);
+        let frame = unsafe { alloc.allocate() }.expect("no more frames");
+        let mut table = InactivePageTable::new(frame, &mut pml4, &mut temp)?;
+        let n_tables = count_tables(pml4.pml4()) + 1;
+
+        let start = unsafe { rtdsc() };
+        for _ in 0..N_SWITCHES {
+            pml4.using(&mut table, &mut temp, |_| Ok(()))?;
+        }
+        let using_cycles = unsafe { rtdsc() } - start;
+
+        let start = unsafe { rtdsc() };
+        for _ in 0..(2 * N_SWITCHES) {
+            unsafe { tlb::flush_all() };
+        }
+        let flush_cycles = unsafe { rtdsc() } - start;
+
+        info!( "bench: using took {} cycles, with {} tables"
+             , using_cycles / N_SWITCHES, n_tables);
+        info!( "bench: two full TLB flushes took {} cycles"
+             , flush_cycles / N_SWITCHES);
+        table.free(&mut pml4, &mut temp, alloc)?;
+    }
+    Ok(())
+
+}
+
+/// A PML4 entry outside the `KERNEL_PML4_ENTRIES`, for tests that map pages
+/// in inactive tables, so that the pages aren't mapped in the active table.
+/// The next entry is free too.
+const TEST_PML4_ENTRY: usize = 2;
+
+/// Allocate `n` physically contiguous frames, one at a time.
+///
+/// The boot allocator can't allocate ranges, but hands out frames in order,
+/// so frames are allocated until `n` in a row are consecutive. The frames
+/// that didn't fit in the run are deallocated again.
+unsafe fn allocate_run<A>(alloc: &mut A, n: usize) -> AllocResult<FrameRange>
+where A: FrameAllocator {
+    let first = alloc.allocate()?;
+    let mut run = first .. first + 1;
+    while run.length() < n {
+        let frame = match alloc.allocate() {
+            Ok(frame) => frame
+          , Err(err) => { alloc.deallocate_range(run); return Err(err) }
+        };
+        if frame != run.end {
+            alloc.deallocate_range(run);
+            run = frame .. frame;
+        }
+        run.end = frame + 1;
+    }
+    Ok(run)
+}
+
+/// A fixed-size buffer that strings can be formatted into, so that
+/// formatting can be tested without a heap.
+struct FmtBuf { buf: [u8; 128]
+              , len: usize
+              }
+
+impl FmtBuf {
+    fn new() -> Self { FmtBuf { buf: [0; 128], len: 0 } }
+
+    fn as_str(&self) -> &str {
+        ::core::str::from_utf8(&self.buf[..self.len])
+            .expect("formatted invalid UTF-8")
+    }
+}
+
+impl fmt::Write for FmtBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() { return Err(fmt::Error) }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A `FrameAllocator` that counts how many frames are taken from and
+/// returned to it.
+struct CountingAlloc<'a, A>
+where A: FrameAllocator
+    , A: 'a { alloc: &'a mut A
+            , allocated: usize
+            , deallocated: usize
+            , contiguous: usize
+            }
+
+impl<'a, A> CountingAlloc<'a, A>
+where A: FrameAllocator
+    , A: 'a {
+
+    /// Wrap `alloc`, with every count starting at zero.
+    fn new(alloc: &'a mut A) -> Self {
+        CountingAlloc { alloc: alloc
+                      , allocated: 0
+                      , deallocated: 0
+                      , contiguous: 0 }
+    }
+}
+
+/// Returns the temporary page the tests edit inactive tables through.
+fn test_temp_page<A>(alloc: &mut A) -> TempPage
+where A: FrameAllocator {
+    TempPage::new(TEMP_PAGE_NUMBER, alloc)
+}
+
+impl<'a, A> FrameAllocator for CountingAlloc<'a, A>
+where A: FrameAllocator
+    , A: 'a {
+
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        let frame = self.alloc.allocate()?;
+        self.allocated += 1;
+        Ok(frame)
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        self.deallocated += 1;
+        self.alloc.deallocate(frame)
+    }
+
+    unsafe fn allocate_near(&mut self, hint: PhysicalPage)
+                           -> AllocResult<PhysicalPage> {
+        let frame = self.alloc.allocate_near(hint)?;
+        self.allocated += 1;
+        Ok(frame)
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        let range = self.alloc.allocate_range(num)?;
+        self.allocated += range.length();
+        Ok(range)
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        self.deallocated += range.length();
+        self.alloc.deallocate_range(range)
+    }
+
+    unsafe fn deallocate_contiguous(&mut self, start: PhysicalPage
+                                   , count: usize) {
+        self.contiguous += 1;
+        self.deallocated += count;
+        self.alloc.deallocate_contiguous(start, count)
+    }
+
+    unsafe fn add_ref(&mut self, frame: PhysicalPage) -> AllocResult<()> {
+        self.alloc.add_ref(frame)
+    }
+
+    unsafe fn remove_ref(&mut self, frame: PhysicalPage) -> usize {
+        self.alloc.remove_ref(frame)
+    }
+
+    fn ref_count(&self, frame: PhysicalPage) -> Option<usize> {
+        self.alloc.ref_count(frame)
+    }
+}
+
+/// A `FrameAllocator` that counts the references to a single shared frame,
+/// and lets every other frame be shared without tracking it.
+struct SharingAlloc<'a, A>
+where A: FrameAllocator
+    , A: 'a { alloc: &'a mut A
+            , frame: PhysicalPage
+            , refs: usize
+            }
+
+impl<'a, A> FrameAllocator for SharingAlloc<'a, A>
+where A: FrameAllocator
+    , A: 'a {
+
+    unsafe fn allocate(&mut self) -> AllocResult<PhysicalPage> {
+        self.alloc.allocate()
+    }
+
+    unsafe fn deallocate(&mut self, frame: PhysicalPage) {
+        self.alloc.deallocate(frame)
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        self.alloc.allocate_range(num)
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        self.alloc.deallocate_range(range)
+    }
+
+    unsafe fn add_ref(&mut self, frame: PhysicalPage) -> AllocResult<()> {
+        if frame == self.frame { self.refs += 1 }
+        Ok(())
+    }
+
+    unsafe fn remove_ref(&mut self, frame: PhysicalPage) -> usize {
+        if frame == self.frame {
+            self.refs -= 1;
+            self.refs
+        } else {
+            0
+        }
+    }
+
+    fn ref_count(&self, frame: PhysicalPage) -> Option<usize> {
+        if frame == self.frame { Some(self.refs) } else { None }
+    }
+}
+
+/// The shootdown requests seen by `record_shootdown`.
+struct ShootdownRecord { n_pages: usize
+                       , first: Option<VirtualPage>
+                       , n_flush_all: usize
+                       }
+
+static SHOOTDOWNS: ::spin::Mutex<ShootdownRecord>
+    = ::spin::Mutex::new(ShootdownRecord { n_pages: 0
+                                         , first: None
+                                         , n_flush_all: 0
+                                         });
+
+/// A mock IPI handler which records the shootdowns it's asked to send.
+fn record_shootdown(request: tlb::Shootdown) {
+    let mut record = SHOOTDOWNS.lock();
+    match request {
+        tlb::Shootdown::Pages(pages) => {
+            if record.first.is_none() {
+                record.first = pages.first().cloned();
+            }
+            record.n_pages += pages.len();
+        }
+      , tlb::Shootdown::All => record.n_flush_all += 1
+    }
+}
+
+/// Returns true if the CPU supports 1 GiB pages.
+///
+/// This is reported by bit 26 of `%edx` in `CPUID` leaf `0x80000001`.
+fn is_1g_pages_supported() -> bool {
+    cpuid(0x8000_0001, 0).edx & (1 << 26) != 0
+}
+
+/// Maps all physical memory below `phys_end` at the fixed virtual `offset`,
+/// so that any frame can be accessed at `PAddr::to_virt(offset)`.
+///
+/// 1 GiB pages are used where the CPU supports them and the remaining memory
+/// fills a whole one, and 2 MiB pages are used otherwise. The window is
+/// writable but not executable.
+///
+/// # Arguments
+/// + `pml4`: the page table to map the window in
+/// + `offset`: the virtual address physical address 0 is mapped to. This
+///             must be aligned to 2 MiB.
+/// + `phys_end`: the end of physical memory. This is rounded up to 2 MiB.
+/// + `alloc`: a `FrameAllocator` for allocating page tables
+pub fn map_physical_memory<A>( pml4: &mut ActivePML4
+                             , offset: VAddr
+                             , phys_end: PAddr
+                             , alloc: &mut A)
+                             -> MapResult<()>
+where A: FrameAllocator {
+    if !offset.is_aligned(LARGE_PAGE_SIZE as usize) {
+        return Err(MapErr::Other {
+            message: "map physical memory"
+          , page: VirtualPage::containing(offset)
+          , cause: "the offset was not aligned to 2 MiB"
+        })
+    }
+    let use_1g = is_1g_pages_supported()
+              && offset.is_aligned(HUGE_PAGE_SIZE as usize);
+    let end = *phys_end.align_up(LARGE_PAGE_SIZE);
+    let flags = WRITABLE | NO_EXECUTE;
+    trace!( "mapping physical memory up to {:#x} at {:?} (1 GiB pages: {})"
+          , end, offset, use_1g);
+
+    let mut paddr = PAddr::from(0);
+    while *paddr < end {
+        let page = VirtualPage::containing(paddr.to_virt(offset));
+        let frame = PhysicalPage::containing(paddr);
+        let size = if use_1g && paddr.is_aligned(HUGE_PAGE_SIZE)
+                             && end - *paddr >= HUGE_PAGE_SIZE {
+            HugePageSize::Size1G
+        } else {
+            HugePageSize::Size2M
+        };
+        pml4.map_huge(page, frame, size, flags, alloc)?;
+        paddr = paddr + size.bytes();
+    }
     Ok(())
+}
+
+/// Options for `kernel_remap_opts`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RemapOptions { /// Log every frame that's mapped, rather than just
+                          /// each section. This is off by default, since a
+                          /// large kernel floods the console.
+                          pub verbose: bool
+                        }
+
+/// Forwards each line written to it to `trace!`.
+struct TraceLines;
+
+impl fmt::Write for TraceLines {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        trace!("{}", s);
+        Ok(())
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        trace!("{}", args);
+        Ok(())
+    }
+}
 
+/// Identity map `frames`, merging `flags` into those of frames that are
+/// already mapped, and write a line to `log` for each frame if `opts` is
+/// verbose.
+fn identity_map_logged<A, W>( pml4: &mut ActivePML4
+                            , frames: FrameRange
+                            , flags: EntryFlags
+                            , opts: RemapOptions
+                            , log: &mut W
+                            , alloc: &mut A)
+                            -> MapResult<()>
+where A: FrameAllocator
+    , W: fmt::Write {
+    for frame in frames {
+        let page =
+            VirtualPage::containing(VAddr::from(*frame.base_addr() as usize));
+        // sections may share a frame, so it needs the permissions of both.
+        pml4.map_or_merge(page, frame, flags, alloc)?;
+        if opts.verbose {
+            let _ = write!(log, "identity mapped {:?} with {:?}", frame, flags);
+        }
+    }
+    Ok(())
 }
 
 /// Remaps the kernel using 4KiB pages.
+///
+/// This is `kernel_remap_opts` with the default, quiet, `RemapOptions`.
+#[inline]
 pub fn kernel_remap<A>(params: &InitParams, alloc: &mut A)
                        -> MapResult<ActivePageTable>
+where A: FrameAllocator {
+    kernel_remap_opts(params, RemapOptions::default(), alloc)
+}
+
+/// Remaps the kernel using 4KiB pages, logging as `opts` asks.
+///
+/// Each section is always reported; every frame mapped is only logged if
+/// `opts.verbose` is set.
+pub fn kernel_remap_opts<A>( params: &InitParams
+                           , opts: RemapOptions
+                           , alloc: &mut A)
+                           -> MapResult<ActivePageTable>
 where A: FrameAllocator {
     use elf::Section;
+    let stats_before = alloc.stats();
+
+    // check that there are enough free frames before changing anything.
+    // the temporary page caches three frames for its own page tables.
+    let plan = kernel_remap_plan(params);
+    let needed = plan.tables + 3;
+    kinfoln!( dots: " . . ", "Remapping {} pages needs {} frames."
+            , plan.pages, needed);
+    if !stats_before.is_unknown() && stats_before.free < needed {
+        return Err(MapErr::NoPage {
+            message: "remap the kernel"
+          , cause: "the frame allocator doesn't have enough free frames"
+        })
+    }
+
     // create a  temporary page for switching page tables
-    // page number chosen fairly arbitrarily.
-    const TEMP_PAGE_NUMBER: usize = 0xfacade;
     let mut temp_page = TempPage::new(TEMP_PAGE_NUMBER, alloc);
     trace!("Created temporary page.");
 
+    // the no-execute bit must be enabled before any of the new page table's
+    // entries can have it set.
+    unsafe {
+        // this is safe because we're in kernel mode
+        ::cpu::msr::enable_nxe();
+    }
+    kinfoln!(dots: " . . ", "Page no execute bit ENABLED");
+
+    // the VGA buffer is mapped write-combining.
+    unsafe {
+        // this is safe because we're in kernel mode
+        ::cpu::msr::enable_write_combining();
+    }
+
+    // the kernel is mapped the same way in every address space, so its
+    // pages can be global.
+    unsafe {
+        // this is safe because we're in kernel mode
+        tlb::enable_global_pages();
+    }
+    kinfoln!(dots: " . . ", "Global pages ENABLED");
+
     // old and new page tables
-    let mut current_table = unsafe { ActivePageTable::new() };
+    let mut current_table = ActivePageTable::take()
+        .ok_or(MapErr::NoPage {
+            message: "remap the kernel"
+          , cause: "the active page table was already taken"
+        })?;
     trace!("Got current page table.");
 
+    // the bootstrap table's kernel entries mustn't be shared with the new
+    // table, since they're what's being replaced.
     let mut new_table = unsafe {
-        InactivePageTable::new(
+        InactivePageTable::new_empty(
              alloc.allocate()
                   .map_err(|err| MapErr::Alloc {
                       message: "create the new page table"
@@ -395,6 +4385,14 @@ where A: FrameAllocator {
 
     // actually remap the kernel --------------------------------------------
     current_table.using(&mut new_table, &mut temp_page, |pml4| {
+        // create the kernel's PDPTs up front, so that every table created
+        // from this one shares all of them.
+        for &i in KERNEL_PML4_ENTRIES.iter() {
+            let page = VirtualPage { number: i << 27 };
+            pml4.pml4_mut().create_next(page, alloc)?;
+        }
+        trace!("created the kernel's PDPTs");
+
         // extract allocated ELF sections
         let sections
             = params.elf_sections()
@@ -404,20 +4402,30 @@ where A: FrameAllocator {
 
         for section in sections { // remap ELF sections
             attempt!(
-                if section.address().is_page_aligned() {
-                    let flags = EntryFlags::from(section);
-
-                    let start_frame = PhysicalPage::from(section.address());
-                    let end_frame = PhysicalPage::from(section.end_address());
+                // a writable, executable section is refused outright,
+                // rather than mapped with weaker permissions.
+                match EntryFlags::from_elf(section) {
+                    Ok(flags) => {
+                        let flags = flags | GLOBAL;
+                        // a section that doesn't start on a frame boundary
+                        // may spill into one more frame than its length
+                        // alone needs.
+                        let (start_frame, offset) =
+                            section.address().frame_offset_pair();
+                        let n_frames = PhysicalPage::frames_for_bytes(
+                            offset as usize + section.length());
 
-                    for frame in start_frame .. end_frame {
-                        let _ = pml4.identity_map(frame, flags, alloc)?;
+                        // sections may share a frame, so the same frame
+                        // can be mapped more than once, with the flags of
+                        // every section that's in it.
+                        identity_map_logged( pml4
+                                           , start_frame.range_of(n_frames)
+                                           , flags, opts
+                                           , &mut TraceLines, alloc)
                     }
-                    Ok(())
-                } else {
-                    Err(MapErr::NoPage::<VirtualPage> {
+                  , Err(why) => Err(MapErr::NoPage::<VirtualPage> {
                         message: "identity map section"
-                      , cause: "the start address was not page aligned"
+                      , cause: why
                     })
                 } =>
                       dots: " . . . ",
@@ -426,7 +4434,9 @@ where A: FrameAllocator {
 
         // remap VGA buffer
         let vga_buffer_frame = PhysicalPage::containing(PAddr::from(0xb8000));
-        attempt!( pml4.identity_map(vga_buffer_frame, WRITABLE, alloc) =>
+        attempt!( pml4.identity_map( vga_buffer_frame
+                                   , EntryFlags::write_combining()
+                                   , alloc) =>
                   dots: " . . ", "Identity mapping VGA buffer" );
 
 
@@ -435,22 +4445,42 @@ where A: FrameAllocator {
         let multiboot_start = PhysicalPage::from(params.multiboot_start());
         let multiboot_end = PhysicalPage::from(params.multiboot_end());
 
-        for frame in multiboot_start .. multiboot_end {
-            let _ = pml4.identity_map(frame, PRESENT, alloc)?;
-                // .expect("couldn't identity map Multiboot {:?}", frame);
-        }
-        Ok(())
+        pml4.identity_map_range( multiboot_start .. multiboot_end
+                               , PRESENT, alloc)
     })?;
 
     trace!("replacing old page table with new page table");
     // switch page tables ---------------------------------------------------
-    let old_table = current_table.replace_with(new_table);
+    let old_table = current_table.replace_with(new_table, &mut temp_page)?;
     kinfoln!(dots: " . . ", "Successfully switched to remapped page table!");
 
     // create guard page at the location of the old PML4 table
     let old_pml4_vaddr = VAddr::from(*(old_table.pml4_frame.base()) as usize);
     let old_pml4_page  = VirtualPage::containing(old_pml4_vaddr);
+
+    // the old table's PDPTs, PDs, and PTs aren't used any more, so they're
+    // given to `alloc`. its PML4 is kept as the guard page.
+    let n_reclaimed = old_table.reclaim_bootstrap_tables( &mut current_table
+                                                        , &mut temp_page
+                                                        , alloc)?;
+    kinfoln!( dots: " . . ", "Reclaimed {} bootstrap page tables."
+            , n_reclaimed);
+
     let _ = current_table.unmap(old_pml4_page, alloc)?;
     trace!("Unmapped guard page at {:?}", old_pml4_page.base());
+
+    for pages in current_table.audit_wx() {
+        warn!("{} is both writable and executable", pages.display());
+    }
+
+    let stats = alloc.stats();
+    if !stats.is_unknown() {
+        kinfoln!( dots: " . . ", "Remapping used {} frames."
+                , stats.used - stats_before.used);
+    }
+    stats.dump();
+    // keep the temporary page, so later edits to inactive tables can reuse
+    // its frames.
+    current_table.temp = Some(temp_page);
     Ok(current_table)
 }
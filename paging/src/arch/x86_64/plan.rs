@@ -0,0 +1,111 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Working out how many frames remapping the kernel will take.
+use core::cmp::{max, min};
+
+use memory::{FrameRange, PAddr, Page, PhysicalPage};
+use params::InitParams;
+
+/// The frames `kernel_remap` needs, worked out without mapping anything.
+///
+/// The kernel is identity mapped, so the mapped pages don't need any new
+/// frames; only the page tables that map them do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RemapPlan { /// The number of pages that are mapped.
+                       pub pages: usize
+                     , /// The number of page tables needed to map them,
+                       /// including the PML4.
+                       pub tables: usize
+                     }
+
+/// Returns the number of distinct page numbers shifted right by `shift` in
+/// the ranges passed to the callback by `for_each_range`.
+///
+/// The ranges may overlap, and nothing is allocated, so the ranges are
+/// walked again for each number counted.
+fn count_distinct<F>(for_each_range: &F, shift: usize) -> usize
+where F: Fn(&mut FnMut(usize, usize)) {
+    let mut count = 0;
+    let mut counted: Option<usize> = None;
+    loop {
+        // the lowest number after the last one counted
+        let mut next: Option<usize> = None;
+        for_each_range(&mut |start, end| {
+            if start >= end { return }
+            let (first, last) = (start >> shift, (end - 1) >> shift);
+            let candidate = match counted {
+                None => Some(first)
+              , Some(counted) if last > counted =>
+                    Some(max(first, counted + 1))
+              , Some(_) => None
+            };
+            if let Some(candidate) = candidate {
+                next = Some(next.map_or(candidate, |n| min(n, candidate)));
+            }
+        });
+        match next {
+            Some(number) => { count += 1; counted = Some(number) }
+          , None => return count
+        }
+    }
+}
+
+impl RemapPlan {
+    /// Plan identity mapping the ranges of page numbers `[start, end)` that
+    /// `for_each_range` passes to its callback.
+    pub fn plan<F>(for_each_range: F) -> Self
+    where F: Fn(&mut FnMut(usize, usize)) {
+        let pages = count_distinct(&for_each_range, 0);
+        // one PML4, plus each PDPT, PD, and PT
+        let tables = 1 + count_distinct(&for_each_range, 27)
+                       + count_distinct(&for_each_range, 18)
+                       + count_distinct(&for_each_range, 9);
+        RemapPlan { pages: pages, tables: tables }
+    }
+
+    /// Plan identity mapping `ranges` of frames, which may overlap.
+    pub fn for_ranges(ranges: &[FrameRange]) -> Self {
+        RemapPlan::plan(|f| {
+            for range in ranges {
+                f(range.start.number as usize, range.end.number as usize)
+            }
+        })
+    }
+}
+
+/// Work out how many pages and page tables `kernel_remap` will map, without
+/// allocating or mapping anything.
+///
+/// The same regions are walked as in `kernel_remap`: the allocated ELF
+/// sections, the VGA buffer, and the multiboot info. Regions `params` has
+/// no address for are skipped.
+pub fn kernel_remap_plan(params: &InitParams) -> RemapPlan {
+    use elf::Section;
+    RemapPlan::plan(|f| {
+        if let Some(sections) = params.elf_sections.clone() {
+            for section in sections.filter(|s| s.is_allocated()) {
+                // a section that doesn't start on a frame boundary may spill
+                // into one more frame than its length alone needs.
+                let (start, offset) = section.address().frame_offset_pair();
+                let n_frames = PhysicalPage::frames_for_bytes(
+                    offset as usize + section.length());
+                f(start.number as usize, start.number as usize + n_frames)
+            }
+        }
+
+        let vga_buffer = PhysicalPage::containing(PAddr::from(0xb8000));
+        f(vga_buffer.number as usize, vga_buffer.number as usize + 1);
+
+        if let (Some(start), Some(end)) = ( params.multiboot_start
+                                          , params.multiboot_end ) {
+            f( PhysicalPage::from(start).number as usize
+             , PhysicalPage::from(end).number as usize)
+        }
+    })
+}
@@ -12,7 +12,8 @@ use memory::{Addr, PAGE_SIZE, PAddr, Page, PhysicalPage, VAddr, VirtualPage};
 
 use core::marker::PhantomData;
 use core::ops::{Index, IndexMut};
-use core::{convert, fmt, intrinsics};
+use core::{convert, fmt, intrinsics, slice};
+use core::iter::Enumerate;
 
 use ::{ MapResult, MapErr};
 
@@ -21,15 +22,58 @@ pub const N_ENTRIES: usize = 512;
 /// Size of a page table (in bytes)
 pub const PAGE_TABLE_SIZE: usize = N_ENTRIES * PAGE_SIZE as usize;
 
+/// Index of the recursive entry in the PML4.
+///
+/// The PML4 maps itself through this entry, so the top 512 GiB of the address
+/// space, starting at `RECURSIVE_REGION_START`, is where the page tables
+/// themselves are mapped. Nothing else may be mapped there.
+pub const RECURSIVE_INDEX: usize = 511;
+
+/// The first address in the region mapped by the recursive PML4 entry.
+pub const RECURSIVE_REGION_START: usize = 0xffff_ff80_0000_0000;
+
+/// Returns the virtual address at which a page table is recursively mapped.
+///
+/// The table is named by the path of indices leading to it from the PML4:
+/// an empty path is the PML4 itself, one index is a PDPT, two are a PD, and
+/// three are a PT. Each index must be less than `N_ENTRIES`.
+///
+/// # Panics
+/// + if `indices` is longer than three
+pub fn recursive_table_addr(indices: &[usize]) -> VAddr {
+    assert!( indices.len() < 4
+           , "a page table path has at most three indices, not {}"
+           , indices.len());
+    // the recursive entry is followed once for each level that the path
+    // doesn't reach down to.
+    let addr = (indices.len()..4).map(|_| RECURSIVE_INDEX)
+                                 .chain(indices.iter().cloned())
+                                 .fold(0, |addr, i| (addr << 9) | i);
+    // sign-extend; the top index is always the recursive one.
+    VAddr::from((addr << 12) | 0xffff_0000_0000_0000)
+}
+
 /// Base virtual address of the PML4 table
 pub const PML4_VADDR: u64 =  0xffffffff_fffff000;
 
 /// A pointer to the PML4 table
 pub const PML4_PTR: *mut Table<PML4Level> = PML4_VADDR as *mut _;
 
+/// A pointer to the PML5 table, when 5-level paging is in use.
+///
+/// The recursive mapping puts the top-level table at `PML4_VADDR`, so with
+/// 5-level paging that's where the PML5 is.
+#[cfg(feature = "la57")]
+pub const PML5_PTR: *mut Table<PML5Level> = PML4_VADDR as *mut _;
+
 /// Mask to apply to a page table entry to isolate the flags
 pub const ENTRY_FLAGS_MASK: u64 = (PAGE_SIZE as u64 - 1) as u64;
 
+/// Mask to apply to a page table entry to isolate the physical address
+///
+/// This excludes the no-execute bit (bit 63) as well as the low flag bits.
+pub const ENTRY_ADDR_MASK: u64 = 0x000fffff_fffff000;
+
 /// A page table
 #[repr(C)]
 pub struct Table<L>
@@ -93,11 +137,18 @@ where T: TableLevel {
 
 }
 
+#[cfg(feature = "la57")]
+pub enum PML5Level {}
 pub enum PML4Level {}
 pub enum PDPTLevel {}
 pub enum PDLevel   {}
 pub enum PTLevel   {}
 
+#[cfg(feature = "la57")]
+impl TableLevel for PML5Level {
+    const ADDR_SHIFT_AMOUNT: usize = 48;
+    const PAGE_SHIFT_AMOUNT: usize = 36;
+}
 impl TableLevel for PML4Level {
     // TODO: make sure these values are correct!
     //          - eliza, 5/29/2017
@@ -120,6 +171,10 @@ impl TableLevel for PTLevel   {
 pub trait Sublevel: TableLevel {
     type Next: TableLevel;
 }
+#[cfg(feature = "la57")]
+impl Sublevel for PML5Level {
+    type Next = PML4Level;
+}
 impl Sublevel for PML4Level {
     type Next = PDPTLevel;
 }
@@ -148,13 +203,29 @@ impl Table<PML4Level> {
 }
 
 
+/// Returns the index of the entry for `i` in a table at level `L`.
+///
+/// Both `Index` and `IndexMut` go through this, so that an index computed
+/// wrongly is caught here in debug builds, rather than as whichever entry it
+/// happens to land on.
+#[inline]
+pub fn entry_index<L, I>(i: I) -> usize
+where L: TableLevel
+    , L: IndexOf<I> {
+    let index = L::index_of(i);
+    debug_assert!( index < N_ENTRIES
+                 , "index {} is out of range for a table of {} entries"
+                 , index, N_ENTRIES);
+    index
+}
+
 impl<L, I> Index<I> for Table<L>
 where L: TableLevel
     , L: IndexOf<I> {
     type Output = Entry;
 
     #[inline] fn index(&self, i: I) -> &Entry {
-        &self.entries[L::index_of(i)]
+        &self.entries[entry_index::<L, I>(i)]
     }
 }
 
@@ -162,7 +233,7 @@ impl<L, I> IndexMut<I> for Table<L>
 where L: TableLevel
     , L: IndexOf<I> {
     #[inline] fn index_mut(&mut self, i: I) -> &mut Entry {
-        &mut self.entries[L::index_of(i)]
+        &mut self.entries[entry_index::<L, I>(i)]
     }
 }
 
@@ -172,13 +243,39 @@ impl<L: TableLevel> Table<L>  {
     /// Zeroes out the page table by setting all entries "unused"
     pub fn zero(&mut self) -> &mut Self {
         trace!("zeroing {:?}", self);
-        for entry in self.entries.iter_mut() {
+        self.clear_range(0, N_ENTRIES);
+        trace!("zeroed {:?} successfully", self);
+        self
+    }
+
+    /// Set the entries from `start` up to (but not including) `end` to
+    /// "unused", leaving the rest of the table alone.
+    pub fn clear_range(&mut self, start: usize, end: usize) -> &mut Self {
+        debug_assert!( start <= end && end <= N_ENTRIES
+                     , "cannot clear entries {}..{} of a table with {} \
+                        entries", start, end, N_ENTRIES);
+        for entry in self.entries[start..end].iter_mut() {
             entry.set_unused();
         }
-        trace!("zeroed {:?} successfully", self);
         self
     }
 
+    /// Returns an iterator over the present entries in this table, along
+    /// with their indices.
+    ///
+    /// This is for walking only the parts of a table tree that are in use,
+    /// without visiting every entry below them.
+    #[inline]
+    pub fn present_entries(&self) -> PresentEntries {
+        PresentEntries { entries: self.entries.iter().enumerate() }
+    }
+
+    /// Returns true if every entry in this table is unused.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(Entry::is_unused)
+    }
+
     /// Return the start physical address of this `Table`
     #[inline]
     pub fn start_paddr(&self) -> PAddr {
@@ -193,16 +290,48 @@ impl<L: TableLevel> Table<L>  {
 
 }
 
+/// An iterator over the present entries in a table, and their indices.
+///
+/// This is returned by `Table::present_entries`.
+pub struct PresentEntries<'a> { entries: Enumerate<slice::Iter<'a, Entry>> }
+
+impl<'a> Iterator for PresentEntries<'a> {
+    type Item = (usize, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((i, entry)) = self.entries.next() {
+            if entry.flags().is_present() { return Some((i, entry)) }
+        }
+        None
+    }
+}
+
 impl<L: Sublevel> Table<L> {
 
 
     /// Returns the address of the next table, or None if none exists.
+    ///
+    /// The entry's `PRESENT` bit is checked before anything else, so an
+    /// entry that isn't present is never followed, whatever stale address
+    /// it holds. Only this table is read, so walking the tables with
+    /// `next_table` never faults.
     #[inline]
     fn next_table_addr(&self, i: usize) -> Option<VAddr> {
         let flags = self[i].flags();
         if flags.contains(PRESENT) && !flags.contains(HUGE_PAGE) {
-            let table_addr = self as *const _ as usize;
-            Some(VAddr::from(table_addr << 9) | (i << 12))
+            let addr = VAddr::from(self as *const _ as usize);
+            let indices = [ addr.pml4_index(), addr.pdpt_index()
+                          , addr.pd_index(), addr.pt_index() ];
+            // the path to this table is the indices of its address after
+            // the leading recursive ones; the next table's path adds `i`.
+            let depth = indices.iter()
+                               .take_while(|&&index| index == RECURSIVE_INDEX)
+                               .count();
+            let n = indices.len() - depth;
+            let mut path = [0; 3];
+            path[..n].copy_from_slice(&indices[depth..]);
+            path[n] = i;
+            Some(recursive_table_addr(&path[..n + 1]))
         } else {
             None
         }
@@ -227,6 +356,49 @@ impl<L: Sublevel> Table<L> {
     }
 
 
+    /// Invalidates the TLB entry for the next table at index `i`, at the
+    /// address it is recursively mapped to.
+    ///
+    /// # Returns
+    /// + `true` if there was a next table to invalidate
+    ///
+    /// # Safety
+    /// + This must be executed in kernel mode.
+    pub unsafe fn invlpg_next<I>(&self, i: I) -> bool
+    where L: IndexOf<I> {
+        use super::tlb::Flush;
+        match self.next_table_addr(L::index_of(i)) {
+            Some(addr) => { addr.invlpg(); true }
+          , None => false
+        }
+    }
+
+    /// Unmaps the next table at index `i` if it is empty.
+    ///
+    /// # Returns
+    /// + `Some(PhysicalPage)` containing the frame of the next table, if it
+    ///   was empty. The caller is responsible for deallocating this frame.
+    /// + `None` if there is no next table or it is not empty.
+    pub fn free_next_if_empty<I>(&mut self, i: I) -> Option<PhysicalPage>
+    where L: IndexOf<I> {
+        use super::tlb::Flush;
+        let i = L::index_of(i);
+        if self.next_table(i).map_or(false, Table::is_empty) {
+            let table_addr = self.next_table_addr(i);
+            let frame = self[i].get_frame();
+            self[i].set_unused();
+            // the table may still be cached in the TLB at the address it was
+            // recursively mapped to.
+            if let Some(addr) = table_addr {
+                // this is safe because we're in kernel mode
+                unsafe { addr.invlpg() };
+            }
+            frame
+        } else {
+            None
+        }
+    }
+
     /// Returns the next table, creating it if it does not exist.
     pub fn create_next<A>(&mut self, i: VirtualPage, alloc: &mut A)
                          -> MapResult<&mut Table<L::Next>>
@@ -281,11 +453,36 @@ bitflags! {
       , const DIRTY =           1 << 6
       , const HUGE_PAGE =       1 << 7
       , const GLOBAL =          1 << 8
+      , /// Page Attribute Table index bit.
+        ///
+        /// This is the same bit as `HUGE_PAGE`, and only selects a PAT
+        /// entry in PT entries, which can't be huge.
+        const PAT =             1 << 7
       , const NO_EXECUTE =      1 << 63
     }
 }
 
 impl EntryFlags {
+    /// Returns flags for memory-mapped I/O.
+    ///
+    /// The page is writable, not executable, and uncacheable: both
+    /// `NO_CACHE` and `WRITE_THROUGH` are set, which selects the strongly
+    /// uncacheable PAT entry.
+    #[inline]
+    pub fn mmio() -> Self {
+        WRITABLE | NO_CACHE | WRITE_THROUGH | NO_EXECUTE
+    }
+
+    /// Returns flags for a writable, write-combining 4KiB page, such as a
+    /// frame buffer.
+    ///
+    /// This selects PAT entry 4, which is only write-combining once
+    /// `cpu::msr::enable_write_combining` has been called.
+    #[inline]
+    pub fn write_combining() -> Self {
+        WRITABLE | PAT | NO_EXECUTE
+    }
+
     /// Returns true if this page is huge
     #[inline]
     pub fn is_huge(&self) -> bool {
@@ -318,6 +515,185 @@ impl EntryFlags {
         else { self.insert(NO_EXECUTE) }
         self
     }
+
+    /// Returns the flags to map an ELF section with.
+    ///
+    /// This is `from_section_flags` with `section`'s header flags.
+    #[inline]
+    pub fn from_elf(section: &elf::Section<u64>) -> elf::ElfResult<Self> {
+        EntryFlags::from_section_flags(section.flags())
+    }
+
+    /// Returns the flags to map an ELF section with the given section
+    /// header flags with.
+    ///
+    /// Each permission is set independently:
+    /// + `PRESENT` is set if the section is allocated (`SHF_ALLOC`)
+    /// + `WRITABLE` is set if, and only if, it's writable (`SHF_WRITE`)
+    /// + `NO_EXECUTE` is set unless it's executable (`SHF_EXECINSTR`)
+    ///
+    /// Every present page can be read, so there's no flag for that.
+    ///
+    /// # Returns
+    /// + `Err` if the section is both writable and executable, or is
+    ///   writable or executable without being allocated
+    pub fn from_section_flags(flags: elf::section::Flags)
+                             -> elf::ElfResult<Self> {
+        use elf::section::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE};
+        let allocated = flags.contains(SHF_ALLOC);
+        let writable = flags.contains(SHF_WRITE);
+        let executable = flags.contains(SHF_EXECINSTR);
+        if writable && executable {
+            Err("the section is both writable and executable")
+        } else if !allocated && (writable || executable) {
+            Err("the section is writable or executable, but not allocated")
+        } else {
+            Ok(*EntryFlags::empty()
+                .set_present(allocated)
+                .set_writable(writable)
+                .set_executable(executable))
+        }
+    }
+}
+
+/// How the memory a page maps is cached.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Caching { /// Write-back caching, the default.
+                   WriteBack
+                 , /// Write-through caching (`WRITE_THROUGH`).
+                   WriteThrough
+                 , /// Uncacheable, for memory-mapped IO (`NO_CACHE` and
+                   /// `WRITE_THROUGH`).
+                   Uncached
+                 , /// Write-combining, for frame buffers (`PAT`). This is
+                   /// only available for 4KiB pages.
+                   WriteCombining
+                 }
+
+/// Builds `EntryFlags`, checking that they make sense for the kind of entry
+/// they're for.
+///
+/// Some bits mean different things at different levels: `PAT` is the same
+/// bit as `HUGE_PAGE`, and `GLOBAL` is ignored in an entry that points at
+/// another table. `build_leaf` and `build_table` reject the flags that
+/// don't belong in each kind of entry, at the table level they're for.
+///
+/// `PRESENT` is always set.
+#[derive(Copy, Clone, Debug)]
+pub struct EntryFlagsBuilder { flags: EntryFlags
+                             , caching: Caching
+                             , huge: bool
+                             }
+
+impl EntryFlagsBuilder {
+    /// Returns a builder for read-only, executable, kernel-only flags with
+    /// write-back caching.
+    pub fn new() -> Self {
+        EntryFlagsBuilder { flags: PRESENT
+                          , caching: Caching::WriteBack
+                          , huge: false
+                          }
+    }
+
+    /// Allow writes.
+    #[inline]
+    pub fn writable(mut self) -> Self {
+        self.flags.insert(WRITABLE);
+        self
+    }
+
+    /// Allow access from user mode.
+    #[inline]
+    pub fn user(mut self) -> Self {
+        self.flags.insert(USER_ACCESSIBLE);
+        self
+    }
+
+    /// Forbid executing code.
+    #[inline]
+    pub fn no_execute(mut self) -> Self {
+        self.flags.insert(NO_EXECUTE);
+        self
+    }
+
+    /// Keep the mapping in the TLB when `$cr3` is reloaded.
+    #[inline]
+    pub fn global(mut self) -> Self {
+        self.flags.insert(GLOBAL);
+        self
+    }
+
+    /// Map a huge page, rather than a 4KiB one.
+    #[inline]
+    pub fn huge(mut self) -> Self {
+        self.huge = true;
+        self
+    }
+
+    /// Set how the mapped memory is cached.
+    #[inline]
+    pub fn cache(mut self, caching: Caching) -> Self {
+        self.caching = caching;
+        self
+    }
+
+    /// Returns the flags for this builder's caching mode.
+    fn caching_flags(&self) -> EntryFlags {
+        match self.caching {
+            Caching::WriteBack => EntryFlags::empty()
+          , Caching::WriteThrough => WRITE_THROUGH
+          , Caching::Uncached => NO_CACHE | WRITE_THROUGH
+          , Caching::WriteCombining => PAT
+        }
+    }
+
+    /// Returns the flags for an entry in an `L` table that maps a page.
+    ///
+    /// A PT entry maps a 4KiB page, and a PD or PDPT entry maps a huge
+    /// page, so `huge` must have been called for those.
+    ///
+    /// # Returns
+    /// + `Err` if `L` is the PML4, which can't map pages
+    /// + `Err` if the page is huge in a PT, or isn't huge in a PD or PDPT
+    /// + `Err` if the page is huge and write-combining, since `PAT` is the
+    ///   same bit as `HUGE_PAGE`
+    pub fn build_leaf<L>(self) -> Result<EntryFlags, &'static str>
+    where L: TableLevel {
+        match L::ADDR_SHIFT_AMOUNT {
+            12 if self.huge => return Err("a PT entry can't be huge")
+          , 12 => {}
+          , 21 | 30 if !self.huge =>
+                return Err("a PD or PDPT entry mapping a page must be huge")
+          , 21 | 30 => {}
+          , _ => return Err("only PT, PD and PDPT entries can map a page")
+        }
+        if self.huge && self.caching == Caching::WriteCombining {
+            return Err("a huge page can't be write-combining")
+        }
+        let huge = if self.huge { HUGE_PAGE } else { EntryFlags::empty() };
+        Ok(self.flags | self.caching_flags() | huge)
+    }
+
+    /// Returns the flags for an entry in an `L` table that points at
+    /// another table.
+    ///
+    /// `L` must have a next level, so this can't be called for a PT.
+    ///
+    /// # Returns
+    /// + `Err` if the flags are for a huge page, are global, or are
+    ///   write-combining, none of which apply to a table
+    pub fn build_table<L>(self) -> Result<EntryFlags, &'static str>
+    where L: Sublevel {
+        if self.huge {
+            Err("a table entry can't be huge")
+        } else if self.flags.contains(GLOBAL) {
+            Err("a table entry can't be global")
+        } else if self.caching == Caching::WriteCombining {
+            Err("a table entry can't be write-combining")
+        } else {
+            Ok(self.flags | self.caching_flags())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -331,18 +707,29 @@ impl Entry {
     }
 
     // TODO: this is one of the worst names I have ever given a thing
-    #[inline]
-    pub fn do_huge(&self, offset: usize) -> Option<PhysicalPage> {
-        if self.is_huge() {
-            self.get_frame()
-                .map(|start_frame| {
-                    assert!( start_frame.number as usize % N_ENTRIES == 0
-                           , "Start frame must be aligned on a 1GB boundary!");
-                    start_frame + offset
-                })
-        } else {
-            None
-        }
+    /// Returns the frame `offset` frames into the huge page this entry maps.
+    ///
+    /// # Arguments
+    /// + `offset`: the number of 4KiB frames into the huge page
+    /// + `n_frames`: the number of 4KiB frames in a huge page at this
+    ///               entry's level
+    ///
+    /// # Returns
+    /// + `None` if this entry isn't a huge page, or its start frame isn't
+    ///   aligned to the huge page size, so it can't be translated
+    pub fn do_huge(&self, offset: usize, n_frames: usize)
+                  -> Option<PhysicalPage> {
+        if !self.is_huge() { return None }
+        self.get_frame()
+            .and_then(|start_frame| {
+                if start_frame.number as usize % n_frames == 0 {
+                    Some(start_frame + offset)
+                } else {
+                    warn!( "huge page entry {:?} is not aligned to {} frames"
+                         , start_frame, n_frames);
+                    None
+                }
+            })
     }
 
     /// Returns true if this is an unused entry
@@ -358,21 +745,50 @@ impl Entry {
     }
 
     /// Returns true if this page is huge
+    ///
+    /// This is only meaningful for PD and PDPT entries. In a PT entry, the
+    /// same bit is `PAT`; use `pt_caching` for those.
     #[inline]
     pub fn is_huge(&self) -> bool {
         self.flags().is_huge()
     }
 
+    /// Returns how the 4KiB page this PT entry maps is cached.
+    ///
+    /// This reads `PAT`, `NO_CACHE` and `WRITE_THROUGH` as the PAT index
+    /// they form in a PT entry, so it never mistakes `PAT` for `HUGE_PAGE`.
+    /// It expects PAT entry 4 to have been made write-combining by
+    /// `cpu::msr::enable_write_combining`, and the others to be left as
+    /// they are at reset.
+    pub fn pt_caching(&self) -> Caching {
+        let flags = self.flags();
+        if flags & (PAT | NO_CACHE | WRITE_THROUGH) == PAT {
+            Caching::WriteCombining
+        } else if flags.contains(NO_CACHE) {
+            Caching::Uncached
+        } else if flags.contains(WRITE_THROUGH) {
+            Caching::WriteThrough
+        } else {
+            Caching::WriteBack
+        }
+    }
+
     /// Access the entry's bitflags.
     #[inline]
     pub fn flags(&self) -> EntryFlags {
         EntryFlags::from_bits_truncate(self.0)
     }
 
+    /// Returns the raw bits of this page table entry.
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
     /// Returns the physical address pointed to by this page table entry
     #[inline]
     pub fn get_addr(&self) -> PAddr {
-        PAddr::from(self.0 & PML4_VADDR)
+        PAddr::from(self.0 & ENTRY_ADDR_MASK)
     }
 
     /// Returns the frame in memory pointed to by this page table entry.
@@ -387,17 +803,43 @@ impl Entry {
 
     pub fn set(&mut self, frame: PhysicalPage, flags: EntryFlags) {
         let addr: u64 = frame.base_addr().into();
-        assert!(addr & !0x000fffff_fffff000 == 0);
+        assert!(addr & !ENTRY_ADDR_MASK == 0);
         self.0 = addr | flags.bits();
     }
 
 }
 
+/// Sections are mapped with the flags from `EntryFlags::from_elf`.
+///
+/// A section that `from_elf` rejects is mapped writable if it asks to be,
+/// but never executable, after a warning, so that it can't be both. Use
+/// `from_elf` to reject it instead.
 impl<'a> convert::From<&'a elf::Section<u64>> for EntryFlags {
     fn from(section: &'a elf::Section<u64>) -> Self {
-        *EntryFlags::empty()
-            .set_present(section.is_allocated())
-            .set_writable(section.is_writable())
-            .set_executable(section.is_executable())
+        EntryFlags::from_elf(section).unwrap_or_else(|why| {
+            warn!("{}: {}", why, section);
+            *EntryFlags::empty()
+                .set_present(section.is_allocated())
+                .set_writable(section.is_writable())
+                .set_executable(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_index_in_range() {
+        assert_eq!(entry_index::<PTLevel, usize>(0), 0);
+        assert_eq!(entry_index::<PTLevel, usize>(N_ENTRIES - 1), N_ENTRIES - 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_entry_index_out_of_range() {
+        entry_index::<PTLevel, usize>(N_ENTRIES);
     }
 }
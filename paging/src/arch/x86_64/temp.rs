@@ -1,11 +1,11 @@
-use memory::{PAGE_SIZE, Page, PhysicalPage, VAddr, VirtualPage, FrameRange};
+use memory::{PAGE_SIZE, Page, PageRange, PhysicalPage, VirtualPage, FrameRange};
 use alloc::{AllocResult, AllocErr, Layout, FrameAllocator};
 
-use core::ops;
+use core::{ops, ptr};
 
 use super::ActivePageTable;
-use super::table::{Table, PTLevel};
-use ::{Mapper, MapResult};
+use super::table::{EntryFlags, Table, PTLevel};
+use ::{Mapper, MapResult, MapErr};
 
 #[derive(Debug)]
 pub struct TempPage { page: VirtualPage
@@ -45,17 +45,17 @@ impl TempPage {
     /// + `table`: the `ActivePageTable`
     ///
     /// # Returns
-    /// + The `VAddr` of the mapped page.
+    /// + A pointer to the start of the mapped page.
     pub fn map_to( &mut self
                  , frame: PhysicalPage
                  , table: &mut ActivePageTable)
-                 -> MapResult<VAddr> {
+                 -> MapResult<*mut u8> {
         //assert!( !table.is_mapped(self)
                 //, "Cannot map {:?}, as it is already mapped", self);
         use super::table::WRITABLE;
         trace!(" . . TempPage::map_to({:?})", frame);
         table.map(self.page, frame, WRITABLE, &mut self.frames)
-             .map(|_| { self.page.base() })
+             .map(|_| { self.page.base().as_mut_ptr() })
     }
 
     pub fn map_to_table( &mut self
@@ -63,21 +63,206 @@ impl TempPage {
                        , table: &mut ActivePageTable)
                        -> MapResult<&mut Table<PTLevel>> {
         self.map_to(frame, table)
-            .map(|addr| unsafe {
-                &mut *(addr.as_mut_ptr::<Table<PTLevel>>())
-            })
+            .map(|ptr| unsafe { &mut *(ptr as *mut Table<PTLevel>) })
    }
 
+    /// Map the `TempPage` to `frame`, call `f` with a pointer to the
+    /// mapped page, and then unmap it again.
+    ///
+    /// The page is unmapped even if `f` panics, as long as the panic
+    /// unwinds.
+    ///
+    /// # Arguments
+    /// + `frame`: the `PhysicalPage` to map to
+    /// + `table`: the `ActivePageTable`
+    /// + `f`: a closure to call while the page is mapped. The pointer it's
+    ///        passed is only valid until it returns.
+    ///
+    /// # Returns
+    /// + the value returned by `f`
+    pub fn with<F, R>( &mut self
+                     , frame: PhysicalPage
+                     , table: &mut ActivePageTable
+                     , f: F)
+                     -> MapResult<R>
+    where F: FnOnce(*mut u8) -> R {
+        let ptr = self.map_to(frame, table)?;
+        let guard = UnmapGuard { temp: self, table: table, unmapped: false };
+        let result = f(ptr);
+        guard.unmap().map(|_| result)
+    }
+
     pub fn unmap(&mut self, table: &mut ActivePageTable) -> MapResult<()> {
         trace!("unmapping temp page {:?}", self);
         // assert!( table.is_mapped(self)
         //         , "Cannot unmap {:?}, as it is not mapped", self);
-        table.unmap(self.page, &mut self.frames)
+        // the temp page doesn't own the frame it was mapped to, so we mustn't
+        // deallocate it; only the page tables go back into the frame cache.
+        table.unmap_keep_frames(self.page, &mut self.frames)
              .map(|_| { trace!("temp page unmapped") })
 
     }
 }
 
+/// A `FrameAllocator` that can allocate frames that have been zeroed.
+///
+/// This is implemented for every `FrameAllocator`; frames are zeroed by
+/// mapping them to a `TempPage`.
+pub trait AllocateZeroed: FrameAllocator {
+    /// Allocate a frame and fill it with zeroes.
+    ///
+    /// # Arguments
+    /// + `temp`: a `TempPage` to zero the frame through
+    /// + `table`: the `ActivePageTable`
+    ///
+    /// # Returns
+    /// + the zeroed frame
+    /// + `MapErr::Alloc` if no frame could be allocated
+    /// + any error from mapping `temp`, in which case the frame is
+    ///   deallocated again
+    fn allocate_zeroed( &mut self
+                      , temp: &mut TempPage
+                      , table: &mut ActivePageTable)
+                      -> MapResult<PhysicalPage>;
+}
+
+impl<A> AllocateZeroed for A
+where A: FrameAllocator {
+    fn allocate_zeroed( &mut self
+                      , temp: &mut TempPage
+                      , table: &mut ActivePageTable)
+                      -> MapResult<PhysicalPage> {
+        let frame = unsafe { self.allocate() }
+            .map_err(|err| MapErr::Alloc { message: "allocate a zeroed frame"
+                                         , page: **temp
+                                         , cause: err })?;
+        let zeroed = temp.with(frame, table, |ptr| unsafe {
+            ptr::write_bytes(ptr, 0, PAGE_SIZE as usize)
+        });
+        match zeroed {
+            Ok(()) => Ok(frame)
+          , Err(err) => {
+                unsafe { self.deallocate(frame) };
+                Err(err)
+            }
+        }
+    }
+}
+
+impl ActivePageTable {
+    /// Lend this page table's temporary page to `f`, along with the page
+    /// table itself.
+    ///
+    /// The temporary page is created the first time it's needed, and kept
+    /// afterwards, so its frames are only allocated once. Calls mustn't be
+    /// nested, or the inner call will create a second temporary page at the
+    /// same address.
+    ///
+    /// # Arguments
+    /// + `alloc`: a `FrameAllocator` to allocate the temporary page's
+    ///            frames from, if it doesn't exist yet
+    /// + `f`: a closure to call with the page table, the temporary page,
+    ///        and `alloc`
+    ///
+    /// # Returns
+    /// + the value returned by `f`
+    pub fn with_temp_page<A, F, R>(&mut self, alloc: &mut A, f: F) -> R
+    where A: FrameAllocator
+        , F: FnOnce(&mut ActivePageTable, &mut TempPage, &mut A) -> R {
+        let mut temp = match self.temp.take() {
+            Some(temp) => temp
+          , None => TempPage::new(super::TEMP_PAGE_NUMBER, alloc)
+        };
+        let result = f(self, &mut temp, alloc);
+        self.temp = Some(temp);
+        result
+    }
+
+    /// Map `page` to a newly allocated frame that's zeroed before it's
+    /// mapped.
+    ///
+    /// The frame is zeroed through `temp`, so nothing that was left in it
+    /// is ever visible at `page`, even for a moment.
+    ///
+    /// # Arguments
+    /// + `page`: the page to map
+    /// + `flags`: the flags to map the page with
+    /// + `temp`: a `TempPage` to zero the frame through
+    /// + `alloc`: the `FrameAllocator` to allocate the frame and any page
+    ///   tables from
+    ///
+    /// # Returns
+    /// + `MapErr::Alloc` if no frame could be allocated
+    /// + `MapErr::AlreadyInUse` if `page` is already mapped
+    pub fn map_zeroed_anon<A>( &mut self
+                             , page: VirtualPage
+                             , flags: EntryFlags
+                             , temp: &mut TempPage
+                             , alloc: &mut A)
+                             -> MapResult<()>
+    where A: FrameAllocator {
+        let frame = alloc.allocate_zeroed(temp, self)?;
+        self.map(page, frame, flags, alloc)
+            .map_err(|err| {
+                unsafe { alloc.deallocate(frame) };
+                err
+            })
+    }
+
+    /// Map each page in `range` to its own zeroed frame.
+    ///
+    /// If any page can't be mapped, the pages before it are unmapped again
+    /// and their frames are deallocated.
+    ///
+    /// # Arguments
+    /// + `range`: the pages to map
+    /// + `flags`: the flags to map the pages with
+    /// + `temp`: a `TempPage` to zero the frames through
+    /// + `alloc`: the `FrameAllocator` to allocate frames and page tables
+    ///   from
+    pub fn map_zeroed_anon_range<A>( &mut self
+                                   , range: PageRange
+                                   , flags: EntryFlags
+                                   , temp: &mut TempPage
+                                   , alloc: &mut A)
+                                   -> MapResult<()>
+    where A: FrameAllocator {
+        for page in range.clone() {
+            if let Err(err) = self.map_zeroed_anon(page, flags, temp, alloc) {
+                for page in range.start .. page {
+                    let _ = self.unmap_unflushed(page, alloc);
+                }
+                self.flush_range(range.start .. page);
+                return Err(err)
+            }
+        }
+        trace!("mapped zeroed anonymous pages {:?}", range);
+        Ok(())
+    }
+}
+
+/// Unmaps a `TempPage` when it's dropped, unless it was already unmapped.
+struct UnmapGuard<'a> { temp: &'a mut TempPage
+                      , table: &'a mut ActivePageTable
+                      , unmapped: bool
+                      }
+
+impl<'a> UnmapGuard<'a> {
+    /// Unmap the `TempPage` now, returning any error.
+    fn unmap(mut self) -> MapResult<()> {
+        self.unmapped = true;
+        self.temp.unmap(self.table)
+    }
+}
+
+impl<'a> Drop for UnmapGuard<'a> {
+    fn drop(&mut self) {
+        if !self.unmapped {
+            let _ = self.temp.unmap(self.table);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FrameCache([Option<PhysicalPage>; 3]);
 
@@ -101,7 +286,7 @@ impl FrameAllocator for FrameCache {
             .find(    |frame| frame.is_some())
             .and_then(|mut frame| frame.take())
             .map(|frame| { trace!("frameCache: alloced {:?}", &frame); frame})
-            .ok_or(AllocErr::Exhausted {
+            .ok_or(AllocErr::OutOfMemory {
                     request: Layout::from_size_align( PAGE_SIZE as usize
                                                     , PAGE_SIZE as usize)
                 })
@@ -119,8 +304,10 @@ impl FrameAllocator for FrameCache {
         unimplemented!()
     }
 
-    unsafe fn deallocate_range(&mut self, _range: FrameRange) {
-        unimplemented!()
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range {
+            self.deallocate(frame)
+        }
     }
 
 }
@@ -1,8 +1,19 @@
-use memory::VAddr;
+use memory::{MemRange, PageRange, VAddr};
 use super::{Page, VirtualPage};
+use spin::Mutex;
+
+use core::mem;
+
+/// The number of pages above which invalidating each page individually is
+/// likely to be slower than just flushing the whole TLB.
+pub const FLUSH_THRESHOLD: usize = 64;
 
 /// Invalidate the TLB completely by reloading the CR3 register.
 ///
+/// Pages mapped with the `GLOBAL` flag are *not* invalidated while global
+/// pages are enabled, since a CR3 reload leaves them in the TLB. Use
+/// `invlpg` or `flush_all_global` to evict them.
+///
 /// # Safety
 /// + Causes a general protection fault if not executed in kernel mode.
 pub unsafe fn flush_all() {
@@ -10,6 +21,152 @@ pub unsafe fn flush_all() {
     cr3::write(cr3::read());
 }
 
+/// Invalidate the TLB completely, including global pages.
+///
+/// This toggles CR4.PGE and then puts it back, which flushes every TLB
+/// entry, whatever PCID it's tagged with.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_all_global() {
+    use cpu::control_regs::cr4;
+    let enabled = cr4::is_global_enabled();
+    cr4::enable_global(!enabled);
+    cr4::enable_global(enabled);
+}
+
+/// Enable global pages by setting CR4.PGE.
+///
+/// Once global pages are enabled, pages mapped with the `GLOBAL` flag stay
+/// in the TLB when CR3 is reloaded, so they should only be used for
+/// mappings that are the same in every address space.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn enable_global_pages() {
+    use cpu::control_regs::cr4;
+    cr4::enable_global(true)
+}
+
+/// Invalidate every page in `range`.
+///
+/// Each page is invalidated with `invlpg`, unless the range is longer than
+/// `FLUSH_THRESHOLD`, in which case the entire TLB is flushed instead. Any
+/// of the pages may be global, which `invlpg` evicts but a `$cr3` reload
+/// wouldn't, so the whole TLB is flushed with `flush_all_global`.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn flush_range(range: PageRange) {
+    flush_range_with(range, |page| page.invlpg(), || flush_all_global())
+}
+
+/// Invalidate every page in `range` using the given functions.
+///
+/// This makes the same choice as `flush_range`, but calls `invlpg` for each
+/// page or `flush_all` once, so that the choice can be checked without
+/// touching the TLB.
+pub fn flush_range_with<I, F>(range: PageRange, mut invlpg: I, flush_all: F)
+where I: FnMut(VirtualPage)
+    , F: FnOnce() {
+    if range.length() > FLUSH_THRESHOLD {
+        trace!("flushing entire TLB for {:?}", range);
+        flush_all()
+    } else {
+        for page in range { invlpg(page) }
+    }
+}
+
+/// A request for other CPUs to invalidate entries in their TLBs.
+#[derive(Copy, Clone, Debug)]
+pub enum Shootdown<'a> {
+    /// Invalidate each of these pages
+    Pages(&'a [VirtualPage])
+  , /// Flush the entire TLB
+    All
+}
+
+impl<'a> Shootdown<'a> {
+    /// Carry out this request on the current CPU.
+    ///
+    /// This is what each CPU receiving a shootdown IPI should call. Flushing
+    /// the entire TLB evicts global pages too, as `flush_range` does.
+    ///
+    /// # Safety
+    /// + Causes a general protection fault if not executed in kernel mode.
+    pub unsafe fn flush_local(self) {
+        match self {
+            Shootdown::Pages(pages) => for &page in pages { page.invlpg() }
+          , Shootdown::All => flush_all_global()
+        }
+    }
+}
+
+/// A function which sends an inter-processor interrupt asking every other
+/// CPU to carry out a `Shootdown`.
+pub type IpiHandler = fn(Shootdown);
+
+/// The registered `IpiHandler`, if there is one.
+static IPI_HANDLER: Mutex<Option<IpiHandler>> = Mutex::new(None);
+
+/// Register the function used to send shootdown IPIs to other CPUs.
+///
+/// Until a handler is registered, `shootdown` only flushes the current CPU.
+///
+/// # Returns
+/// + the previously registered handler, if there was one
+pub fn set_ipi_handler(handler: IpiHandler) -> Option<IpiHandler> {
+    mem::replace(&mut *IPI_HANDLER.lock(), Some(handler))
+}
+
+/// Unregister the shootdown IPI handler.
+///
+/// # Returns
+/// + the previously registered handler, if there was one
+pub fn clear_ipi_handler() -> Option<IpiHandler> {
+    IPI_HANDLER.lock().take()
+}
+
+/// Invalidate `pages` in the TLB of every CPU.
+///
+/// The pages are invalidated on the current CPU, and then the registered
+/// `IpiHandler` is asked to invalidate them on every other CPU. If there are
+/// more than `FLUSH_THRESHOLD` pages, every CPU flushes its entire TLB
+/// instead.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn shootdown(pages: &[VirtualPage]) {
+    let request = if pages.len() > FLUSH_THRESHOLD {
+        Shootdown::All
+    } else {
+        Shootdown::Pages(pages)
+    };
+    send(request)
+}
+
+/// Flush the entire TLB of every CPU, including global pages and the
+/// entries of every PCID.
+///
+/// # Safety
+/// + Causes a general protection fault if not executed in kernel mode.
+pub unsafe fn shootdown_all() {
+    send(Shootdown::All)
+}
+
+/// Carry out `request` on the current CPU, and then pass it to the
+/// registered `IpiHandler`.
+unsafe fn send(request: Shootdown) {
+    request.flush_local();
+    // copy the handler out, so that the lock isn't held while waiting on
+    // the other CPUs.
+    let handler = *IPI_HANDLER.lock();
+    if let Some(handler) = handler {
+        trace!("sending TLB shootdown {:?}", request);
+        handler(request)
+    }
+}
+
 /// Something which may be flushed from the TLB
 pub trait Flush {
     /// Invalidate this object in the TLB using the `invlpg` instruction.
@@ -54,3 +211,14 @@ impl Flush for VirtualPage {
         self.base().invlpg()
     }
 }
+
+impl Flush for PageRange {
+    /// Invalidate every page in this range.
+    ///
+    /// If the range is larger than `FLUSH_THRESHOLD`, the entire TLB is
+    /// flushed instead.
+    #[inline]
+    unsafe fn invlpg(self) {
+        flush_range(self)
+    }
+}
@@ -0,0 +1,67 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Choosing pages to evict with the clock (second-chance) algorithm.
+use memory::VirtualPage;
+use ::Mapper;
+
+/// Sweeps a set of candidate pages with a clock hand, picking a page that
+/// hasn't been accessed recently to evict.
+///
+/// A page that has been accessed gets a second chance: its accessed bit is
+/// cleared, and the hand moves on. The first page the hand reaches whose
+/// accessed bit is already clear is the victim.
+#[derive(Debug)]
+pub struct ClockSweeper<'a> { pages: &'a [VirtualPage]
+                            , hand: usize
+                            }
+
+impl<'a> ClockSweeper<'a> {
+    /// Returns a new `ClockSweeper` over `pages`, with the hand on the
+    /// first page.
+    pub fn new(pages: &'a [VirtualPage]) -> Self {
+        ClockSweeper { pages: pages, hand: 0 }
+    }
+
+    /// Returns the index in the candidate set of the next page the hand
+    /// will look at.
+    #[inline]
+    pub fn hand(&self) -> usize { self.hand }
+
+    /// Advance the hand until it finds a page to evict.
+    ///
+    /// The accessed bit of each accessed page the hand passes is cleared,
+    /// and unmapped pages are skipped. The hand is left on the page after
+    /// the victim. After two sweeps, every mapped page's bit has been cleared
+    /// once, so one will have been found.
+    ///
+    /// # Returns
+    /// + `Some(VirtualPage)` with the page to evict
+    /// + `None` if none of the candidate pages are mapped
+    pub fn evict_candidate<M>(&mut self, table: &mut M)
+                             -> Option<VirtualPage>
+    where M: Mapper {
+        let len = self.pages.len();
+        for _ in 0 .. 2 * len {
+            let page = self.pages[self.hand];
+            self.hand = (self.hand + 1) % len;
+            match table.is_accessed(page) {
+                Some(true) => {
+                    // the page is mapped, so this can't fail.
+                    let _ = table.clear_accessed(page);
+                }
+              , Some(false) => {
+                    trace!("clock hand chose {:?} to evict", page);
+                    return Some(page)
+                }
+              , None => {}
+            }
+        }
+        None
+    }
+}
@@ -29,10 +29,15 @@ extern crate elf;
 extern crate params;
 
 pub mod arch;
+pub mod clock;
 pub mod stack;
-pub use self::arch::{kernel_remap, test_paging};
+pub use self::arch::{ kernel_remap, kernel_remap_opts, kernel_remap_plan
+                    , handle_page_fault, set_fault_frames
+                    , set_fault_table, FaultFrames
+                    , test_paging, RemapOptions };
 
-use memory::{Page, PAddr, PhysicalPage, VAddr, VirtualPage};
+use memory::{ FrameRange, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE, MemRange, PAGE_SIZE
+            , Page, PageRange, PAddr, PhysicalPage, VAddr, VirtualPage };
 use alloc::{FrameAllocator, AllocErr};
 use core::fmt;
 
@@ -45,17 +50,124 @@ pub enum MapErr<P: Page + fmt::Debug = VirtualPage> {
   , TableNotFound { message: &'static str, page: VirtualPage, what: &'static str }
   , AlreadyInUse { message: &'static str, page: VirtualPage, frame: PhysicalPage }
   , NoPage { message: &'static str, cause: &'static str}
+  , NonCanonical { addr: VAddr }
+  , ReservedRegion { page: VirtualPage }
+  , Overlaps { message: &'static str, page: VirtualPage }
+  , Capacity { message: &'static str, max: usize }
 }
 
-impl<P> fmt::Debug for MapErr<P> where P: Page + fmt::Debug {
+impl<P> fmt::Debug for MapErr<P>
+where P: Page + fmt::Debug
+    , P::Address: fmt::LowerHex {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unimplemented!()
+        write!(f, "MapErr({})", self)
+    }
+}
+
+/// Pages are always shown by their base address, as `page 0x1000`.
+impl<P> fmt::Display for MapErr<P>
+where P: Page + fmt::Debug
+    , P::Address: fmt::LowerHex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapErr::Alloc { message, ref page, ref cause } =>
+                write!( f, "cannot {} for page {:#x}: {}"
+                      , message, page.base(), cause)
+          , MapErr::Other { message, ref page, cause } =>
+                write!( f, "cannot {} at page {:#x}: {}"
+                      , message, page.base(), cause)
+          , MapErr::TableNotFound { message, page, what } =>
+                write!( f, "cannot {} page {:#x}: no {} table"
+                      , message, page.base(), what)
+          , MapErr::AlreadyInUse { message, page, frame } =>
+                write!( f, "cannot {} {:#x} to page {:#x}: already in use"
+                      , message, frame.base(), page.base())
+          , MapErr::NoPage { message, cause } =>
+                write!(f, "cannot {}: {}", message, cause)
+          , MapErr::NonCanonical { addr } =>
+                write!(f, "{:#x} is not a canonical address", addr)
+          , MapErr::ReservedRegion { page } =>
+                write!( f, "cannot map page {:#x}: it is reserved for the \
+                            recursive mapping"
+                      , page.base())
+          , MapErr::Overlaps { message, page } =>
+                write!( f, "cannot {} at page {:#x}: it overlaps an existing \
+                            region"
+                      , message, page.base())
+          , MapErr::Capacity { message, max } =>
+                write!(f, "cannot {}: all {} slots are in use", message, max)
+        }
+    }
+}
+
+/// The size of a huge page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePageSize {
+    /// A 2 MiB page, mapped by a PD entry
+    Size2M
+  , /// A 1 GiB page, mapped by a PDPT entry
+    Size1G
+}
+
+impl HugePageSize {
+    /// Returns the size of this huge page, in bytes.
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            HugePageSize::Size2M => LARGE_PAGE_SIZE
+          , HugePageSize::Size1G => HUGE_PAGE_SIZE
+        }
+    }
+
+    /// Returns the number of 4KiB pages covered by this huge page.
+    #[inline]
+    pub fn n_pages(&self) -> usize {
+        (self.bytes() / PAGE_SIZE) as usize
+    }
+}
+
+/// The size of the page mapping an address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    /// A 4 KiB page, mapped by a PT entry
+    Size4K
+  , /// A 2 MiB huge page, mapped by a PD entry
+    Size2M
+  , /// A 1 GiB huge page, mapped by a PDPT entry
+    Size1G
+}
+
+impl PageSize {
+    /// Returns the size of this page, in bytes.
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        match *self {
+            PageSize::Size4K => PAGE_SIZE
+          , PageSize::Size2M => LARGE_PAGE_SIZE
+          , PageSize::Size1G => HUGE_PAGE_SIZE
+        }
+    }
+
+    /// Returns the number of 4KiB pages covered by this page.
+    #[inline]
+    pub fn n_pages(&self) -> usize {
+        (self.bytes() / PAGE_SIZE) as usize
+    }
+}
+
+impl From<HugePageSize> for PageSize {
+    #[inline]
+    fn from(size: HugePageSize) -> Self {
+        match size {
+            HugePageSize::Size2M => PageSize::Size2M
+          , HugePageSize::Size1G => PageSize::Size1G
+        }
     }
 }
 
 pub trait Mapper {
-    type Flags;
+    type Flags: Copy;
 
     /// Translates a virtual address to the corresponding physical address.
     ///
@@ -80,6 +192,22 @@ pub trait Mapper {
              -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Modifies the page tables so that `page` maps to `frame` using a single
+    /// huge page.
+    ///
+    /// # Arguments
+    /// + `page`: the virtual `Page` to map. This must be aligned on a `size`
+    ///           boundary.
+    /// + `frame`: the physical `Frame` that `Page` should map to. This must
+    ///            be aligned on a `size` boundary.
+    /// + `size`: the size of the huge page.
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn map_huge<A>( &mut self, page: VirtualPage, frame: PhysicalPage
+                  , size: HugePageSize, flags: Self::Flags, alloc: &mut A )
+                  -> MapResult<()>
+    where A: FrameAllocator;
+
     /// Identity map a given `frame`.
     ///
     /// # Arguments
@@ -91,10 +219,27 @@ pub trait Mapper {
                       -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Identity map every frame in `frames`.
+    ///
+    /// This is intended for device and firmware regions, like the multiboot
+    /// info. A frame that's already identity mapped is tolerated; its flags
+    /// are merged with `flags`, so it keeps the permissions of both. If its
+    /// caching differs, `MapErr::AlreadyInUse` is returned.
+    ///
+    /// # Arguments
+    /// + `frames`: the range of physical `Frame`s to identity map
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn identity_map_range<A>( &mut self, frames: FrameRange
+                            , flags: Self::Flags, alloc: &mut A )
+                            -> MapResult<()>
+    where A: FrameAllocator;
+
     /// Map the given `VirtualPage` to any free frame.
     ///
     /// This is like the fire and forget version of `map_to`: we just pick the
-    /// first available free frame and map the page to it.
+    /// first available free frame and map the page to it. If the page can't
+    /// be mapped, the frame is deallocated again.
     ///
     /// # Arguments
     /// + `page`: the`VirtualPage` to map
@@ -106,10 +251,146 @@ pub trait Mapper {
                     -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Map the given `VirtualPage` to a free frame as close as possible to
+    /// `hint`.
+    ///
+    /// This is like `map_to_any`, but keeps the frames backing a large
+    /// buffer close together, so that they can later be promoted to a huge
+    /// page.
+    ///
+    /// # Arguments
+    /// + `page`: the`VirtualPage` to map
+    /// + `flags`: the page table entry flags.
+    /// + `hint`: the frame to allocate near
+    /// + `alloc`: a memory allocator
+    fn map_to_any_near<A>( &mut self, page: VirtualPage
+                         , flags: Self::Flags
+                         , hint: PhysicalPage
+                         , alloc: &mut A)
+                         -> MapResult<()>
+    where A: FrameAllocator {
+        let frame = unsafe { alloc.allocate_near(hint) }
+            .map_err(|err| MapErr::Alloc {
+                message: "map to any near"
+              , page: page
+              , cause: err
+            })?;
+        self.map(page, frame, flags, alloc)
+            .map_err(|err| {
+                unsafe { alloc.deallocate(frame) };
+                err
+            })
+    }
+
     /// Unmap the given `VirtualPage`.
     ///
     /// All freed frames are returned to the given `FrameAllocator`.
     fn unmap<A>(&mut self, page: VirtualPage, alloc: &mut A) -> MapResult<()>
     where A: FrameAllocator;
 
+    /// Change the page table entry flags for an already-mapped page.
+    ///
+    /// The frame that `page` is mapped to is preserved.
+    ///
+    /// # Arguments
+    /// + `page`: the `VirtualPage` to update
+    /// + `flags`: the new page table entry flags.
+    fn update_flags(&mut self, page: VirtualPage, flags: Self::Flags)
+                   -> MapResult<()>;
+
+    /// Change the frame that an already-mapped page maps to.
+    ///
+    /// The page table entry is rewritten in place, so the page is never
+    /// unmapped while it's repointed. The old frame is not deallocated.
+    ///
+    /// # Arguments
+    /// + `page`: the `VirtualPage` to repoint
+    /// + `new_frame`: the physical `Frame` that `page` should map to
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    ///
+    /// # Returns
+    /// + `Ok(PhysicalPage)` with the frame `page` was mapped to
+    /// + `Err(MapErr)` if `page` was not mapped
+    fn remap_to<A>( &mut self, page: VirtualPage, new_frame: PhysicalPage
+                  , flags: Self::Flags, alloc: &mut A )
+                  -> MapResult<PhysicalPage>
+    where A: FrameAllocator;
+
+    /// Change the page table entry flags for every mapped page in `range`.
+    ///
+    /// Unmapped pages are skipped, and the TLB is flushed once for the whole
+    /// range, rather than once for each page. This is intended for setting
+    /// up many pages at once, such as write-protecting them for
+    /// copy-on-write.
+    ///
+    /// # Arguments
+    /// + `range`: the range of `VirtualPage`s to update
+    /// + `flags`: the new page table entry flags.
+    ///
+    /// # Returns
+    /// + the number of mappings whose flags were changed. A huge page
+    ///   counts once, however much of it is in `range`.
+    fn protect_range(&mut self, range: PageRange, flags: Self::Flags)
+                    -> MapResult<usize>;
+
+    /// Returns whether `page` has been accessed since its accessed bit was
+    /// last cleared.
+    ///
+    /// # Returns
+    /// + `Some(bool)` if `page` is mapped
+    /// + `None` if `page` is not mapped
+    fn is_accessed(&self, page: VirtualPage) -> Option<bool>;
+
+    /// Returns whether `page` has been written to since it was mapped.
+    ///
+    /// # Returns
+    /// + `Some(bool)` if `page` is mapped
+    /// + `None` if `page` is not mapped
+    fn is_dirty(&self, page: VirtualPage) -> Option<bool>;
+
+    /// Clear the accessed bit for an already-mapped page.
+    ///
+    /// This is intended for page-replacement algorithms (such as clock or
+    /// aging) which sample the accessed bit periodically.
+    fn clear_accessed(&mut self, page: VirtualPage) -> MapResult<()>;
+
+    /// Modifies the page tables so that each page in `pages` maps to the
+    /// corresponding frame in `frames`.
+    ///
+    /// # Arguments
+    /// + `pages`: the range of `VirtualPage`s to map
+    /// + `frames`: the range of `PhysicalPage`s to map them to. This must be
+    ///             the same length as `pages`.
+    /// + `flags`: the page table entry flags.
+    /// + `alloc`: a memory allocator
+    fn map_range<A>( &mut self, pages: PageRange, frames: FrameRange
+                   , flags: Self::Flags, alloc: &mut A )
+                   -> MapResult<()>
+    where A: FrameAllocator {
+        if pages.length() != frames.length() {
+            return Err(MapErr::Other {
+                message: "map range"
+              , page: pages.start
+              , cause: "the page and frame ranges were not the same length"
+            })
+        }
+        for (page, frame) in pages.zip(frames) {
+            self.map(page, frame, flags, alloc)?;
+        }
+        Ok(())
+    }
+
+    /// Unmap every page in the given `PageRange`.
+    ///
+    /// All freed frames are returned to the given `FrameAllocator`.
+    fn unmap_range<A>(&mut self, pages: PageRange, alloc: &mut A)
+                     -> MapResult<()>
+    where A: FrameAllocator {
+        for page in pages {
+            self.unmap(page, alloc)?;
+        }
+        Ok(())
+    }
+
 }
@@ -8,14 +8,26 @@
 //
 //! Stack allocator
 use alloc::{AllocResult, AllocErr, FrameAllocator, Layout};
-use memory::{PageRange, VAddr};
-use ::Mapper;
+use memory::{Page, PageRange, VAddr, VirtualPage};
+use ::{Mapper, MapResult, MapErr};
 use arch::ActivePageTable;
 
+/// The first page of the region that `ActivePageTable::alloc_stack` places
+/// stacks in (PML4 entry 510, just below the recursive mapping).
+pub const STACK_AREA_START: usize = 0xffff_ff00_0000_0000 >> 12;
 
-use core::ops::Range;
+/// The number of pages in the region stacks are placed in.
+pub const STACK_AREA_PAGES: usize = 512 * 512 * 512;
 
-pub type Stack = Range<VAddr>;
+/// A kernel stack.
+///
+/// The stack grows down from `top` to `bottom`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Stack { /// The address just past the highest byte of the stack
+                   pub top: VAddr
+                 , /// The address of the lowest byte of the stack
+                   pub bottom: VAddr
+                 }
 
 pub trait StackAllocator {
     fn allocate<A>( &mut self
@@ -35,7 +47,7 @@ impl StackAllocator for PageRange {
         use memory::{PAGE_SIZE, Page};
         use arch::table::WRITABLE;
         let exhausted = || {
-            AllocErr::Exhausted {
+            AllocErr::OutOfMemory {
                 request: Layout::from_size_align( PAGE_SIZE as usize * num_pages
                                                 , PAGE_SIZE as usize)
             }
@@ -67,7 +79,96 @@ impl StackAllocator for PageRange {
             }
 
             let stack_top = end_page.end_address();
-            Ok(stack_top .. start_page.base())
+            Ok(Stack { top: stack_top, bottom: start_page.base() })
         }
     }
 }
+
+impl ActivePageTable {
+
+    /// Allocate a new kernel stack with a guard page below it.
+    ///
+    /// `size_pages` writable pages are mapped to newly allocated frames, and
+    /// the page just below the bottom of the stack is left unmapped, so that
+    /// overflowing the stack page faults instead of writing over whatever
+    /// is below it. The guard page is marked with `mark_guard`, so that the
+    /// fault can be reported as a stack overflow. The stack is placed in the
+    /// first unmapped run of pages in the stack region that is large enough.
+    ///
+    /// # Arguments
+    /// + `size_pages`: the number of usable pages in the stack
+    /// + `alloc`: the `FrameAllocator` to allocate frames from
+    ///
+    /// # Returns
+    /// + the new `Stack`
+    /// + `MapErr::NoPage` if `size_pages` is zero or the stack region is full
+    pub fn alloc_stack<A>(&mut self, size_pages: usize, alloc: &mut A)
+                         -> MapResult<Stack>
+    where A: FrameAllocator {
+        use arch::table::{NO_EXECUTE, WRITABLE};
+        if size_pages == 0 {
+            return Err(MapErr::NoPage {
+                message: "allocate stack"
+              , cause: "the stack must be at least one page"
+            })
+        }
+        // the guard page plus the stack itself
+        let n_pages = size_pages + 1;
+        let mut start = STACK_AREA_START;
+        let end = STACK_AREA_START + STACK_AREA_PAGES;
+        while start + n_pages <= end {
+            let run = VirtualPage { number: start }.range_of(n_pages);
+            let mapped = run.clone()
+                            .find(|&page| self.translate_page(page).is_some());
+            match mapped {
+                // if a page in the run is mapped, try starting after it.
+                Some(mapped) => start = mapped.number + 1
+              , None => {
+                    let guard = run.start;
+                    if let Err(why) = self.mark_guard(guard) {
+                        warn!("couldn't mark guard page {:?}: {}", guard, why);
+                    }
+                    let pages = (guard + 1).range_of(size_pages);
+                    for page in pages.clone() {
+                        self.map_to_any(page, WRITABLE | NO_EXECUTE, alloc)?;
+                    }
+                    let stack = Stack { top: pages.end.base()
+                                      , bottom: pages.start.base() };
+                    trace!("allocated {:?} with guard page {:?}", stack, guard);
+                    return Ok(stack)
+                }
+            }
+        }
+        Err(MapErr::NoPage {
+            message: "allocate stack"
+          , cause: "no unmapped region in the stack area was large enough"
+        })
+    }
+
+    /// Free a kernel stack that was allocated by `alloc_stack`.
+    ///
+    /// The stack's pages are unmapped and their frames are returned to
+    /// `alloc`, and its guard page is unmarked, so that the pages can be
+    /// used for another stack.
+    ///
+    /// # Arguments
+    /// + `stack`: the `Stack` to free, which must no longer be in use
+    /// + `alloc`: the `FrameAllocator` to return the stack's frames to
+    ///
+    /// # Returns
+    /// + the error from `unmap_range` if one of the stack's pages wasn't
+    ///   mapped
+    pub fn free_stack<A>(&mut self, stack: Stack, alloc: &mut A)
+                        -> MapResult<()>
+    where A: FrameAllocator {
+        let pages = VirtualPage::containing(stack.bottom) ..
+                    VirtualPage::containing(stack.top);
+        let guard = pages.start - 1;
+        self.unmap_range(pages, alloc)?;
+        if !self.unmark_guard(guard) {
+            warn!("{:?} had no guard page at {:?}", stack, guard);
+        }
+        trace!("freed {:?}", stack);
+        Ok(())
+    }
+}
@@ -22,7 +22,7 @@ extern crate memory;
 extern crate elf;
 extern crate arrayvec;
 
-use memory::{ PAddr, Page, PhysicalPage, FrameRange };
+use memory::{ Addr, PAddr, Page, PhysicalPage, FrameRange, PAGE_SIZE };
 use core::default::Default;
 use core::iter::Step;
 use core::slice::Iter as SliceIter;
@@ -32,6 +32,12 @@ pub mod mem;
 
 const MAX_MEM_AREAS: usize = 32;
 
+/// The number of frames in the first MiB of physical memory.
+///
+/// This is where the BIOS data, the VGA buffer, and other memory-mapped IO
+/// live, so none of it is ever allocated.
+pub const LOW_MEMORY_FRAMES: u64 = 256;
+
 /// If we are on x86_64 or armv7 this uses the 64-bit ELF word
 #[cfg(target_pointer_width = "64")]
 pub type ElfSections = elf::section::Sections<'static, u64>;
@@ -172,5 +178,127 @@ impl InitParams {
         self.mem_map.iter()
     }
 
+    /// Returns an iterator over the ranges of frames that are free to
+    /// allocate from.
+    ///
+    /// These are the frames in the usable areas of the memory map, less the
+    /// first MiB of physical memory, the kernel, and the multiboot info
+    /// structure. Only frames that lie entirely within a usable area are
+    /// included.
+    pub fn usable_frame_ranges(&self) -> UsableFrames {
+        let empty = PhysicalPage { number: 0 } .. PhysicalPage { number: 0 };
+        let multiboot = match (self.multiboot_start, self.multiboot_end) {
+            (Some(start), Some(end)) =>
+                PhysicalPage::containing(start) ..
+                PhysicalPage::containing(end).add_one()
+          , _ => empty
+        };
+        let mut reserved = [ PhysicalPage { number: 0 } ..
+                             PhysicalPage { number: LOW_MEMORY_FRAMES }
+                           , self.kernel_frames()
+                           , multiboot ];
+        // sort the reserved ranges by their start frames.
+        for i in 0..reserved.len() {
+            for j in i + 1..reserved.len() {
+                if reserved[j].start < reserved[i].start {
+                    reserved.swap(i, j);
+                }
+            }
+        }
+        UsableFrames { areas: self.mem_map(), reserved: reserved, rest: None }
+    }
+}
+
+/// An iterator over the ranges of frames that are free to allocate from.
+///
+/// This is returned by `InitParams::usable_frame_ranges`.
+#[derive(Clone, Debug)]
+pub struct UsableFrames<'a> { areas: mem::Map<'a>
+                            , /// Reserved ranges, sorted by their start frames
+                              reserved: [FrameRange; 3]
+                            , /// The part of the current area that hasn't
+                              /// been returned yet
+                              rest: Option<FrameRange>
+                            }
+
+impl<'a> Iterator for UsableFrames<'a> {
+    type Item = FrameRange;
+
+    fn next(&mut self) -> Option<FrameRange> {
+        loop {
+            let area = match self.rest.take() {
+                Some(rest) => rest
+              , None => match self.areas.next() {
+                    Some(area) if area.is_usable =>
+                        // the area's end address is inclusive
+                        PhysicalPage::containing(
+                            area.start_addr.align_up(PAGE_SIZE)) ..
+                        PhysicalPage::containing(area.end_addr + 1)
+                  , Some(_) => continue
+                  , None => return None
+                }
+            };
+            // skip past any reserved ranges the area starts in. since they're
+            // sorted, one pass finds ranges that overlap each other, too.
+            let mut start = area.start;
+            for reserved in &self.reserved {
+                if reserved.start <= start && start < reserved.end {
+                    start = reserved.end;
+                }
+            }
+            if start >= area.end { continue }
+            // stop at the next reserved range in the area.
+            let end = self.reserved.iter()
+                          .map(|reserved| reserved.start)
+                          .filter(|&next| start < next && next < area.end)
+                          .min()
+                          .unwrap_or(area.end);
+            if end < area.end {
+                self.rest = Some(end .. area.end);
+            }
+            return Some(start .. end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::Area;
 
+    #[test]
+    fn test_usable_frame_ranges() {
+        let mut params = InitParams::default();
+        // the kernel overlaps the low 1 MiB and the multiboot info.
+        params.kernel_base = PAddr::from(0xf_0000);
+        params.kernel_top = PAddr::from(0x20_0fff);
+        params.multiboot_start = Some(PAddr::from(0x20_0800));
+        params.multiboot_end = Some(PAddr::from(0x20_2fff));
+        params.mem_map.push(Area { start_addr: PAddr::from(0x0)
+                                 , end_addr: PAddr::from(0x7f_ffff)
+                                 , is_usable: true });
+        params.mem_map.push(Area { start_addr: PAddr::from(0x80_0000)
+                                 , end_addr: PAddr::from(0x8f_ffff)
+                                 , is_usable: false });
+        // only part of a frame at each end of this area is usable.
+        params.mem_map.push(Area { start_addr: PAddr::from(0x90_0800)
+                                 , end_addr: PAddr::from(0xff_f7ff)
+                                 , is_usable: true });
+
+        let frames = |start, end| PhysicalPage { number: start } ..
+                                  PhysicalPage { number: end };
+        let mut ranges = params.usable_frame_ranges();
+        assert_eq!(ranges.next(), Some(frames(0x203, 0x800)));
+        assert_eq!(ranges.next(), Some(frames(0x901, 0xfff)));
+        assert_eq!(ranges.next(), None);
+
+        // a reserved range in the middle of an area splits it.
+        params.multiboot_start = Some(PAddr::from(0x95_0000));
+        params.multiboot_end = Some(PAddr::from(0x95_1fff));
+        let mut ranges = params.usable_frame_ranges();
+        assert_eq!(ranges.next(), Some(frames(0x201, 0x800)));
+        assert_eq!(ranges.next(), Some(frames(0x901, 0x950)));
+        assert_eq!(ranges.next(), Some(frames(0x952, 0xfff)));
+        assert_eq!(ranges.next(), None);
+    }
 }
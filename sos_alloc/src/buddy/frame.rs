@@ -0,0 +1,383 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A buddy-block allocator for physical frames.
+//!
+//! Unlike the buddy [`Heap`], which keeps its free lists inside the free
+//! blocks themselves, this allocator can't touch the memory it manages, since
+//! physical frames aren't necessarily mapped. Instead, it keeps one byte for
+//! every frame, recording the order of the free block starting at that frame.
+//!
+//! A block of order `n` is `2^n` frames long, and always starts on a frame
+//! number that is a multiple of `2^n`, so an order-0 block is a single 4KiB
+//! frame.
+//!
+//! [`Heap`]: ../struct.Heap.html
+use frame::{Allocator, FrameStats};
+use ::{AllocResult, AllocErr, Layout};
+use params::mem;
+use memory::{Addr, FrameRange, Page, PhysicalPage as Frame, PAGE_SIZE};
+use super::math::PowersOf2;
+
+use core::cmp;
+
+/// The largest order of block this allocator manages (4MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// Set in the entry for a frame that starts a free block.
+const FREE: u8 = 0x80;
+
+/// A frame allocator that hands out power-of-two blocks of frames.
+///
+/// Frame `n` is tracked by byte `n` of the order map, so an order map of
+/// `n` bytes can track the first `n` frames of physical memory. Frames past
+/// the end of the order map are never allocated.
+pub struct BuddyFrameAllocator<'a> { orders: &'a mut [u8]
+                                   , n_usable: usize
+                                   , n_free: usize
+                                   }
+
+impl<'a> BuddyFrameAllocator<'a> {
+
+    /// Create a new `BuddyFrameAllocator` over the given memory areas.
+    ///
+    /// Every frame that lies entirely within a usable memory area starts out
+    /// free, and every other frame starts out used.
+    ///
+    /// # Arguments
+    /// + `orders`: the memory to store the order map in
+    /// + `areas`: the memory map
+    pub fn new(orders: &'a mut [u8], areas: mem::Map) -> Self {
+        for entry in orders.iter_mut() {
+            *entry = 0;
+        }
+        let mut allocator = BuddyFrameAllocator { orders: orders
+                                                , n_usable: 0
+                                                , n_free: 0
+                                                };
+        for area in areas.filter(|area| area.is_usable) {
+            // the area's end address is inclusive
+            let start = Frame::containing(area.start_addr.align_up(PAGE_SIZE));
+            let end = Frame::containing(area.end_addr + 1);
+            allocator.free_frames( start.number as usize
+                                 , end.number as usize);
+        }
+        allocator.n_usable = allocator.n_free;
+        allocator
+    }
+
+    /// Mark a range of frames as used, so that they will not be allocated.
+    ///
+    /// Frames that are already used, or that this allocator can't track, are
+    /// ignored.
+    pub fn reserve(&mut self, frames: FrameRange) {
+        trace!("reserving {:?}", frames);
+        for frame in frames {
+            self.take_frame(frame.number as usize);
+        }
+    }
+
+    /// Returns the number of frames this allocator can track.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Allocate a block of `2^order` contiguous frames.
+    ///
+    /// # Returns
+    /// + `Ok(Frame)` with the first frame of the block, which is aligned on
+    ///   `2^order` frames
+    /// + `Err(AllocErr)` if `order` is greater than `MAX_ORDER`, or there
+    ///   was no free block large enough
+    pub unsafe fn allocate_order(&mut self, order: usize)
+                                 -> AllocResult<Frame> {
+        if order > MAX_ORDER {
+            return Err(AllocErr::SizeTooLarge {
+                requested: (1 << order) * PAGE_SIZE as usize
+              , max: (1 << MAX_ORDER) * PAGE_SIZE as usize
+            })
+        }
+        for block_order in order .. MAX_ORDER + 1 {
+            if let Some(i) = self.find_block(block_order) {
+                self.orders[i] = 0;
+                // split the block, freeing the upper half each time, until
+                // it's the requested order.
+                for half_order in (order .. block_order).rev() {
+                    self.orders[i + (1 << half_order)]
+                        = FREE | half_order as u8;
+                }
+                self.n_free -= 1 << order;
+                let frame = Frame { number: i as u64 };
+                trace!("allocated order {} block at {:?}", order, frame);
+                return Ok(frame)
+            }
+        }
+        Err(exhausted(1 << order))
+    }
+
+    /// Returns true if a free block of the given order starts at frame `i`.
+    #[inline]
+    fn is_free_block(&self, i: usize, order: usize) -> bool {
+        i < self.capacity() && self.orders[i] == FREE | order as u8
+    }
+
+    /// Returns the index of the first free block of the given order.
+    fn find_block(&self, order: usize) -> Option<usize> {
+        // blocks of this order can only start on multiples of its size.
+        let mut i = 0;
+        while i < self.capacity() {
+            if self.is_free_block(i, order) { return Some(i) }
+            i += 1 << order;
+        }
+        None
+    }
+
+    /// Free the block of `2^order` frames starting at frame `i`, merging it
+    /// with its buddy for as long as the buddy is also free.
+    fn free_block(&mut self, mut i: usize, mut order: usize) {
+        self.n_free += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = i ^ (1 << order);
+            if !self.is_free_block(buddy, order) { break }
+            self.orders[buddy] = 0;
+            i = cmp::min(i, buddy);
+            order += 1;
+        }
+        self.orders[i] = FREE | order as u8;
+    }
+
+    /// Free every frame from `start` up to (but not including) `end`.
+    ///
+    /// The frames are freed as the largest aligned blocks that fit, and
+    /// frames that this allocator can't track are ignored.
+    fn free_frames(&mut self, mut start: usize, end: usize) {
+        let end = cmp::min(end, self.capacity());
+        while start < end {
+            let mut order = cmp::min( start.trailing_zeros() as usize
+                                    , MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Remove frame `i` from the free block containing it, if there is one.
+    ///
+    /// # Returns
+    /// + `true` if the frame was free
+    /// + `false` if it was already used
+    fn take_frame(&mut self, i: usize) -> bool {
+        if i >= self.capacity() { return false }
+        for order in 0 .. MAX_ORDER + 1 {
+            let head = i & !((1 << order) - 1);
+            if self.is_free_block(head, order) {
+                self.orders[head] = 0;
+                // split the block, freeing every half that doesn't contain
+                // frame `i`.
+                for half_order in (0 .. order).rev() {
+                    let half = (i & !((1 << half_order) - 1))
+                             ^ (1 << half_order);
+                    self.orders[half] = FREE | half_order as u8;
+                }
+                self.n_free -= 1;
+                return true
+            }
+        }
+        false
+    }
+
+}
+
+/// Returns the error for when no block of `num` frames is free.
+#[inline]
+fn exhausted(num: usize) -> AllocErr {
+    AllocErr::OutOfMemory {
+        request: Layout::from_size_align( num * PAGE_SIZE as usize
+                                        , PAGE_SIZE as usize)
+    }
+}
+
+impl<'a> Allocator for BuddyFrameAllocator<'a> {
+
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        self.allocate_order(0)
+    }
+
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        let i = frame.number as usize;
+        debug_assert!( i >= self.capacity() || !self.is_free_block(i, 0)
+                     , "double free of {:?}", frame);
+        self.free_frames(i, i + 1);
+        trace!("deallocated {:?}", frame);
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        self.allocate_contiguous(num, 1)
+            .map(|frame| frame.range_of(num))
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        trace!("deallocating {:?}", range);
+        self.free_frames( range.start.number as usize
+                        , range.end.number as usize);
+    }
+
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        self.reserve(range);
+        Ok(())
+    }
+
+    fn stats(&self) -> FrameStats {
+        FrameStats { total: self.n_usable
+                   , used: self.n_usable - self.n_free
+                   , free: self.n_free
+                   }
+    }
+
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        // a block is aligned on its own size, so the smallest block at least
+        // as large as both the request and its alignment will do.
+        let order = cmp::max(count, align_frames).next_pow2().log2();
+        let frame = self.allocate_order(order)?;
+        // give back the frames past the end of the request.
+        let start = frame.number as usize;
+        self.free_frames(start + count, start + (1 << order));
+        trace!("allocated {} contiguous frames at {:?}", count, frame);
+        Ok(frame)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use params::mem::Area;
+    use memory::PAddr;
+
+    /// A memory map where frames 0 through `n - 1` are usable.
+    fn areas(n: u64) -> [Area; 1] {
+        [ Area { start_addr: PAddr::from(0)
+               , end_addr: PAddr::from(n * PAGE_SIZE - 1)
+               , is_usable: true }
+        ]
+    }
+
+    #[test]
+    fn test_allocate_orders() {
+        let mut orders = [0; 64];
+        let areas = areas(64);
+        let mut allocator =
+            BuddyFrameAllocator::new(&mut orders, areas.iter());
+        let mut blocks = [Frame { number: 0 }; 5];
+        unsafe {
+            for order in 0..5 {
+                let frame = allocator.allocate_order(order)
+                                     .expect("no free block of this order");
+                assert_eq!(frame.number % (1 << order), 0);
+                blocks[order] = frame;
+            }
+        }
+        // no two blocks may overlap
+        for a in 0..5 {
+            for b in 0..5 {
+                if a == b { continue }
+                let start = blocks[b].number;
+                let end = start + (1 << b);
+                assert!( blocks[a].number < start || blocks[a].number >= end
+                       , "order {} block overlaps order {} block", a, b);
+            }
+        }
+        assert_eq!(allocator.stats().used, 1 + 2 + 4 + 8 + 16);
+    }
+
+    #[test]
+    fn test_order_too_large() {
+        let mut orders = [0; 16];
+        let areas = areas(16);
+        let mut allocator =
+            BuddyFrameAllocator::new(&mut orders, areas.iter());
+        let err = unsafe { allocator.allocate_order(MAX_ORDER + 1) }.err();
+        let max = (1 << MAX_ORDER) * PAGE_SIZE as usize;
+        assert_eq!( err
+                  , Some(AllocErr::SizeTooLarge { requested: 2 * max
+                                                , max: max }));
+        // there aren't enough frames for an order 5 block
+        assert!( unsafe { allocator.allocate_order(5) }
+                    .err()
+                    .map(|err| err.is_memory_exhausted())
+                    .unwrap_or(false));
+    }
+
+    #[test]
+    fn test_buddy_merge_on_free() {
+        let mut orders = [0; 16];
+        let areas = areas(16);
+        let mut allocator =
+            BuddyFrameAllocator::new(&mut orders, areas.iter());
+        unsafe {
+            let mut frames = [Frame { number: 0 }; 16];
+            for frame in frames.iter_mut() {
+                *frame = allocator.allocate().expect("no more frames");
+            }
+            assert!(allocator.allocate().is_err());
+            for frame in frames.iter() {
+                allocator.deallocate(*frame);
+            }
+            // every frame was freed, so the buddies should have merged back
+            // into a single block of order 4.
+            assert!(allocator.is_free_block(0, 4));
+            assert_eq!( allocator.allocate_order(4).ok()
+                      , Some(Frame { number: 0 }));
+        }
+    }
+
+    #[test]
+    fn test_allocate_range_frees_excess() {
+        let mut orders = [0; 8];
+        let areas = areas(8);
+        let mut allocator =
+            BuddyFrameAllocator::new(&mut orders, areas.iter());
+        unsafe {
+            let frames = allocator.allocate_range(3)
+                                  .expect("no run of 3 free frames");
+            assert_eq!(frames.start, Frame { number: 0 });
+            assert_eq!(allocator.stats().used, 3);
+            // the fourth frame of the order 2 block was given back
+            assert_eq!(allocator.allocate().ok(), Some(Frame { number: 3 }));
+            let frames = allocator.allocate_range(4)
+                                  .expect("no run of 4 free frames");
+            assert_eq!(frames.start, Frame { number: 4 });
+        }
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut orders = [0; 16];
+        let areas = areas(16);
+        let mut allocator =
+            BuddyFrameAllocator::new(&mut orders, areas.iter());
+        allocator.reserve(Frame { number: 5 }.range_of(1));
+        assert_eq!(allocator.stats().free, 15);
+        unsafe {
+            // the order 3 block containing frame 5 was split, so the only
+            // order 3 block left is the upper half.
+            assert_eq!( allocator.allocate_order(3).ok()
+                      , Some(Frame { number: 8 }));
+            let mut n_allocated = 0;
+            while let Ok(frame) = allocator.allocate() {
+                assert!(frame.number != 5, "allocated a reserved frame");
+                n_allocated += 1;
+            }
+            assert_eq!(n_allocated, 7);
+        }
+    }
+}
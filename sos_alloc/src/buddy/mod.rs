@@ -10,21 +10,21 @@
 
 #![warn(missing_docs)]
 mod math;
+pub mod frame;
 #[cfg(feature = "buddy_as_system")]
 pub mod system;
-#[cfg(feature = "buddy_as_system")]
-pub use self::system::BuddyFrameAllocator;
+pub use self::frame::BuddyFrameAllocator;
 
-use super::{Allocator, Layout, Address, AllocErr};
+use super::{Allocator, Layout, Address, AllocErr, CannotReallocInPlace};
 use self::math::PowersOf2;
 
-use core::mem;
+use core::{fmt, mem, ptr};
 use core::cmp::{max, min};
 use core::ptr::Unique;
 
 use intrusive::list::{List, Node};
 use intrusive::rawlink::RawLink;
-use memory::PAGE_SIZE;
+use memory::{PAGE_SIZE, VAddr};
 
 #[cfg(test)]
 mod test;
@@ -62,6 +62,41 @@ macro_rules! max {
     ($x:expr, $($xs:expr),+) => (max($x, max!($($xs),+)));
 }
 
+/// Errors that can occur while seeding a `Heap` from several memory regions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeapInitErr {
+    /// No regions were given.
+    NoRegions
+  , /// A region can't hold a single block once it's aligned, so there's no
+    /// room in it for even one `FreeBlock` header.
+    TooSmall { start: VAddr, size: usize, min_size: usize }
+  , /// Two regions overlap.
+    Overlapping { first: (VAddr, usize), second: (VAddr, usize) }
+  , /// The top of the heap isn't above its base.
+    Inverted { base: VAddr, top: VAddr }
+}
+
+impl fmt::Display for HeapInitErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeapInitErr::NoRegions =>
+                write!(f, "cannot initialize heap: no memory regions")
+          , HeapInitErr::TooSmall { start, size, min_size } =>
+                write!( f, "cannot initialize heap: the {} byte region at \
+                            {:#x} is smaller than one {} byte block"
+                      , size, start, min_size)
+          , HeapInitErr::Overlapping { first, second } =>
+                write!( f, "cannot initialize heap: the regions at {:#x} \
+                            ({} bytes) and {:#x} ({} bytes) overlap"
+                      , first.0, first.1, second.0, second.1)
+          , HeapInitErr::Inverted { base, top } =>
+                write!( f, "cannot initialize heap: the heap top {:#x} is not \
+                            above its base {:#x}"
+                      , top, base)
+        }
+    }
+}
+
 /// Structure with data for implementing the buddy block allocation strategy.
 pub struct Heap<'a> {
     /// Address of the base of the heap. This must be aligned
@@ -154,6 +189,125 @@ impl<'a> Heap<'a> {
         heap
     }
 
+    /// Construct a new `Heap` from several, possibly discontiguous, regions
+    /// of memory.
+    ///
+    /// The heap spans from the page containing the lowest region to the end
+    /// of the highest one, rounded up to a power of two, and only the
+    /// blocks inside a region are ever put on the free lists. Blocks never
+    /// merge across the gaps between regions, since a buddy in a gap is
+    /// never free.
+    ///
+    /// # Arguments
+    /// + `regions`: the start address and size (in bytes) of each region
+    /// + `free_lists`: an array of [`FreeList`]s, as for [`new`]
+    ///
+    /// # Returns
+    /// + `Ok(Heap)` containing every region
+    /// + `Err(HeapInitErr::NoRegions)` if `regions` is empty
+    /// + `Err(HeapInitErr::Overlapping)` if two regions overlap
+    /// + `Err(HeapInitErr::TooSmall)` if a region can't hold a single block
+    ///
+    /// # Panics
+    /// + If the array of `free_lists` is empty
+    ///
+    /// # Safety
+    /// + If any of the regions are not valid, you will have a bad time
+    ///
+    /// [`FreeList`]: type.FreeList.html
+    /// [`new`]: #method.new
+    pub unsafe fn from_regions( regions: &[(VAddr, usize)]
+                              , free_lists: &'a mut [FreeList])
+                              -> Result<Heap<'a>, HeapInitErr> {
+        let n_free_lists = free_lists.len();
+        assert!( n_free_lists > 0
+               , "Allocator must have at least one free list.");
+
+        for (i, &(start, size)) in regions.iter().enumerate() {
+            for &(other, other_size) in &regions[i + 1..] {
+                if *start < *other + other_size && *other < *start + size {
+                    return Err(HeapInitErr::Overlapping {
+                        first: (start, size)
+                      , second: (other, other_size)
+                    })
+                }
+            }
+        }
+
+        // start on a page boundary, so that blocks aligned within the heap
+        // are aligned in memory as well.
+        let base = regions.iter()
+                          .map(|&(start, _)| *start & !(PAGE_SIZE as usize - 1))
+                          .min()
+                          .ok_or(HeapInitErr::NoRegions)?;
+        let top = regions.iter()
+                         .map(|&(start, size)| *start + size)
+                         .max()
+                         .unwrap_or(base);
+        // the smallest block has to hold a free block header.
+        let heap_size
+            = max(top - base, mem::size_of::<FreeBlock>() << (n_free_lists - 1))
+                .next_power_of_two();
+        let min_block_size = heap_size >> (n_free_lists - 1);
+
+        // check every region before writing to any of them.
+        for &(start, size) in regions {
+            let (lo, hi) = Self::aligned_region( base, min_block_size
+                                               , *start, size);
+            if lo >= hi {
+                return Err(HeapInitErr::TooSmall { start: start
+                                                 , size: size
+                                                 , min_size: min_block_size
+                                                 })
+            }
+        }
+
+        for list in free_lists.iter_mut() {
+            *list = FreeList::new();
+        }
+        let mut heap
+            = Heap { start_addr: Unique::new(base as Address)
+                   , free_lists: free_lists
+                   , heap_size: heap_size
+                   , min_block_size: min_block_size
+                   };
+        for &(start, size) in regions {
+            let (lo, hi) = Self::aligned_region( base, min_block_size
+                                               , *start, size);
+            heap.push_region(lo, hi);
+        }
+        Ok(heap)
+    }
+
+    /// Returns the offsets from `base` of the first and last minimum-size
+    /// blocks that fit entirely within a region.
+    #[inline]
+    fn aligned_region( base: usize, min_block_size: usize
+                     , start: usize, size: usize)
+                     -> (usize, usize) {
+        let lo = (start - base + min_block_size - 1) & !(min_block_size - 1);
+        let hi = (start + size - base) & !(min_block_size - 1);
+        (lo, hi)
+    }
+
+    /// Push the blocks between offsets `lo` and `hi` onto the free lists,
+    /// using the largest aligned block that fits at each offset.
+    unsafe fn push_region(&mut self, lo: usize, hi: usize) {
+        let max_order = self.free_lists.len() - 1;
+        let mut offset = lo;
+        while offset < hi {
+            let mut order = max_order;
+            while order > 0
+                && ( offset % self.order_alloc_size(order) != 0
+                  || offset + self.order_alloc_size(order) > hi ) {
+                order -= 1;
+            }
+            let block = self.start_addr.as_ptr().offset(offset as isize);
+            self.push_block(block, order);
+            offset += self.order_alloc_size(order);
+        }
+    }
+
     /// Add a block of max order
     ///
     /// # Safety
@@ -185,10 +339,7 @@ impl<'a> Heap<'a> {
         let align = layout.align();
         debug_assert!(align.is_power_of_two());
         if align > PAGE_SIZE as usize {
-            Err(AllocErr::Unsupported {
-                details: "Cannot allocate requests with alignments greater \
-                          than the base alignment of the heap!"
-                })
+            Err(AllocErr::UnsupportedAlignment { requested: align })
         // If the request is valid, compute the size we need to allocate
         } else {
             // the allocation size for the request is the next power of 2
@@ -201,9 +352,8 @@ impl<'a> Heap<'a> {
             if alloc_size > self.heap_size {
                 // if the calculated size is greater than the size of the heap,
                 // we (obviously) cannot allocate this request.
-                Err(AllocErr::Unsupported {
-                    details: "Cannot allocate requests larger than the heap!"
-                })
+                Err(AllocErr::SizeTooLarge { requested: alloc_size
+                                           , max: self.heap_size })
             } else {
                 // otherwise, return the calculated size.
                 Ok(alloc_size)
@@ -367,7 +517,11 @@ unsafe impl<'a> Allocator for Heap<'a> {
         self.alloc_order(&layout)
             .and_then(|order|
                 if order > self.free_lists.len() - 1 {
-                    Err(AllocErr::Exhausted { request: layout.clone() })
+                    let max_order = self.free_lists.len() - 1;
+                    Err(AllocErr::SizeTooLarge {
+                        requested: self.order_alloc_size(order)
+                      , max: self.order_alloc_size(max_order)
+                    })
                 } else { Ok(order) } )
             // If the allocation order is defined, then we try to allocate
             // a block of that order. Otherwise, the request is invalid.
@@ -392,7 +546,7 @@ unsafe impl<'a> Allocator for Heap<'a> {
                         return Ok(block)
                     }
                 }
-                Err(AllocErr::Exhausted { request: layout })
+                Err(AllocErr::OutOfMemory { request: layout })
             })
     }
 
@@ -429,4 +583,63 @@ unsafe impl<'a> Allocator for Heap<'a> {
             return;
         }
     }
+
+    /// Resize the block at `ptr` to fit `new_layout`, moving it only if it
+    /// can't be resized in place.
+    ///
+    /// If the block moves, the lesser of the old and new sizes is copied to
+    /// the new block, and the old one is deallocated.
+    unsafe fn realloc( &mut self
+                     , ptr: Address
+                     , layout: Layout
+                     , new_layout: Layout)
+                     -> Result<Address, AllocErr> {
+        if self.realloc_in_place(ptr, layout.clone(), new_layout.clone())
+               .is_ok() {
+            return Ok(ptr)
+        }
+        let new_ptr = self.alloc(new_layout.clone())?;
+        trace!(target: "alloc", "in realloc(): moving block to {:p}", new_ptr);
+        ptr::copy_nonoverlapping( ptr as *const u8, new_ptr
+                                , min(layout.size(), new_layout.size()));
+        self.dealloc(ptr, layout);
+        Ok(new_ptr)
+    }
+
+    /// Resize the block at `ptr` to fit `new_layout` without moving it.
+    ///
+    /// A block shrinks by splitting off the halves it no longer needs onto
+    /// the free lists. It can only grow into the next order, by merging with
+    /// its buddy, if the buddy is free and comes after it.
+    unsafe fn realloc_in_place( &mut self
+                              , ptr: Address
+                              , layout: Layout
+                              , new_layout: Layout)
+                              -> Result<(), CannotReallocInPlace> {
+        let old_order = self.alloc_order(&layout)
+                            .map_err(|_| CannotReallocInPlace)?;
+        let new_order = self.alloc_order(&new_layout)
+                            .map_err(|_| CannotReallocInPlace)?;
+        if new_order < old_order {
+            self.split_block(ptr, old_order, new_order);
+            Ok(())
+        } else if new_order == old_order {
+            Ok(())
+        } else if new_order == old_order + 1
+               && new_order < self.free_lists.len() {
+            let buddy = self.get_buddy(old_order, ptr);
+            match buddy {
+                Some(buddy) if buddy > ptr
+                            && self.remove_block(old_order, buddy) => {
+                    trace!( target: "alloc"
+                          , "in realloc_in_place(): merged buddy {:p}"
+                          , buddy);
+                    Ok(())
+                }
+              , _ => Err(CannotReallocInPlace)
+            }
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
 }
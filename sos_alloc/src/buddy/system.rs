@@ -1,15 +1,16 @@
 //! This module integrates the buddy heap allocator into the Rust runtime.
-use spin::Mutex;
 use core::ptr;
 
-use ::{Allocator, Layout};
-use super::{Heap, FreeList};
+use memory::VAddr;
+
+use ::{Allocator, Layout, LockedAllocator};
+use super::{Heap, HeapInitErr, FreeList};
 
 /// The number of free lists for the kernel heap
 pub const NUM_FREE_LISTS: usize = 19;
 
-static ALLOC: Mutex<Option<Heap<'static>>>
-    = Mutex::new(None);
+static ALLOC: LockedAllocator<Option<Heap<'static>>>
+    = LockedAllocator::new(None);
 
 static mut KERNEL_FREE_LISTS: [FreeList; NUM_FREE_LISTS]
     // TODO: I really wish there was a less awful way to do this...
@@ -22,22 +23,37 @@ static mut KERNEL_FREE_LISTS: [FreeList; NUM_FREE_LISTS]
       , FreeList::new()
       , ];
 
-/// Initialize the system heap at the given start address
+/// Initialize the system heap from one or more regions of memory
 ///
 /// # Arguments
-/// + `start_addr`: a pointer to the start address of the kernel heap
-/// + `heap_size`: the maximum size (in bytes) of the kernel heap
+/// + `regions`: the start address and size (in bytes) of each region of
+///   memory to use for the kernel heap
+///
+/// # Returns
+/// + `Ok(())` if the heap was initialized
+/// + `Err(HeapInitErr)` if the regions overlap, or one is too small to hold
+///   a single block
 ///
 /// # Panics
 /// + If called once the kernel heap is already initialized
-pub unsafe fn init_heap(start_addr: *mut u8, heap_size: usize ) {
+pub unsafe fn init_heap(regions: &[(VAddr, usize)]) -> Result<(), HeapInitErr> {
     assert_has_not_been_called!("the kernel heap may not be initialized \
                                  more than once!");
     trace!(target: "alloc", "init_heap() was called.");
-    *(ALLOC.lock())
-        = Some(Heap::new( start_addr
-                                      , &mut KERNEL_FREE_LISTS
-                                      , heap_size));
+    let heap = Heap::from_regions(regions, &mut KERNEL_FREE_LISTS)?;
+    *(ALLOC.lock()) = Some(heap);
+    Ok(())
+}
+
+/// Forcibly unlock the kernel heap.
+///
+/// This is intended for the panic path, so that a CPU which panicked while
+/// holding the heap lock doesn't deadlock the panic handler.
+///
+/// # Safety
+/// + If another CPU is still using the heap, it may be corrupted.
+pub unsafe fn force_unlock() {
+    ALLOC.force_unlock()
 }
 
 // -- integrate the heap allocator into the Rust runtime ------------------
@@ -105,55 +121,3 @@ pub extern "C" fn __rust_reallocate_inplace( _ptr: *mut u8
 pub extern "C" fn __rust_usable_size(size: usize, _: usize) -> usize {
     size
 }
-
-/// A frame allocator using the system's buddy-block heap allocator
-// quick first pass on using the heap allocator as a frame allocator
-// TODO: this is Extremely Bad And Ugly And Awful. pleae make better.
-//       – eliza, 1/21/2017
-pub struct BuddyFrameAllocator;
-
-impl BuddyFrameAllocator {
-    /// Construct a new `BuddyFrameAllocator`
-    pub const fn new() -> Self { BuddyFrameAllocator }
-}
-//
-// impl FrameAllocator for BuddyFrameAllocator {
-//
-//     unsafe fn allocate(&self) -> Option<PhysicalPage> {
-//         ALLOC.lock().as_mut()
-//              .expect("Cannot allocate frame, no system allocator exists!")
-//              .allocate(PAGE_SIZE as usize, PAGE_SIZE as usize)
-//              .map(|block| {
-//                 let addr = VAddr::from_ptr(block);
-//                 // TODO: make this not be bad and ugly.
-//                 PhysicalPage::containing_addr(
-//                     PAddr::from(addr.as_usize() as u64))
-//              })
-//
-//     }
-//
-//     unsafe fn deallocate(&self, frame: PhysicalPage) {
-//         ALLOC.lock().as_mut()
-//              .expect("Cannot deallocate frame, no system allocator exists!")
-//              .deallocate( frame.as_mut_ptr()
-//                         , PAGE_SIZE as usize
-//                         , PAGE_SIZE as usize);
-//
-//     }
-//
-//     unsafe fn allocate_range(&self, _num: usize) -> Option<FrameRange> {
-//         unimplemented!()
-//     }
-//
-//     unsafe fn deallocate_range(&self, range: FrameRange) {
-//         for frame in range {
-//             ALLOC.lock().as_mut()
-//                  .expect("Cannot deallocate frames, no system allocator exists")
-//                  .deallocate( frame.as_mut_ptr()
-//                             , PAGE_SIZE as usize
-//                             , PAGE_SIZE as usize);
-//         }
-//     }
-//
-//
-// }
@@ -179,3 +179,205 @@ fn test_alloc_and_dealloc() {
         free(mem);
     }
 }
+
+#[test]
+fn test_realloc_grow_in_place() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+
+        let block = heap.alloc(Layout::from_size_align(16, 16)).unwrap();
+        assert_eq!(mem, block);
+        *block = 42;
+        // the buddy at offset 16 is free, so the block grows into it.
+        let grown = heap.realloc( block, Layout::from_size_align(16, 16)
+                                , Layout::from_size_align(32, 16));
+        assert_eq!(Ok(block), grown);
+        assert_eq!(42, *block);
+        // the buddy is no longer free.
+        assert_eq!( Ok(mem.offset(32))
+                  , heap.alloc(Layout::from_size_align(16, 16)));
+        // growing by two orders is never done in place.
+        assert!(heap.realloc_in_place( block, Layout::from_size_align(32, 16)
+                                     , Layout::from_size_align(128, 16))
+                    .is_err());
+        free(mem);
+    }
+}
+
+#[test]
+fn test_realloc_shrink_in_place() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+
+        let block = heap.alloc(Layout::from_size_align(128, 16)).unwrap();
+        assert_eq!(mem, block);
+        let shrunk = heap.realloc( block, Layout::from_size_align(128, 16)
+                                 , Layout::from_size_align(16, 16));
+        assert_eq!(Ok(block), shrunk);
+        // the halves split off the block can be allocated again.
+        assert_eq!( Ok(mem.offset(16))
+                  , heap.alloc(Layout::from_size_align(16, 16)));
+        assert_eq!( Ok(mem.offset(32))
+                  , heap.alloc(Layout::from_size_align(32, 16)));
+        assert_eq!( Ok(mem.offset(64))
+                  , heap.alloc(Layout::from_size_align(64, 16)));
+        free(mem);
+    }
+}
+
+#[test]
+fn test_realloc_moves() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+
+        let block = heap.alloc(Layout::from_size_align(16, 16)).unwrap();
+        // take the block's buddy, so it can't grow in place.
+        let buddy = heap.alloc(Layout::from_size_align(16, 16)).unwrap();
+        assert_eq!(mem.offset(16), buddy);
+        ptr::write_bytes(block, 0xab, 16);
+
+        let moved = heap.realloc( block, Layout::from_size_align(16, 16)
+                                , Layout::from_size_align(32, 16))
+                        .unwrap();
+        assert_eq!(mem.offset(32), moved);
+        for i in 0..16 {
+            assert_eq!(0xab, *moved.offset(i));
+        }
+        // the old block was freed.
+        assert_eq!(Ok(block), heap.alloc(Layout::from_size_align(16, 16)));
+        free(mem);
+    }
+}
+
+#[test]
+fn test_alloc_errors() {
+    use ::AllocErr;
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, HEAP_SIZE);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let mut heap = Heap::new( mem, &mut free_lists, HEAP_SIZE );
+
+        let too_large = heap.alloc(Layout::from_size_align(512, 1));
+        assert_eq!( too_large.err()
+                  , Some(AllocErr::SizeTooLarge { requested: 512
+                                                , max: HEAP_SIZE }));
+        assert_eq!( heap.alloc(Layout::from_size_align(512, 1))
+                        .err()
+                        .and_then(|err| err.max_size())
+                  , Some(HEAP_SIZE));
+
+        let too_aligned = heap.alloc(Layout::from_size_align(16, 8192));
+        assert_eq!( too_aligned.err()
+                  , Some(AllocErr::UnsupportedAlignment { requested: 8192 }));
+
+        let whole_heap = heap.alloc(Layout::from_size_align(HEAP_SIZE, 1))
+                             .unwrap();
+        let layout = Layout::from_size_align(16, 16);
+        let out_of_memory = heap.alloc(layout.clone()).err();
+        assert!(out_of_memory.as_ref()
+                             .map(|err| err.is_memory_exhausted())
+                             .unwrap_or(false));
+        assert_eq!( out_of_memory
+                  , Some(AllocErr::OutOfMemory { request: layout }));
+
+        heap.dealloc(whole_heap, Layout::from_size_align(HEAP_SIZE, 1));
+        free(mem);
+    }
+}
+
+#[test]
+fn test_heap_from_two_regions() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, 4096);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        // two regions, with a 1024 byte gap between them.
+        let regions = [ (VAddr::from_ptr(mem), 1024)
+                      , (VAddr::from_ptr(mem.offset(2048)), 2048) ];
+        let mut heap = Heap::from_regions(&regions, &mut free_lists)
+                           .expect("couldn't seed heap from two regions");
+        assert_eq!(heap.min_block_size, 256);
+
+        let block_2048 = heap.alloc(Layout::from_size_align(2048, 1));
+        assert_eq!(Ok(mem.offset(2048)), block_2048);
+        let block_1024 = heap.alloc(Layout::from_size_align(1024, 1));
+        assert_eq!(Ok(mem), block_1024);
+        // nothing was allocated from the gap.
+        assert!(heap.alloc(Layout::from_size_align(256, 1)).is_err());
+
+        heap.dealloc(block_1024.unwrap(), Layout::from_size_align(1024, 1));
+        heap.dealloc(block_2048.unwrap(), Layout::from_size_align(2048, 1));
+        // the regions don't merge across the gap.
+        assert!(heap.alloc(Layout::from_size_align(4096, 1)).is_err());
+        let block_1024 = heap.alloc(Layout::from_size_align(1024, 1));
+        assert_eq!(Ok(mem), block_1024);
+
+        free(mem);
+    }
+}
+
+#[test]
+fn test_heap_from_bad_regions() {
+    unsafe {
+        let mem = memalign(HEAP_ALIGN, 4096);
+        let mut free_lists: [FreeList; 5]
+            = [ FreeList::new(), FreeList::new()
+              , FreeList::new(), FreeList::new()
+              , FreeList::new()
+              ];
+        let start = VAddr::from_ptr(mem);
+
+        assert_eq!( Heap::from_regions(&[], &mut free_lists).err()
+                  , Some(HeapInitErr::NoRegions));
+
+        let undersized = VAddr::from_ptr(mem.offset(2048));
+        assert_eq!( Heap::from_regions( &[(start, 1024), (undersized, 100)]
+                                      , &mut free_lists).err()
+                  , Some(HeapInitErr::TooSmall { start: undersized
+                                               , size: 100
+                                               , min_size: 256 }));
+
+        // big enough, but not once it's aligned to the minimum block size.
+        let unaligned = VAddr::from_ptr(mem.offset(100));
+        assert_eq!( Heap::from_regions(&[(unaligned, 300)], &mut free_lists)
+                        .err()
+                  , Some(HeapInitErr::TooSmall { start: unaligned
+                                               , size: 300
+                                               , min_size: 256 }));
+
+        let overlapping = VAddr::from_ptr(mem.offset(512));
+        assert_eq!( Heap::from_regions( &[(start, 1024), (overlapping, 1024)]
+                                      , &mut free_lists).err()
+                  , Some(HeapInitErr::Overlapping {
+                        first: (start, 1024)
+                      , second: (overlapping, 1024)
+                    }));
+
+        free(mem);
+    }
+}
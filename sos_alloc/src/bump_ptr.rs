@@ -36,7 +36,7 @@ unsafe impl Allocator for BumpPtr {
         // TODO: can this be a saturating add?
         let end = start + layout.size() as <PAddr as Addr>::Repr;
         if end > self.end {
-            Err(AllocErr::Exhausted{ request: layout.clone() })
+            Err(AllocErr::OutOfMemory{ request: layout.clone() })
         } else {
             // bump
             self.ptr = end;
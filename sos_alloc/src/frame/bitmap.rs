@@ -0,0 +1,432 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2015-2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! A frame allocator using a bitmap.
+//!
+//! Every frame of physical memory is tracked by a single bit, which is set
+//! if the frame is in use. Unlike the [`MemMapAllocator`], this allocator can
+//! reuse frames after they are deallocated.
+//!
+//! [`MemMapAllocator`]: ../mem_map/struct.MemMapAllocator.html
+use super::{ Frame, FrameRange, FrameStats, Allocator
+           , reserve_boot_frames };
+use ::{AllocResult, AllocErr, Layout};
+use params::{InitParams, mem};
+use memory::{Addr, Page, PAGE_SIZE};
+
+use core::cmp;
+
+/// The number of frames tracked by each word in the bitmap.
+const BITS_PER_WORD: usize = 64;
+
+/// A frame allocator that tracks free frames with a bitmap.
+///
+/// Frame `n` is tracked by bit `n % 64` of word `n / 64` in the bitmap, so
+/// a bitmap of `n` words can track the first `64 * n` frames of physical
+/// memory. Frames past the end of the bitmap are never allocated.
+pub struct BitmapFrameAllocator<'a> { bitmap: &'a mut [u64]
+                                    , next_free: usize
+                                    , n_usable: usize
+                                    , n_free: usize
+                                    }
+
+impl<'a> BitmapFrameAllocator<'a> {
+
+    /// Create a new `BitmapFrameAllocator` over the given memory areas.
+    ///
+    /// Every frame that lies entirely within a usable memory area starts out
+    /// free, and every other frame starts out used.
+    ///
+    /// # Arguments
+    /// + `bitmap`: the memory to store the bitmap in
+    /// + `areas`: the memory map
+    pub fn new(bitmap: &'a mut [u64], areas: mem::Map) -> Self {
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let mut allocator = BitmapFrameAllocator { bitmap: bitmap
+                                                 , next_free: 0
+                                                 , n_usable: 0
+                                                 , n_free: 0
+                                                 };
+        for area in areas.filter(|area| area.is_usable) {
+            // the area's end address is inclusive
+            let start = Frame::containing(area.start_addr.align_up(PAGE_SIZE));
+            let end = Frame::containing(area.end_addr + 1);
+            allocator.set_range(start .. end, false);
+        }
+        allocator.n_usable = allocator.n_free;
+        allocator
+    }
+
+    /// Create a new `BitmapFrameAllocator` from the memory map in the
+    /// given `InitParams`.
+    ///
+    /// The first MiB of physical memory, and the frames containing the
+    /// kernel's ELF sections, the kernel heap, and the multiboot info
+    /// structure are marked as used, as by `reserve_boot_frames`.
+    ///
+    /// # Arguments
+    /// + `params`: the `InitParams` to create the allocator from
+    /// + `bitmap`: the memory to store the bitmap in
+    pub fn from_params(params: &InitParams, bitmap: &'a mut [u64]) -> Self {
+        let mut allocator = BitmapFrameAllocator::new(bitmap, params.mem_map());
+        reserve_boot_frames(&mut allocator, params)
+            .expect("a bitmap frame allocator can always reserve frames");
+        allocator.reserve(params.heap_frames());
+        trace!("created bitmap frame allocator tracking {} frames"
+              , allocator.capacity());
+        allocator
+    }
+
+    /// Mark a range of frames as used, so that they will not be allocated.
+    #[inline]
+    pub fn reserve(&mut self, frames: FrameRange) {
+        trace!("reserving {:?}", frames);
+        self.set_range(frames, true)
+    }
+
+    /// Returns the number of frames this allocator can track.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.bitmap.len() * BITS_PER_WORD
+    }
+
+    /// Returns true if the frame at index `i` is in use.
+    #[inline]
+    fn is_used(&self, i: usize) -> bool {
+        self.bitmap[i / BITS_PER_WORD] & (1 << (i % BITS_PER_WORD)) != 0
+    }
+
+    /// Set whether or not the frame at index `i` is in use.
+    #[inline]
+    fn set(&mut self, i: usize, used: bool) {
+        let bit = 1 << (i % BITS_PER_WORD);
+        match (self.is_used(i), used) {
+            (false, true) => self.n_free -= 1
+          , (true, false) => self.n_free += 1
+          , _ => {}
+        }
+        if used {
+            self.bitmap[i / BITS_PER_WORD] |= bit;
+        } else {
+            self.bitmap[i / BITS_PER_WORD] &= !bit;
+        }
+    }
+
+    /// Set whether or not every frame in `frames` is in use.
+    ///
+    /// Frames that this allocator can't track are ignored.
+    fn set_range(&mut self, frames: FrameRange, used: bool) {
+        let capacity = self.capacity();
+        for frame in frames {
+            let i = frame.number as usize;
+            if i < capacity { self.set(i, used) }
+        }
+    }
+
+    /// Returns the index of the first frame in a run of `count` free frames
+    /// whose index is a multiple of `align`.
+    fn find_run(&self, count: usize, align: usize) -> Option<usize> {
+        assert!(align > 0, "frame alignment must be nonzero");
+        let capacity = self.capacity();
+        let mut start = 0;
+        while start + count <= capacity {
+            match (start .. start + count).find(|&i| self.is_used(i)) {
+                // if a frame in the run is used, try the next aligned start
+                // after it.
+                Some(used) => start = (used + align) / align * align
+              , None => return Some(start)
+            }
+        }
+        None
+    }
+
+}
+
+/// Returns the error for when no frames are free.
+#[inline]
+fn exhausted(num: usize) -> AllocErr {
+    AllocErr::OutOfMemory {
+        request: Layout::from_size_align( num * PAGE_SIZE as usize
+                                        , PAGE_SIZE as usize)
+    }
+}
+
+impl<'a> Allocator for BitmapFrameAllocator<'a> {
+
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        let n_words = self.bitmap.len();
+        // start searching at the word containing the next free frame, and
+        // wrap around to the beginning of the bitmap.
+        let start = self.next_free / BITS_PER_WORD;
+        for w in (start .. n_words).chain(0 .. start) {
+            let word = self.bitmap[w];
+            if word != !0 {
+                let i = w * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+                self.set(i, true);
+                self.next_free = i + 1;
+                let frame = Frame { number: i as u64 };
+                trace!("allocated {:?}", frame);
+                return Ok(frame)
+            }
+        }
+        Err(exhausted(1))
+    }
+
+    /// The nearest free frame to `hint` is used. If two free frames are
+    /// equally near, the one after `hint` is used, and a hint past the end of
+    /// the bitmap is treated as the last frame in it.
+    unsafe fn allocate_near(&mut self, hint: Frame) -> AllocResult<Frame> {
+        let capacity = self.capacity();
+        if capacity == 0 { return Err(exhausted(1)) }
+        let hint = cmp::min(hint.number as usize, capacity - 1);
+        for distance in 0..capacity {
+            let after = hint + distance;
+            let nearest = if after < capacity && !self.is_used(after) {
+                Some(after)
+            } else if distance > 0 && distance <= hint
+                   && !self.is_used(hint - distance) {
+                Some(hint - distance)
+            } else if after >= capacity && distance >= hint {
+                break
+            } else {
+                None
+            };
+            if let Some(i) = nearest {
+                self.set(i, true);
+                let frame = Frame { number: i as u64 };
+                trace!("allocated {:?} near frame #{}", frame, hint);
+                return Ok(frame)
+            }
+        }
+        Err(exhausted(1))
+    }
+
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        let i = frame.number as usize;
+        if i >= self.capacity() { return }
+        debug_assert!(self.is_used(i), "double free of {:?}", frame);
+        self.set(i, false);
+        self.next_free = i;
+        trace!("deallocated {:?}", frame);
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        let start = self.find_run(num, 1).ok_or(exhausted(num))?;
+        let frames = Frame { number: start as u64 }.range_of(num);
+        self.set_range(frames.clone(), true);
+        trace!("allocated {:?}", frames);
+        Ok(frames)
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range {
+            self.deallocate(frame);
+        }
+    }
+
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        self.reserve(range);
+        Ok(())
+    }
+
+    fn stats(&self) -> FrameStats {
+        FrameStats { total: self.n_usable
+                   , used: self.n_usable - self.n_free
+                   , free: self.n_free
+                   }
+    }
+
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        let start = self.find_run(count, cmp::max(align_frames, 1))
+                        .ok_or(exhausted(count))?;
+        let frame = Frame { number: start as u64 };
+        self.set_range(frame.range_of(count), true);
+        trace!("allocated {} contiguous frames at {:?}", count, frame);
+        Ok(frame)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use params::mem::Area;
+    use params::InitParams;
+    use memory::PAddr;
+
+    #[test]
+    fn test_allocate_until_exhausted() {
+        let mut bitmap = [0; 2];
+        // frames 1 through 8 are usable; everything else is not.
+        let areas = [ Area { start_addr: PAddr::from(0x0800)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    , Area { start_addr: PAddr::from(0x9000)
+                           , end_addr: PAddr::from(0xffff)
+                           , is_usable: false }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        allocator.reserve(Frame { number: 3 }.range_of(1));
+
+        let mut n_allocated = 0;
+        unsafe {
+            while let Ok(frame) = allocator.allocate() {
+                assert!(frame.number >= 1 && frame.number <= 8);
+                assert!(frame.number != 3, "allocated a reserved frame");
+                n_allocated += 1;
+            }
+            assert_eq!(n_allocated, 7);
+
+            allocator.deallocate(Frame { number: 5 });
+            assert_eq!(allocator.allocate().ok(), Some(Frame { number: 5 }));
+            assert!(allocator.allocate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_allocate_near() {
+        let mut bitmap = [0; 1];
+        // frames 1 through 8 are usable.
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        allocator.reserve(Frame { number: 4 }.range_of(3));
+        unsafe {
+            let near = |allocator: &mut BitmapFrameAllocator, n| {
+                allocator.allocate_near(Frame { number: n }).ok()
+            };
+            // 7 and 3 are both two frames from 5; the one after wins.
+            assert_eq!(near(&mut allocator, 5), Some(Frame { number: 7 }));
+            assert_eq!(near(&mut allocator, 5), Some(Frame { number: 3 }));
+            assert_eq!(near(&mut allocator, 5), Some(Frame { number: 8 }));
+            // a free hint is used as is.
+            assert_eq!(near(&mut allocator, 1), Some(Frame { number: 1 }));
+            // past the end of the bitmap, the last free frame is nearest.
+            assert_eq!(near(&mut allocator, 1000), Some(Frame { number: 2 }));
+            assert_eq!(near(&mut allocator, 5), None);
+        }
+    }
+
+    #[test]
+    fn test_allocate_range() {
+        let mut bitmap = [0; 1];
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        allocator.reserve(Frame { number: 3 }.range_of(1));
+        unsafe {
+            let frames = allocator.allocate_range(4)
+                                  .expect("no run of 4 free frames");
+            assert_eq!(frames.start, Frame { number: 4 });
+            assert!(allocator.allocate_range(4).is_err());
+        }
+    }
+
+    #[test]
+    fn test_allocate_contiguous_aligned() {
+        let mut bitmap = [0; 4];
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0xfffff)
+                           , is_usable: true }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        // a used frame inside the first aligned run
+        allocator.reserve(Frame { number: 20 }.range_of(1));
+        unsafe {
+            // 16 frames aligned to 64 KiB
+            let frame = allocator.allocate_contiguous(16, 16)
+                                 .expect("no aligned run of 16 free frames");
+            assert_eq!(*frame.base_addr() % 0x10000, 0);
+            assert_eq!(frame, Frame { number: 32 });
+            for i in 0..16 {
+                assert!(allocator.is_used(32 + i));
+            }
+            allocator.deallocate_contiguous(frame, 16);
+            assert!(!allocator.is_used(32));
+        }
+    }
+
+    #[test]
+    fn test_reserve_range() {
+        let mut bitmap = [0; 1];
+        let areas = [ Area { start_addr: PAddr::from(0x0)
+                           , end_addr: PAddr::from(0x3_ffff)
+                           , is_usable: true }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        let reserved = Frame { number: 10 } .. Frame { number: 20 };
+        allocator.reserve_range(reserved.clone())
+                 .expect("couldn't reserve frames");
+        unsafe {
+            let mut n_allocated = 0;
+            while let Ok(frame) = allocator.allocate() {
+                assert!( frame < reserved.start || frame >= reserved.end
+                       , "allocated reserved frame {:?}", frame);
+                n_allocated += 1;
+            }
+            assert_eq!(n_allocated, 64 - 10);
+        }
+    }
+
+    #[test]
+    fn test_reserve_boot_frames() {
+        let mut bitmap = [0; 8];
+        let mut params = InitParams::default();
+        params.mem_map.push(Area { start_addr: PAddr::from(0x0)
+                                 , end_addr: PAddr::from(0x1f_ffff)
+                                 , is_usable: true });
+        params.multiboot_start = Some(PAddr::from(0x10_0800));
+        params.multiboot_end = Some(PAddr::from(0x10_1fff));
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, params.mem_map());
+        reserve_boot_frames(&mut allocator, &params)
+            .expect("couldn't reserve boot frames");
+        unsafe {
+            let frame = allocator.allocate().expect("no more frames");
+            // the first frame after the low 1 MiB and the multiboot info
+            assert_eq!(frame, Frame { number: 0x102 });
+            while let Ok(frame) = allocator.allocate() {
+                assert!(frame.number >= 0x102, "allocated {:?}", frame);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut bitmap = [0; 1];
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    ];
+        let mut allocator =
+            BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        let before = allocator.stats();
+        assert_eq!(before, FrameStats { total: 8, used: 0, free: 8 });
+        unsafe {
+            for _ in 0..5 {
+                allocator.allocate().expect("no more frames");
+            }
+        }
+        let after = allocator.stats();
+        assert_eq!(after.used - before.used, 5);
+        assert_eq!(after.free, 3);
+        assert_eq!(after.total, before.total);
+    }
+}
@@ -111,7 +111,7 @@ impl<'a> Allocator for MemMapAllocator<'a> {
             self.allocate()
         } else {
             // println!("No free frames remain!");
-            Err(AllocErr::Exhausted {
+            Err(AllocErr::OutOfMemory {
                     request: Layout::from_size_align( PAGE_SIZE as usize, PAGE_SIZE as usize)
             })
         }
@@ -8,12 +8,55 @@
 //
 //! Frame allocation
 #![warn(missing_docs)]
-use memory::{FrameRange, PhysicalPage as Frame};
-use super::AllocResult;
-use core::ops;
+use memory::{FrameRange, Page, PhysicalPage as Frame, PAGE_SIZE};
+use super::{AllocErr, AllocResult};
+use params::InitParams;
+use core::{fmt, ops, usize};
+use core::iter::Step;
 use spin::Mutex;
 
 pub mod mem_map;
+pub mod bitmap;
+pub mod refcount;
+
+/// Statistics on the frames managed by a frame allocator.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The total number of frames managed by the allocator
+    pub total: usize
+  , /// The number of frames currently in use
+    pub used: usize
+  , /// The number of frames currently free
+    pub free: usize
+}
+
+impl FrameStats {
+    /// Statistics for an allocator which doesn't track them.
+    pub const UNKNOWN: FrameStats = FrameStats { total: usize::MAX
+                                               , used: usize::MAX
+                                               , free: usize::MAX
+                                               };
+
+    /// Returns true if these are the `UNKNOWN` statistics.
+    #[inline]
+    pub fn is_unknown(&self) -> bool { *self == FrameStats::UNKNOWN }
+
+    /// Log these statistics, in the style of `kinfoln!`.
+    pub fn dump(&self) {
+        info!(" . . Frames: {}", self);
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_unknown() {
+            write!(f, "unknown")
+        } else {
+            write!( f, "{} used, {} free, {} total"
+                  , self.used, self.free, self.total)
+        }
+    }
+}
 
 /// An allocator for allocating physical frames.
 pub trait Allocator: Sized  {
@@ -23,11 +66,129 @@ pub trait Allocator: Sized  {
     /// Deallocate a frame
     unsafe fn deallocate(&mut self, frame: Frame);
 
+    /// Allocate a new frame, as close as possible to `hint`.
+    ///
+    /// Keeping the frames backing a buffer close together makes it more
+    /// likely they can later be promoted to a huge page. By default, the
+    /// hint is ignored.
+    unsafe fn allocate_near(&mut self, _hint: Frame) -> AllocResult<Frame> {
+        self.allocate()
+    }
+
     /// Allocate a range of frames
     unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange>;
     /// Deallocate a range of frames
     unsafe fn deallocate_range(&mut self, range: FrameRange);
 
+    /// Allocate `count` physically contiguous frames, starting on a frame
+    /// number that is a multiple of `align_frames`.
+    ///
+    /// This is intended for things like DMA buffers, which must be
+    /// physically contiguous. By default, only unaligned requests
+    /// (`align_frames <= 1`) are supported.
+    ///
+    /// # Returns
+    /// + `Ok(Frame)` with the first frame of the contiguous run
+    /// + `Err(AllocErr)` if there was no suitable run of free frames
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        if align_frames > 1 {
+            return Err(AllocErr::UnsupportedAlignment {
+                requested: align_frames * PAGE_SIZE as usize
+            })
+        }
+        self.allocate_range(count).map(|range| range.start)
+    }
+
+    /// Deallocate `count` contiguous frames starting at `start`.
+    unsafe fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        self.deallocate_range(start.range_of(count))
+    }
+
+    /// Add a reference to a frame that is shared between several mappings.
+    ///
+    /// A shared frame should only actually be deallocated once
+    /// `remove_ref` has been called once for every reference to it.
+    /// Allocators that cannot track shared frames return
+    /// `AllocErr::Unsupported`.
+    unsafe fn add_ref(&mut self, _frame: Frame) -> AllocResult<()> {
+        Err(AllocErr::Unsupported {
+            details: "this allocator cannot share frames"
+        })
+    }
+
+    /// Remove a reference to a frame, such as when a page mapping it is
+    /// unmapped.
+    ///
+    /// Allocators that cannot track shared frames treat every reference as
+    /// the last one.
+    ///
+    /// # Returns
+    /// + the number of references left. Once this is zero, the frame should
+    ///   be deallocated.
+    unsafe fn remove_ref(&mut self, _frame: Frame) -> usize { 0 }
+
+    /// Mark every frame in `range` as used, so that it will never be
+    /// allocated.
+    ///
+    /// This is for frames which were in use before the allocator was
+    /// created, such as the kernel image. Allocators that cannot reserve
+    /// frames return `AllocErr::Unsupported`.
+    fn reserve_range(&mut self, _range: FrameRange) -> AllocResult<()> {
+        Err(AllocErr::Unsupported {
+            details: "this allocator cannot reserve frames"
+        })
+    }
+
+    /// Returns statistics on the frames managed by this allocator.
+    ///
+    /// Allocators which don't track these return `FrameStats::UNKNOWN`.
+    fn stats(&self) -> FrameStats { FrameStats::UNKNOWN }
+
+    /// Returns the number of references to `frame`.
+    ///
+    /// # Returns
+    /// + `Some(usize)` with the number of references, if this allocator
+    ///   tracks shared frames.
+    /// + `None` if it doesn't.
+    fn ref_count(&self, _frame: Frame) -> Option<usize> { None }
+
+}
+
+pub use params::LOW_MEMORY_FRAMES;
+
+/// Reserve every frame that's in use before any allocation happens.
+///
+/// This marks the first MiB of physical memory, the frames containing the
+/// kernel's allocated ELF sections, and the frames containing the multiboot
+/// info structure as used. It should be called on a new allocator, before
+/// anything is allocated from it.
+///
+/// # Returns
+/// + `Err(AllocErr)` if `allocator` can't reserve frames
+pub fn reserve_boot_frames<A>(allocator: &mut A, params: &InitParams)
+                              -> AllocResult<()>
+where A: Allocator {
+    use elf::Section;
+
+    allocator.reserve_range(Frame { number: 0 } .. Frame {
+        number: LOW_MEMORY_FRAMES
+    })?;
+    if let Some(ref sections) = params.elf_sections {
+        for section in sections.clone().filter(|s| s.is_allocated()) {
+            allocator.reserve_range(
+                Frame::containing(section.address()) ..
+                Frame::containing(section.end_address()).add_one()
+            )?;
+        }
+    }
+    // TODO: handle non-multiboot case
+    if let (Some(start), Some(end)) =
+        (params.multiboot_start, params.multiboot_end) {
+        allocator.reserve_range( Frame::containing(start) ..
+                                 Frame::containing(end).add_one())?;
+    }
+    Ok(())
 }
 
 /// An allocator capable of lending [borrowed frame]s
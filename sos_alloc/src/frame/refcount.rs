@@ -0,0 +1,266 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Counting the references to frames shared between several mappings.
+//!
+//! Copy-on-write and shared memory map the same frame at more than one
+//! page, so a frame can only be freed once the last of those pages is
+//! unmapped. A [`RefCounted`] allocator counts each frame's references in a
+//! [`FrameRefCount`], and `unmap` only deallocates a frame once
+//! `remove_ref` says its last reference is gone.
+//!
+//! [`RefCounted`]: struct.RefCounted.html
+//! [`FrameRefCount`]: struct.FrameRefCount.html
+use super::{Allocator, Frame, FrameRange, FrameStats};
+use ::{AllocErr, AllocResult};
+use params::InitParams;
+use memory::{Page, PAGE_SIZE};
+
+/// A slab of reference counts, indexed by frame number.
+///
+/// Frame `n`'s count is stored in entry `n` of the slab, so a slab of `n`
+/// counters can track the first `n` frames of physical memory. Frames past
+/// the end of the slab are never counted.
+pub struct FrameRefCount<'a> { counts: &'a mut [u32] }
+
+impl<'a> FrameRefCount<'a> {
+    /// Create a new `FrameRefCount` with every count set to zero.
+    ///
+    /// # Arguments
+    /// + `counts`: the memory to store the counts in
+    pub fn new(counts: &'a mut [u32]) -> Self {
+        for count in counts.iter_mut() {
+            *count = 0;
+        }
+        FrameRefCount { counts: counts }
+    }
+
+    /// Returns the number of counters needed to track every frame of usable
+    /// memory in `params`' memory map.
+    pub fn counters_for(params: &InitParams) -> usize {
+        params.mem_map()
+              .filter(|area| area.is_usable)
+              // the area's end address is inclusive
+              .map(|area| ((*area.end_addr + 1) / PAGE_SIZE) as usize)
+              .max()
+              .unwrap_or(0)
+    }
+
+    /// Returns the number of frames this `FrameRefCount` can track.
+    #[inline]
+    pub fn capacity(&self) -> usize { self.counts.len() }
+
+    /// Returns the number of references to `frame`.
+    ///
+    /// Frames past the end of the slab always have zero references.
+    #[inline]
+    pub fn get(&self, frame: Frame) -> u32 {
+        self.counts.get(frame.number as usize).cloned().unwrap_or(0)
+    }
+
+    /// Add a reference to `frame`.
+    ///
+    /// # Returns
+    /// + `Ok(u32)` with the new number of references
+    /// + `Err(AllocErr)` if `frame` is past the end of the slab
+    pub fn incr(&mut self, frame: Frame) -> AllocResult<u32> {
+        let count = self.counts.get_mut(frame.number as usize)
+                        .ok_or(AllocErr::invalid_input(
+                            "the frame is past the end of the count slab"))?;
+        *count += 1;
+        Ok(*count)
+    }
+
+    /// Remove a reference to `frame`.
+    ///
+    /// Frames with no references, and frames past the end of the slab, are
+    /// left at zero.
+    ///
+    /// # Returns
+    /// + the new number of references
+    pub fn decr(&mut self, frame: Frame) -> u32 {
+        match self.counts.get_mut(frame.number as usize) {
+            Some(count) => {
+                *count = count.saturating_sub(1);
+                *count
+            }
+          , None => 0
+        }
+    }
+
+    /// Forget every reference to `frame`.
+    #[inline]
+    fn clear(&mut self, frame: Frame) {
+        if let Some(count) = self.counts.get_mut(frame.number as usize) {
+            *count = 0;
+        }
+    }
+}
+
+/// A frame allocator that counts the references to the frames it allocates.
+///
+/// Every frame starts out with one reference when it's allocated, and
+/// `add_ref` adds another each time it's shared. `remove_ref` returns zero
+/// once the last reference is gone, and the frame can be deallocated.
+/// Deallocating a frame frees it whatever its count is.
+pub struct RefCounted<'a, A> { alloc: A
+                             , refs: FrameRefCount<'a>
+                             }
+
+impl<'a, A> RefCounted<'a, A>
+where A: Allocator {
+    /// Count the references to frames allocated from `alloc` in `refs`.
+    pub fn new(alloc: A, refs: FrameRefCount<'a>) -> Self {
+        RefCounted { alloc: alloc, refs: refs }
+    }
+
+    /// Returns the reference counts.
+    #[inline]
+    pub fn refs(&self) -> &FrameRefCount<'a> { &self.refs }
+
+    /// Returns the allocator that frames are allocated from.
+    #[inline]
+    pub fn inner(&self) -> &A { &self.alloc }
+
+    /// Give the first reference to each newly allocated frame in `range`.
+    fn count_new(&mut self, range: FrameRange) {
+        for frame in range {
+            // frames past the end of the slab just aren't counted.
+            let _ = self.refs.incr(frame);
+        }
+    }
+}
+
+impl<'a, A> Allocator for RefCounted<'a, A>
+where A: Allocator {
+
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        let frame = self.alloc.allocate()?;
+        self.count_new(frame.range_of(1));
+        Ok(frame)
+    }
+
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        self.refs.clear(frame);
+        self.alloc.deallocate(frame)
+    }
+
+    unsafe fn allocate_near(&mut self, hint: Frame) -> AllocResult<Frame> {
+        let frame = self.alloc.allocate_near(hint)?;
+        self.count_new(frame.range_of(1));
+        Ok(frame)
+    }
+
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        let range = self.alloc.allocate_range(num)?;
+        self.count_new(range.clone());
+        Ok(range)
+    }
+
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        for frame in range.clone() {
+            self.refs.clear(frame);
+        }
+        self.alloc.deallocate_range(range)
+    }
+
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        let start = self.alloc.allocate_contiguous(count, align_frames)?;
+        self.count_new(start.range_of(count));
+        Ok(start)
+    }
+
+    unsafe fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        for frame in start.range_of(count) {
+            self.refs.clear(frame);
+        }
+        self.alloc.deallocate_contiguous(start, count)
+    }
+
+    unsafe fn add_ref(&mut self, frame: Frame) -> AllocResult<()> {
+        self.refs.incr(frame).map(|_| ())
+    }
+
+    unsafe fn remove_ref(&mut self, frame: Frame) -> usize {
+        self.refs.decr(frame) as usize
+    }
+
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        self.alloc.reserve_range(range)
+    }
+
+    fn stats(&self) -> FrameStats { self.alloc.stats() }
+
+    fn ref_count(&self, frame: Frame) -> Option<usize> {
+        Some(self.refs.get(frame) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::bitmap::BitmapFrameAllocator;
+    use params::mem::Area;
+    use memory::PAddr;
+
+    #[test]
+    fn test_incr_and_decr() {
+        let mut counts = [0; 4];
+        let mut refs = FrameRefCount::new(&mut counts);
+        let frame = Frame { number: 2 };
+        assert_eq!(refs.incr(frame), Ok(1));
+        assert_eq!(refs.incr(frame), Ok(2));
+        assert_eq!(refs.get(Frame { number: 1 }), 0);
+        assert_eq!(refs.decr(frame), 1);
+        assert_eq!(refs.decr(frame), 0);
+        // the count doesn't go below zero.
+        assert_eq!(refs.decr(frame), 0);
+
+        // frames past the end of the slab aren't counted.
+        assert!(refs.incr(Frame { number: 4 }).is_err());
+        assert_eq!(refs.decr(Frame { number: 4 }), 0);
+    }
+
+    #[test]
+    fn test_shared_frame_refs() {
+        let mut bitmap = [0; 1];
+        let mut counts = [0; 64];
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    ];
+        let frames = BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        let mut alloc = RefCounted::new(frames, FrameRefCount::new(&mut counts));
+        unsafe {
+            let frame = alloc.allocate().unwrap();
+            assert_eq!(alloc.ref_count(frame), Some(1));
+            alloc.add_ref(frame).unwrap();
+            assert_eq!(alloc.ref_count(frame), Some(2));
+
+            assert_eq!(alloc.remove_ref(frame), 1);
+            assert_eq!(alloc.remove_ref(frame), 0);
+            let used = alloc.stats().used;
+            alloc.deallocate(frame);
+            assert_eq!(alloc.stats().used, used - 1);
+            assert_eq!(alloc.ref_count(frame), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_counters_for() {
+        let mut params = InitParams::default();
+        params.mem_map.push(Area { start_addr: PAddr::from(0x0)
+                                 , end_addr: PAddr::from(0x9fff)
+                                 , is_usable: true });
+        params.mem_map.push(Area { start_addr: PAddr::from(0xa000)
+                                 , end_addr: PAddr::from(0xffff_ffff)
+                                 , is_usable: false });
+        assert_eq!(FrameRefCount::counters_for(&params), 10);
+    }
+}
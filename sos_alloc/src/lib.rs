@@ -60,6 +60,7 @@
 #![cfg_attr(test, feature(collections))]
 #[cfg(all(test, feature = "bench"))] extern crate test;
 #[cfg(test)] extern crate collections;
+#[cfg(all(test, feature = "std"))] #[macro_use] extern crate std;
 
 extern crate memory;
 
@@ -77,8 +78,9 @@ extern crate spin;
 #[macro_use] extern crate log;
 
 extern crate params;
+extern crate elf;
 
-use core::{cmp, ops, ptr, mem};
+use core::{cmp, fmt, ops, ptr, mem};
 use ptr::Unique;
 
 pub type AllocResult<T> = Result<T, AllocErr>;
@@ -90,7 +92,18 @@ pub type Alignment = usize;
 pub type Address = *mut u8;
 
 pub mod frame;
-pub use frame::{Allocator as FrameAllocator, Lender as FrameLender};
+pub use frame::{ Allocator as FrameAllocator, FrameStats
+               , Lender as FrameLender };
+pub use frame::refcount::{FrameRefCount, RefCounted};
+
+pub mod locked;
+pub use locked::LockedAllocator;
+
+pub mod region;
+pub use region::VirtualRegionAllocator;
+
+pub mod unified;
+pub use unified::UnifiedAllocator;
 
 /// Represents the combination of a starting address and
 /// a total capacity of the returned block.
@@ -369,7 +382,17 @@ pub enum AllocErr {
     /// out of memory. This condition strongly implies that *some*
     /// series of deallocations would allow a subsequent reissuing of
     /// the original allocation request to succeed.
-    Exhausted { request: Layout },
+    OutOfMemory { request: Layout },
+
+    /// The requested alignment (in bytes) is larger than this allocator can
+    /// satisfy.
+    UnsupportedAlignment { requested: usize },
+
+    /// The requested size (in bytes) is larger than the largest allocation
+    /// this allocator can make, which is `max` bytes.
+    ///
+    /// A smaller request may still succeed.
+    SizeTooLarge { requested: usize, max: usize },
 
     /// Error due to allocator being fundamentally incapable of
     /// satisfying the original request. This condition implies that
@@ -387,10 +410,49 @@ impl AllocErr {
         AllocErr::Unsupported { details: details }
     }
     pub fn is_memory_exhausted(&self) -> bool {
-        if let AllocErr::Exhausted { .. } = *self { true } else { false }
+        if let AllocErr::OutOfMemory { .. } = *self { true } else { false }
     }
+
+    /// Returns true if this allocator can never satisfy the request.
+    ///
+    /// This used to be true only for `Unsupported`; it's now also true for
+    /// `UnsupportedAlignment` and `SizeTooLarge`, which split out cases
+    /// that were reported as `Unsupported`.
     pub fn is_request_unsupported(&self) -> bool {
-        if let AllocErr::Unsupported { .. } = *self { true } else { false }
+        match *self {
+            AllocErr::Unsupported { .. }
+          | AllocErr::UnsupportedAlignment { .. }
+          | AllocErr::SizeTooLarge { .. } => true
+          , _ => false
+        }
+    }
+
+    /// Returns the largest size (in bytes) the allocator could have
+    /// allocated, if the request was too large.
+    ///
+    /// Callers can use this to decide whether to retry with a smaller
+    /// request.
+    pub fn max_size(&self) -> Option<usize> {
+        if let AllocErr::SizeTooLarge { max, .. } = *self { Some(max) }
+        else { None }
+    }
+}
+
+impl fmt::Display for AllocErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AllocErr::OutOfMemory { ref request } =>
+                write!( f, "out of memory ({} bytes aligned to {} requested)"
+                      , request.size(), request.align())
+          , AllocErr::UnsupportedAlignment { requested } =>
+                write!(f, "unsupported alignment of {} bytes", requested)
+          , AllocErr::SizeTooLarge { requested, max } =>
+                write!( f, "{} bytes requested, but at most {} can be \
+                            allocated"
+                      , requested, max)
+          , AllocErr::Unsupported { details } =>
+                write!(f, "unsupported request: {}", details)
+        }
     }
 }
 
@@ -0,0 +1,327 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Allocators shared between several CPUs.
+use spin::{Mutex, MutexGuard};
+
+use memory::{FrameRange, PhysicalPage as Frame};
+
+use super::{Address, AllocErr, AllocResult, Allocator, Capacity, Layout};
+use super::frame::{Allocator as FrameAllocator, FrameStats};
+
+/// An allocator wrapped in a spinlock, so that it may be shared between
+/// several CPUs.
+///
+/// Both `LockedAllocator<A>` and `&LockedAllocator<A>` implement `Allocator`
+/// and `FrameAllocator` when `A` does, so a single locked allocator in a
+/// `static` can be used from every CPU. Each call locks the allocator for
+/// as long as it runs.
+pub struct LockedAllocator<A> { inner: Mutex<A> }
+
+impl<A> LockedAllocator<A> {
+    /// Wrap `allocator` in a lock.
+    pub const fn new(allocator: A) -> Self {
+        LockedAllocator { inner: Mutex::new(allocator) }
+    }
+
+    /// Lock the allocator, spinning until it's available.
+    #[inline]
+    pub fn lock(&self) -> MutexGuard<A> { self.inner.lock() }
+
+    /// Lock the allocator if it isn't already locked.
+    #[inline]
+    pub fn try_lock(&self) -> Option<MutexGuard<A>> { self.inner.try_lock() }
+
+    /// Forcibly unlock the allocator.
+    ///
+    /// This is intended for the panic path, where the CPU that panicked
+    /// may have been holding the lock, and something still needs to be
+    /// allocated.
+    ///
+    /// # Safety
+    /// + If anything is still using the allocator, two CPUs may modify it at
+    ///   once and corrupt it.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+unsafe impl<'a, A> Allocator for &'a LockedAllocator<A>
+where A: Allocator {
+
+    #[inline]
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<Address, AllocErr> {
+        self.lock().alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: Address, layout: Layout) {
+        self.lock().dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn usable_size(&self, layout: &Layout) -> (Capacity, Capacity) {
+        self.lock().usable_size(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc( &mut self
+                     , ptr: Address
+                     , layout: Layout
+                     , new_layout: Layout)
+                     -> Result<Address, AllocErr> {
+        // lock once, rather than once for each of the allocation, the copy,
+        // and the deallocation.
+        self.lock().realloc(ptr, layout, new_layout)
+    }
+}
+
+unsafe impl<A> Allocator for LockedAllocator<A>
+where A: Allocator {
+
+    #[inline]
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<Address, AllocErr> {
+        (&*self).alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&mut self, ptr: Address, layout: Layout) {
+        (&*self).dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn usable_size(&self, layout: &Layout) -> (Capacity, Capacity) {
+        self.lock().usable_size(layout)
+    }
+
+    #[inline]
+    unsafe fn realloc( &mut self
+                     , ptr: Address
+                     , layout: Layout
+                     , new_layout: Layout)
+                     -> Result<Address, AllocErr> {
+        (&*self).realloc(ptr, layout, new_layout)
+    }
+}
+
+impl<'a, A> FrameAllocator for &'a LockedAllocator<A>
+where A: FrameAllocator {
+
+    #[inline]
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        self.lock().allocate()
+    }
+
+    #[inline]
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        self.lock().deallocate(frame)
+    }
+
+    #[inline]
+    unsafe fn allocate_near(&mut self, hint: Frame) -> AllocResult<Frame> {
+        self.lock().allocate_near(hint)
+    }
+
+    #[inline]
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        self.lock().allocate_range(num)
+    }
+
+    #[inline]
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        self.lock().deallocate_range(range)
+    }
+
+    #[inline]
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        self.lock().allocate_contiguous(count, align_frames)
+    }
+
+    #[inline]
+    unsafe fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        self.lock().deallocate_contiguous(start, count)
+    }
+
+    #[inline]
+    unsafe fn add_ref(&mut self, frame: Frame) -> AllocResult<()> {
+        self.lock().add_ref(frame)
+    }
+
+    #[inline]
+    unsafe fn remove_ref(&mut self, frame: Frame) -> usize {
+        self.lock().remove_ref(frame)
+    }
+
+    #[inline]
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        self.lock().reserve_range(range)
+    }
+
+    #[inline]
+    fn stats(&self) -> FrameStats { self.lock().stats() }
+
+    #[inline]
+    fn ref_count(&self, frame: Frame) -> Option<usize> {
+        self.lock().ref_count(frame)
+    }
+}
+
+impl<A> FrameAllocator for LockedAllocator<A>
+where A: FrameAllocator {
+
+    #[inline]
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        (&*self).allocate()
+    }
+
+    #[inline]
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        (&*self).deallocate(frame)
+    }
+
+    #[inline]
+    unsafe fn allocate_near(&mut self, hint: Frame) -> AllocResult<Frame> {
+        (&*self).allocate_near(hint)
+    }
+
+    #[inline]
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        (&*self).allocate_range(num)
+    }
+
+    #[inline]
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        (&*self).deallocate_range(range)
+    }
+
+    #[inline]
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        (&*self).allocate_contiguous(count, align_frames)
+    }
+
+    #[inline]
+    unsafe fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        (&*self).deallocate_contiguous(start, count)
+    }
+
+    #[inline]
+    unsafe fn add_ref(&mut self, frame: Frame) -> AllocResult<()> {
+        (&*self).add_ref(frame)
+    }
+
+    #[inline]
+    unsafe fn remove_ref(&mut self, frame: Frame) -> usize {
+        (&*self).remove_ref(frame)
+    }
+
+    #[inline]
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        (&*self).reserve_range(range)
+    }
+
+    #[inline]
+    fn stats(&self) -> FrameStats { self.lock().stats() }
+
+    #[inline]
+    fn ref_count(&self, frame: Frame) -> Option<usize> {
+        self.lock().ref_count(frame)
+    }
+}
+
+#[cfg(all(test, feature = "std", feature = "buddy"))]
+mod tests {
+    use super::*;
+    use ::buddy::{FreeList, Heap};
+
+    use std::boxed::Box;
+    use std::ptr;
+    use std::thread;
+    use std::vec::Vec;
+
+    extern "C" {
+        /// We need this to allocate aligned memory for our heap.
+        #[cfg(target_os = "macos")]
+        #[link_name = "je_posix_memalign"]
+        fn memalign(alignment: usize, size: usize) -> *mut u8;
+
+        #[cfg(not(target_os = "macos"))]
+        fn memalign(alignment: usize, size: usize) -> *mut u8;
+    }
+
+    const HEAP_SIZE: usize = 4096;
+    const N_ROUNDS: usize = 10_000;
+
+    /// Allocate and free blocks in `heap` over and over, checking that no
+    /// other thread writes to a block while we own it.
+    fn hammer(heap: &'static LockedAllocator<Heap<'static>>, tag: u8) {
+        let mut heap = heap;
+        for round in 0..N_ROUNDS {
+            let size = 16 << (round % 4);
+            let layout = Layout::from_size_align(size, 16);
+            unsafe {
+                let block = match heap.alloc(layout.clone()) {
+                    Ok(block) => block
+                    // the other thread may be holding the rest of the heap
+                  , Err(_) => continue
+                };
+                ptr::write_bytes(block, tag, size);
+                thread::yield_now();
+                let bytes = ::core::slice::from_raw_parts(block, size);
+                assert!( bytes.iter().all(|&byte| byte == tag)
+                       , "block {:?} was written to by another thread"
+                       , block);
+                heap.dealloc(block, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_alloc_and_free() {
+        // the heap has to outlive both threads, so it's leaked.
+        let heap: &'static LockedAllocator<Heap<'static>> = unsafe {
+            let mem = memalign(HEAP_SIZE, HEAP_SIZE);
+            let free_lists: &'static mut [FreeList; 9] =
+                &mut *Box::into_raw(Box::new(
+                    [ FreeList::new(), FreeList::new(), FreeList::new()
+                    , FreeList::new(), FreeList::new(), FreeList::new()
+                    , FreeList::new(), FreeList::new(), FreeList::new()
+                    ]));
+            let heap = Heap::new(mem, free_lists, HEAP_SIZE);
+            &*Box::into_raw(Box::new(LockedAllocator::new(heap)))
+        };
+
+        let threads: Vec<_> = (0..2u8).map(|tag| {
+            thread::spawn(move || hammer(heap, tag + 1))
+        }).collect();
+        for thread in threads {
+            thread.join().expect("a thread panicked");
+        }
+
+        // if the free lists weren't corrupted, every block was merged back
+        // together, and the whole heap can be allocated at once.
+        let mut heap = heap;
+        let layout = Layout::from_size_align(HEAP_SIZE, 16);
+        unsafe {
+            let block = heap.alloc(layout.clone())
+                            .expect("the heap wasn't merged back together");
+            heap.dealloc(block, layout);
+        }
+    }
+
+    #[test]
+    fn test_force_unlock() {
+        let locked = LockedAllocator::new(0usize);
+        let guard = locked.lock();
+        assert!(locked.try_lock().is_none());
+        unsafe { locked.force_unlock() };
+        assert!(locked.try_lock().is_some());
+        ::core::mem::forget(guard);
+    }
+}
@@ -0,0 +1,262 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Allocating regions of virtual address space.
+use memory::{MemRange, Page, PageRange, VirtualPage};
+
+use super::{AllocErr, AllocResult};
+
+/// The maximum number of free regions a `VirtualRegionAllocator` can track.
+pub const MAX_FREE_REGIONS: usize = 32;
+
+/// A contiguous run of free pages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Free { start: VirtualPage
+            , end: VirtualPage
+            }
+
+impl Free {
+    /// Returns the range of pages in this region.
+    #[inline]
+    fn pages(&self) -> PageRange { self.start .. self.end }
+}
+
+/// Tracks the free regions of a virtual address space.
+///
+/// Free regions are kept sorted by address, so that a range being freed
+/// only has to be compared with its neighbours to be merged with them.
+/// Allocations are placed in the smallest free region they fit in.
+pub struct VirtualRegionAllocator { free: [Free; MAX_FREE_REGIONS]
+                                  , len: usize
+                                  }
+
+impl VirtualRegionAllocator {
+    /// Returns a new `VirtualRegionAllocator` with no free regions.
+    pub fn new() -> Self {
+        let empty = Free { start: VirtualPage { number: 0 }
+                         , end: VirtualPage { number: 0 } };
+        VirtualRegionAllocator { free: [empty; MAX_FREE_REGIONS], len: 0 }
+    }
+
+    /// Returns the free regions, in order of address.
+    #[inline]
+    fn regions(&self) -> &[Free] { &self.free[..self.len] }
+
+    /// Returns true if `page` is free.
+    pub fn contains(&self, page: VirtualPage) -> bool {
+        self.regions().iter().any(|region| region.pages().contains_page(page))
+    }
+
+    /// Returns the total number of free pages.
+    pub fn free_pages(&self) -> usize {
+        self.regions().iter().map(|region| region.pages().length()).sum()
+    }
+
+    /// Insert `region` at `index`, moving the regions after it up.
+    fn insert(&mut self, index: usize, region: Free) -> AllocResult<()> {
+        if self.len == MAX_FREE_REGIONS {
+            return Err(AllocErr::invalid_input("too many free regions"))
+        }
+        let mut i = self.len;
+        while i > index {
+            self.free[i] = self.free[i - 1];
+            i -= 1;
+        }
+        self.free[index] = region;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the region at `index`, moving the regions after it down.
+    fn remove(&mut self, index: usize) {
+        for i in index .. self.len - 1 {
+            self.free[i] = self.free[i + 1];
+        }
+        self.len -= 1;
+    }
+
+    /// Allocate `pages` contiguous pages, starting on a page number that's
+    /// a multiple of `align`.
+    ///
+    /// The smallest free region that the request fits in is used.
+    ///
+    /// # Returns
+    /// + `Some(PageRange)` if a large enough free region existed
+    /// + `None` if no free region was large enough
+    ///
+    /// # Panics
+    /// + If `align` is not a power of two
+    pub fn alloc(&mut self, pages: usize, align: usize) -> Option<PageRange> {
+        assert!( align.is_power_of_two()
+               , "alignment must be a power of two, not {}", align);
+        if pages == 0 { return None }
+
+        let full = self.len == MAX_FREE_REGIONS;
+        let best = self.regions().iter().enumerate()
+            .filter_map(|(i, region)| {
+                let start = (region.start.number + align - 1) & !(align - 1);
+                let fits = start + pages <= region.end.number;
+                // splitting a region in two needs another slot.
+                let splits = start != region.start.number
+                          && start + pages != region.end.number;
+                if fits && !(splits && full) {
+                    Some((i, VirtualPage { number: start }))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|&(i, _)| self.free[i].pages().length());
+
+        best.map(|(i, start)| {
+            let region = self.free[i];
+            let end = start + pages;
+            match (region.start == start, region.end == end) {
+                (true, true) => self.remove(i)
+              , (true, false) => self.free[i].start = end
+              , (false, true) => self.free[i].end = start
+              , (false, false) => {
+                    self.free[i].end = start;
+                    let _ = self.insert(i + 1, Free { start: end
+                                                    , end: region.end });
+                }
+            }
+            trace!(target: "alloc", "allocated virtual region {:?}"
+                  , start .. end);
+            start .. end
+        })
+    }
+
+    /// Return `range` to the free regions, merging it with any free regions
+    /// directly before or after it.
+    ///
+    /// # Returns
+    /// + `Err(AllocErr)` if `range` overlaps a free region, or it can't be
+    ///   merged and `MAX_FREE_REGIONS` regions are already free.
+    pub fn free(&mut self, range: PageRange) -> AllocResult<()> {
+        if range.is_empty_range() { return Ok(()) }
+        // the index of the first free region after `range`
+        let next = self.regions().iter()
+                       .position(|region| region.start > range.start)
+                       .unwrap_or(self.len);
+        let overlaps = self.regions()[next.saturating_sub(1) .. next]
+                           .iter()
+                           .chain(self.regions()[next..].iter().take(1))
+                           .any(|region| region.pages().overlaps(&range));
+        if overlaps {
+            return Err(AllocErr::invalid_input(
+                "the range overlaps a free region"))
+        }
+
+        let joins_prev = next > 0 && self.free[next - 1].end == range.start;
+        let joins_next = next < self.len && self.free[next].start == range.end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.free[next - 1].end = self.free[next].end;
+                self.remove(next);
+            }
+          , (true, false) => self.free[next - 1].end = range.end
+          , (false, true) => self.free[next].start = range.start
+          , (false, false) => {
+                self.insert(next, Free { start: range.start
+                                       , end: range.end })?;
+            }
+        }
+        trace!(target: "alloc", "freed virtual region {:?}", range);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: usize, end: usize) -> PageRange {
+        VirtualPage { number: start } .. VirtualPage { number: end }
+    }
+
+    #[test]
+    fn test_best_fit() {
+        let mut regions = VirtualRegionAllocator::new();
+        regions.free(range(0, 16)).unwrap();
+        regions.free(range(32, 36)).unwrap();
+        regions.free(range(64, 72)).unwrap();
+
+        // the 4-page region is the best fit.
+        assert_eq!(regions.alloc(3, 1), Some(range(32, 35)));
+        // then the 8-page one.
+        assert_eq!(regions.alloc(5, 1), Some(range(64, 69)));
+        // only the 16-page region is left that fits.
+        assert_eq!(regions.alloc(4, 1), Some(range(0, 4)));
+        assert_eq!(regions.free_pages(), 12 + 1 + 3);
+    }
+
+    #[test]
+    fn test_aligned_alloc() {
+        let mut regions = VirtualRegionAllocator::new();
+        regions.free(range(3, 40)).unwrap();
+        assert_eq!(regions.alloc(8, 16), Some(range(16, 24)));
+        assert!(regions.contains(VirtualPage { number: 15 }));
+        assert!(!regions.contains(VirtualPage { number: 16 }));
+        assert!(regions.contains(VirtualPage { number: 24 }));
+        // 3..16 and 24..40 are still free.
+        assert_eq!(regions.free_pages(), 13 + 16);
+        assert_eq!(regions.alloc(16, 16), None);
+    }
+
+    #[test]
+    fn test_free_coalesces() {
+        let mut regions = VirtualRegionAllocator::new();
+        regions.free(range(0, 4)).unwrap();
+        regions.free(range(8, 12)).unwrap();
+        // neither neighbour is adjacent.
+        regions.free(range(14, 16)).unwrap();
+        assert_eq!(regions.regions().len(), 3);
+
+        // joins both neighbours.
+        regions.free(range(4, 8)).unwrap();
+        assert_eq!(regions.regions().len(), 2);
+        // joins the region before it.
+        regions.free(range(12, 13)).unwrap();
+        // joins the region after it.
+        regions.free(range(13, 14)).unwrap();
+        assert_eq!(regions.regions().len(), 1);
+
+        // everything merged, so all 16 pages can be allocated at once.
+        assert_eq!(regions.alloc(16, 1), Some(range(0, 16)));
+        assert_eq!(regions.regions().len(), 0);
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mut regions = VirtualRegionAllocator::new();
+        regions.free(range(0, 8)).unwrap();
+        assert!(regions.free(range(4, 12)).is_err());
+        assert!(regions.free(range(0, 1)).is_err());
+        assert_eq!(regions.free_pages(), 8);
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let mut regions = VirtualRegionAllocator::new();
+        assert_eq!(regions.alloc(1, 1), None);
+
+        regions.free(range(0, 8)).unwrap();
+        assert_eq!(regions.alloc(9, 1), None);
+        assert_eq!(regions.alloc(8, 1), Some(range(0, 8)));
+        assert_eq!(regions.alloc(1, 1), None);
+
+        // every slot is taken by a region that can't be merged.
+        for i in 0..MAX_FREE_REGIONS {
+            regions.free(range(i * 2, i * 2 + 1)).unwrap();
+        }
+        let last = MAX_FREE_REGIONS * 2;
+        assert!(regions.free(range(last, last + 1)).is_err());
+        // a range that merges doesn't need another slot.
+        regions.free(range(1, 2)).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//
+//  SOS: the Stupid Operating System
+//  by Eliza Weisman (eliza@elizas.website)
+//
+//  Copyright (c) 2017 Eliza Weisman
+//  Released under the terms of the MIT license. See `LICENSE` in the root
+//  directory of this repository for more information.
+//
+//! Serving byte allocations from a frame allocator.
+use core::cmp;
+
+use memory::{FrameRange, PhysicalPage as Frame, PAGE_SIZE, VAddr};
+
+use super::{Address, AllocErr, AllocResult, Allocator, Capacity, Layout};
+use super::frame::{Allocator as FrameAllocator, FrameStats};
+
+/// A frame allocator that can also be used as an `Allocator`.
+///
+/// Code that needs both frames and byte allocations, such as early boot
+/// code before the heap exists, can take a single `UnifiedAllocator`
+/// rather than two allocators. Frame allocations are passed straight
+/// through to the wrapped allocator.
+///
+/// Byte allocations are rounded up to whole frames, so every allocation
+/// is contiguous and frame aligned, but a small allocation wastes most of
+/// a frame: a 16 byte buffer still takes 4 KiB. This is only worth it for
+/// a few, large, or long-lived allocations; anything else should come
+/// from the heap.
+///
+/// The pointers returned are in the window mapping all of physical memory,
+/// so unless the allocator was made `with_offset`,
+/// `memory::set_phys_map_offset` must have been called before any bytes
+/// are allocated.
+pub struct UnifiedAllocator<A> { frames: A
+                               , offset: Option<VAddr>
+                               }
+
+impl<A> UnifiedAllocator<A> {
+    /// Serve both frame and byte allocations from `frames`.
+    pub const fn new(frames: A) -> Self {
+        UnifiedAllocator { frames: frames, offset: None }
+    }
+
+    /// Serve both frame and byte allocations from `frames`, with pointers
+    /// into a window mapping all of physical memory that starts at
+    /// `offset`, rather than the one set with `set_phys_map_offset`.
+    pub const fn with_offset(frames: A, offset: VAddr) -> Self {
+        UnifiedAllocator { frames: frames, offset: Some(offset) }
+    }
+
+    /// Returns the frame allocator that allocations are served from.
+    #[inline]
+    pub fn inner(&self) -> &A { &self.frames }
+
+    /// Returns the frame allocator, consuming the `UnifiedAllocator`.
+    #[inline]
+    pub fn into_inner(self) -> A { self.frames }
+}
+
+/// Returns the number of frames, and the alignment in frames, that an
+/// allocation with `layout` takes.
+#[inline]
+fn frames_for(layout: &Layout) -> (usize, usize) {
+    // even a zero-sized allocation needs a frame for its address.
+    let count = cmp::max(Frame::frames_for_bytes(layout.size()), 1);
+    let align_frames = cmp::max(layout.align() / PAGE_SIZE as usize, 1);
+    (count, align_frames)
+}
+
+unsafe impl<A> Allocator for UnifiedAllocator<A>
+where A: FrameAllocator {
+
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<Address, AllocErr> {
+        let (count, align_frames) = frames_for(&layout);
+        let start = self.frames.allocate_contiguous(count, align_frames)?;
+        trace!( target: "alloc", "allocated {} bytes in {} frames at {:?}"
+              , layout.size(), count, start);
+        match self.offset {
+            Some(offset) => Ok(start.to_virt_with(offset).as_mut_ptr())
+          , None => Ok(start.as_mut_ptr())
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: Address, layout: Layout) {
+        let (count, _) = frames_for(&layout);
+        let start = match self.offset {
+            Some(offset) => Frame::from_ptr_with(ptr, offset)
+          , None => Frame::from_ptr(ptr)
+        };
+        self.frames.deallocate_contiguous(start, count)
+    }
+
+    unsafe fn usable_size(&self, layout: &Layout) -> (Capacity, Capacity) {
+        let (count, _) = frames_for(layout);
+        (layout.size(), count * PAGE_SIZE as usize)
+    }
+}
+
+impl<A> FrameAllocator for UnifiedAllocator<A>
+where A: FrameAllocator {
+
+    #[inline]
+    unsafe fn allocate(&mut self) -> AllocResult<Frame> {
+        self.frames.allocate()
+    }
+
+    #[inline]
+    unsafe fn deallocate(&mut self, frame: Frame) {
+        self.frames.deallocate(frame)
+    }
+
+    #[inline]
+    unsafe fn allocate_near(&mut self, hint: Frame) -> AllocResult<Frame> {
+        self.frames.allocate_near(hint)
+    }
+
+    #[inline]
+    unsafe fn allocate_range(&mut self, num: usize) -> AllocResult<FrameRange> {
+        self.frames.allocate_range(num)
+    }
+
+    #[inline]
+    unsafe fn deallocate_range(&mut self, range: FrameRange) {
+        self.frames.deallocate_range(range)
+    }
+
+    #[inline]
+    unsafe fn allocate_contiguous(&mut self, count: usize, align_frames: usize)
+                                  -> AllocResult<Frame> {
+        self.frames.allocate_contiguous(count, align_frames)
+    }
+
+    #[inline]
+    unsafe fn deallocate_contiguous(&mut self, start: Frame, count: usize) {
+        self.frames.deallocate_contiguous(start, count)
+    }
+
+    #[inline]
+    unsafe fn add_ref(&mut self, frame: Frame) -> AllocResult<()> {
+        self.frames.add_ref(frame)
+    }
+
+    #[inline]
+    unsafe fn remove_ref(&mut self, frame: Frame) -> usize {
+        self.frames.remove_ref(frame)
+    }
+
+    #[inline]
+    fn reserve_range(&mut self, range: FrameRange) -> AllocResult<()> {
+        self.frames.reserve_range(range)
+    }
+
+    #[inline]
+    fn stats(&self) -> FrameStats { self.frames.stats() }
+
+    #[inline]
+    fn ref_count(&self, frame: Frame) -> Option<usize> {
+        self.frames.ref_count(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frame::bitmap::BitmapFrameAllocator;
+    use params::mem::Area;
+    use memory::PAddr;
+
+    #[test]
+    fn test_frames_and_bytes() {
+        // the pointers are only compared, never dereferenced.
+        let offset = VAddr::from(0xffff_8000_0000_0000);
+        let mut bitmap = [0; 1];
+        let areas = [ Area { start_addr: PAddr::from(0x1000)
+                           , end_addr: PAddr::from(0x8fff)
+                           , is_usable: true }
+                    ];
+        let frames = BitmapFrameAllocator::new(&mut bitmap, areas.iter());
+        let mut alloc = UnifiedAllocator::with_offset(frames, offset);
+        unsafe {
+            let used = alloc.stats().used;
+            let frame = alloc.allocate().unwrap();
+            let layout = Layout::from_size_align(16, 8);
+            let buf = alloc.alloc(layout.clone()).unwrap();
+            // the 16 bytes take a whole frame of their own.
+            let buf_frame = Frame::from_ptr_with(buf, offset);
+            assert!(buf_frame != frame);
+            assert_eq!(buf, buf_frame.to_virt_with(offset).as_mut_ptr());
+            assert_eq!(alloc.stats().used, used + 2);
+            assert_eq!( alloc.usable_size(&layout)
+                      , (16, PAGE_SIZE as usize));
+
+            alloc.dealloc(buf, layout);
+            alloc.deallocate(frame);
+            assert_eq!(alloc.stats().used, used);
+        }
+    }
+}
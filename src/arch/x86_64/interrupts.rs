@@ -157,7 +157,7 @@ lazy_static! {
         idt.segment_not_present = Gate::from(segment_not_present as ErrorCodeHandler);
         idt.stack_segment_fault = Gate::from(stack_segment_fault as ErrorCodeHandler);
         idt.general_protection_fault = Gate::from(general_protection_fault as ErrorCodeHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
+        idt.page_fault = Gate::from(demand_page_fault as ErrorCodeHandler);
 
         idt.floating_point_error = Gate::from(floating_point_error as InterruptHandler);
         idt.alignment_check = Gate::from(alignment_check as ErrorCodeHandler);
@@ -165,7 +165,7 @@ lazy_static! {
         idt.simd_fp_exception = Gate::from(simd_fp_exception as InterruptHandler);
 
         idt.breakpoint = Gate::from(breakpoint as InterruptHandler);
-        idt.page_fault = Gate::from(page_fault as ErrorCodeHandler);
+        idt.page_fault = Gate::from(demand_page_fault as ErrorCodeHandler);
 
         idt.interrupts[0x20 - 32] = Gate::from(timer as InterruptHandler);
         idt.interrupts[0x21 - 32] = Gate::from(keyboard as InterruptHandler);
@@ -205,6 +205,25 @@ pub extern "x86-interrupt" fn breakpoint(frame: &InterruptFrame) {
    }
 }
 
+/// Handles page faults, mapping pages in regions reserved for demand
+/// paging, and reporting every other fault.
+#[no_mangle] #[inline(never)]
+pub extern "x86-interrupt" fn demand_page_fault( frame: &InterruptFrame
+                                               , error_code: usize) {
+    use cpu::control_regs::cr2;
+    use memory::VAddr;
+
+    // this is safe because we're in kernel mode
+    let faulting = VAddr::from(unsafe { cr2::read() });
+    let resolved = ::paging::handle_page_fault(faulting);
+    if let Err(why) = resolved {
+        exception_inner!( "Page Fault", "Fault"
+                        , "Any memory reference", frame, error_code);
+        debug!("the page fault couldn't be resolved: {}", why);
+        loop {}
+    }
+}
+
 /// Empty dummy handler for undefined interrupts.
 #[no_mangle] #[inline(never)]
 pub extern "x86-interrupt" fn empty_handler(_frame: &InterruptFrame) {
@@ -17,25 +17,55 @@ pub mod interrupts;
 pub const ARCH_BITS: u8 = 64;
 
 extern {
-    // TODO: It would be really nice if there was a less ugly way of doing
-    // this... (read: after the Revolution when we add memory regions to the
-    // heap programmatically.)
+    // These are only read by `kernel_heap_bounds` and `kernel_stack_range`,
+    // which turn them into addresses and page ranges.
     #[link_name = "heap_base_addr"]
     #[linkage = "external"]
-    pub static HEAP_BASE: *mut u8;
+    static HEAP_BASE: *mut u8;
     #[link_name = "heap_top_addr"]
     #[linkage = "external"]
-    pub static HEAP_TOP: *mut u8;
-    // Of course, we will still need to export the kernel stack addresses like
-    // this, but it would be nice if they could be, i dont know, not mut u8s
-    // pointers, like God intended.
-    #[link_name = "stack_base"]
-    pub static STACK_BASE: *mut u8;
-    #[link_name = "stack_top"]
-    pub static STACK_TOP: *mut u8;
+    static HEAP_TOP: *mut u8;
+    #[link_name = "stack_base_addr"]
+    #[linkage = "external"]
+    static STACK_BASE: *mut u8;
+    #[link_name = "stack_top_addr"]
+    #[linkage = "external"]
+    static STACK_TOP: *mut u8;
+}
+
+use memory::{PAddr, Page, PageRange, VAddr, VirtualPage};
+
+/// Returns the pages from the one containing `base` to the one containing
+/// the last byte before `top`.
+///
+/// If `top` isn't above `base`, the range is empty.
+fn pages_between(base: usize, top: usize) -> PageRange {
+    let start = VirtualPage::containing(VAddr::from(base));
+    if top <= base {
+        start .. start
+    } else {
+        start .. VirtualPage::containing(VAddr::from(top - 1)).next()
+    }
+}
+
+/// Returns the pages of the kernel stack reserved by the boot code.
+pub fn kernel_stack_range() -> PageRange {
+    // this is safe since the boot code always exports the stack addresses
+    unsafe { pages_between(STACK_BASE as usize, STACK_TOP as usize) }
+}
+
+/// Returns the base and top addresses of the kernel heap reserved by the
+/// boot code.
+pub fn kernel_heap_bounds() -> (VAddr, VAddr) {
+    // this is safe since the boot code always exports the heap addresses
+    unsafe { (VAddr::from(HEAP_BASE), VAddr::from(HEAP_TOP)) }
 }
 
-use memory::PAddr;
+/// Returns the pages of the kernel heap reserved by the boot code.
+pub fn kernel_heap_range() -> PageRange {
+    let (base, top) = kernel_heap_bounds();
+    pages_between(*base, *top)
+}
 
 /// Trampoline to ensure we have a correct stack frame for calling [`arch_init`]
 ///
@@ -66,7 +96,6 @@ pub unsafe extern "C" fn long_mode_init() {
 /// bad problem and not go to space today.
 #[no_mangle]
 pub extern "C" fn arch_init(multiboot_addr: PAddr) {
-    use cpu::{control_regs, msr};
     use params::{InitParams, mem};
 
     kinfoln!(dots: " . ", "Beginning `arch_init()` for x86_64");
@@ -126,14 +155,17 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
     kinfoln!( dots: " . . ", "Multiboot info begins at {:#x} and ends at {:#x}."
             , multiboot_addr, multiboot_end);
 
+    // the heap and stack are identity mapped.
+    let (heap, stack) = (kernel_heap_range(), kernel_stack_range());
+    let phys = |page: VirtualPage| PAddr::from(page.base().as_usize() as u64);
     let mut params = InitParams { kernel_base: kernel_begin
                             , kernel_top: kernel_end
                             , multiboot_start: Some(multiboot_addr)
                             , multiboot_end: Some(multiboot_end)
-                            , heap_base: unsafe { PAddr::from(HEAP_BASE) }
-                            , heap_top: unsafe { PAddr::from(HEAP_TOP) }
-                            , stack_base: unsafe { PAddr::from(STACK_BASE) }
-                            , stack_top: unsafe { PAddr::from(STACK_TOP) }
+                            , heap_base: phys(heap.start)
+                            , heap_top: phys(heap.end)
+                            , stack_base: phys(stack.start)
+                            , stack_top: phys(stack.end)
                             , elf_sections: Some(elf_sections_tag.sections())
                             , ..Default::default()
                         };
@@ -149,19 +181,33 @@ pub extern "C" fn arch_init(multiboot_addr: PAddr) {
         if a.is_usable == true { params.mem_map.push(a); }
     }
 
-     //-- enable flags needed for paging ------------------------------------
-     unsafe {
-        //  control_regs::cr0::enable_write_protect(true);
-        //  kinfoln!(dots: " . ", "Page write protect ENABED" );
-
-        let efer = msr::read(msr::IA32_EFER);
-        trace!("EFER = {:#x}", efer);
-        msr::write(msr::IA32_EFER, efer | (1 << 11));
-        let efer = msr::read(msr::IA32_EFER);
-        trace!("EFER = {:#x}", efer);
-        kinfoln!(dots: " . ", "Page no execute bit ENABLED");
-     }
+     // the no-execute bit is enabled by `kernel_remap()`, before any page
+     // table entries with it set are written.
 
     kinfoln!(dots: " . ", "Transferring to `kernel_init()`.");
     ::kernel_init(&params);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::pages_between;
+    use memory::{MemRange, VirtualPage};
+
+    #[test]
+    fn test_pages_between() {
+        // a 4-page stack and 4 MiB heap, like the ones the boot code reserves
+        let stack = pages_between(0x10_3000, 0x10_7000);
+        assert_eq!(stack.start, VirtualPage { number: 0x103 });
+        assert_eq!(stack.end, VirtualPage { number: 0x107 });
+        let heap = pages_between(0x10_7000, 0x50_7000);
+        assert_eq!(heap.length(), 1024);
+        assert_eq!(heap.start, stack.end);
+
+        // unaligned symbols are rounded out to whole pages.
+        let range = pages_between(0x1800, 0x2001);
+        assert_eq!(range.start, VirtualPage { number: 1 });
+        assert_eq!(range.end, VirtualPage { number: 3 });
+
+        assert!(pages_between(0x5000, 0x5000).is_empty_range());
+    }
+}
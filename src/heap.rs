@@ -6,13 +6,45 @@
 //  Released under the terms of the MIT license. See `LICENSE` in the root
 //  directory of this repository for more information.
 //
-use params::InitParams;
+use memory::VAddr;
+use sos_alloc::buddy::{self, HeapInitErr};
+
+/// Returns the start and size of the heap between `base` and `top`.
+///
+/// # Returns
+/// + `Err(HeapInitErr::Inverted)` if `top` isn't above `base`
+fn heap_region(base: VAddr, top: VAddr) -> Result<(VAddr, usize), HeapInitErr> {
+    match (*top).checked_sub(*base) {
+        Some(size) if size > 0 => Ok((base, size))
+      , _ => Err(HeapInitErr::Inverted { base: base, top: top })
+    }
+}
 
 /// Initialise the kernel heap.
-//  TODO: this is the Worst Thing In The Universe. De-stupid-ify it.
-pub unsafe fn initialize<'a>(params: &InitParams) -> Result<&'a str, &'a str> {
-    // let heap_base_ptr = params.heap_base.as_mut_ptr();
-    let heap_size: u64 = (params.heap_top - params.heap_base).into();
-    // buddy::system::init_heap(heap_base_ptr, heap_size as usize);
-    Ok("[ OKAY ]")
+///
+/// The heap lies between the `kernel_heap_bounds` reserved by the boot code,
+/// which is identity mapped.
+pub unsafe fn initialize() -> Result<(), HeapInitErr> {
+    let (base, top) = ::arch::kernel_heap_bounds();
+    let heap = heap_region(base, top)?;
+    buddy::system::init_heap(&[heap])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::heap_region;
+    use memory::VAddr;
+    use sos_alloc::buddy::HeapInitErr;
+
+    #[test]
+    fn test_heap_region() {
+        let (base, top) = (VAddr::from(0x10_7000), VAddr::from(0x50_7000));
+        assert_eq!(heap_region(base, top), Ok((base, 0x40_0000)));
+
+        // symbols the linker placed the wrong way round aren't a heap.
+        assert_eq!( heap_region(top, base)
+                  , Err(HeapInitErr::Inverted { base: top, top: base }));
+        assert_eq!( heap_region(base, base)
+                  , Err(HeapInitErr::Inverted { base: base, top: base }));
+    }
 }
@@ -134,9 +134,11 @@ pub fn kernel_init(params: &InitParams) {
 
     attempt!(paging::test_paging(&mut frame_allocator) =>
              dots: " . . ", "Testing paging...");
+    // the page fault handler borrows the page table from here on.
+    let _ = paging::set_fault_table(page_table);
 
     // -- initialize the heap ------------------------------------------------
-    attempt!( unsafe { heap::initialize(params) } =>
+    attempt!( unsafe { heap::initialize() } =>
              dots: " . ", "Intializing heap...");
     kinfoln!( dots: " . . "
             , "Heap begins at {:#x} and ends at {:#x}"
@@ -27,7 +27,7 @@ macro_rules! attempt {
                         .fail()
                         .expect("Could not write to VGA buffer! Something's \
                                  really wrong!");
-                    panic!("{:?}", why);
+                    panic!("{}", why);
               }
         }
     });